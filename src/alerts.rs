@@ -0,0 +1,44 @@
+// alerts.rs
+use crate::config::config;
+use serde_json::json;
+
+// Logs an operator alert and forwards it to the operator
+pub async fn send_alert(message: &str) {
+    tracing::error!("ALERT: {}", message);
+    notify_operator(message).await;
+}
+
+// Forwards a message to ALERT_WEBHOOK_URL when configured (Slack/Discord style webhooks) and to
+// ALERT_TELEGRAM_CHAT_ID through TELEGRAM_BOT_TOKEN's bot when both are set
+pub async fn notify_operator(message: &str) {
+    let config = config();
+    let client = reqwest::Client::new();
+    if let Some(webhook_url) = &config.alert_webhook_url {
+        let response = client
+            .post(webhook_url)
+            .json(&json!({ "text": message, "content": message }))
+            .send()
+            .await;
+        match response {
+            Ok(response) if !response.status().is_success() => {
+                eprintln!("Alert webhook returned {}", response.status())
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Failed to send alert: {:?}", e),
+        }
+    }
+    if let (Some(token), Some(chat_id)) = (&config.telegram_bot_token, &config.alert_telegram_chat_id) {
+        let response = client
+            .post(format!("https://api.telegram.org/bot{}/sendMessage", token))
+            .json(&json!({ "chat_id": chat_id, "text": message }))
+            .send()
+            .await;
+        match response {
+            Ok(response) if !response.status().is_success() => {
+                eprintln!("Telegram alert returned {}", response.status())
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Failed to send Telegram alert: {}", e.without_url()),
+        }
+    }
+}
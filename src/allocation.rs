@@ -0,0 +1,100 @@
+// allocation.rs
+// How a user's SOL is split at the lockin stage. An allocation lists output mints with a share in
+// basis points each, adding up to 10000. The native SOL mint keeps its share as SOL, sent straight to
+// the user's wallet or, for users who opted into staking, deposited into STAKE_POOL_ADDRESS. Every
+// other share is swapped into its mint through Jupiter. Users without an allocation get all LOCKIN.
+use crate::config::config;
+use crate::error_handling::AppError;
+use crate::mongo::{get_users_collection, AllocationLeg};
+use mongodb::bson::doc;
+use solana_sdk::pubkey::Pubkey;
+use std::collections::HashSet;
+use std::str::FromStr;
+
+pub const NATIVE_SOL_MINT: &str = "So11111111111111111111111111111111111111112";
+pub const MAX_ALLOCATION_LEGS: usize = 5;
+
+// One output of a lockin stage
+#[derive(Debug, Clone, Copy)]
+pub struct SwapLeg {
+    // Position in the user's allocation, 0 without one
+    pub index: usize,
+    pub output_mint: Pubkey,
+    pub amount_lamports: u64,
+    // Deposit the SOL into the stake pool rather than sending it, only set on SOL legs
+    pub stake: bool,
+}
+
+// Checks an allocation and resolves its "SOL" and "LOCKIN" aliases into mints
+pub fn validate(allocation: Vec<AllocationLeg>) -> Result<Vec<AllocationLeg>, AppError> {
+    if allocation.is_empty() || allocation.len() > MAX_ALLOCATION_LEGS {
+        return Err(AppError::BadRequest(format!(
+            "An allocation needs between 1 and {} legs",
+            MAX_ALLOCATION_LEGS
+        )));
+    }
+    let mut mints = HashSet::new();
+    let mut total_bps = 0u32;
+    let mut resolved = Vec::with_capacity(allocation.len());
+    for leg in allocation {
+        let mint = match leg.mint.trim() {
+            "SOL" => NATIVE_SOL_MINT.to_string(),
+            "LOCKIN" => config().lockin_mint.clone(),
+            mint => Pubkey::from_str(mint)
+                .map_err(|_| AppError::BadRequest(format!("{:?} is not a base58 mint address", mint)))?
+                .to_string(),
+        };
+        if leg.bps == 0 {
+            return Err(AppError::BadRequest(format!("The share of {} must be above 0 bps", mint)));
+        }
+        if !mints.insert(mint.clone()) {
+            return Err(AppError::BadRequest(format!("{} appears more than once", mint)));
+        }
+        total_bps += leg.bps as u32;
+        resolved.push(AllocationLeg { mint, bps: leg.bps });
+    }
+    if total_bps != 10_000 {
+        return Err(AppError::BadRequest(format!(
+            "The shares must add up to 10000 bps, they add up to {}",
+            total_bps
+        )));
+    }
+    Ok(resolved)
+}
+
+// Splits `amount_lamports` into the legs of an allocation. The last leg takes the rounding remainder
+// and legs too small to get a lamport are left out. The SOL leg is staked with `stake_sol` while a
+// stake pool is configured.
+pub fn split(allocation: &[AllocationLeg], stake_sol: bool, amount_lamports: u64) -> Result<Vec<SwapLeg>, AppError> {
+    if allocation.is_empty() {
+        let output_mint = Pubkey::from_str(&config().lockin_mint)
+            .map_err(|e| AppError::CustomError(format!("Invalid LOCKIN_MINT: {}", e)))?;
+        return Ok(vec![SwapLeg { index: 0, output_mint, amount_lamports, stake: false }]);
+    }
+    let stake_sol = stake_sol && config().stake_pool_address.is_some();
+
+    let mut legs = Vec::with_capacity(allocation.len());
+    let mut remaining = amount_lamports;
+    for (index, leg) in allocation.iter().enumerate() {
+        let output_mint = Pubkey::from_str(&leg.mint)
+            .map_err(|e| AppError::CustomError(format!("Invalid allocation mint {}: {}", leg.mint, e)))?;
+        let leg_amount = if index == allocation.len() - 1 {
+            remaining
+        } else {
+            (amount_lamports as u128 * leg.bps as u128 / 10_000) as u64
+        };
+        remaining -= leg_amount;
+        if leg_amount > 0 {
+            let stake = stake_sol && leg.mint == NATIVE_SOL_MINT;
+            legs.push(SwapLeg { index, output_mint, amount_lamports: leg_amount, stake });
+        }
+    }
+    Ok(legs)
+}
+
+// The legs `amount_lamports` of the user's SOL is split into
+pub async fn user_legs(user_id: i64, amount_lamports: u64) -> Result<Vec<SwapLeg>, AppError> {
+    let user = get_users_collection().await?.find_one(doc! { "user_id": user_id }, None).await?;
+    let (allocation, stake_sol) = user.map(|user| (user.allocation, user.stake_sol)).unwrap_or_default();
+    split(&allocation, stake_sol, amount_lamports)
+}
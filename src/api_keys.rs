@@ -0,0 +1,265 @@
+// api_keys.rs
+// API keys and their scopes. The key handed out at registration (the primary key) has every scope,
+// and users can add more keys in the `api_keys` collection, each limited to some scopes and revocable
+// on its own. Secrets stay encrypted with the primary key: every additional key keeps it sealed under
+// itself, so keys can be added, rotated and revoked without re-encrypting the wallets.
+use futures_util::TryStreamExt;
+use mongodb::bson::{doc, oid::ObjectId, DateTime as BsonDateTime, Document};
+use mongodb::Database;
+use zeroize::Zeroizing;
+
+use crate::encryption::{api_key_hash, open_for_token, reencrypt_user, seal_for_token};
+use crate::entropy;
+use crate::error_handling::AppError;
+use crate::mongo::User;
+
+pub const SCOPE_READ_ONLY: &str = "read-only";
+pub const SCOPE_TRADE: &str = "trade";
+pub const SCOPE_WITHDRAW: &str = "withdraw";
+pub const SCOPE_DECRYPT: &str = "decrypt";
+
+pub const SCOPES: &[&str] = &[SCOPE_READ_ONLY, SCOPE_TRADE, SCOPE_WITHDRAW, SCOPE_DECRYPT];
+
+// The id the primary key is listed and revoked under
+pub const PRIMARY_KEY_ID: &str = "primary";
+
+// A user authenticated by one of their API keys
+pub struct ApiKeyAuth {
+    pub user: User,
+    pub key_id: String,
+    pub scopes: Vec<String>,
+    // The primary key, which the user's secrets are encrypted with
+    pub secrets_key: Zeroizing<String>,
+}
+
+impl ApiKeyAuth {
+    pub fn allows(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|allowed| allowed == scope)
+    }
+
+    // Fails with Forbidden unless the key has `scope`
+    pub fn require(&self, scope: &str) -> Result<(), AppError> {
+        if self.allows(scope) {
+            Ok(())
+        } else {
+            Err(AppError::Forbidden)
+        }
+    }
+}
+
+// Finds the user an API key belongs to, None for unknown and revoked keys. Users from before API keys
+// were hashed are still found by the plaintext primary key, which is then replaced by its hash.
+pub async fn authenticate(db: &Database, api_key: &str) -> Result<Option<ApiKeyAuth>, AppError> {
+    let hash = api_key_hash(api_key);
+    let users = db.collection::<User>("users");
+    let keys = db.collection::<Document>("api_keys");
+
+    let filter = doc! { "key_hash": &hash, "revoked_at": { "$exists": false } };
+    if let Some(key) = keys.find_one(filter, None).await? {
+        let user_id = key.get_i64("user_id").map_err(|_| AppError::Unauthorized)?;
+        let Some(user) = users.find_one(doc! { "user_id": user_id }, None).await? else {
+            return Ok(None);
+        };
+        let secrets_key = key
+            .get_str("secrets_key")
+            .map_err(|_| AppError::DecryptionError)
+            .and_then(|sealed| open_for_token(api_key, sealed))?;
+        let key_id = key.get_object_id("_id").map_err(|_| AppError::Unauthorized)?;
+        keys.update_one(doc! { "_id": key_id }, doc! { "$set": { "last_used_at": BsonDateTime::now() } }, None)
+            .await?;
+        return Ok(Some(ApiKeyAuth {
+            user,
+            key_id: key_id.to_hex(),
+            scopes: string_array(&key, "scopes"),
+            secrets_key,
+        }));
+    }
+
+    let filter = doc! { "$or": [{ "api_key_hash": &hash }, { "api_key": api_key }] };
+    let Some(user) = users.find_one(filter, None).await? else {
+        return Ok(None);
+    };
+    if user.api_key.is_some() {
+        users
+            .update_one(
+                doc! { "_id": user.id, "api_key": api_key },
+                doc! { "$set": { "api_key_hash": &hash }, "$unset": { "api_key": "" } },
+                None,
+            )
+            .await?;
+    }
+    Ok(Some(ApiKeyAuth {
+        user,
+        key_id: PRIMARY_KEY_ID.to_string(),
+        scopes: SCOPES.iter().map(|scope| scope.to_string()).collect(),
+        secrets_key: Zeroizing::new(api_key.to_string()),
+    }))
+}
+
+fn string_array(document: &Document, key: &str) -> Vec<String> {
+    document
+        .get_array(key)
+        .map(|values| values.iter().filter_map(|value| value.as_str().map(str::to_string)).collect())
+        .unwrap_or_default()
+}
+
+// Creates a key for the authenticated user limited to `scopes`, which have to be scopes of the key
+// creating it. Returns the new key's id and the key, which is only shown this once.
+pub async fn create_api_key(
+    db: &Database,
+    auth: &ApiKeyAuth,
+    name: Option<&str>,
+    scopes: &[String],
+) -> Result<(String, String), AppError> {
+    if let Some(unknown) = scopes.iter().find(|scope| !SCOPES.contains(&scope.as_str())) {
+        return Err(AppError::BadRequest(format!(
+            "Unknown scope {}, expected one of {}",
+            unknown,
+            SCOPES.join(", ")
+        )));
+    }
+    if scopes.is_empty() {
+        return Err(AppError::BadRequest("An API key needs at least one scope".to_string()));
+    }
+    if !scopes.iter().all(|scope| auth.allows(scope)) {
+        return Err(AppError::Forbidden);
+    }
+
+    let api_key = entropy::uuid()?.to_string();
+    let id = ObjectId::new();
+    db.collection::<Document>("api_keys")
+        .insert_one(
+            doc! {
+                "_id": id,
+                "key_hash": api_key_hash(&api_key),
+                "user_id": auth.user.user_id,
+                "name": name,
+                "scopes": scopes,
+                "secrets_key": seal_for_token(&api_key, &auth.secrets_key)?,
+                "created_at": BsonDateTime::now(),
+            },
+            None,
+        )
+        .await?;
+    Ok((id.to_hex(), api_key))
+}
+
+// The user's keys, the primary one first, revoked keys included
+pub async fn list_api_keys(db: &Database, user: &User) -> Result<Vec<Document>, AppError> {
+    let mut keys = Vec::new();
+    if user.api_key_hash.is_some() || user.api_key.is_some() {
+        keys.push(doc! { "id": PRIMARY_KEY_ID, "scopes": SCOPES.to_vec() });
+    }
+    let mut cursor = db
+        .collection::<Document>("api_keys")
+        .find(doc! { "user_id": user.user_id }, None)
+        .await?;
+    while let Some(key) = cursor.try_next().await? {
+        let mut listed = doc! {
+            "id": key.get_object_id("_id").map(|id| id.to_hex()).unwrap_or_default(),
+            "name": key.get("name").cloned().unwrap_or(mongodb::bson::Bson::Null),
+            "scopes": string_array(&key, "scopes"),
+        };
+        for field in ["created_at", "last_used_at", "revoked_at"] {
+            if let Ok(at) = key.get_datetime(field) {
+                listed.insert(field, at.try_to_rfc3339_string().unwrap_or_default());
+            }
+        }
+        keys.push(listed);
+    }
+    Ok(keys)
+}
+
+// Revokes one of the authenticated user's keys and ends the sessions opened with it. A key can only
+// revoke keys whose scopes it has itself, and the last active key can't be revoked, since the user's
+// secrets couldn't be decrypted anymore.
+pub async fn revoke_api_key(db: &Database, auth: &ApiKeyAuth, id: &str) -> Result<(), AppError> {
+    let keys = db.collection::<Document>("api_keys");
+    let active_keys = keys
+        .count_documents(doc! { "user_id": auth.user.user_id, "revoked_at": { "$exists": false } }, None)
+        .await?;
+    let has_primary = auth.user.api_key_hash.is_some() || auth.user.api_key.is_some();
+    let active = active_keys + u64::from(has_primary);
+
+    if id == PRIMARY_KEY_ID {
+        if !has_primary {
+            return Err(AppError::NotFound);
+        }
+        if !SCOPES.iter().all(|scope| auth.allows(scope)) {
+            return Err(AppError::Forbidden);
+        }
+        if active <= 1 {
+            return Err(AppError::BadRequest("Create another API key before revoking the last one".to_string()));
+        }
+        db.collection::<User>("users")
+            .update_one(doc! { "_id": auth.user.id }, doc! { "$unset": { "api_key_hash": "", "api_key": "" } }, None)
+            .await?;
+    } else {
+        let object_id = ObjectId::parse_str(id).map_err(|_| AppError::NotFound)?;
+        let filter = doc! { "_id": object_id, "user_id": auth.user.user_id, "revoked_at": { "$exists": false } };
+        let key = keys.find_one(filter.clone(), None).await?.ok_or(AppError::NotFound)?;
+        if !string_array(&key, "scopes").iter().all(|scope| auth.allows(scope)) {
+            return Err(AppError::Forbidden);
+        }
+        if active <= 1 {
+            return Err(AppError::BadRequest("Create another API key before revoking the last one".to_string()));
+        }
+        keys.update_one(filter, doc! { "$set": { "revoked_at": BsonDateTime::now() } }, None)
+            .await?;
+    }
+
+    db.collection::<Document>("sessions")
+        .delete_many(doc! { "user_id": auth.user.user_id, "api_key_id": id }, None)
+        .await?;
+    Ok(())
+}
+
+// Replaces the primary key with a new one and re-encrypts the user's secrets under it, returning the
+// new key. The secrets and the key's hash are swapped in one update, which fails if the secrets
+// changed during the re-encryption. Every other key and session holds the old key, so they are all
+// revoked.
+pub async fn rotate_primary_key(db: &Database, auth: &ApiKeyAuth) -> Result<String, AppError> {
+    let api_key = entropy::uuid()?.to_string();
+    let (mut filter, mut update) = reencrypt_user(&auth.user, &auth.secrets_key, &api_key).await?;
+    update
+        .get_document_mut("$set")
+        .map_err(|_| AppError::InternalServerError)?
+        .insert("api_key_hash", api_key_hash(&api_key));
+    // The TOTP secret is sealed under the old key too
+    if let Some(sealed) = auth.user.totp_secret.as_deref() {
+        let secret = open_for_token(&auth.secrets_key, sealed)?;
+        filter.insert("totp_secret", sealed);
+        update
+            .get_document_mut("$set")
+            .map_err(|_| AppError::InternalServerError)?
+            .insert("totp_secret", seal_for_token(&api_key, &secret)?);
+    }
+    match update.get_document_mut("$unset") {
+        Ok(unset) => {
+            unset.insert("api_key", "");
+        }
+        Err(_) => {
+            update.insert("$unset", doc! { "api_key": "" });
+        }
+    }
+
+    let result = db.collection::<User>("users").update_one(filter, update, None).await?;
+    if result.matched_count == 0 {
+        return Err(AppError::ServiceUnavailable(
+            "The secrets changed during the rotation, try again".to_string(),
+        ));
+    }
+
+    db.collection::<Document>("api_keys")
+        .update_many(
+            doc! { "user_id": auth.user.user_id, "revoked_at": { "$exists": false } },
+            doc! { "$set": { "revoked_at": BsonDateTime::now() } },
+            None,
+        )
+        .await?;
+    db.collection::<Document>("sessions")
+        .delete_many(doc! { "user_id": auth.user.user_id }, None)
+        .await?;
+    println!("Rotated the API key of user {}", auth.user.user_id);
+    Ok(api_key)
+}
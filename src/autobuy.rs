@@ -0,0 +1,179 @@
+// autobuy.rs
+use crate::config::config;
+use crate::error_handling::AppError;
+use crate::lockin::DEFAULT_SLIPPAGE_BPS;
+use crate::maintenance;
+use crate::mongo::{get_transactions_collection, get_users_collection, AtomicWrites, User};
+use crate::poller::{hold_for_attention, process_successful_transaction, PipelineClients};
+use crate::transaction_state::{initial_state_fields, TransactionState, TransactionStateMachine};
+use crate::validation::solana_address;
+use mongodb::bson::{doc, oid::ObjectId, DateTime as BsonDateTime, Document};
+use mongodb::Collection;
+use std::time::Duration;
+use tokio::time::interval;
+
+// Smallest amount Kraken will trade, pending funds below it are left to accumulate
+pub const MIN_AUTOBUY_AMOUNT: f64 = 0.0001;
+
+// Holds a deposit on Kraken by adding it to the user's pending balance, as part of `writes`
+pub async fn hold_deposit(
+    writes: &mut AtomicWrites,
+    users_collection: &Collection<User>,
+    user_id: i64,
+    amount: f64,
+) -> Result<(), AppError> {
+    writes
+        .update_one(
+            users_collection,
+            doc! { "user_id": user_id },
+            doc! { "$inc": { "pending_autobuy": amount } },
+        )
+        .await?;
+    Ok(())
+}
+
+// Adds a deposit to the user's pending autobuy balance and claims the whole balance once it reaches the threshold
+pub async fn accumulate_deposit(
+    users_collection: &Collection<User>,
+    user_id: i64,
+    amount: f64,
+    threshold: f64,
+) -> Result<Option<f64>, AppError> {
+    hold_deposit(&mut AtomicWrites::none(), users_collection, user_id, amount).await?;
+
+    let user = match users_collection.find_one(doc! { "user_id": user_id }, None).await? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+    println!(
+        "Autobuy pending for user {}: {} / {}",
+        user_id, user.pending_autobuy, threshold
+    );
+    if user.pending_autobuy < threshold {
+        return Ok(None);
+    }
+
+    claim_pending(users_collection, user_id, user.pending_autobuy).await
+}
+
+// Atomically takes `pending` out of the user's pending autobuy balance, so concurrent deposits can't buy it twice
+pub(crate) async fn claim_pending(
+    users_collection: &Collection<User>,
+    user_id: i64,
+    pending: f64,
+) -> Result<Option<f64>, AppError> {
+    let result = users_collection
+        .update_one(
+            doc! { "user_id": user_id, "pending_autobuy": { "$gte": pending } },
+            doc! { "$inc": { "pending_autobuy": -pending } },
+            None,
+        )
+        .await?;
+    if result.modified_count == 1 {
+        Ok(Some(pending))
+    } else {
+        Ok(None)
+    }
+}
+
+// Starts a scheduler that buys pending balances whose threshold was lowered or cleared since the last deposit
+pub async fn start_autobuy_scheduler() -> Result<(), AppError> {
+    let mut interval = interval(Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        if maintenance::is_enabled() {
+            continue;
+        }
+        if let Err(e) = run_due_autobuys().await {
+            eprintln!("Autobuy scheduler failed: {:?}", e);
+        }
+    }
+}
+
+async fn run_due_autobuys() -> Result<(), AppError> {
+    let users_collection = get_users_collection().await?;
+    let transactions_collection = get_transactions_collection().await?;
+
+    // Users on a DCA plan have their pending balance drawn down by the DCA scheduler instead. Balances
+    // under MIN_DEPOSIT_BTC are left to accumulate.
+    let min_pending = config().min_deposit_btc.map_or(MIN_AUTOBUY_AMOUNT, |min_deposit| min_deposit.max(MIN_AUTOBUY_AMOUNT));
+    let filter = doc! {
+        "pending_autobuy": { "$gte": min_pending },
+        "dca_active": { "$ne": true },
+        "$or": [
+            { "autobuy_amount": null },
+            { "$expr": { "$gte": ["$pending_autobuy", "$autobuy_amount"] } },
+        ]
+    };
+    let mut cursor = users_collection.find(filter, None).await?;
+    while cursor.advance().await? {
+        let user = cursor.deserialize_current()?;
+        let amount = match claim_pending(&users_collection, user.user_id, user.pending_autobuy).await? {
+            Some(amount) => amount,
+            None => continue,
+        };
+        println!("Autobuy triggered for user {}: {}", user.user_id, amount);
+
+        if let Err(e) = run_purchase(&users_collection, &transactions_collection, &user, amount, "BTC", "Autobuy").await {
+            eprintln!("Autobuy failed for user {}: {:?}", user.user_id, e);
+        }
+    }
+    Ok(())
+}
+
+// Buys `amount` of `deposit_asset` worth of LOCKIN for the user out of funds already held on Kraken
+pub(crate) async fn run_purchase(
+    users_collection: &Collection<User>,
+    transactions_collection: &Collection<Document>,
+    user: &User,
+    amount: f64,
+    deposit_asset: &str,
+    source: &str,
+) -> Result<ObjectId, AppError> {
+    // Record the purchase as its own transaction so fills and fees have somewhere to live.
+    // The funds are already on Kraken, so the purchase starts out settled.
+    let mut tx = doc! {
+        "user_id": user.user_id,
+        "amount": amount,
+        // Already taken out of the user's balance, what a release or retry of the purchase sells
+        "purchase_amount": amount,
+        "deposit_asset": deposit_asset,
+        "source": source,
+        "timestamp": BsonDateTime::now(),
+    };
+    tx.extend(initial_state_fields(TransactionState::DepositSettled));
+    let tx_id = transactions_collection
+        .insert_one(tx, None)
+        .await?
+        .inserted_id
+        .as_object_id()
+        .ok_or_else(|| AppError::CustomError("Inserted transaction has no ObjectId".to_string()))?;
+
+    let state_machine = TransactionStateMachine::new(transactions_collection.clone(), tx_id);
+
+    let user_sol_address = match solana_address(user.solana_public_key.as_deref().unwrap_or_default()) {
+        Ok(address) => address,
+        Err(e) => {
+            hold_for_attention(users_collection, &state_machine, user.user_id, &e.to_string()).await?;
+            return Err(e);
+        }
+    };
+    if let Err(e) = process_successful_transaction(
+        &PipelineClients::live(),
+        amount,
+        deposit_asset,
+        user_sol_address,
+        user.user_id,
+        users_collection,
+        transactions_collection,
+        &state_machine,
+        user.deposited_sats(),
+        user.slippage_bps.unwrap_or(DEFAULT_SLIPPAGE_BPS),
+    )
+    .await
+    {
+        state_machine.fail(&e.report()).await?;
+        return Err(e);
+    }
+    Ok(tx_id)
+}
@@ -0,0 +1,157 @@
+// backup.rs
+// Password protected wallet backups. The key is stretched with Argon2id and the backup sealed with
+// AES-256-GCM, every parameter needed to open it is stored in the blob so it can be decrypted
+// offline and the parameters can change between versions.
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use argon2::{Algorithm, Argon2, Params, Version};
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+use crate::entropy;
+use crate::error_handling::AppError;
+
+pub const BACKUP_VERSION: u32 = 1;
+pub const MIN_PASSWORD_LENGTH: usize = 12;
+// OWASP's recommended Argon2id parameters: 19 MiB of memory, 2 passes, 1 lane. Backups of this version
+// are only opened with exactly these, so an uploaded blob can't make the server spend arbitrary memory
+// and time on the key.
+const ARGON2_MEMORY_KIB: u32 = 19_456;
+const ARGON2_ITERATIONS: u32 = 2;
+const ARGON2_PARALLELISM: u32 = 1;
+const SALT_LEN: usize = 16;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KdfParams {
+    pub name: String,
+    pub salt: String,
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+// The exported backup, everything but `user_id` and `created_at` is needed to open it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EncryptedBackup {
+    pub version: u32,
+    pub user_id: i64,
+    pub created_at: String,
+    pub kdf: KdfParams,
+    pub cipher: String,
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+fn derive_key(password: &str, salt: &[u8], kdf: &KdfParams) -> Result<Zeroizing<[u8; 32]>, AppError> {
+    if (kdf.memory_kib, kdf.iterations, kdf.parallelism) != (ARGON2_MEMORY_KIB, ARGON2_ITERATIONS, ARGON2_PARALLELISM) {
+        return Err(AppError::BadRequest(format!(
+            "Unsupported backup KDF parameters for version {}",
+            BACKUP_VERSION
+        )));
+    }
+    let params = Params::new(kdf.memory_kib, kdf.iterations, kdf.parallelism, Some(32))
+        .map_err(|e| AppError::BadRequest(format!("Invalid backup KDF parameters: {}", e)))?;
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(password.as_bytes(), salt, key.as_mut())
+        .map_err(|e| AppError::CustomError(format!("Failed to derive backup key: {}", e)))?;
+    Ok(key)
+}
+
+// Encrypts `plaintext` under `password`
+pub fn seal(user_id: i64, plaintext: &[u8], password: &str) -> Result<EncryptedBackup, AppError> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(AppError::BadRequest(format!(
+            "Backup password must be at least {} characters",
+            MIN_PASSWORD_LENGTH
+        )));
+    }
+    let salt: [u8; SALT_LEN] = entropy::random_bytes()?;
+    let nonce = entropy::nonce()?;
+    let kdf = KdfParams {
+        name: "argon2id".to_string(),
+        salt: hex::encode(salt),
+        memory_kib: ARGON2_MEMORY_KIB,
+        iterations: ARGON2_ITERATIONS,
+        parallelism: ARGON2_PARALLELISM,
+    };
+    let key = derive_key(password, &salt, &kdf)?;
+    let ciphertext = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key.as_slice()))
+        .encrypt(Nonce::from_slice(&nonce), plaintext)
+        .map_err(|_| AppError::InternalServerError)?;
+    Ok(EncryptedBackup {
+        version: BACKUP_VERSION,
+        user_id,
+        created_at: chrono::Utc::now().to_rfc3339(),
+        kdf,
+        cipher: "aes-256-gcm".to_string(),
+        nonce: hex::encode(nonce),
+        ciphertext: hex::encode(ciphertext),
+    })
+}
+
+// Decrypts a backup, a wrong password and a tampered backup both fail authentication
+pub fn open(backup: &EncryptedBackup, password: &str) -> Result<Vec<u8>, AppError> {
+    if backup.version != BACKUP_VERSION {
+        return Err(AppError::BadRequest(format!("Unsupported backup version {}", backup.version)));
+    }
+    if backup.kdf.name != "argon2id" || backup.cipher != "aes-256-gcm" {
+        return Err(AppError::BadRequest("Unsupported backup encryption".to_string()));
+    }
+    let salt = hex::decode(&backup.kdf.salt)
+        .ok()
+        .filter(|salt| salt.len() == SALT_LEN)
+        .ok_or_else(|| AppError::BadRequest("Invalid backup salt".to_string()))?;
+    let nonce = hex::decode(&backup.nonce)
+        .ok()
+        .filter(|nonce| nonce.len() == 12)
+        .ok_or_else(|| AppError::BadRequest("Invalid backup nonce".to_string()))?;
+    let ciphertext = hex::decode(&backup.ciphertext)
+        .map_err(|_| AppError::BadRequest("Invalid backup ciphertext".to_string()))?;
+    let key = derive_key(password, &salt, &backup.kdf)?;
+    Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key.as_slice()))
+        .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+        .map_err(|_| AppError::DecryptionError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSWORD: &str = "correct horse battery";
+
+    #[test]
+    fn backups_open_with_their_password_only() {
+        let backup = seal(7, b"secrets", PASSWORD).unwrap();
+        assert_eq!(open(&backup, PASSWORD).unwrap(), b"secrets");
+        assert!(matches!(open(&backup, "wrong horse battery"), Err(AppError::DecryptionError)));
+        assert!(matches!(seal(7, b"secrets", "too short"), Err(AppError::BadRequest(_))));
+    }
+
+    #[test]
+    fn kdf_parameters_other_than_the_versions_are_rejected() {
+        let tweaks: [fn(&mut KdfParams); 4] = [
+            |kdf| kdf.memory_kib = 4 * 1024 * 1024,
+            |kdf| kdf.iterations = 1_000_000,
+            |kdf| kdf.parallelism = 64,
+            |kdf| kdf.memory_kib = 8,
+        ];
+        for tweak in tweaks {
+            let mut backup = seal(7, b"secrets", PASSWORD).unwrap();
+            tweak(&mut backup.kdf);
+            assert!(matches!(open(&backup, PASSWORD), Err(AppError::BadRequest(_))));
+        }
+    }
+
+    #[test]
+    fn tampered_backups_fail() {
+        let mut backup = seal(7, b"secrets", PASSWORD).unwrap();
+        let flipped = if backup.ciphertext.starts_with('0') { "1" } else { "0" };
+        backup.ciphertext.replace_range(0..1, flipped);
+        assert!(matches!(open(&backup, PASSWORD), Err(AppError::DecryptionError)));
+
+        let mut backup = seal(7, b"secrets", PASSWORD).unwrap();
+        backup.kdf.salt = "00".to_string();
+        assert!(matches!(open(&backup, PASSWORD), Err(AppError::BadRequest(_))));
+    }
+}
@@ -0,0 +1,94 @@
+// balance_watchdog.rs
+// Watches the balances the pipeline spends from: the bot wallet's SOL, which pays network fees, rent
+// and refunds, and the Kraken balances listed in KRAKEN_MIN_BALANCES. While any of them is below its
+// threshold new swaps are held back and deferred, the same way as swaps during a Jupiter or Solana
+// RPC outage. Operators are alerted once when a balance runs low and again when every balance has
+// recovered.
+use crate::alerts::send_alert;
+use crate::config::{config, ExchangeKind};
+use crate::dry_run;
+use crate::error_handling::AppError;
+use crate::exchange::kraken::get_balances;
+use crate::lockin::{bot_wallet_address, PooledRpc, RpcPool, SolanaRpc};
+use crate::units::lamports_to_sol;
+use serde_json::json;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use thiserror::Error;
+use tokio::time::{interval, Duration};
+
+static SWAPS_HELD: AtomicBool = AtomicBool::new(false);
+// The balances found below their thresholds by the last check
+static LOW_BALANCES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+// Returned instead of running a swap while swaps are held
+#[derive(Debug, Error)]
+#[error("Swaps are held until the bot wallet and Kraken balances are back above their thresholds")]
+pub struct SwapsHeld;
+
+pub fn swaps_held() -> bool {
+    SWAPS_HELD.load(Ordering::SeqCst)
+}
+
+pub fn low_balances() -> Vec<String> {
+    LOW_BALANCES.lock().map(|low| low.clone()).unwrap_or_default()
+}
+
+pub async fn start_balance_watchdog() -> Result<(), AppError> {
+    let mut interval = interval(Duration::from_secs(config().balance_watchdog_interval_secs));
+    loop {
+        interval.tick().await;
+        let low = match check_balances().await {
+            Ok(low) => low,
+            Err(e) => {
+                eprintln!("Balance watchdog check failed: {:?}", e);
+                continue;
+            }
+        };
+        let was_held = SWAPS_HELD.swap(!low.is_empty(), Ordering::SeqCst);
+        if let Ok(mut low_balances) = LOW_BALANCES.lock() {
+            low_balances.clone_from(&low);
+        }
+        match (was_held, low.is_empty()) {
+            (false, false) => {
+                send_alert(&format!("Holding new swaps, balances below their thresholds: {}", low.join("; "))).await
+            }
+            (true, true) => send_alert("Balances are back above their thresholds, swaps resume").await,
+            _ => {}
+        }
+    }
+}
+
+// Describes every watched balance below its threshold
+async fn check_balances() -> Result<Vec<String>, AppError> {
+    let config = config();
+    let mut low = Vec::new();
+
+    let bot_wallet = bot_wallet_address()?;
+    let response = PooledRpc::new(RpcPool::shared())
+        .send_request("getBalance", json!([bot_wallet.to_string(), { "commitment": "confirmed" }]))
+        .await?;
+    let lamports = response["result"]["value"]
+        .as_u64()
+        .ok_or_else(|| AppError::CustomError(format!("getBalance returned no balance: {}", response)))?;
+    let sol = lamports_to_sol(lamports);
+    if sol < config.min_bot_wallet_sol {
+        low.push(format!("bot wallet {} holds {} SOL, below {} SOL", bot_wallet, sol, config.min_bot_wallet_sol));
+    }
+
+    // Simulated exchange balances never run low
+    if config.exchange == ExchangeKind::Kraken && !dry_run::simulates_exchange() && !config.kraken_min_balances.is_empty() {
+        match get_balances().await {
+            Ok(balances) => {
+                for (asset, minimum) in &config.kraken_min_balances {
+                    let balance = balances.get(asset);
+                    if balance < *minimum {
+                        low.push(format!("Kraken holds {} {}, below {} {}", balance, asset, minimum, asset));
+                    }
+                }
+            }
+            Err(e) => eprintln!("Balance watchdog failed to read the Kraken balances: {:?}", e),
+        }
+    }
+    Ok(low)
+}
@@ -0,0 +1,245 @@
+// btc_watcher.rs
+use crate::config::config;
+use crate::dry_run;
+use crate::error_handling::AppError;
+use crate::maintenance;
+use crate::mongo::{get_transactions_collection, get_users_collection, User};
+use crate::settlement;
+use crate::poller::{claim_and_process_deposit, is_poller_paused, process_user_transaction, PipelineClients};
+use crate::transaction_state::{initial_state_fields, TransactionState, TransactionStateMachine};
+use crate::units::sats_to_btc;
+use bdk::bitcoin::Address;
+use bdk::blockchain::{ConfigurableBlockchain, ElectrumBlockchain, ElectrumBlockchainConfig, GetHeight};
+use bdk::database::MemoryDatabase;
+use bdk::wallet::AddressIndex;
+use bdk::{SyncOptions, Wallet};
+use mongodb::bson::{doc, oid::ObjectId, DateTime as BsonDateTime, Document};
+use mongodb::options::UpdateOptions;
+use mongodb::Collection;
+use serde_json::json;
+use std::time::Duration;
+use tokio::task::spawn_blocking;
+use tokio::time::interval;
+
+const ONCHAIN_SOURCE: &str = "BTC on-chain";
+
+// An incoming transaction to one of a user's BDK wallets
+#[derive(Debug, Clone)]
+struct OnchainDeposit {
+    user_id: i64,
+    txid: String,
+    address: String,
+    amount_sats: u64,
+    confirmations: u32,
+    time: i64,
+}
+
+// Scans every user's Bitcoin wallet against Electrum every BTC_WATCHER_INTERVAL_SECS and feeds
+// confirmed deposits into the same processing pipeline as Kraken deposits
+pub async fn start_btc_watcher() -> Result<(), AppError> {
+    let clients = PipelineClients::live();
+    let mut interval = interval(Duration::from_secs(config().btc_watcher_interval_secs));
+    loop {
+        interval.tick().await;
+        if maintenance::is_enabled() || is_poller_paused() {
+            continue;
+        }
+        if let Err(e) = scan_wallets(&clients).await {
+            eprintln!("Bitcoin watcher failed: {:?}", e);
+        }
+    }
+}
+
+async fn scan_wallets(clients: &PipelineClients) -> Result<(), AppError> {
+    let users_collection = get_users_collection().await?;
+    let transactions_collection = get_transactions_collection().await?;
+
+    let mut descriptors = Vec::new();
+    let mut cursor = users_collection
+        .find(doc! { "bitcoin_public_key": { "$nin": [null, ""] } }, None)
+        .await?;
+    while cursor.advance().await? {
+        let user = cursor.deserialize_current()?;
+        if let Some(descriptor) = user.bitcoin_public_key {
+            let last_index = u32::try_from(user.bitcoin_address_index).unwrap_or(0);
+            descriptors.push((user.user_id, descriptor, last_index));
+        }
+    }
+    if descriptors.is_empty() {
+        return Ok(());
+    }
+
+    // BDK's Electrum sync is blocking
+    let deposits = spawn_blocking(move || sync_wallets(descriptors))
+        .await
+        .map_err(|e| AppError::CustomError(format!("Bitcoin wallet sync panicked: {}", e)))??;
+
+    for deposit in deposits {
+        if let Err(e) = record_deposit(clients, &users_collection, &transactions_collection, &deposit).await {
+            eprintln!("Error processing on-chain deposit {}: {:?}", deposit.txid, e);
+        }
+    }
+    Ok(())
+}
+
+// Syncs each descriptor as a watch-only wallet and returns its incoming transactions. Every address
+// up to the last one handed out is scanned, then BTC_GAP_LIMIT unused addresses past the last used one.
+fn sync_wallets(descriptors: Vec<(i64, String, u32)>) -> Result<Vec<OnchainDeposit>, AppError> {
+    let network = config().bitcoin_network;
+    let blockchain = ElectrumBlockchain::from_config(&ElectrumBlockchainConfig {
+        url: config().electrum_url.clone(),
+        socks5: None,
+        retry: 3,
+        timeout: None,
+        stop_gap: config().btc_gap_limit as usize,
+        validate_domain: true,
+    })?;
+    let tip = blockchain.get_height()?;
+
+    let mut deposits = Vec::new();
+    for (user_id, descriptor, last_index) in descriptors {
+        let wallet = match Wallet::new(descriptor.as_str(), None, network, MemoryDatabase::default()) {
+            Ok(wallet) => wallet,
+            Err(e) => {
+                eprintln!("Invalid Bitcoin descriptor for user {}: {:?}", user_id, e);
+                continue;
+            }
+        };
+        // Moving the wallet's index to the last handed out address makes the sync cache and scan
+        // all addresses up to it, even when more than the gap limit of them are still unused
+        if let Err(e) = wallet.get_address(AddressIndex::Reset(last_index)) {
+            eprintln!("Failed to derive Bitcoin addresses for user {}: {:?}", user_id, e);
+            continue;
+        }
+        if let Err(e) = wallet.sync(&blockchain, SyncOptions::default()) {
+            eprintln!("Failed to sync Bitcoin wallet for user {}: {:?}", user_id, e);
+            continue;
+        }
+
+        for details in wallet.list_transactions(true)? {
+            if details.received <= details.sent {
+                continue;
+            }
+            let confirmations = details
+                .confirmation_time
+                .as_ref()
+                .map(|block| tip.saturating_sub(block.height) + 1)
+                .unwrap_or(0);
+            let address = details
+                .transaction
+                .as_ref()
+                .and_then(|tx| {
+                    tx.output
+                        .iter()
+                        .find(|output| wallet.is_mine(&output.script_pubkey).unwrap_or(false))
+                        .and_then(|output| Address::from_script(&output.script_pubkey, network).ok())
+                })
+                .map(|address| address.to_string())
+                .unwrap_or_default();
+            deposits.push(OnchainDeposit {
+                user_id,
+                txid: details.txid.to_string(),
+                address,
+                amount_sats: details.received.saturating_sub(details.sent),
+                confirmations,
+                time: details
+                    .confirmation_time
+                    .as_ref()
+                    .map(|block| block.timestamp as i64)
+                    .unwrap_or_else(|| BsonDateTime::now().timestamp_millis() / 1000),
+            });
+        }
+    }
+    Ok(deposits)
+}
+
+// Records the deposit once, keeps its confirmation count current and processes it once it has
+// BTC_MIN_CONFIRMATIONS confirmations
+async fn record_deposit(
+    clients: &PipelineClients,
+    users_collection: &Collection<User>,
+    transactions_collection: &Collection<Document>,
+    deposit: &OnchainDeposit,
+) -> Result<(), AppError> {
+    let confirmed = deposit.confirmations >= config().btc_min_confirmations;
+
+    // In dry-run mode the deposit is not recorded and each one is only run through once
+    if dry_run::is_enabled() {
+        if !confirmed || dry_run::has_processed_deposit(&deposit.txid).await? {
+            return Ok(());
+        }
+        let user = match users_collection.find_one(doc! { "user_id": deposit.user_id }, None).await? {
+            Some(user) => user,
+            None => return Ok(()),
+        };
+        dry_run::record_action(
+            "process_deposit",
+            json!({ "refid": deposit.txid, "user_id": deposit.user_id, "amount": sats_to_btc(deposit.amount_sats), "address": deposit.address }),
+        )
+        .await;
+        let state_machine = TransactionStateMachine::new(transactions_collection.clone(), ObjectId::new());
+        return process_user_transaction(
+            clients,
+            deposit.amount_sats,
+            deposit.user_id,
+            &deposit.address,
+            "Success",
+            deposit.time,
+            user,
+            users_collection,
+            transactions_collection,
+            &state_machine,
+        )
+        .await;
+    }
+
+    let filter = doc! { "refid": &deposit.txid, "source": ONCHAIN_SOURCE };
+    let mut new_deposit = doc! {
+        "user_id": deposit.user_id,
+        "amount": sats_to_btc(deposit.amount_sats),
+        "address": &deposit.address,
+        "txid": &deposit.txid,
+        "timestamp": BsonDateTime::from_millis(deposit.time * 1000),
+    };
+    new_deposit.extend(initial_state_fields(TransactionState::DepositPending));
+    transactions_collection
+        .update_one(
+            filter.clone(),
+            doc! {
+                "$setOnInsert": new_deposit,
+                "$set": { "confirmations": deposit.confirmations as i64 },
+            },
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .await?;
+
+    let tx = match transactions_collection.find_one(filter, None).await? {
+        Some(tx) => tx,
+        None => return Ok(()),
+    };
+    if TransactionState::of_document(&tx) != TransactionState::DepositPending {
+        return Ok(());
+    }
+    // Confirmations lost to a reorg restart the settlement delay
+    if !settlement::is_creditable(transactions_collection, &tx, confirmed).await? {
+        return Ok(());
+    }
+    let tx_id = tx
+        .get_object_id("_id")
+        .map_err(|_| AppError::CustomError("Transaction document is missing _id".to_string()))?;
+    println!(
+        "Processing on-chain deposit {} of {} BTC for user {}",
+        deposit.txid, sats_to_btc(deposit.amount_sats), deposit.user_id
+    );
+    claim_and_process_deposit(
+        clients,
+        users_collection,
+        transactions_collection,
+        tx_id,
+        deposit.user_id,
+        deposit.amount_sats,
+        &deposit.address,
+        deposit.time,
+    )
+    .await
+}
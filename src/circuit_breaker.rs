@@ -0,0 +1,162 @@
+// circuit_breaker.rs
+// Circuit breakers for the Kraken, Jupiter and Solana RPC clients. After enough failed calls in a row
+// an upstream is treated as down and calls to it fail straight away, so the poller stops hammering it.
+// Once the open period has passed one call is let through as a probe: the breaker closes again when
+// it succeeds and reopens when it fails.
+use serde_json::{json, Value};
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+use crate::alerts::send_alert;
+use crate::config::config;
+
+pub static KRAKEN: CircuitBreaker = CircuitBreaker::new("Kraken");
+pub static JUPITER: CircuitBreaker = CircuitBreaker::new("Jupiter");
+pub static SOLANA_RPC: CircuitBreaker = CircuitBreaker::new("Solana RPC");
+
+// The error of a call that wasn't made because the upstream's breaker is open
+#[derive(Debug, Error)]
+#[error("{name} is unavailable, calls resume in {retry_in_secs}s")]
+pub struct CircuitOpen {
+    pub name: &'static str,
+    pub retry_in_secs: u64,
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    // When the probe of a half-open breaker was let through
+    probe_started_at: Option<Instant>,
+}
+
+pub struct CircuitBreaker {
+    name: &'static str,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            state: Mutex::new(BreakerState { consecutive_failures: 0, opened_at: None, probe_started_at: None }),
+        }
+    }
+
+    fn open_for() -> Duration {
+        Duration::from_secs(config().circuit_breaker_open_secs)
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, BreakerState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Whether a call may be made now. A half-open breaker lets one probe through at a time, or another
+    // one when the last probe never reported back.
+    pub fn check(&self) -> Result<(), CircuitOpen> {
+        let mut state = self.state();
+        let Some(opened_at) = state.opened_at else {
+            return Ok(());
+        };
+        let open_for = Self::open_for();
+        let elapsed = opened_at.elapsed();
+        if elapsed < open_for {
+            return Err(CircuitOpen { name: self.name, retry_in_secs: (open_for - elapsed).as_secs().max(1) });
+        }
+        match state.probe_started_at {
+            Some(probe_started_at) if probe_started_at.elapsed() < open_for => {
+                let retry_in = open_for - probe_started_at.elapsed();
+                Err(CircuitOpen { name: self.name, retry_in_secs: retry_in.as_secs().max(1) })
+            }
+            _ => {
+                println!("{} circuit half-open, letting a probe call through", self.name);
+                state.probe_started_at = Some(Instant::now());
+                Ok(())
+            }
+        }
+    }
+
+    // Whether calls are currently refused, without taking the probe of a half-open breaker
+    pub fn is_open(&self) -> bool {
+        let state = self.state();
+        match state.opened_at {
+            Some(opened_at) => {
+                let open_for = Self::open_for();
+                opened_at.elapsed() < open_for
+                    || state.probe_started_at.is_some_and(|probe| probe.elapsed() < open_for)
+            }
+            None => false,
+        }
+    }
+
+    // Whether the upstream is healthy, neither refusing calls nor waiting on a probe
+    pub fn is_closed(&self) -> bool {
+        self.state().opened_at.is_none()
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state();
+        if state.opened_at.is_some() {
+            println!("{} circuit closed, the upstream has recovered", self.name);
+        }
+        *state = BreakerState::default();
+    }
+
+    pub fn record_failure(&self) {
+        let mut state = self.state();
+        state.consecutive_failures += 1;
+        if state.opened_at.is_some() {
+            // The probe failed, so the breaker stays open for another period
+            if state.probe_started_at.take().is_some() {
+                eprintln!("{} circuit probe failed, staying open", self.name);
+                state.opened_at = Some(Instant::now());
+            }
+            return;
+        }
+        if state.consecutive_failures >= config().circuit_breaker_failure_threshold {
+            state.opened_at = Some(Instant::now());
+            let message = format!(
+                "{} circuit opened after {} failed calls in a row, pausing calls for {}s",
+                self.name,
+                state.consecutive_failures,
+                config().circuit_breaker_open_secs
+            );
+            eprintln!("{}", message);
+            tokio::spawn(async move { send_alert(&message).await });
+        }
+    }
+
+    // Runs `operation` unless the breaker is open, counting its outcome. Errors `is_failure` rejects
+    // mean the upstream answered, so they count as successes.
+    pub async fn call<T, E, Fut, P>(&self, is_failure: P, operation: Fut) -> Result<T, E>
+    where
+        Fut: Future<Output = Result<T, E>>,
+        P: Fn(&E) -> bool,
+        E: From<CircuitOpen>,
+    {
+        self.check()?;
+        let result = operation.await;
+        match &result {
+            Err(error) if is_failure(error) => self.record_failure(),
+            _ => self.record_success(),
+        }
+        result
+    }
+
+    pub fn status(&self) -> Value {
+        let state = self.state();
+        json!({
+            "name": self.name,
+            "open": state.opened_at.is_some(),
+            "consecutive_failures": state.consecutive_failures,
+            "open_for_secs": state.opened_at.map(|opened_at| opened_at.elapsed().as_secs()),
+        })
+    }
+}
+
+// The state of every breaker, for the admin poller status
+pub fn statuses() -> Vec<Value> {
+    [&KRAKEN, &JUPITER, &SOLANA_RPC].iter().map(|breaker| breaker.status()).collect()
+}
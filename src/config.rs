@@ -0,0 +1,837 @@
+// config.rs
+use bdk::keys::bip39::Mnemonic;
+use dotenv::dotenv;
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use thiserror::Error;
+
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+const DEFAULT_BIND_ADDRESS: &str = "0.0.0.0:8080";
+const DEFAULT_RPC_URL: &str = "https://api.mainnet-beta.solana.com";
+const DEFAULT_DEVNET_RPC_URL: &str = "https://api.devnet.solana.com";
+const DEFAULT_JUPITER_API_URL: &str = "https://quote-api.jup.ag/v6";
+const DEFAULT_JUPITER_PRICE_API_URL: &str = "https://api.jup.ag/price/v2";
+const DEFAULT_JITO_BLOCK_ENGINE_URL: &str = "https://mainnet.block-engine.jito.wtf";
+const DEFAULT_LOCKIN_MINT: &str = "8Ki8DpuWNxu9VsS3kQbarsCWMcFGWkzzA8pUPto9zBd5";
+const DEFAULT_COINBASE_API_URL: &str = "https://api.coinbase.com";
+const DEFAULT_ELECTRUM_URL: &str = "ssl://electrum.blockstream.info:50002";
+const DEFAULT_TESTNET_ELECTRUM_URL: &str = "ssl://electrum.blockstream.info:60002";
+const DEFAULT_SIGNET_ELECTRUM_URL: &str = "ssl://mempool.space:60602";
+const DEFAULT_REGTEST_ELECTRUM_URL: &str = "tcp://127.0.0.1:50001";
+const DEFAULT_PRICE_SOURCES: &str = "kraken,coingecko,pyth";
+const DEFAULT_COINGECKO_API_URL: &str = "https://api.coingecko.com/api/v3";
+const DEFAULT_DEXSCREENER_API_URL: &str = "https://api.dexscreener.com";
+const DEFAULT_PYTH_HERMES_URL: &str = "https://hermes.pyth.network";
+const DEFAULT_ETH_RPC_URL: &str = "https://ethereum-rpc.publicnode.com";
+const DEFAULT_SEPOLIA_ETH_RPC_URL: &str = "https://ethereum-sepolia-rpc.publicnode.com";
+const DEFAULT_VAULT_TRANSIT_KEY: &str = "coinlocker";
+
+// Every setting name, the environment variable name is the upper-cased key
+const KNOWN_KEYS: &[&str] = &[
+    "bind_address",
+    "network",
+    "bitcoin_network",
+    "mongo_url",
+    "mongo_max_pool_size",
+    "mongo_min_pool_size",
+    "mongo_connect_timeout_secs",
+    "mongo_server_selection_timeout_secs",
+    "exchange",
+    "kraken_api_key",
+    "kraken_api_secret",
+    "kraken_ws_enabled",
+    "kraken_order_type",
+    "kraken_limit_max_deviation_bps",
+    "kraken_withdrawal_key",
+    "kraken_withdrawal_address",
+    "coinbase_api_url",
+    "coinbase_api_key",
+    "coinbase_api_secret",
+    "private_key",
+    "signer_backend",
+    "remote_signer_url",
+    "remote_signer_token",
+    "solana_master_mnemonic",
+    "btc_watcher_enabled",
+    "btc_watcher_interval_secs",
+    "btc_min_confirmations",
+    "btc_gap_limit",
+    "electrum_url",
+    "lnd_rest_url",
+    "lnd_macaroon",
+    "lnd_tls_cert_path",
+    "lnd_invoice_expiry_secs",
+    "lnd_poll_interval_secs",
+    "sol_watcher_enabled",
+    "sol_watcher_interval_secs",
+    "eth_watcher_enabled",
+    "eth_watcher_interval_secs",
+    "eth_min_confirmations",
+    "eth_rpc_url",
+    "admin_api_key",
+    "alert_webhook_url",
+    "telegram_bot_token",
+    "alert_telegram_chat_id",
+    "user_notification_webhook_url",
+    "otel_exporter_otlp_endpoint",
+    "otel_service_name",
+    "poller_alert_threshold",
+    "balance_watchdog_interval_secs",
+    "min_bot_wallet_sol",
+    "kraken_min_balances",
+    "deposit_concurrency",
+    "deposit_pending_expiry_hours",
+    "deposit_settlement_delay_secs",
+    "min_deposit_btc",
+    "max_deposit_btc",
+    "max_daily_deposit_btc",
+    "withdrawal_arrival_timeout_mins",
+    "reconciliation_hour_utc",
+    "circuit_breaker_failure_threshold",
+    "circuit_breaker_open_secs",
+    "dry_run",
+    "redact_register_secrets",
+    "session_ttl_secs",
+    "key_wrapper",
+    "master_key",
+    "vault_addr",
+    "vault_token",
+    "vault_transit_key",
+    "gcp_kms_key_name",
+    "aws_kms_key_id",
+    "aws_region",
+    "aws_access_key_id",
+    "aws_secret_access_key",
+    "aws_session_token",
+    "rpc_url",
+    "rpc_urls",
+    "rpc_ws_url",
+    "jupiter_api_url",
+    "jupiter_price_api_url",
+    "price_sources",
+    "price_cache_ttl_secs",
+    "coingecko_api_url",
+    "coingecko_api_key",
+    "pyth_hermes_url",
+    "lockin_mint",
+    "compute_unit_limit",
+    "priority_fee_micro_lamports",
+    "priority_fee_percentile",
+    "max_priority_fee_micro_lamports",
+    "priority_fee_escalation_pct",
+    "max_escalated_priority_fee_micro_lamports",
+    "submission_backend",
+    "jito_block_engine_url",
+    "jito_tip_lamports",
+    "platform_fee_bps",
+    "treasury_address",
+    "cold_wallet_address",
+    "hot_wallet_ceiling_sol",
+    "treasury_sweep_interval_secs",
+    "max_spend_per_tx_sol",
+    "max_spend_per_hour_sol",
+    "stake_pool_address",
+    "quote_max_deviation_bps",
+    "dexscreener_api_url",
+];
+
+#[derive(Debug, Error)]
+#[error("Invalid configuration:\n  - {}", .0.join("\n  - "))]
+pub struct ConfigError(pub Vec<String>);
+
+// Which networks the stack runs against. Devnet uses the Solana devnet cluster and Bitcoin testnet,
+// Ethereum Sepolia, and simulates Kraken orders and withdrawals so the flow can be exercised without
+// real funds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Network {
+    Mainnet,
+    Devnet,
+}
+
+impl Network {
+    // The Bitcoin network used unless BITCOIN_NETWORK overrides it
+    fn default_bitcoin_network(&self) -> bdk::bitcoin::Network {
+        match self {
+            Network::Mainnet => bdk::bitcoin::Network::Bitcoin,
+            Network::Devnet => bdk::bitcoin::Network::Testnet,
+        }
+    }
+
+    fn default_rpc_url(&self) -> &'static str {
+        match self {
+            Network::Mainnet => DEFAULT_RPC_URL,
+            Network::Devnet => DEFAULT_DEVNET_RPC_URL,
+        }
+    }
+
+    fn default_eth_rpc_url(&self) -> &'static str {
+        match self {
+            Network::Mainnet => DEFAULT_ETH_RPC_URL,
+            Network::Devnet => DEFAULT_SEPOLIA_ETH_RPC_URL,
+        }
+    }
+}
+
+// Blockstream's Electrum servers for mainnet and testnet, mempool.space for signet and a local
+// electrs for regtest
+fn default_electrum_url(bitcoin_network: bdk::bitcoin::Network) -> &'static str {
+    match bitcoin_network {
+        bdk::bitcoin::Network::Bitcoin => DEFAULT_ELECTRUM_URL,
+        bdk::bitcoin::Network::Testnet => DEFAULT_TESTNET_ELECTRUM_URL,
+        bdk::bitcoin::Network::Signet => DEFAULT_SIGNET_ELECTRUM_URL,
+        bdk::bitcoin::Network::Regtest => DEFAULT_REGTEST_ELECTRUM_URL,
+    }
+}
+
+// Which exchange deposits are received on and swapped through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExchangeKind {
+    Kraken,
+    Coinbase,
+}
+
+// Where USD prices come from, tried in the order PRICE_SOURCES lists them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PriceSourceKind {
+    Kraken,
+    CoinGecko,
+    Pyth,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OrderType {
+    Market,
+    Limit,
+}
+
+// Which master key wraps the per-user data keys, none leaves the API key as the only key material
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyWrapperKind {
+    None,
+    Local,
+    Vault,
+    GcpKms,
+    AwsKms,
+}
+
+// Where the bot wallet's key lives, see signer.rs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SignerBackendKind {
+    Env,
+    Mongo,
+    Remote,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubmissionBackendKind {
+    Rpc,
+    Jito,
+}
+
+// Typed application settings, loaded once at startup. Not Debug, it holds the API secrets and signing key.
+#[derive(Clone)]
+pub struct Config {
+    pub bind_address: String,
+    pub network: Network,
+    pub bitcoin_network: bdk::bitcoin::Network,
+    pub mongo_url: String,
+    pub mongo_max_pool_size: u32,
+    pub mongo_min_pool_size: u32,
+    pub mongo_connect_timeout_secs: u64,
+    pub mongo_server_selection_timeout_secs: u64,
+    pub exchange: ExchangeKind,
+    pub kraken_api_key: String,
+    pub kraken_api_secret: String,
+    pub kraken_ws_enabled: bool,
+    pub kraken_order_type: OrderType,
+    pub kraken_limit_max_deviation_bps: u32,
+    // Fallback withdrawal key for when none in the withdrawal_keys collection is marked default
+    pub kraken_withdrawal_key: Option<String>,
+    pub kraken_withdrawal_address: Option<String>,
+    pub coinbase_api_url: String,
+    pub coinbase_api_key: String,
+    pub coinbase_api_secret: String,
+    // Empty on the remote signer backend, and on the mongo backend once the key is stored
+    pub private_key: String,
+    pub signer_backend: SignerBackendKind,
+    pub remote_signer_url: String,
+    pub remote_signer_token: Option<String>,
+    // None once the mnemonic is stored sealed in Mongo, see wallets::solana::init_master_mnemonic
+    pub solana_master_mnemonic: Option<String>,
+    pub btc_watcher_enabled: bool,
+    pub btc_watcher_interval_secs: u64,
+    pub btc_min_confirmations: u32,
+    pub btc_gap_limit: u32,
+    pub electrum_url: String,
+    pub lnd_rest_url: Option<String>,
+    pub lnd_macaroon: Option<String>,
+    pub lnd_tls_cert_path: Option<String>,
+    pub lnd_invoice_expiry_secs: u64,
+    pub lnd_poll_interval_secs: u64,
+    pub sol_watcher_enabled: bool,
+    pub sol_watcher_interval_secs: u64,
+    pub eth_watcher_enabled: bool,
+    pub eth_watcher_interval_secs: u64,
+    pub eth_min_confirmations: u64,
+    pub eth_rpc_url: String,
+    pub admin_api_key: Option<String>,
+    pub alert_webhook_url: Option<String>,
+    pub telegram_bot_token: Option<String>,
+    pub alert_telegram_chat_id: Option<String>,
+    pub user_notification_webhook_url: Option<String>,
+    pub otel_exporter_otlp_endpoint: Option<String>,
+    pub otel_service_name: String,
+    pub poller_alert_threshold: u64,
+    pub balance_watchdog_interval_secs: u64,
+    pub min_bot_wallet_sol: f64,
+    // Kraken assets and the balance each has to stay above, e.g. ("SOL", 1)
+    pub kraken_min_balances: Vec<(String, Decimal)>,
+    pub deposit_concurrency: usize,
+    pub deposit_pending_expiry_hours: u64,
+    pub deposit_settlement_delay_secs: u64,
+    pub min_deposit_btc: Option<f64>,
+    pub max_deposit_btc: Option<f64>,
+    pub max_daily_deposit_btc: Option<f64>,
+    pub withdrawal_arrival_timeout_mins: u64,
+    pub reconciliation_hour_utc: u32,
+    pub circuit_breaker_failure_threshold: u32,
+    pub circuit_breaker_open_secs: u64,
+    pub dry_run: bool,
+    pub redact_register_secrets: bool,
+    pub session_ttl_secs: u64,
+    pub key_wrapper: KeyWrapperKind,
+    pub master_key: String,
+    pub vault_addr: String,
+    pub vault_token: String,
+    pub vault_transit_key: String,
+    pub gcp_kms_key_name: String,
+    pub aws_kms_key_id: String,
+    pub aws_region: String,
+    pub aws_access_key_id: String,
+    pub aws_secret_access_key: String,
+    pub aws_session_token: Option<String>,
+    pub rpc_urls: Vec<String>,
+    pub rpc_ws_url: Option<String>,
+    pub jupiter_api_url: String,
+    pub jupiter_price_api_url: String,
+    pub price_sources: Vec<PriceSourceKind>,
+    pub price_cache_ttl_secs: u64,
+    pub coingecko_api_url: String,
+    pub coingecko_api_key: Option<String>,
+    pub pyth_hermes_url: String,
+    pub lockin_mint: String,
+    pub compute_unit_limit: u32,
+    pub priority_fee_micro_lamports: Option<u64>,
+    pub priority_fee_percentile: u64,
+    pub max_priority_fee_micro_lamports: u64,
+    // How much the compute unit price rises each time an expired transaction is rebuilt, 0 keeps it
+    pub priority_fee_escalation_pct: u64,
+    pub max_escalated_priority_fee_micro_lamports: u64,
+    pub submission_backend: SubmissionBackendKind,
+    pub jito_block_engine_url: String,
+    pub jito_tip_lamports: u64,
+    // Taken from the SOL of every lockin swap
+    pub platform_fee_bps: u64,
+    // Where platform fees are sent, they stay in the bot wallet without one
+    pub treasury_address: Option<String>,
+    pub cold_wallet_address: Option<String>,
+    pub hot_wallet_ceiling_sol: f64,
+    pub treasury_sweep_interval_secs: u64,
+    pub max_spend_per_tx_sol: Option<f64>,
+    pub max_spend_per_hour_sol: Option<f64>,
+    // SPL stake pool users can have their SOL share deposited into, staking is unavailable without one
+    pub stake_pool_address: Option<String>,
+    // How far a Jupiter quote may stray from the reference price before the swap is aborted, 0 turns the check off
+    pub quote_max_deviation_bps: u64,
+    pub dexscreener_api_url: String,
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+// The loaded configuration. The first call loads it and exits the process listing every problem if it is invalid.
+pub fn config() -> &'static Config {
+    CONFIG.get_or_init(|| match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    })
+}
+
+impl Config {
+    // Loads settings from the TOML file at CONFIG_FILE (config.toml by default, optional),
+    // with environment variables and .env taking precedence
+    pub fn load() -> Result<Self, ConfigError> {
+        dotenv().ok();
+        let path = std::env::var("CONFIG_FILE").unwrap_or_else(|_| DEFAULT_CONFIG_FILE.to_string());
+        let mut values = match std::fs::read_to_string(&path) {
+            Ok(contents) => parse_toml(&path, &contents)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && std::env::var("CONFIG_FILE").is_err() => {
+                HashMap::new()
+            }
+            Err(e) => return Err(ConfigError(vec![format!("Failed to read {}: {}", path, e)])),
+        };
+        for key in KNOWN_KEYS {
+            if let Ok(value) = std::env::var(key.to_uppercase()) {
+                values.insert(key.to_string(), value);
+            }
+        }
+        Self::from_values(&values)
+    }
+
+    // Parses and validates every setting, collecting all problems instead of stopping at the first
+    fn from_values(values: &HashMap<String, String>) -> Result<Self, ConfigError> {
+        let mut settings = Settings { values, errors: Vec::new() };
+
+        let network = match settings.optional("network").as_deref() {
+            None | Some("mainnet") => Network::Mainnet,
+            Some("devnet") | Some("testnet") => Network::Devnet,
+            Some(other) => {
+                settings.invalid("network", other, "expected \"mainnet\" or \"devnet\"");
+                Network::Mainnet
+            }
+        };
+
+        let bitcoin_network = match settings.optional("bitcoin_network").as_deref() {
+            None => network.default_bitcoin_network(),
+            Some("bitcoin") | Some("mainnet") => bdk::bitcoin::Network::Bitcoin,
+            Some("testnet") => bdk::bitcoin::Network::Testnet,
+            Some("signet") => bdk::bitcoin::Network::Signet,
+            Some("regtest") => bdk::bitcoin::Network::Regtest,
+            Some(other) => {
+                settings.invalid("bitcoin_network", other, "expected bitcoin, testnet, signet or regtest");
+                network.default_bitcoin_network()
+            }
+        };
+
+        let rpc_urls: Vec<String> = settings
+            .optional("rpc_urls")
+            .or_else(|| settings.optional("rpc_url"))
+            .unwrap_or_else(|| network.default_rpc_url().to_string())
+            .split(',')
+            .map(|url| url.trim().to_string())
+            .filter(|url| !url.is_empty())
+            .collect();
+        for url in &rpc_urls {
+            settings.check_url("rpc_urls", url, &["http://", "https://"]);
+        }
+        let mut price_sources = Vec::new();
+        for source in settings.or_default("price_sources", DEFAULT_PRICE_SOURCES).split(',') {
+            match source.trim() {
+                "kraken" => price_sources.push(PriceSourceKind::Kraken),
+                "coingecko" => price_sources.push(PriceSourceKind::CoinGecko),
+                "pyth" => price_sources.push(PriceSourceKind::Pyth),
+                "" => {}
+                other => settings.invalid("price_sources", other, "expected kraken, coingecko or pyth"),
+            }
+        }
+        if price_sources.is_empty() {
+            settings.invalid("price_sources", "", "at least one price source is needed");
+        }
+        let coingecko_api_url = settings.or_default("coingecko_api_url", DEFAULT_COINGECKO_API_URL);
+        settings.check_url("coingecko_api_url", &coingecko_api_url, &["http://", "https://"]);
+        let pyth_hermes_url = settings.or_default("pyth_hermes_url", DEFAULT_PYTH_HERMES_URL);
+        settings.check_url("pyth_hermes_url", &pyth_hermes_url, &["http://", "https://"]);
+
+        let mut kraken_min_balances = Vec::new();
+        for entry in settings.optional("kraken_min_balances").unwrap_or_default().split(',') {
+            let entry = entry.trim();
+            if entry.is_empty() {
+                continue;
+            }
+            match entry.split_once(':').map(|(asset, amount)| (asset.trim(), amount.trim().parse::<Decimal>())) {
+                Some((asset, Ok(amount))) if !asset.is_empty() && amount >= Decimal::ZERO => {
+                    kraken_min_balances.push((asset.to_uppercase(), amount))
+                }
+                _ => settings.invalid("kraken_min_balances", entry, "expected ASSET:amount, e.g. SOL:1"),
+            }
+        }
+
+        let rpc_ws_url = settings.optional("rpc_ws_url");
+        if let Some(url) = &rpc_ws_url {
+            settings.check_url("rpc_ws_url", url, &["ws://", "wss://"]);
+        }
+
+        // Only the selected exchange's credentials are required
+        let exchange = match settings.optional("exchange").as_deref() {
+            None | Some("kraken") => ExchangeKind::Kraken,
+            Some("coinbase") => ExchangeKind::Coinbase,
+            Some(other) => {
+                settings.invalid("exchange", other, "expected \"kraken\" or \"coinbase\"");
+                ExchangeKind::Kraken
+            }
+        };
+        let kraken_api_key = settings.required_if("kraken_api_key", exchange == ExchangeKind::Kraken);
+        let kraken_api_secret = settings.required_if("kraken_api_secret", exchange == ExchangeKind::Kraken);
+        let coinbase_api_key = settings.required_if("coinbase_api_key", exchange == ExchangeKind::Coinbase);
+        let coinbase_api_secret = settings.required_if("coinbase_api_secret", exchange == ExchangeKind::Coinbase);
+        let coinbase_api_url = settings.or_default("coinbase_api_url", DEFAULT_COINBASE_API_URL);
+        settings.check_url("coinbase_api_url", &coinbase_api_url, &["http://", "https://"]);
+
+        // LND is optional, but a node without a macaroon can't be used
+        let lnd_rest_url = settings.optional("lnd_rest_url");
+        if let Some(url) = &lnd_rest_url {
+            settings.check_url("lnd_rest_url", url, &["http://", "https://"]);
+        }
+        let lnd_macaroon = settings.optional("lnd_macaroon");
+        if lnd_rest_url.is_some() && lnd_macaroon.is_none() {
+            settings.required("lnd_macaroon");
+        }
+
+        let otel_exporter_otlp_endpoint = settings.optional("otel_exporter_otlp_endpoint");
+        if let Some(url) = &otel_exporter_otlp_endpoint {
+            settings.check_url("otel_exporter_otlp_endpoint", url, &["http://", "https://"]);
+        }
+
+        let eth_rpc_url = settings.or_default("eth_rpc_url", network.default_eth_rpc_url());
+        settings.check_url("eth_rpc_url", &eth_rpc_url, &["http://", "https://"]);
+
+        let kraken_order_type = match settings.optional("kraken_order_type").as_deref() {
+            None | Some("market") => OrderType::Market,
+            Some("limit") => OrderType::Limit,
+            Some(other) => {
+                settings.invalid("kraken_order_type", other, "expected \"market\" or \"limit\"");
+                OrderType::Market
+            }
+        };
+        let submission_backend = match settings.optional("submission_backend").as_deref() {
+            None | Some("rpc") => SubmissionBackendKind::Rpc,
+            Some("jito") => SubmissionBackendKind::Jito,
+            Some(other) => {
+                settings.invalid("submission_backend", other, "expected \"rpc\" or \"jito\"");
+                SubmissionBackendKind::Rpc
+            }
+        };
+
+        let signer_backend = match settings.optional("signer_backend").as_deref() {
+            None | Some("env") => SignerBackendKind::Env,
+            Some("mongo") => SignerBackendKind::Mongo,
+            Some("remote") => SignerBackendKind::Remote,
+            Some(other) => {
+                settings.invalid("signer_backend", other, "expected env, mongo or remote");
+                SignerBackendKind::Env
+            }
+        };
+        let remote_signer_url = settings.required_if("remote_signer_url", signer_backend == SignerBackendKind::Remote);
+        if signer_backend == SignerBackendKind::Remote {
+            settings.check_url("remote_signer_url", &remote_signer_url, &["http://", "https://"]);
+        }
+        let private_key = settings.required_if("private_key", signer_backend == SignerBackendKind::Env);
+        if !private_key.is_empty() {
+            match bs58::decode(&private_key).into_vec() {
+                Ok(bytes) if bytes.len() == 64 => {}
+                _ => settings.invalid("private_key", "<redacted>", "expected a base58 encoded 64 byte keypair"),
+            }
+        }
+
+        // The fallback withdrawal key is only usable with its address
+        let kraken_withdrawal_key = settings.optional("kraken_withdrawal_key");
+        let kraken_withdrawal_address = settings.optional("kraken_withdrawal_address");
+        if kraken_withdrawal_key.is_some() && kraken_withdrawal_address.is_none() {
+            settings.errors.push("KRAKEN_WITHDRAWAL_ADDRESS is required with KRAKEN_WITHDRAWAL_KEY".to_string());
+        }
+
+        // Every user's Solana wallet is derived from this mnemonic, so it must be kept and backed up. It
+        // is only optional once stored in Mongo, which is checked at startup.
+        let solana_master_mnemonic = settings.optional("solana_master_mnemonic");
+        if solana_master_mnemonic.as_deref().is_some_and(|mnemonic| Mnemonic::parse(mnemonic).is_err()) {
+            settings.invalid("solana_master_mnemonic", "<redacted>", "expected a BIP39 mnemonic");
+        }
+
+        // Only the selected key wrapper's settings are required
+        let key_wrapper = match settings.optional("key_wrapper").as_deref() {
+            None | Some("none") => KeyWrapperKind::None,
+            Some("local") => KeyWrapperKind::Local,
+            Some("vault") => KeyWrapperKind::Vault,
+            Some("gcp_kms") => KeyWrapperKind::GcpKms,
+            Some("aws_kms") => KeyWrapperKind::AwsKms,
+            Some(other) => {
+                settings.invalid("key_wrapper", other, "expected none, local, vault, gcp_kms or aws_kms");
+                KeyWrapperKind::None
+            }
+        };
+        if signer_backend == SignerBackendKind::Mongo && key_wrapper == KeyWrapperKind::None {
+            settings.invalid("signer_backend", "mongo", "the key stored in Mongo is encrypted through KEY_WRAPPER, set one");
+        }
+        let master_key = settings.required_if("master_key", key_wrapper == KeyWrapperKind::Local);
+        if !master_key.is_empty() && hex::decode(&master_key).map_or(true, |key| key.len() != 32) {
+            settings.invalid("master_key", "<redacted>", "expected 32 hex encoded bytes");
+        }
+        let vault_addr = settings.required_if("vault_addr", key_wrapper == KeyWrapperKind::Vault);
+        if key_wrapper == KeyWrapperKind::Vault {
+            settings.check_url("vault_addr", &vault_addr, &["http://", "https://"]);
+        }
+        let vault_token = settings.required_if("vault_token", key_wrapper == KeyWrapperKind::Vault);
+        let gcp_kms_key_name = settings.required_if("gcp_kms_key_name", key_wrapper == KeyWrapperKind::GcpKms);
+        let aws_kms_key_id = settings.required_if("aws_kms_key_id", key_wrapper == KeyWrapperKind::AwsKms);
+        let aws_region = settings.required_if("aws_region", key_wrapper == KeyWrapperKind::AwsKms);
+        let aws_access_key_id = settings.required_if("aws_access_key_id", key_wrapper == KeyWrapperKind::AwsKms);
+        let aws_secret_access_key =
+            settings.required_if("aws_secret_access_key", key_wrapper == KeyWrapperKind::AwsKms);
+
+        let priority_fee_percentile = settings.parsed("priority_fee_percentile", 75);
+        if priority_fee_percentile > 100 {
+            settings.invalid("priority_fee_percentile", &priority_fee_percentile.to_string(), "must be at most 100");
+        }
+
+        let platform_fee_bps = settings.parsed("platform_fee_bps", 1000);
+        if platform_fee_bps > 10_000 {
+            settings.invalid("platform_fee_bps", &platform_fee_bps.to_string(), "must be at most 10000");
+        }
+        let treasury_address = settings.optional("treasury_address");
+        if let Some(address) = &treasury_address {
+            match bs58::decode(address).into_vec() {
+                Ok(bytes) if bytes.len() == 32 => {}
+                _ => settings.invalid("treasury_address", address, "expected a base58 encoded Solana address"),
+            }
+        }
+        let cold_wallet_address = settings.optional("cold_wallet_address");
+        if let Some(address) = &cold_wallet_address {
+            match bs58::decode(address).into_vec() {
+                Ok(bytes) if bytes.len() == 32 => {}
+                _ => settings.invalid("cold_wallet_address", address, "expected a base58 encoded Solana address"),
+            }
+        }
+        // Sweeping below the watchdog's minimum would hold every swap after each sweep
+        let min_bot_wallet_sol = settings.parsed("min_bot_wallet_sol", 0.05);
+        let hot_wallet_ceiling_sol = settings.parsed("hot_wallet_ceiling_sol", 1.0);
+        if cold_wallet_address.is_some() && hot_wallet_ceiling_sol <= min_bot_wallet_sol {
+            settings.invalid(
+                "hot_wallet_ceiling_sol",
+                &hot_wallet_ceiling_sol.to_string(),
+                "must be above MIN_BOT_WALLET_SOL",
+            );
+        }
+        let quote_max_deviation_bps = settings.parsed("quote_max_deviation_bps", 500);
+        if quote_max_deviation_bps > 10_000 {
+            settings.invalid("quote_max_deviation_bps", &quote_max_deviation_bps.to_string(), "must be at most 10000");
+        }
+        let dexscreener_api_url = settings.or_default("dexscreener_api_url", DEFAULT_DEXSCREENER_API_URL);
+        settings.check_url("dexscreener_api_url", &dexscreener_api_url, &["http://", "https://"]);
+        let stake_pool_address = settings.optional("stake_pool_address");
+        if let Some(address) = &stake_pool_address {
+            match bs58::decode(address).into_vec() {
+                Ok(bytes) if bytes.len() == 32 => {}
+                _ => settings.invalid("stake_pool_address", address, "expected a base58 encoded Solana address"),
+            }
+        }
+
+        let config = Config {
+            bind_address: settings.or_default("bind_address", DEFAULT_BIND_ADDRESS),
+            network,
+            bitcoin_network,
+            mongo_url: settings.required("mongo_url"),
+            mongo_max_pool_size: settings.parsed("mongo_max_pool_size", 10),
+            mongo_min_pool_size: settings.parsed("mongo_min_pool_size", 0),
+            mongo_connect_timeout_secs: settings.parsed("mongo_connect_timeout_secs", 10),
+            mongo_server_selection_timeout_secs: settings.parsed("mongo_server_selection_timeout_secs", 30),
+            exchange,
+            kraken_api_key,
+            kraken_api_secret,
+            kraken_ws_enabled: settings.flag("kraken_ws_enabled"),
+            kraken_order_type,
+            kraken_limit_max_deviation_bps: settings.parsed("kraken_limit_max_deviation_bps", 50),
+            kraken_withdrawal_key,
+            kraken_withdrawal_address,
+            coinbase_api_url,
+            coinbase_api_key,
+            coinbase_api_secret,
+            private_key,
+            signer_backend,
+            remote_signer_url,
+            remote_signer_token: settings.optional("remote_signer_token"),
+            solana_master_mnemonic,
+            btc_watcher_enabled: settings.flag("btc_watcher_enabled"),
+            btc_watcher_interval_secs: settings.parsed("btc_watcher_interval_secs", 120),
+            btc_min_confirmations: settings.parsed("btc_min_confirmations", 2),
+            btc_gap_limit: settings.parsed("btc_gap_limit", 20),
+            electrum_url: settings.or_default("electrum_url", default_electrum_url(bitcoin_network)),
+            lnd_rest_url,
+            lnd_macaroon,
+            lnd_tls_cert_path: settings.optional("lnd_tls_cert_path"),
+            lnd_invoice_expiry_secs: settings.parsed("lnd_invoice_expiry_secs", 3600),
+            lnd_poll_interval_secs: settings.parsed("lnd_poll_interval_secs", 10),
+            sol_watcher_enabled: settings.flag("sol_watcher_enabled"),
+            sol_watcher_interval_secs: settings.parsed("sol_watcher_interval_secs", 30),
+            eth_watcher_enabled: settings.flag("eth_watcher_enabled"),
+            eth_watcher_interval_secs: settings.parsed("eth_watcher_interval_secs", 60),
+            eth_min_confirmations: settings.parsed("eth_min_confirmations", 12),
+            eth_rpc_url,
+            admin_api_key: settings.optional("admin_api_key"),
+            alert_webhook_url: settings.optional("alert_webhook_url"),
+            telegram_bot_token: settings.optional("telegram_bot_token"),
+            alert_telegram_chat_id: settings.optional("alert_telegram_chat_id"),
+            user_notification_webhook_url: settings.optional("user_notification_webhook_url"),
+            otel_exporter_otlp_endpoint,
+            otel_service_name: settings.or_default("otel_service_name", "coinlockerapi"),
+            poller_alert_threshold: settings.parsed("poller_alert_threshold", 5),
+            balance_watchdog_interval_secs: settings.parsed::<u64>("balance_watchdog_interval_secs", 300).max(1),
+            min_bot_wallet_sol,
+            kraken_min_balances,
+            deposit_concurrency: settings.parsed::<usize>("deposit_concurrency", 4).max(1),
+            deposit_pending_expiry_hours: settings.parsed("deposit_pending_expiry_hours", 72),
+            deposit_settlement_delay_secs: settings.parsed("deposit_settlement_delay_secs", 0),
+            min_deposit_btc: settings.optional("min_deposit_btc").map(|_| settings.parsed("min_deposit_btc", 0.0)),
+            max_deposit_btc: settings.optional("max_deposit_btc").map(|_| settings.parsed("max_deposit_btc", 0.0)),
+            max_daily_deposit_btc: settings
+                .optional("max_daily_deposit_btc")
+                .map(|_| settings.parsed("max_daily_deposit_btc", 0.0)),
+            withdrawal_arrival_timeout_mins: settings.parsed("withdrawal_arrival_timeout_mins", 30),
+            reconciliation_hour_utc: settings.parsed::<u32>("reconciliation_hour_utc", 2).min(23),
+            circuit_breaker_failure_threshold: settings.parsed("circuit_breaker_failure_threshold", 5),
+            circuit_breaker_open_secs: settings.parsed("circuit_breaker_open_secs", 60),
+            dry_run: settings.flag("dry_run"),
+            redact_register_secrets: settings.flag("redact_register_secrets"),
+            session_ttl_secs: settings.parsed("session_ttl_secs", 300),
+            key_wrapper,
+            master_key,
+            vault_addr,
+            vault_token,
+            vault_transit_key: settings.or_default("vault_transit_key", DEFAULT_VAULT_TRANSIT_KEY),
+            gcp_kms_key_name,
+            aws_kms_key_id,
+            aws_region,
+            aws_access_key_id,
+            aws_secret_access_key,
+            aws_session_token: settings.optional("aws_session_token"),
+            rpc_urls,
+            rpc_ws_url,
+            jupiter_api_url: settings.or_default("jupiter_api_url", DEFAULT_JUPITER_API_URL),
+            jupiter_price_api_url: settings.or_default("jupiter_price_api_url", DEFAULT_JUPITER_PRICE_API_URL),
+            price_sources,
+            price_cache_ttl_secs: settings.parsed("price_cache_ttl_secs", 10),
+            coingecko_api_url,
+            coingecko_api_key: settings.optional("coingecko_api_key"),
+            pyth_hermes_url,
+            lockin_mint: settings.or_default("lockin_mint", DEFAULT_LOCKIN_MINT),
+            compute_unit_limit: settings.parsed("compute_unit_limit", 400_000),
+            priority_fee_micro_lamports: settings
+                .optional("priority_fee_micro_lamports")
+                .map(|_| settings.parsed("priority_fee_micro_lamports", 0)),
+            priority_fee_percentile,
+            max_priority_fee_micro_lamports: settings.parsed("max_priority_fee_micro_lamports", 1_000_000),
+            priority_fee_escalation_pct: settings.parsed("priority_fee_escalation_pct", 50),
+            max_escalated_priority_fee_micro_lamports: settings
+                .parsed("max_escalated_priority_fee_micro_lamports", 5_000_000),
+            submission_backend,
+            jito_block_engine_url: settings.or_default("jito_block_engine_url", DEFAULT_JITO_BLOCK_ENGINE_URL),
+            jito_tip_lamports: settings.parsed("jito_tip_lamports", 10_000),
+            platform_fee_bps,
+            treasury_address,
+            cold_wallet_address,
+            hot_wallet_ceiling_sol,
+            treasury_sweep_interval_secs: settings.parsed::<u64>("treasury_sweep_interval_secs", 3600).max(1),
+            max_spend_per_tx_sol: settings
+                .optional("max_spend_per_tx_sol")
+                .map(|_| settings.parsed("max_spend_per_tx_sol", 0.0)),
+            max_spend_per_hour_sol: settings
+                .optional("max_spend_per_hour_sol")
+                .map(|_| settings.parsed("max_spend_per_hour_sol", 0.0)),
+            stake_pool_address,
+            quote_max_deviation_bps,
+            dexscreener_api_url,
+        };
+
+        if settings.errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError(settings.errors))
+        }
+    }
+}
+
+// Flattens a TOML file into setting name -> string value, so file and environment values parse the same way
+fn parse_toml(path: &str, contents: &str) -> Result<HashMap<String, String>, ConfigError> {
+    let table: toml::value::Table =
+        toml::from_str(contents).map_err(|e| ConfigError(vec![format!("Failed to parse {}: {}", path, e)]))?;
+    let mut values = HashMap::new();
+    let mut errors = Vec::new();
+    for (key, value) in table {
+        let key = key.to_lowercase();
+        if !KNOWN_KEYS.contains(&key.as_str()) {
+            errors.push(format!("{}: unknown setting `{}`", path, key));
+            continue;
+        }
+        let value = match value {
+            toml::Value::String(value) => value,
+            toml::Value::Array(urls) => urls
+                .iter()
+                .map(|url| url.as_str().map(str::to_string).unwrap_or_else(|| url.to_string()))
+                .collect::<Vec<_>>()
+                .join(","),
+            other => other.to_string(),
+        };
+        values.insert(key, value);
+    }
+    if errors.is_empty() {
+        Ok(values)
+    } else {
+        Err(ConfigError(errors))
+    }
+}
+
+struct Settings<'a> {
+    values: &'a HashMap<String, String>,
+    errors: Vec<String>,
+}
+
+impl Settings<'_> {
+    fn optional(&self, key: &str) -> Option<String> {
+        self.values.get(key).filter(|value| !value.is_empty()).cloned()
+    }
+
+    fn required(&mut self, key: &str) -> String {
+        self.optional(key).unwrap_or_else(|| {
+            self.errors.push(format!("{} is required but not set", key.to_uppercase()));
+            String::new()
+        })
+    }
+
+    // Required when `needed`, empty otherwise
+    fn required_if(&mut self, key: &str, needed: bool) -> String {
+        if needed {
+            self.required(key)
+        } else {
+            self.optional(key).unwrap_or_default()
+        }
+    }
+
+    fn or_default(&self, key: &str, default: &str) -> String {
+        self.optional(key).unwrap_or_else(|| default.to_string())
+    }
+
+    fn flag(&mut self, key: &str) -> bool {
+        match self.optional(key).as_deref() {
+            None | Some("false") | Some("0") => false,
+            Some("true") | Some("1") => true,
+            Some(other) => {
+                self.invalid(key, other, "expected true or false");
+                false
+            }
+        }
+    }
+
+    fn parsed<T: FromStr>(&mut self, key: &str, default: T) -> T {
+        match self.optional(key) {
+            None => default,
+            Some(value) => value.parse().unwrap_or_else(|_| {
+                self.invalid(key, &value, "expected a non-negative number");
+                default
+            }),
+        }
+    }
+
+    fn check_url(&mut self, key: &str, url: &str, schemes: &[&str]) {
+        if !schemes.iter().any(|scheme| url.starts_with(scheme)) {
+            self.invalid(key, url, &format!("expected a {} URL", schemes.join(" or ")));
+        }
+    }
+
+    fn invalid(&mut self, key: &str, value: &str, reason: &str) {
+        self.errors
+            .push(format!("{} has invalid value `{}`: {}", key.to_uppercase(), value, reason));
+    }
+}
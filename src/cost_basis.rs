@@ -0,0 +1,122 @@
+// cost_basis.rs
+// Cost basis of the LOCKIN users acquire. The USD price of BTC is recorded on a transaction when its
+// deposit is credited, and the LOCKIN amount and USD price when its swap lands. Each swap is a tax
+// lot: its cost basis is the LOCKIN's USD value when it was acquired, and its unrealized P&L is the
+// difference to the LOCKIN's value at the current price.
+use crate::config::config;
+use crate::error_handling::AppError;
+use crate::holdings::get_prices;
+use crate::lockin::{PooledRpc, RpcPool, SolanaRpc, SwapOutcome};
+use crate::mongo::get_transactions_collection;
+use mongodb::bson::{doc, oid::ObjectId, DateTime as BsonDateTime, Document};
+use mongodb::options::FindOptions;
+use serde_json::{json, Value};
+use tokio::sync::OnceCell;
+
+// The decimals of the LOCKIN mint, read once
+async fn lockin_decimals() -> Result<u8, AppError> {
+    static DECIMALS: OnceCell<u8> = OnceCell::const_new();
+    DECIMALS
+        .get_or_try_init(|| async {
+            let rpc = PooledRpc::new(RpcPool::shared());
+            let response = rpc.send_request("getTokenSupply", json!([config().lockin_mint])).await?;
+            response["result"]["value"]["decimals"]
+                .as_u64()
+                .map(|decimals| decimals as u8)
+                .ok_or_else(|| AppError::CustomError(format!("No decimals for LOCKIN mint: {}", response)))
+        })
+        .await
+        .copied()
+}
+
+// The current USD price of LOCKIN on the Jupiter price API
+pub async fn lockin_price_usd() -> Result<Option<f64>, AppError> {
+    let mint = config().lockin_mint.clone();
+    Ok(get_prices(std::slice::from_ref(&mint)).await?.get(&mint).copied())
+}
+
+// Records the LOCKIN a swap delivered and its USD price on the transaction
+pub async fn record_acquisition(tx_id: ObjectId, outcome: &SwapOutcome) -> Result<(), AppError> {
+    let Some(out_amount) = outcome.out_amount else {
+        return Ok(());
+    };
+    let lockin_amount = out_amount as f64 / 10f64.powi(lockin_decimals().await? as i32);
+    let lockin_price_usd = match lockin_price_usd().await {
+        Ok(price) => price,
+        Err(e) => {
+            eprintln!("Failed to look up the LOCKIN price of transaction {}: {:?}", tx_id, e);
+            None
+        }
+    };
+
+    get_transactions_collection()
+        .await?
+        .update_one(
+            doc! { "_id": tx_id },
+            doc! { "$set": {
+                "cost_basis.lockin_amount": lockin_amount,
+                "cost_basis.lockin_price_usd": lockin_price_usd,
+                "cost_basis.acquired_at": BsonDateTime::now(),
+            } },
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+// The user's lots, oldest first, with their cost basis and unrealized P&L at the current LOCKIN price.
+// Lots acquired while no LOCKIN price was available have no cost basis.
+pub async fn lots(user_id: i64) -> Result<Value, AppError> {
+    let current_price = lockin_price_usd().await?;
+
+    // The bot stores user_id as either an int32 or an int64
+    let filter = doc! {
+        "$or": [ { "user_id": user_id }, { "user_id": user_id as i32 } ],
+        "cost_basis.lockin_amount": { "$exists": true },
+    };
+    let options = FindOptions::builder().sort(doc! { "cost_basis.acquired_at": 1 }).build();
+    let mut cursor = get_transactions_collection().await?.find(filter, options).await?;
+
+    let mut lots = Vec::new();
+    let (mut total_amount, mut total_cost_basis, mut total_pnl) = (0.0, 0.0, 0.0);
+    while cursor.advance().await? {
+        let tx: Document = cursor.deserialize_current()?;
+        let Ok(cost_basis) = tx.get_document("cost_basis") else {
+            continue;
+        };
+        let amount = cost_basis.get_f64("lockin_amount").unwrap_or_default();
+        let acquisition_price = cost_basis.get_f64("lockin_price_usd").ok();
+        let btc_price = cost_basis.get_f64("btc_price_usd").ok();
+        let deposit_amount = tx.get_f64("amount").ok();
+
+        let lot_cost_basis = acquisition_price.map(|price| price * amount);
+        let value = current_price.map(|price| price * amount);
+        let pnl = value.zip(lot_cost_basis).map(|(value, cost_basis)| value - cost_basis);
+        total_amount += amount;
+        total_cost_basis += lot_cost_basis.unwrap_or_default();
+        total_pnl += pnl.unwrap_or_default();
+
+        lots.push(json!({
+            "transaction_id": tx.get_object_id("_id").ok().map(|id| id.to_hex()),
+            "acquired_at": cost_basis.get_datetime("acquired_at").ok().and_then(|at| at.try_to_rfc3339_string().ok()),
+            "deposit_asset": tx.get_str("deposit_asset").unwrap_or("BTC"),
+            "deposit_amount": deposit_amount,
+            "btc_price_usd": btc_price,
+            "deposit_value_usd": btc_price.zip(deposit_amount).map(|(price, amount)| price * amount),
+            "lockin_amount": amount,
+            "lockin_price_usd": acquisition_price,
+            "cost_basis_usd": lot_cost_basis,
+            "current_value_usd": value,
+            "unrealized_pnl_usd": pnl,
+        }));
+    }
+
+    Ok(json!({
+        "lockin_price_usd": current_price,
+        "lots": lots,
+        "total_lockin": total_amount,
+        "total_cost_basis_usd": total_cost_basis,
+        "total_value_usd": current_price.map(|price| price * total_amount),
+        "unrealized_pnl_usd": current_price.map(|_| total_pnl),
+    }))
+}
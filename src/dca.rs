@@ -0,0 +1,147 @@
+// dca.rs
+use crate::autobuy::{claim_pending, run_purchase};
+use crate::error_handling::AppError;
+use crate::maintenance;
+use crate::price::{PriceOracle, PriceSource};
+use crate::mongo::{
+    get_dca_executions_collection, get_dca_plans_collection, get_transactions_collection,
+    get_users_collection, DcaExecution, DcaPlan,
+};
+use mongodb::bson::{doc, DateTime as BsonDateTime};
+use std::time::Duration;
+use tokio::time::interval;
+
+const HOUR_MILLIS: i64 = 60 * 60 * 1000;
+
+// Starts the scheduler that executes due DCA plans every minute
+pub async fn start_dca_scheduler() -> Result<(), AppError> {
+    let mut interval = interval(Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        if maintenance::is_enabled() {
+            continue;
+        }
+        if let Err(e) = run_due_plans().await {
+            eprintln!("DCA scheduler failed: {:?}", e);
+        }
+    }
+}
+
+async fn run_due_plans() -> Result<(), AppError> {
+    let plans_collection = get_dca_plans_collection().await?;
+    let now = BsonDateTime::now();
+
+    let mut cursor = plans_collection
+        .find(doc! { "active": true, "next_run_at": { "$lte": now } }, None)
+        .await?;
+    while cursor.advance().await? {
+        let plan = cursor.deserialize_current()?;
+        let plan_id = match plan.id {
+            Some(plan_id) => plan_id,
+            None => continue,
+        };
+
+        // Claim this run by moving next_run_at forward, so each interval executes once across instances
+        let next_run_at = BsonDateTime::from_millis(
+            plan.next_run_at.timestamp_millis().max(now.timestamp_millis())
+                + plan.interval_hours * HOUR_MILLIS,
+        );
+        let claimed = plans_collection
+            .update_one(
+                doc! { "_id": plan_id, "next_run_at": plan.next_run_at },
+                doc! { "$set": { "next_run_at": next_run_at } },
+                None,
+            )
+            .await?;
+        if claimed.modified_count != 1 {
+            continue;
+        }
+
+        // The run is claimed, so a plan that fails still gets its execution record and the other due
+        // plans still run
+        if let Err(e) = execute_plan(&plan).await {
+            eprintln!("DCA plan {} failed: {:?}", plan_id, e);
+            record_failed_execution(&plan, &e).await;
+        }
+    }
+    Ok(())
+}
+
+async fn record_failed_execution(plan: &DcaPlan, error: &AppError) {
+    let execution = DcaExecution {
+        id: None,
+        plan_id: plan.id.unwrap_or_default(),
+        user_id: plan.user_id,
+        amount_usd: plan.amount_usd,
+        amount_btc: 0.0,
+        transaction_id: None,
+        status: "failed".to_string(),
+        error: Some(error.to_string()),
+        executed_at: BsonDateTime::now(),
+    };
+    let result = match get_dca_executions_collection().await {
+        Ok(executions_collection) => executions_collection.insert_one(execution, None).await.map(|_| ()).map_err(AppError::from),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        eprintln!("Error recording failed DCA execution of plan {:?}: {:?}", plan.id, e);
+    }
+}
+
+// Buys the plan's USD amount out of the user's BTC held on Kraken and records the execution
+async fn execute_plan(plan: &DcaPlan) -> Result<(), AppError> {
+    let users_collection = get_users_collection().await?;
+    let transactions_collection = get_transactions_collection().await?;
+    let executions_collection = get_dca_executions_collection().await?;
+
+    let btc_value_in_usd = PriceOracle::shared().usd_price("BTC").await?;
+    let amount_btc = plan.amount_usd / btc_value_in_usd;
+    println!(
+        "Executing DCA plan for user {}: {} USD = {} BTC",
+        plan.user_id, plan.amount_usd, amount_btc
+    );
+
+    let mut execution = DcaExecution {
+        id: None,
+        plan_id: plan.id.unwrap_or_default(),
+        user_id: plan.user_id,
+        amount_usd: plan.amount_usd,
+        amount_btc,
+        transaction_id: None,
+        status: "executed".to_string(),
+        error: None,
+        executed_at: BsonDateTime::now(),
+    };
+
+    let user = users_collection
+        .find_one(doc! { "user_id": plan.user_id }, None)
+        .await?;
+    match user {
+        None => {
+            execution.status = "skipped".to_string();
+            execution.error = Some("User not found".to_string());
+        }
+        Some(user) => match claim_pending(&users_collection, user.user_id, amount_btc).await? {
+            None => {
+                execution.status = "skipped".to_string();
+                execution.error = Some(format!(
+                    "Insufficient held balance: {} < {} BTC",
+                    user.pending_autobuy, amount_btc
+                ));
+            }
+            Some(amount) => {
+                match run_purchase(&users_collection, &transactions_collection, &user, amount, "BTC", "DCA").await {
+                    Ok(tx_id) => execution.transaction_id = Some(tx_id),
+                    Err(e) => {
+                        execution.status = "failed".to_string();
+                        execution.error = Some(e.to_string());
+                    }
+                }
+            }
+        },
+    }
+
+    println!("DCA execution {}: {:?}", execution.status, execution.error);
+    executions_collection.insert_one(execution, None).await?;
+    Ok(())
+}
@@ -0,0 +1,50 @@
+// dry_run.rs
+use crate::config::{config, Network};
+use crate::error_handling::AppError;
+use crate::mongo::get_dry_run_actions_collection;
+use mongodb::bson::{doc, to_bson, DateTime as BsonDateTime};
+use serde_json::Value;
+
+// Dry-run mode prices and simulates the whole pipeline without placing orders,
+// withdrawing or submitting transactions
+pub fn is_enabled() -> bool {
+    config().dry_run
+}
+
+// Neither exchange has a sandbox for spot trading, so orders and withdrawals are simulated on devnet as well
+pub fn simulates_exchange() -> bool {
+    is_enabled() || config().network == Network::Devnet
+}
+
+// Logs a would-be action and records it in the dry_run_actions collection
+pub async fn record_action(action: &str, details: Value) {
+    println!("[DRY RUN] {}: {}", action, details);
+    if let Err(e) = insert_action(action, details).await {
+        eprintln!("Error recording dry-run action: {:?}", e);
+    }
+}
+
+// Checks whether a deposit has already been run through the pipeline in dry-run mode
+pub async fn has_processed_deposit(refid: &str) -> Result<bool, AppError> {
+    let collection = get_dry_run_actions_collection().await?;
+    let existing = collection
+        .find_one(doc! { "action": "process_deposit", "details.refid": refid }, None)
+        .await?;
+    Ok(existing.is_some())
+}
+
+async fn insert_action(action: &str, details: Value) -> Result<(), AppError> {
+    let collection = get_dry_run_actions_collection().await?;
+    let details = to_bson(&details).map_err(|e| AppError::CustomError(e.to_string()))?;
+    collection
+        .insert_one(
+            doc! {
+                "action": action,
+                "details": details,
+                "timestamp": BsonDateTime::now(),
+            },
+            None,
+        )
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,529 @@
+// encryption.rs
+// Encryption of the secrets stored on user documents. Each value is sealed in a versioned envelope,
+// "enc:" followed by the hex of a version byte, the user's salt, the nonce and the AES-256-GCM
+// ciphertext. The key is stretched from the user's API key and their random salt with Argon2id, and
+// every value gets its own nonce. Version 2 also authenticates the name of the field a value belongs
+// to, so ciphertexts can't be swapped between fields. Version 1 values and values from before the
+// envelope (hex of nonce and ciphertext, keyed with the first 32 bytes of the API key) still decrypt,
+// and a user's outdated values are re-encrypted with the current version after their next successful
+// decrypt. With a KEY_WRAPPER configured, the current version is 3: the Argon2id key is mixed with a
+// random per-user data key that is stored wrapped by the master key (see key_wrap.rs).
+//
+// API keys themselves are only stored as their SHA-256, so sessions and one-time tokens that need to
+// decrypt carry the API key sealed under a key derived from the token, which the database never sees.
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use argon2::{Algorithm, Argon2, Params, Version};
+use hmac::{Hmac, Mac};
+use mongodb::bson::{doc, Bson, Document};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::sync::{Mutex, OnceLock};
+use tokio::task::spawn_blocking;
+use zeroize::Zeroizing;
+
+use crate::entropy;
+use crate::error_handling::AppError;
+use crate::key_wrap::{key_wrapper, new_data_key, unwrap_data_key};
+use crate::mongo::{get_users_collection, User};
+
+const ENVELOPE_PREFIX: &str = "enc:";
+const ENVELOPE_V1: u8 = 1;
+const ENVELOPE_V2: u8 = 2;
+const ENVELOPE_V3: u8 = 3;
+const TOKEN_SEAL_LABEL: &[u8] = b"coinlocker token seal";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+// OWASP's recommended Argon2id parameters: 19 MiB of memory, 2 passes, 1 lane
+const ARGON2_MEMORY_KIB: u32 = 19_456;
+const ARGON2_ITERATIONS: u32 = 2;
+const ARGON2_PARALLELISM: u32 = 1;
+
+// Users with a migration in flight, so concurrent decrypts don't start another one
+static MIGRATING: OnceLock<Mutex<HashSet<i64>>> = OnceLock::new();
+
+// The encrypted fields of a user document
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecretField {
+    SolanaPrivateKey,
+    BitcoinMnemonic,
+    BitcoinPrivateKey,
+    EthereumPrivateKey,
+}
+
+impl SecretField {
+    pub const ALL: [SecretField; 4] = [
+        SecretField::SolanaPrivateKey,
+        SecretField::BitcoinMnemonic,
+        SecretField::BitcoinPrivateKey,
+        SecretField::EthereumPrivateKey,
+    ];
+
+    // The field's name on the user document, also the associated data of its version 2 envelope
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SecretField::SolanaPrivateKey => "solana_private_key",
+            SecretField::BitcoinMnemonic => "bitcoin_mnemonic",
+            SecretField::BitcoinPrivateKey => "bitcoin_private_key",
+            SecretField::EthereumPrivateKey => "ethereum_private_key",
+        }
+    }
+
+    // The stored value of the field, empty values count as missing
+    pub fn value(self, user: &User) -> Option<&str> {
+        match self {
+            SecretField::SolanaPrivateKey => user.solana_private_key.as_deref(),
+            SecretField::BitcoinMnemonic => user.bitcoin_mnemonic.as_deref(),
+            SecretField::BitcoinPrivateKey => user.bitcoin_private_key.as_deref(),
+            SecretField::EthereumPrivateKey => user.ethereum_private_key.as_deref(),
+        }
+        .filter(|data| !data.is_empty())
+    }
+}
+
+// The key for one user's secrets, derived once and reused for every value sealed under their salt
+pub struct UserCipher {
+    salt: [u8; SALT_LEN],
+    key: Zeroizing<[u8; 32]>,
+    version: u8,
+    // A data key created for this cipher, which has to be stored on the user with the values
+    new_data_key: Option<String>,
+}
+
+impl UserCipher {
+    // A cipher with a fresh random salt, and a fresh data key when a key wrapper is configured, for a
+    // new user
+    pub async fn new(api_key: &str) -> Result<Self, AppError> {
+        Self::with_salt(api_key, entropy::random_bytes()?, None).await
+    }
+
+    // The cipher of an existing user, reusing the salt their secrets are already sealed with and
+    // their data key
+    pub async fn for_user(user: &User, api_key: &str) -> Result<Self, AppError> {
+        let salt = SecretField::ALL
+            .into_iter()
+            .filter_map(|field| field.value(user).and_then(|data| parse_envelope(data).ok()))
+            .map(|envelope| envelope.salt)
+            .next();
+        let salt = match salt {
+            Some(salt) => salt,
+            None => entropy::random_bytes()?,
+        };
+        Self::with_salt(api_key, salt, user.data_key.as_deref()).await
+    }
+
+    async fn with_salt(api_key: &str, salt: [u8; SALT_LEN], stored_data_key: Option<&str>) -> Result<Self, AppError> {
+        let (data_key, new_data_key) = match (stored_data_key, key_wrapper()) {
+            (Some(stored), _) => (Some(unwrap_data_key(stored).await?), None),
+            (None, Some(wrapper)) => {
+                let (data_key, stored) = new_data_key(wrapper.as_ref()).await?;
+                (Some(Zeroizing::new(data_key.to_vec())), Some(stored))
+            }
+            (None, None) => (None, None),
+        };
+        let stretched = stretch_blocking(api_key, salt).await?;
+        let key = cipher_key(&stretched, data_key.as_ref().map(|key| key.as_slice()));
+        let version = if data_key.is_some() { ENVELOPE_V3 } else { ENVELOPE_V2 };
+        Ok(UserCipher { salt, key, version, new_data_key })
+    }
+
+    // The wrapped data key created for this cipher, None when the user already had one or no key
+    // wrapper is configured
+    pub fn new_data_key(&self) -> Option<&str> {
+        self.new_data_key.as_deref()
+    }
+
+    // Seals `data` for `field` in an envelope of the current version with a fresh nonce
+    pub fn encrypt(&self, field: SecretField, data: &str) -> Result<String, AppError> {
+        let nonce = entropy::nonce()?;
+        let payload = Payload { msg: data.as_bytes(), aad: field.as_str().as_bytes() };
+        let ciphertext = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(self.key.as_slice()))
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| AppError::InternalServerError)?;
+
+        let mut envelope = Vec::with_capacity(1 + SALT_LEN + NONCE_LEN + ciphertext.len());
+        envelope.push(self.version);
+        envelope.extend_from_slice(&self.salt);
+        envelope.extend_from_slice(&nonce);
+        envelope.extend_from_slice(&ciphertext);
+        Ok(format!("{}{}", ENVELOPE_PREFIX, hex::encode(envelope)))
+    }
+}
+
+// The hash API keys are stored and looked up by. API keys are random UUIDs, so a fast hash is enough
+// and keeps the lookup an index match.
+pub fn api_key_hash(api_key: &str) -> String {
+    hex::encode(Sha256::digest(api_key.as_bytes()))
+}
+
+// The key a token seals a secret with, an HMAC of the token so it differs from the token's stored
+// SHA-256
+fn token_key(token: &str) -> Zeroizing<[u8; 32]> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(token.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(TOKEN_SEAL_LABEL);
+    let mut key = Zeroizing::new([0u8; 32]);
+    key.copy_from_slice(&mac.finalize().into_bytes());
+    key
+}
+
+// Seals `secret` so only the holder of `token` can open it, as the hex of a nonce and the ciphertext
+pub fn seal_for_token(token: &str, secret: &str) -> Result<String, AppError> {
+    let nonce = entropy::nonce()?;
+    let ciphertext = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(token_key(token).as_slice()))
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: secret.as_bytes(), aad: TOKEN_SEAL_LABEL })
+        .map_err(|_| AppError::InternalServerError)?;
+    Ok(hex::encode([nonce.as_slice(), ciphertext.as_slice()].concat()))
+}
+
+// Opens a secret sealed with `seal_for_token`
+pub fn open_for_token(token: &str, sealed: &str) -> Result<Zeroizing<String>, AppError> {
+    let decoded = hex::decode(sealed).map_err(|_| AppError::DecryptionError)?;
+    if decoded.len() < NONCE_LEN {
+        return Err(AppError::DecryptionError);
+    }
+    let (nonce, ciphertext) = decoded.split_at(NONCE_LEN);
+    open(token_key(token).as_slice(), nonce, ciphertext, TOKEN_SEAL_LABEL)
+}
+
+// Stretches the API key with the user's salt
+fn stretch(api_key: &str, salt: &[u8]) -> Result<Zeroizing<[u8; 32]>, AppError> {
+    let params = Params::new(ARGON2_MEMORY_KIB, ARGON2_ITERATIONS, ARGON2_PARALLELISM, Some(32))
+        .map_err(|e| AppError::CustomError(format!("Invalid Argon2 parameters: {}", e)))?;
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(api_key.as_bytes(), salt, key.as_mut_slice())
+        .map_err(|e| AppError::CustomError(format!("Failed to derive encryption key: {}", e)))?;
+    Ok(key)
+}
+
+// Argon2 is deliberately slow, so async callers stretch on the blocking pool
+async fn stretch_blocking(api_key: &str, salt: [u8; SALT_LEN]) -> Result<Zeroizing<[u8; 32]>, AppError> {
+    let api_key = Zeroizing::new(api_key.to_string());
+    spawn_blocking(move || stretch(&api_key, &salt))
+        .await
+        .map_err(|e| AppError::CustomError(format!("Key derivation panicked: {}", e)))?
+}
+
+// The AES key of an envelope, version 3 keys need both the stretched API key and the data key
+fn cipher_key(stretched: &[u8; 32], data_key: Option<&[u8]>) -> Zeroizing<[u8; 32]> {
+    match data_key {
+        Some(data_key) => {
+            let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(data_key).expect("HMAC accepts keys of any length");
+            mac.update(stretched);
+            let mut key = Zeroizing::new([0u8; 32]);
+            key.copy_from_slice(&mac.finalize().into_bytes());
+            key
+        }
+        None => Zeroizing::new(*stretched),
+    }
+}
+
+struct Envelope {
+    version: u8,
+    salt: [u8; SALT_LEN],
+    nonce: [u8; NONCE_LEN],
+    ciphertext: Vec<u8>,
+}
+
+fn parse_envelope(data: &str) -> Result<Envelope, AppError> {
+    let bytes = data
+        .strip_prefix(ENVELOPE_PREFIX)
+        .and_then(|data| hex::decode(data).ok())
+        .ok_or(AppError::DecryptionError)?;
+    match bytes.split_first() {
+        Some((&version, rest))
+            if (ENVELOPE_V1..=ENVELOPE_V3).contains(&version) && rest.len() > SALT_LEN + NONCE_LEN =>
+        {
+            let (salt, rest) = rest.split_at(SALT_LEN);
+            let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+            Ok(Envelope {
+                version,
+                salt: salt.try_into().map_err(|_| AppError::DecryptionError)?,
+                nonce: nonce.try_into().map_err(|_| AppError::DecryptionError)?,
+                ciphertext: ciphertext.to_vec(),
+            })
+        }
+        _ => Err(AppError::DecryptionError),
+    }
+}
+
+fn open(key: &[u8], nonce: &[u8], ciphertext: &[u8], aad: &[u8]) -> Result<Zeroizing<String>, AppError> {
+    let plaintext = Zeroizing::new(
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key))
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+            .map_err(|_| AppError::DecryptionError)?,
+    );
+    std::str::from_utf8(&plaintext)
+        .map(|plaintext| Zeroizing::new(plaintext.to_string()))
+        .map_err(|_| AppError::DecryptionError)
+}
+
+// Values written before the envelope: the API key's first 32 bytes (zero padded) used as the key
+fn decrypt_legacy(data: &str, api_key: &str) -> Result<Zeroizing<String>, AppError> {
+    let mut key_bytes = Zeroizing::new([0u8; 32]);
+    let api_key_bytes = api_key.as_bytes();
+    let len = std::cmp::min(api_key_bytes.len(), 32);
+    key_bytes[..len].copy_from_slice(&api_key_bytes[..len]);
+
+    let decoded = hex::decode(data).map_err(|_| AppError::DecryptionError)?;
+    // Ensure there is enough data for a nonce and ciphertext
+    if decoded.len() < NONCE_LEN {
+        return Err(AppError::DecryptionError);
+    }
+    let (nonce, ciphertext) = decoded.split_at(NONCE_LEN);
+    open(key_bytes.as_slice(), nonce, ciphertext, &[])
+}
+
+// Decrypts a stored value of `field` with the user's API key, in any format. Version 3 values also
+// need the user's unwrapped data key.
+fn decrypt(
+    field: SecretField,
+    data: &str,
+    api_key: &str,
+    data_key: Option<&[u8]>,
+) -> Result<Zeroizing<String>, AppError> {
+    if !data.starts_with(ENVELOPE_PREFIX) {
+        return decrypt_legacy(data, api_key);
+    }
+    let envelope = parse_envelope(data)?;
+    let stretched = stretch(api_key, &envelope.salt)?;
+    let (key, aad): (Zeroizing<[u8; 32]>, &[u8]) = match envelope.version {
+        ENVELOPE_V1 => (cipher_key(&stretched, None), &[]),
+        ENVELOPE_V2 => (cipher_key(&stretched, None), field.as_str().as_bytes()),
+        _ => (
+            cipher_key(&stretched, Some(data_key.ok_or(AppError::DecryptionError)?)),
+            field.as_str().as_bytes(),
+        ),
+    };
+    open(key.as_slice(), &envelope.nonce, &envelope.ciphertext, aad)
+}
+
+// The envelope version new values are sealed with
+fn current_version() -> u8 {
+    if key_wrapper().is_some() {
+        ENVELOPE_V3
+    } else {
+        ENVELOPE_V2
+    }
+}
+
+// Whether a stored value predates the current envelope version, values from before the envelope
+// always do
+fn is_outdated(data: &str) -> bool {
+    parse_envelope(data).map_or(true, |envelope| envelope.version < current_version())
+}
+
+// Decrypts one of a user's secrets, and once it decrypted starts re-encrypting the user's outdated
+// values in the background
+pub async fn decrypt_field(user: &User, field: SecretField, api_key: &str) -> Result<Zeroizing<String>, AppError> {
+    let data = field.value(user).ok_or(AppError::DecryptionError)?;
+    let data_key = match (parse_envelope(data), user.data_key.as_deref()) {
+        (Ok(envelope), Some(stored)) if envelope.version == ENVELOPE_V3 => Some(unwrap_data_key(stored).await?),
+        _ => None,
+    };
+    // Argon2 is deliberately slow, keep it off the async workers
+    let stored = data.to_string();
+    let owned_api_key = Zeroizing::new(api_key.to_string());
+    let plaintext = spawn_blocking(move || {
+        decrypt(field, &stored, &owned_api_key, data_key.as_ref().map(|key| key.as_slice()))
+    })
+    .await
+    .map_err(|e| AppError::CustomError(format!("Decryption panicked: {}", e)))??;
+    if SecretField::ALL.into_iter().any(|field| field.value(user).is_some_and(is_outdated)) {
+        schedule_migration(user.user_id, api_key.to_string());
+    }
+    Ok(plaintext)
+}
+
+fn schedule_migration(user_id: i64, api_key: String) {
+    let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+        return;
+    };
+    if !MIGRATING.get_or_init(Default::default).lock().unwrap().insert(user_id) {
+        return;
+    }
+    runtime.spawn(async move {
+        if let Err(e) = migrate_user(user_id, &api_key).await {
+            eprintln!("Failed to re-encrypt the secrets of user {}: {}", user_id, e);
+        }
+        MIGRATING.get_or_init(Default::default).lock().unwrap().remove(&user_id);
+    });
+}
+
+// Re-encrypts every secret of a user from `old_key` to `new_key` under a fresh salt and data key.
+// Returns the filter and update that swap the new values in, the filter only matches while the stored
+// values and data key are still the ones that were re-encrypted.
+pub async fn reencrypt_user(user: &User, old_key: &str, new_key: &str) -> Result<(Document, Document), AppError> {
+    let stored: Vec<(SecretField, String)> = SecretField::ALL
+        .into_iter()
+        .filter_map(|field| field.value(user).map(|data| (field, data.to_string())))
+        .collect();
+    let data_key = match user.data_key.as_deref() {
+        Some(stored) => Some(unwrap_data_key(stored).await?),
+        None => None,
+    };
+    let cipher = UserCipher::new(new_key).await?;
+
+    let id = user.id;
+    let old_data_key = user.data_key.clone();
+    let old_key = old_key.to_string();
+    spawn_blocking(move || -> Result<(Document, Document), AppError> {
+        let mut filter = doc! { "_id": id };
+        let mut set = doc! {};
+        for (field, data) in &stored {
+            let plaintext = decrypt(*field, data, &old_key, data_key.as_ref().map(|key| key.as_slice()))?;
+            filter.insert(field.as_str(), data.as_str());
+            set.insert(field.as_str(), cipher.encrypt(*field, &plaintext)?);
+        }
+        match old_data_key {
+            Some(old_data_key) => filter.insert("data_key", old_data_key),
+            None => filter.insert("data_key", Bson::Null),
+        };
+        let mut update = doc! {};
+        match cipher.new_data_key() {
+            Some(new_data_key) => {
+                set.insert("data_key", new_data_key);
+            }
+            None => {
+                update.insert("$unset", doc! { "data_key": "" });
+            }
+        }
+        update.insert("$set", set);
+        Ok((filter, update))
+    })
+    .await
+    .map_err(|e| AppError::CustomError(format!("Re-encryption panicked: {}", e)))?
+}
+
+// Re-encrypts every outdated value of a user with the current version, storing a new data key along
+// with them when the user gets their first one. The update only applies if none of the values changed
+// in the meantime, a concurrent write wins and the next decrypt tries again.
+async fn migrate_user(user_id: i64, api_key: &str) -> Result<(), AppError> {
+    let users = get_users_collection().await?;
+    let user = users.find_one(doc! { "user_id": user_id }, None).await?.ok_or(AppError::NotFound)?;
+    let outdated: Vec<(SecretField, String)> = SecretField::ALL
+        .into_iter()
+        .filter_map(|field| field.value(&user).filter(|data| is_outdated(data)).map(|data| (field, data.to_string())))
+        .collect();
+    if outdated.is_empty() {
+        return Ok(());
+    }
+    let migrated = outdated.len();
+    let cipher = UserCipher::for_user(&user, api_key).await?;
+
+    // Argon2 is deliberately slow, keep it off the async workers. Outdated values are never version 3,
+    // so no data key is needed to open them.
+    let api_key = api_key.to_string();
+    let (filter, set) = spawn_blocking(move || -> Result<(Document, Document), AppError> {
+        let mut filter = doc! { "_id": user.id };
+        let mut set = doc! {};
+        for (field, data) in &outdated {
+            let plaintext = decrypt(*field, data, &api_key, None)?;
+            filter.insert(field.as_str(), data.as_str());
+            set.insert(field.as_str(), cipher.encrypt(*field, &plaintext)?);
+        }
+        if let Some(data_key) = cipher.new_data_key() {
+            // Matches a missing data key as well as the null stored for users registered without one
+            filter.insert("data_key", Bson::Null);
+            set.insert("data_key", data_key);
+        }
+        Ok((filter, set))
+    })
+    .await
+    .map_err(|e| AppError::CustomError(format!("Re-encryption panicked: {}", e)))??;
+
+    if users.update_one(filter, doc! { "$set": set }, None).await?.modified_count == 1 {
+        println!("Re-encrypted {} secrets of user {} with envelope version {}", migrated, user_id, current_version());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const API_KEY: &str = "6f1c1a4e-0b7d-4d0e-9a51-3c2f8e9b7d10";
+    const SALT: [u8; SALT_LEN] = [9; SALT_LEN];
+
+    fn cipher(version: u8, data_key: Option<&[u8]>) -> UserCipher {
+        let key = cipher_key(&stretch(API_KEY, &SALT).unwrap(), data_key);
+        UserCipher { salt: SALT, key, version, new_data_key: None }
+    }
+
+    // A version 1 envelope, sealed without associated data
+    fn seal_v1(data: &str) -> String {
+        let key = stretch(API_KEY, &SALT).unwrap();
+        let nonce = [3u8; NONCE_LEN];
+        let ciphertext = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key.as_slice()))
+            .encrypt(Nonce::from_slice(&nonce), data.as_bytes())
+            .unwrap();
+        let bytes = [[ENVELOPE_V1].as_slice(), SALT.as_slice(), nonce.as_slice(), ciphertext.as_slice()].concat();
+        format!("{}{}", ENVELOPE_PREFIX, hex::encode(bytes))
+    }
+
+    fn flip_last_byte(data: &str) -> String {
+        let mut bytes = hex::decode(data.strip_prefix(ENVELOPE_PREFIX).unwrap()).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        format!("{}{}", ENVELOPE_PREFIX, hex::encode(bytes))
+    }
+
+    #[test]
+    fn version_2_round_trips_and_binds_the_field() {
+        let sealed = cipher(ENVELOPE_V2, None).encrypt(SecretField::SolanaPrivateKey, "secret").unwrap();
+        let envelope = parse_envelope(&sealed).unwrap();
+        assert_eq!((envelope.version, envelope.salt), (ENVELOPE_V2, SALT));
+
+        assert_eq!(*decrypt(SecretField::SolanaPrivateKey, &sealed, API_KEY, None).unwrap(), "secret");
+        // The field is the associated data, so a value moved to another field does not open
+        assert!(decrypt(SecretField::BitcoinMnemonic, &sealed, API_KEY, None).is_err());
+        assert!(decrypt(SecretField::SolanaPrivateKey, &sealed, "another-api-key", None).is_err());
+        assert!(decrypt(SecretField::SolanaPrivateKey, &flip_last_byte(&sealed), API_KEY, None).is_err());
+    }
+
+    #[test]
+    fn version_3_needs_the_data_key() {
+        let data_key = [5u8; 32];
+        let sealed = cipher(ENVELOPE_V3, Some(&data_key)).encrypt(SecretField::BitcoinPrivateKey, "secret").unwrap();
+
+        assert_eq!(*decrypt(SecretField::BitcoinPrivateKey, &sealed, API_KEY, Some(&data_key)).unwrap(), "secret");
+        assert!(decrypt(SecretField::BitcoinPrivateKey, &sealed, API_KEY, None).is_err());
+        assert!(decrypt(SecretField::BitcoinPrivateKey, &sealed, API_KEY, Some(&[6u8; 32])).is_err());
+    }
+
+    #[test]
+    fn older_formats_still_open() {
+        assert_eq!(*decrypt(SecretField::EthereumPrivateKey, &seal_v1("secret"), API_KEY, None).unwrap(), "secret");
+
+        // Before the envelope the key was the API key's first 32 bytes and the value plain hex
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&API_KEY.as_bytes()[..32]);
+        let nonce = [4u8; NONCE_LEN];
+        let ciphertext = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+            .encrypt(Nonce::from_slice(&nonce), b"secret".as_slice())
+            .unwrap();
+        let legacy = hex::encode([nonce.as_slice(), ciphertext.as_slice()].concat());
+        assert_eq!(*decrypt(SecretField::EthereumPrivateKey, &legacy, API_KEY, None).unwrap(), "secret");
+    }
+
+    #[test]
+    fn malformed_envelopes_are_rejected() {
+        let sealed = cipher(ENVELOPE_V2, None).encrypt(SecretField::SolanaPrivateKey, "secret").unwrap();
+        let hex_part = sealed.strip_prefix(ENVELOPE_PREFIX).unwrap();
+
+        let unknown_version = format!("{}04{}", ENVELOPE_PREFIX, &hex_part[2..]);
+        // Salt and nonce with no ciphertext
+        let truncated = format!("{}{}", ENVELOPE_PREFIX, &hex_part[..2 * (1 + SALT_LEN + NONCE_LEN)]);
+        for data in [unknown_version, truncated, format!("{}zz", ENVELOPE_PREFIX), hex_part.to_string()] {
+            assert!(parse_envelope(&data).is_err(), "{}", data);
+        }
+    }
+
+    #[test]
+    fn token_seals_only_open_with_the_token() {
+        let sealed = seal_for_token("token", "secret").unwrap();
+        assert_ne!(seal_for_token("token", "secret").unwrap(), sealed, "nonces must not repeat");
+        assert_eq!(*open_for_token("token", &sealed).unwrap(), "secret");
+        assert!(open_for_token("other token", &sealed).is_err());
+        assert!(open_for_token("token", "00").is_err());
+    }
+}
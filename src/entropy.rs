@@ -0,0 +1,79 @@
+// entropy.rs
+// All key material and nonces are drawn from here. Randomness comes straight from the operating
+// system (getrandom), never from a seeded or user space generator.
+use bdk::keys::bip39::Mnemonic;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use uuid::{Builder, Uuid};
+
+use crate::error_handling::AppError;
+
+// Fills `bytes` from the OS CSPRNG, failing instead of falling back to weaker randomness
+pub fn fill(bytes: &mut [u8]) -> Result<(), AppError> {
+    OsRng
+        .try_fill_bytes(bytes)
+        .map_err(|e| AppError::CustomError(format!("OS randomness unavailable: {}", e)))
+}
+
+pub fn random_bytes<const N: usize>() -> Result<[u8; N], AppError> {
+    let mut bytes = [0u8; N];
+    fill(&mut bytes)?;
+    Ok(bytes)
+}
+
+// A 96 bit AES-GCM nonce
+pub fn nonce() -> Result<[u8; 12], AppError> {
+    random_bytes()
+}
+
+// A version 4 UUID, used for API keys
+pub fn uuid() -> Result<Uuid, AppError> {
+    Ok(Builder::from_random_bytes(random_bytes()?).into_uuid())
+}
+
+// A 12 word English BIP39 mnemonic from 128 bits of entropy
+pub fn mnemonic() -> Result<Mnemonic, AppError> {
+    let entropy: [u8; 16] = random_bytes()?;
+    Mnemonic::from_entropy(&entropy).map_err(|e| AppError::CustomError(format!("Invalid mnemonic entropy: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn draws_never_repeat() {
+        let keys: HashSet<[u8; 32]> = (0..1000).map(|_| random_bytes().unwrap()).collect();
+        assert_eq!(keys.len(), 1000);
+        let nonces: HashSet<[u8; 12]> = (0..1000).map(|_| nonce().unwrap()).collect();
+        assert_eq!(nonces.len(), 1000);
+    }
+
+    // Over 32 KiB of output every byte value shows up, and each bit is set close to half the time
+    #[test]
+    fn bytes_are_evenly_spread() {
+        let mut bytes = vec![0u8; 32 * 1024];
+        fill(&mut bytes).unwrap();
+        let values: HashSet<u8> = bytes.iter().copied().collect();
+        assert_eq!(values.len(), 256);
+        let ones: u32 = bytes.iter().map(|byte| byte.count_ones()).sum();
+        let ratio = ones as f64 / (bytes.len() * 8) as f64;
+        assert!((0.49..0.51).contains(&ratio), "bit ratio {}", ratio);
+    }
+
+    #[test]
+    fn uuids_are_random_version_4() {
+        let uuid = uuid().unwrap();
+        assert_eq!(uuid.get_version_num(), 4);
+        assert_eq!(uuid.get_variant(), uuid::Variant::RFC4122);
+        assert_ne!(uuid, super::uuid().unwrap());
+    }
+
+    #[test]
+    fn mnemonics_have_twelve_words_and_differ() {
+        let first = mnemonic().unwrap();
+        assert_eq!(first.word_count(), 12);
+        assert_ne!(first.to_string(), mnemonic().unwrap().to_string());
+    }
+}
@@ -0,0 +1,237 @@
+// erc20_watcher.rs
+use crate::config::config;
+use crate::dry_run;
+use crate::error_handling::AppError;
+use crate::maintenance;
+use crate::mongo::{get_settings_collection, get_token_deposits_collection, get_users_collection, TokenDeposit, User};
+use crate::poller::is_poller_paused;
+use crate::wallets::ethereum::{address_from_public_key, supported_tokens, Erc20Token};
+use mongodb::bson::{doc, DateTime as BsonDateTime};
+use mongodb::options::UpdateOptions;
+use mongodb::Collection;
+use reqwest::Client;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::interval;
+
+const SETTINGS_ID: &str = "erc20_watcher";
+// keccak256("Transfer(address,address,uint256)")
+const TRANSFER_TOPIC: &str = "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef";
+// Public RPC providers cap the block range of a single eth_getLogs call
+const MAX_BLOCK_RANGE: u64 = 1_000;
+
+// A Transfer log into one of the users' addresses
+#[derive(Debug, Clone)]
+struct TokenTransfer {
+    user_id: i64,
+    symbol: &'static str,
+    contract: &'static str,
+    tx_hash: String,
+    log_index: i64,
+    block_number: i64,
+    from_address: String,
+    amount: f64,
+}
+
+// Scans Transfer logs of the supported stablecoins every ETH_WATCHER_INTERVAL_SECS and credits
+// transfers with ETH_MIN_CONFIRMATIONS confirmations to the receiving user's token balance
+pub async fn start_erc20_watcher() -> Result<(), AppError> {
+    let client = Client::new();
+    let mut interval = interval(Duration::from_secs(config().eth_watcher_interval_secs));
+    loop {
+        interval.tick().await;
+        if maintenance::is_enabled() || is_poller_paused() {
+            continue;
+        }
+        if let Err(e) = scan_transfers(&client).await {
+            eprintln!("ERC-20 watcher failed: {:?}", e);
+        }
+    }
+}
+
+async fn rpc(client: &Client, method: &str, params: Value) -> Result<Value, AppError> {
+    let request = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+    let response: Value = client.post(&config().eth_rpc_url).json(&request).send().await?.json().await?;
+    if let Some(error) = response.get("error") {
+        return Err(AppError::CustomError(format!("Ethereum RPC {} failed: {}", method, error)));
+    }
+    Ok(response["result"].clone())
+}
+
+// Parses a hex quantity. Stablecoin amounts fit comfortably in 128 bits.
+fn parse_quantity(value: &Value) -> Option<u128> {
+    let hex = value.as_str()?.trim_start_matches("0x");
+    if hex.is_empty() {
+        return Some(0);
+    }
+    u128::from_str_radix(hex, 16).ok()
+}
+
+// The address held in the last 20 bytes of an indexed topic
+fn topic_address(topic: &Value) -> Option<String> {
+    let topic = topic.as_str()?;
+    (topic.len() >= 40).then(|| format!("0x{}", topic[topic.len() - 40..].to_lowercase()))
+}
+
+async fn scan_transfers(client: &Client) -> Result<(), AppError> {
+    let users_collection = get_users_collection().await?;
+    let deposits_collection = get_token_deposits_collection().await?;
+    let settings = get_settings_collection().await?;
+
+    let mut watched = HashMap::new();
+    let mut cursor = users_collection
+        .find(doc! { "ethereum_public_key": { "$nin": [null, ""] } }, None)
+        .await?;
+    while cursor.advance().await? {
+        let user = cursor.deserialize_current()?;
+        match user.ethereum_public_key.as_deref().and_then(address_from_public_key) {
+            Some(address) => {
+                watched.insert(address.to_lowercase(), user.user_id);
+            }
+            None => eprintln!("Invalid Ethereum public key for user {}", user.user_id),
+        }
+    }
+    if watched.is_empty() {
+        return Ok(());
+    }
+
+    let latest = parse_quantity(&rpc(client, "eth_blockNumber", json!([])).await?)
+        .ok_or_else(|| AppError::CustomError("Invalid block number from Ethereum RPC".to_string()))?
+        as u64;
+    let safe_head = latest.saturating_sub(config().eth_min_confirmations);
+
+    // The first scan starts at the confirmed head instead of replaying the whole chain
+    let last_scanned = settings
+        .find_one(doc! { "_id": SETTINGS_ID }, None)
+        .await?
+        .and_then(|setting| setting.get_i64("last_block").ok());
+    let mut from = match last_scanned {
+        Some(block) => block as u64 + 1,
+        None => safe_head,
+    };
+
+    let tokens = supported_tokens();
+    let contracts: Vec<&str> = tokens.iter().map(|token| token.contract).collect();
+    let recipients: Vec<String> = watched
+        .keys()
+        .map(|address| format!("0x{:0>64}", address.trim_start_matches("0x")))
+        .collect();
+
+    while from <= safe_head {
+        let to = (from + MAX_BLOCK_RANGE - 1).min(safe_head);
+        let filter = json!({
+            "fromBlock": format!("0x{:x}", from),
+            "toBlock": format!("0x{:x}", to),
+            "address": contracts,
+            "topics": [TRANSFER_TOPIC, null, recipients],
+        });
+        let logs = rpc(client, "eth_getLogs", json!([filter])).await?;
+        for log in logs.as_array().into_iter().flatten() {
+            if let Some(transfer) = parse_transfer(log, tokens, &watched) {
+                // Leave the cursor where it is so the range is scanned again next time
+                record_transfer(&users_collection, &deposits_collection, &transfer).await?;
+            }
+        }
+
+        settings
+            .update_one(
+                doc! { "_id": SETTINGS_ID },
+                doc! { "$set": { "last_block": to as i64, "updated_at": BsonDateTime::now() } },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+        from = to + 1;
+    }
+    Ok(())
+}
+
+fn parse_transfer(log: &Value, tokens: &'static [Erc20Token], watched: &HashMap<String, i64>) -> Option<TokenTransfer> {
+    if log["removed"] == true {
+        return None;
+    }
+    let contract = log["address"].as_str()?.to_lowercase();
+    let token = tokens.iter().find(|token| token.contract == contract)?;
+    let topics = log["topics"].as_array()?;
+    let to_address = topic_address(topics.get(2)?)?;
+    let user_id = *watched.get(&to_address)?;
+    let raw_amount = parse_quantity(&log["data"])?;
+    Some(TokenTransfer {
+        user_id,
+        symbol: token.symbol,
+        contract: token.contract,
+        tx_hash: log["transactionHash"].as_str()?.to_lowercase(),
+        log_index: parse_quantity(&log["logIndex"])? as i64,
+        block_number: parse_quantity(&log["blockNumber"])? as i64,
+        from_address: topic_address(topics.get(1)?)?,
+        amount: raw_amount as f64 / 10f64.powi(token.decimals as i32),
+    })
+}
+
+// Records the transfer once and credits it to the user's token balance exactly once
+async fn record_transfer(
+    users_collection: &Collection<User>,
+    deposits_collection: &Collection<TokenDeposit>,
+    transfer: &TokenTransfer,
+) -> Result<(), AppError> {
+    let deposit_key = format!("{}:{}", transfer.tx_hash, transfer.log_index);
+
+    // In dry-run mode balances are left untouched and each transfer is only reported once
+    if dry_run::is_enabled() {
+        if dry_run::has_processed_deposit(&deposit_key).await? {
+            return Ok(());
+        }
+        dry_run::record_action(
+            "process_deposit",
+            json!({
+                "refid": deposit_key,
+                "user_id": transfer.user_id,
+                "symbol": transfer.symbol,
+                "amount": transfer.amount,
+            }),
+        )
+        .await;
+        return Ok(());
+    }
+
+    let filter = doc! { "tx_hash": &transfer.tx_hash, "log_index": transfer.log_index };
+    deposits_collection
+        .update_one(
+            filter.clone(),
+            doc! { "$setOnInsert": {
+                "user_id": transfer.user_id,
+                "symbol": transfer.symbol,
+                "contract": transfer.contract,
+                "block_number": transfer.block_number,
+                "from_address": &transfer.from_address,
+                "amount": transfer.amount,
+                "credited": false,
+                "created_at": BsonDateTime::now(),
+            } },
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .await?;
+
+    // Claim the credit first so a rescan can't add the same transfer twice
+    let mut unclaimed = filter;
+    unclaimed.insert("credited", false);
+    let claimed = deposits_collection
+        .update_one(unclaimed, doc! { "$set": { "credited": true } }, None)
+        .await?;
+    if claimed.modified_count == 0 {
+        return Ok(());
+    }
+
+    users_collection
+        .update_one(
+            doc! { "user_id": transfer.user_id },
+            doc! { "$inc": { format!("token_balances.{}", transfer.symbol): transfer.amount } },
+            None,
+        )
+        .await?;
+    println!(
+        "Credited {} {} to user {} from {}",
+        transfer.amount, transfer.symbol, transfer.user_id, transfer.tx_hash
+    );
+    Ok(())
+}
@@ -6,6 +6,10 @@ use thiserror::Error;
 use kraken_rest_client::Error as KrakenError;
 use std::num::ParseFloatError;
 
+use crate::circuit_breaker::CircuitOpen;
+use crate::lockin::LockinClientError;
+use crate::request_id;
+
 #[derive(Error, Debug)]
 pub enum AppError {
     #[error("Database error")]
@@ -23,42 +27,161 @@ pub enum AppError {
     #[error("Decryption error")]
     DecryptionError,
 
+    #[error("Unauthorized")]
+    Unauthorized,
+
+    #[error("Forbidden")]
+    Forbidden,
+
+    #[error("Not found")]
+    NotFound,
+
+    #[error("Bad request: {0}")]
+    BadRequest(String),
+
+    #[error("{0}")]
+    TooManyRequests(String),
+
+    #[error("{0}")]
+    ServiceUnavailable(String),
+
+    #[error(transparent)]
+    UpstreamUnavailable(#[from] CircuitOpen),
+
     #[error("Bitcoin consensus error")]
     BitcoinConsensusError(#[from] bdk::bitcoin::consensus::encode::Error),
 
     #[error("Electrum client error")]
     ElectrumClientError(#[from] bdk::electrum_client::Error),
 
-    #[error("Kraken API error")]
+    #[error("Bitcoin wallet error")]
+    BitcoinWalletError(#[from] bdk::Error),
+
+    #[error("Kraken API error: {0}")]
     KrakenError(#[from] KrakenError),
 
+    #[error("Kraken order failed: {0}")]
+    KrakenOrderFailed(String),
+
+    #[error("Order volume too small: {0}")]
+    InsufficientVolume(String),
+
+    #[error("Coinbase API error: {0}")]
+    CoinbaseError(String),
+
+    #[error("Lightning node error: {0}")]
+    LightningError(String),
+
+    #[error("Price unavailable: {0}")]
+    PriceError(String),
+
+    #[error("Invalid address: {0}")]
+    InvalidAddress(String),
+
     #[error("Reqwest error")]
     ReqwestError(#[from] reqwest::Error),
 
     #[error("Serde JSON error")]
     SerdeJsonError(#[from] serde_json::Error),
 
-    #[error("Custom error")]
+    #[error("WebSocket error")]
+    WebSocketError(#[source] Box<tokio_tungstenite::tungstenite::Error>),
+
+    #[error("{0}")]
     CustomError(String),
+
+    #[error(transparent)]
+    SwapFailed(#[from] LockinClientError),
+
+    // Any other error, with its chain of causes
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl AppError {
+    // The status code, a stable machine-readable error code, and whether the same request can succeed
+    // when retried later
+    fn details(&self) -> (StatusCode, &'static str, bool) {
+        match self {
+            AppError::DatabaseError(_) => (StatusCode::INTERNAL_SERVER_ERROR, "DATABASE_ERROR", true),
+            AppError::EnvVarError(_) => (StatusCode::INTERNAL_SERVER_ERROR, "CONFIGURATION_ERROR", false),
+            AppError::UuidError(_) => (StatusCode::BAD_REQUEST, "INVALID_UUID", false),
+            AppError::InternalServerError => (StatusCode::INTERNAL_SERVER_ERROR, "INTERNAL_ERROR", false),
+            AppError::DecryptionError => (StatusCode::BAD_REQUEST, "DECRYPTION_FAILED", false),
+            AppError::Unauthorized => (StatusCode::UNAUTHORIZED, "UNAUTHORIZED", false),
+            AppError::Forbidden => (StatusCode::FORBIDDEN, "FORBIDDEN", false),
+            AppError::NotFound => (StatusCode::NOT_FOUND, "NOT_FOUND", false),
+            AppError::BadRequest(_) => (StatusCode::BAD_REQUEST, "BAD_REQUEST", false),
+            AppError::TooManyRequests(_) => (StatusCode::TOO_MANY_REQUESTS, "TOO_MANY_ATTEMPTS", true),
+            AppError::ServiceUnavailable(_) => (StatusCode::SERVICE_UNAVAILABLE, "SERVICE_UNAVAILABLE", true),
+            AppError::UpstreamUnavailable(_) => (StatusCode::SERVICE_UNAVAILABLE, "UPSTREAM_UNAVAILABLE", true),
+            AppError::BitcoinConsensusError(_) => (StatusCode::INTERNAL_SERVER_ERROR, "BITCOIN_ENCODING_ERROR", false),
+            AppError::ElectrumClientError(_) => (StatusCode::INTERNAL_SERVER_ERROR, "ELECTRUM_UNAVAILABLE", true),
+            AppError::BitcoinWalletError(_) => (StatusCode::INTERNAL_SERVER_ERROR, "BITCOIN_WALLET_ERROR", false),
+            AppError::KrakenError(_) => (StatusCode::INTERNAL_SERVER_ERROR, "KRAKEN_API_ERROR", true),
+            AppError::KrakenOrderFailed(_) => (StatusCode::INTERNAL_SERVER_ERROR, "KRAKEN_ORDER_FAILED", false),
+            AppError::InsufficientVolume(_) => (StatusCode::BAD_REQUEST, "INSUFFICIENT_VOLUME", false),
+            AppError::CoinbaseError(_) => (StatusCode::INTERNAL_SERVER_ERROR, "COINBASE_API_ERROR", false),
+            AppError::LightningError(_) => (StatusCode::BAD_GATEWAY, "LIGHTNING_NODE_ERROR", true),
+            AppError::PriceError(_) => (StatusCode::BAD_GATEWAY, "PRICE_UNAVAILABLE", true),
+            AppError::InvalidAddress(_) => (StatusCode::BAD_REQUEST, "INVALID_ADDRESS", false),
+            AppError::ReqwestError(_) => (StatusCode::INTERNAL_SERVER_ERROR, "UPSTREAM_REQUEST_FAILED", true),
+            AppError::SerdeJsonError(_) => (StatusCode::INTERNAL_SERVER_ERROR, "INVALID_JSON", false),
+            AppError::WebSocketError(_) => (StatusCode::INTERNAL_SERVER_ERROR, "WEBSOCKET_ERROR", true),
+            AppError::CustomError(_) => (StatusCode::INTERNAL_SERVER_ERROR, "INTERNAL_ERROR", false),
+            AppError::SwapFailed(error) => match error {
+                LockinClientError::QuoteError(_)
+                | LockinClientError::SwapError(_)
+                | LockinClientError::SwapInstructionsError(_) => {
+                    (StatusCode::INTERNAL_SERVER_ERROR, "JUPITER_ERROR", true)
+                }
+                LockinClientError::RetriesExhausted { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "SWAP_FAILED", false),
+                LockinClientError::QuoteRejected { .. } => (StatusCode::BAD_GATEWAY, "QUOTE_REJECTED", true),
+                _ => (StatusCode::INTERNAL_SERVER_ERROR, "SOLANA_ERROR", false),
+            },
+            AppError::Other(_) => (StatusCode::INTERNAL_SERVER_ERROR, "INTERNAL_ERROR", false),
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        self.details().2
+    }
+
+    // The error followed by its causes, for logs and stored failure reasons
+    pub fn report(&self) -> String {
+        let mut report = self.to_string();
+        let mut source = std::error::Error::source(self);
+        while let Some(cause) = source {
+            let cause_message = cause.to_string();
+            if !report.contains(&cause_message) {
+                report.push_str(": ");
+                report.push_str(&cause_message);
+            }
+            source = cause.source();
+        }
+        report
+    }
 }
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        let (status, error_message) = match self {
-            AppError::DatabaseError(_) => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()),
-            AppError::EnvVarError(_) => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()),
-            AppError::UuidError(_) => (StatusCode::BAD_REQUEST, self.to_string()),
-            AppError::InternalServerError => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()),
-            AppError::DecryptionError => (StatusCode::BAD_REQUEST, self.to_string()),
-            AppError::BitcoinConsensusError(_) => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()),
-            AppError::ElectrumClientError(_) => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()),
-            AppError::KrakenError(_) => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()),
-            AppError::ReqwestError(_) => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()),
-            AppError::SerdeJsonError(_) => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()),
-            AppError::CustomError(_) => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()),
-        };
+        let request_id = request_id::current();
+        if let Some(request_id) = &request_id {
+            eprintln!("Request {} failed: {}", request_id, self.report());
+        }
+        let (status, code, retryable) = self.details();
 
-        (status, axum::Json(json!({"error": error_message}))).into_response()
+        // The request ID lets users quote the failure when reporting it
+        (
+            status,
+            axum::Json(json!({
+                "error": self.to_string(),
+                "code": code,
+                "retryable": retryable,
+                "request_id": request_id,
+            })),
+        )
+            .into_response()
     }
 }
 
@@ -69,17 +192,32 @@ impl From<ParseFloatError> for AppError {
     }
 }
 
+// Boxed, tungstenite errors are large enough to bloat every Result carrying an AppError
+impl From<tokio_tungstenite::tungstenite::Error> for AppError {
+    fn from(error: tokio_tungstenite::tungstenite::Error) -> Self {
+        AppError::WebSocketError(Box::new(error))
+    }
+}
+
 impl From<anyhow::Error> for AppError {
+    // Errors that were only carried through anyhow keep their type when it is known, anything else
+    // is kept whole with its context chain
     fn from(error: anyhow::Error) -> Self {
-        let _ = error;
-        // Convert the `anyhow::Error` into an `AppError`
-        // based on the specific error handling logic you want.
-        // You can handle different error types here and map them to `AppError`.
-        // For example:
-        // match error.downcast_ref::<ParseFloatError>() {
-        //     Some(parse_float_error) => AppError::from(parse_float_error),
-        //     None => AppError::OtherError("Unknown error".to_string()),
-        // }
-        AppError::CustomError("An error occurred".to_string())
+        let error = match error.downcast::<LockinClientError>() {
+            Ok(error) => return AppError::SwapFailed(error),
+            Err(error) => error,
+        };
+        let error = match error.downcast::<CircuitOpen>() {
+            Ok(error) => return AppError::UpstreamUnavailable(error),
+            Err(error) => error,
+        };
+        let error = match error.downcast::<mongodb::error::Error>() {
+            Ok(error) => return AppError::DatabaseError(error),
+            Err(error) => error,
+        };
+        match error.downcast::<reqwest::Error>() {
+            Ok(error) => AppError::ReqwestError(error),
+            Err(error) => AppError::Other(error),
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,876 @@
+// kraken.rs
+use crate::circuit_breaker;
+use crate::dry_run;
+use crate::error_handling::AppError; // Import the custom error type
+use crate::config::{config, OrderType};
+use crate::exchange::{base_asset, Exchange, OrderFill, WithdrawalQuote};
+use crate::redact::redact_json;
+use crate::retry::{self, retry_with_backoff};
+use async_trait::async_trait;
+use kraken_rest_client::{Client, Error, OrderSide}; // Replace with the actual crate name
+use reqwest::Client as SimpleClient;
+use rust_decimal::{Decimal, RoundingStrategy};
+use rust_decimal_macros::dec;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::time::{sleep, Duration, Instant};
+
+// Structs
+#[allow(dead_code)]
+#[derive(Debug, Deserialize, Serialize)]
+struct ApiResponse {
+    id: String,
+    success: bool,
+    data: HashMap<String, String>,
+}
+
+// Account balances keyed by Kraken asset name, e.g. "XXBT", "ZUSD" or "SOL"
+#[derive(Debug, Deserialize)]
+#[serde(transparent)]
+pub struct KrakenBalances {
+    balances: HashMap<String, String>,
+}
+
+impl KrakenBalances {
+    // Returns the balance for an asset, accepting common tickers like "BTC" or "USD"
+    pub fn get(&self, asset: &str) -> Decimal {
+        kraken_asset_names(asset)
+            .iter()
+            .filter_map(|name| self.balances.get(name))
+            .filter_map(|balance| balance.parse::<Decimal>().ok())
+            .sum()
+    }
+}
+
+// Maps a ticker to the asset names Kraken may report it under
+fn kraken_asset_names(asset: &str) -> Vec<String> {
+    match asset {
+        "BTC" | "XBT" | "XXBT" => vec!["XXBT".to_string(), "XBT".to_string()],
+        "USD" | "ZUSD" => vec!["ZUSD".to_string(), "USD".to_string()],
+        _ => vec![asset.to_string(), format!("X{}", asset)],
+    }
+}
+
+// Order details as returned by QueryOrders, Kraken reports all amounts as strings
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct KrakenOrder {
+    pub status: String,
+    pub vol: String,
+    pub vol_exec: String,
+    pub cost: String,
+    pub fee: String,
+    pub price: String,
+}
+
+impl KrakenOrder {
+    pub fn volume_executed(&self) -> Decimal {
+        self.vol_exec.parse().unwrap_or_default()
+    }
+
+    pub fn cost(&self) -> Decimal {
+        self.cost.parse().unwrap_or_default()
+    }
+
+    pub fn fee(&self) -> Decimal {
+        self.fee.parse().unwrap_or_default()
+    }
+
+    pub fn average_price(&self) -> Decimal {
+        self.price.parse().unwrap_or_default()
+    }
+}
+
+impl From<KrakenOrder> for OrderFill {
+    fn from(order: KrakenOrder) -> Self {
+        OrderFill {
+            volume_executed: order.volume_executed(),
+            cost: order.cost(),
+            fee: order.fee(),
+            average_price: order.average_price(),
+            status: order.status,
+        }
+    }
+}
+
+// Hands out strictly increasing nonces for private Kraken calls. Seeded from wall-clock millis,
+// but never goes backwards on clock skew and never repeats under concurrent requests.
+pub struct NonceProvider {
+    last: AtomicU64,
+}
+
+impl NonceProvider {
+    pub const fn new() -> Self {
+        Self {
+            last: AtomicU64::new(0),
+        }
+    }
+
+    pub fn next(&self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since_the_epoch| since_the_epoch.as_millis() as u64)
+            .unwrap_or(0);
+        let previous = self
+            .last
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| {
+                Some(now.max(last + 1))
+            })
+            .unwrap_or_else(|last| last);
+        now.max(previous + 1)
+    }
+}
+
+static NONCE_PROVIDER: NonceProvider = NonceProvider::new();
+
+// Function to create a Kraken client from the configured API credentials
+fn kraken_client() -> Client {
+    let config = config();
+    Client::new(config.kraken_api_key.clone(), config.kraken_api_secret.clone())
+}
+
+// Function to get the next nonce from the shared provider
+pub fn get_nonce() -> String {
+    NONCE_PROVIDER.next().to_string()
+}
+
+// The number of decimals Kraken accepts in order volumes (lot_decimals) and prices (pair_decimals)
+fn volume_decimals(_pair: &str) -> u32 {
+    8
+}
+
+fn price_decimals(pair: &str) -> u32 {
+    match pair {
+        "BTCUSD" | "XBTUSD" => 1,
+        "SOLUSD" => 2,
+        _ => 4,
+    }
+}
+
+// Function to format the volume, truncated to the pair's precision so an order never asks for more
+// than is held
+pub fn format_volume(pair: &str, volume: Decimal) -> String {
+    volume
+        .round_dp_with_strategy(volume_decimals(pair), RoundingStrategy::ToZero)
+        .to_string()
+}
+
+// Whether a failed Kraken call may succeed when retried. Kraken's own API errors (bad key, missing
+// permission, invalid arguments) fail the same way again, except for outages and rate limiting.
+fn is_transient(error: &AppError) -> bool {
+    match error {
+        AppError::KrakenError(Error::Api(message)) => {
+            message.contains("EService:") || message.contains("EAPI:Rate limit")
+        }
+        other => other.is_retryable(),
+    }
+}
+
+// Function to send a private request that only reads, retrying transient failures with a new nonce
+async fn private_read<T: DeserializeOwned>(path: &str, params: Value) -> Result<T, AppError> {
+    let request = retry_with_backoff(&retry::KRAKEN, path, is_transient, || {
+        let mut payload = params.clone();
+        payload["nonce"] = json!(get_nonce());
+        async move {
+            let response: T = kraken_client().send_private_json(path, payload).await?;
+            Ok::<T, AppError>(response)
+        }
+    });
+    circuit_breaker::KRAKEN.call(is_transient, request).await
+}
+
+// Function to check the minimum volume
+pub fn check_minimum_volume(asset: &str, volume: Decimal) -> Result<(), AppError> {
+    let min_volume = match asset {
+        "BTC" => dec!(0.0001), // Example minimum volume for BTC
+        "USDC" | "USDT" => dec!(1),
+        // Add other assets and their minimum volumes as needed
+        _ => Decimal::ZERO,
+    };
+
+    if volume < min_volume {
+        println!("Volume too small: {} < {}", volume, min_volume);
+        return Err(AppError::InsufficientVolume(format!(
+            "{} {} is below the minimum of {}",
+            volume, asset, min_volume
+        )));
+    }
+
+    Ok(())
+}
+
+// Function to get asset trading value in USD from Kraken
+#[tracing::instrument(name = "kraken.ticker", skip_all, fields(asset = asset))]
+pub async fn get_asset_value(asset: &str) -> Result<Decimal, AppError> {
+    let request = retry_with_backoff(&retry::KRAKEN, "Kraken Ticker", is_transient, || fetch_asset_value(asset));
+    circuit_breaker::KRAKEN.call(is_transient, request).await
+}
+
+async fn fetch_asset_value(asset: &str) -> Result<Decimal, AppError> {
+    // Construct the trading pair (e.g., "XBTUSD")
+    let pair = format!("{}USD", asset);
+
+    // Define the Kraken API endpoint
+    let api_url = format!("https://api.kraken.com/0/public/Ticker?pair={}", pair);
+
+    // Create a reqwest client
+    let client = SimpleClient::new();
+
+    // Send the GET request
+    let response = client.get(&api_url).send().await?.text().await?;
+    println!("Kraken API response: {}", response); // Debug print
+
+    // Parse the JSON response
+    let json: Value = serde_json::from_str(&response).map_err(|e| {
+        println!("Error parsing JSON response: {:?}", e); // Debug print
+        AppError::PriceError(format!("Invalid Kraken ticker response for {}", pair))
+    })?;
+
+    // Extract the trading value in USD
+    if let Some(result) = json["result"].as_object() {
+        for (key, value) in result {
+            if key.contains(asset) || key.contains("USD") {
+                if let Some(price) = value["c"][0].as_str() {
+                    let price: Decimal = price.parse().map_err(|e| {
+                        println!("Error parsing price value: {:?}", e); // Debug print
+                        AppError::PriceError(format!("Invalid Kraken price for {}", pair))
+                    })?;
+                    return Ok(price);
+                } else {
+                    println!("Price value not found in JSON response"); // Debug print
+                    return Err(AppError::PriceError(format!("No Kraken price for {}", pair)));
+                }
+            }
+        }
+        println!("No matching asset pair found in JSON response"); // Debug print
+    } else {
+        println!("Result field not found in JSON response"); // Debug print
+    }
+
+    Err(AppError::PriceError(format!("No Kraken ticker for {}", pair)))
+}
+
+// Function to execute a market swap on Kraken
+#[tracing::instrument(name = "kraken.add_order", skip_all, fields(pair = pair, side = %side, volume = %volume))]
+pub async fn execute_swap(pair: &str, side: OrderSide, volume: Decimal) -> Result<Value, AppError> {
+    // Check the minimum volume
+    let asset = base_asset(pair);
+    check_minimum_volume(asset, volume)?;
+
+    // Get the asset value in USD
+    let asset_value_in_usd = get_asset_value(asset).await?;
+
+    // Calculate the notional USD value of the swap
+    let notional_usd_value = volume * asset_value_in_usd;
+
+    // Get the SOL value in USD
+    let sol_value_in_usd = get_asset_value("SOL").await?;
+
+    // Calculate the notional SOL value of the swap
+    let notional_sol_value = notional_sol_value(notional_usd_value, sol_value_in_usd)?;
+
+    // Create the client
+    let client = kraken_client();
+
+    // Format the volume
+    let formatted_volume = format_volume(pair, volume);
+
+    // Construct the request payload
+    let payload = json!({
+        "nonce": get_nonce(),
+        "pair": pair,
+        "type": side.to_string(),
+        "ordertype": "market",
+        "volume": formatted_volume
+    });
+    println!("Payload: {}", redact_json(&payload)); // Debug print
+
+    // Send the order request
+    let response: Result<Value, Error> = client
+        .send_private_json("/0/private/AddOrder", payload)
+        .await;
+
+    match response {
+        Ok(mut value) => {
+            println!("Response: {}", redact_json(&value)); // Debug print
+                                             // Add notional USD value to the response
+            value["notional_usd_value"] = json!(notional_usd_value);
+            // Add notional SOL value to the response
+            value["notional_sol_value"] = json!(notional_sol_value);
+            Ok(value)
+        }
+        Err(e) => {
+            let reason = e.to_string();
+            match e {
+                Error::Api(api_err) => {
+                    if api_err.starts_with('{') {
+                        match serde_json::from_str::<Value>(&api_err) {
+                            Ok(json) => {
+                                if let Some(errors) = json.get("error") {
+                                    println!("Kraken API Error: {:?}", errors);
+                                } else {
+                                    println!("Error sending order: {:?}", api_err);
+                                    // Fallback debug print
+                                }
+                            }
+                            Err(parse_err) => {
+                                println!("Failed to parse error JSON: {:?}", parse_err); // Debug print
+                                println!("Error sending order: {:?}", api_err); // Fallback debug print
+                            }
+                        }
+                    } else {
+                        // Print non-JSON error string directly
+                        println!("Kraken API Error: {}", api_err);
+                    }
+                }
+                other_err => {
+                    println!("Error sending order: {:?}", other_err); // Debug print
+                }
+            }
+            Err(AppError::KrakenOrderFailed(reason))
+        }
+    }
+}
+
+// Function to extract the order txid from an AddOrder response
+pub fn order_txid(response: &Value) -> Result<String, AppError> {
+    response["txid"][0]
+        .as_str()
+        .map(|txid| txid.to_string())
+        .ok_or_else(|| AppError::CustomError("Order txid missing from Kraken response".to_string()))
+}
+
+// Function to query the status of an order on Kraken
+#[tracing::instrument(name = "kraken.query_order", skip_all, fields(txid = txid))]
+pub async fn query_order(txid: &str) -> Result<KrakenOrder, AppError> {
+    // Construct the request payload
+    let payload = json!({
+        "txid": txid, // Order txid returned by AddOrder
+    });
+
+    // Send the request
+    let mut response: HashMap<String, KrakenOrder> = private_read("/0/private/QueryOrders", payload).await?;
+
+    response
+        .remove(txid)
+        .ok_or_else(|| AppError::CustomError(format!("Order {} not found on Kraken", txid)))
+}
+
+// Function to cancel an open order on Kraken
+#[tracing::instrument(name = "kraken.cancel_order", skip_all, fields(txid = txid))]
+pub async fn cancel_order(txid: &str) -> Result<Value, AppError> {
+    private_read("/0/private/CancelOrder", json!({ "txid": txid })).await
+}
+
+// Seconds an order is waited on before it is cancelled, limit orders also expire on Kraken after it
+const FILL_TIMEOUT_SECS: u64 = 60;
+
+// Function to poll an order until it is filled, cancelled or the timeout elapses. An order still open
+// at the timeout is cancelled and queried once more, so it can't fill after the deposit has moved on:
+// whatever filled by then is returned, and the order only fails when nothing did.
+#[tracing::instrument(name = "kraken.wait_for_fill", skip_all, fields(txid = txid))]
+pub async fn wait_for_order_fill(txid: &str) -> Result<KrakenOrder, AppError> {
+    const POLL_INTERVAL_SECS: u64 = 2;
+
+    let deadline = Instant::now() + Duration::from_secs(FILL_TIMEOUT_SECS);
+    loop {
+        let order = query_order(txid).await?;
+        println!("Order {} status: {}, executed: {}", txid, order.status, order.vol_exec);
+        match order.status.as_str() {
+            "closed" => return Ok(order),
+            "canceled" | "expired" => {
+                return Err(AppError::CustomError(format!(
+                    "Order {} was {} before filling",
+                    txid, order.status
+                )))
+            }
+            _ => {}
+        }
+
+        if Instant::now() >= deadline {
+            if let Err(e) = cancel_order(txid).await {
+                eprintln!("Error cancelling order {}: {:?}", txid, e);
+            }
+            let order = query_order(txid).await?;
+            let executed = order.vol_exec.parse::<Decimal>().unwrap_or_default();
+            return match order.status.as_str() {
+                "closed" => Ok(order),
+                "canceled" | "expired" if executed > Decimal::ZERO => {
+                    eprintln!("Order {} timed out partly filled, {} of {} executed", txid, order.vol_exec, order.vol);
+                    Ok(order)
+                }
+                "canceled" | "expired" => Err(AppError::CustomError(format!(
+                    "Timed out waiting for order {} to fill, it was cancelled unfilled",
+                    txid
+                ))),
+                // The cancel didn't take, the order may still fill and must not be placed again
+                _ => Err(AppError::CustomError(format!(
+                    "Timed out waiting for order {} to fill and it is still {}, cancel it on Kraken before retrying",
+                    txid, order.status
+                ))),
+            };
+        }
+        sleep(Duration::from_secs(POLL_INTERVAL_SECS)).await;
+    }
+}
+
+// Function to execute a limit order on Kraken, priced at most `max_deviation_bps` away from the ticker
+#[tracing::instrument(name = "kraken.add_limit_order", skip_all, fields(pair = pair, side = %side, volume = %volume))]
+pub async fn execute_limit(
+    pair: &str,
+    side: OrderSide,
+    volume: Decimal,
+    max_deviation_bps: u32,
+) -> Result<Value, AppError> {
+    // Check the minimum volume
+    let asset = base_asset(pair);
+    check_minimum_volume(asset, volume)?;
+
+    // Get the current ticker price and derive the worst price we are willing to accept
+    let asset_value_in_usd = get_asset_value(asset).await?;
+    let limit_price = limit_price(asset_value_in_usd, &side, max_deviation_bps);
+    println!("{}ing {} at limit price: {} (ticker {})", side, pair, limit_price, asset_value_in_usd);
+
+    // A buy's volume is sized at the ticker price. Filled at the limit price it would cost more than
+    // the USD on hand, so it buys what that USD affords at the limit price instead.
+    let volume = match side {
+        OrderSide::Buy => buy_volume_at_limit(volume, asset_value_in_usd, limit_price)?,
+        OrderSide::Sell => volume,
+    };
+
+    // Calculate the notional USD and SOL values of the swap
+    let notional_usd_value = volume * asset_value_in_usd;
+    let sol_value_in_usd = get_asset_value("SOL").await?;
+    let notional_sol_value = notional_sol_value(notional_usd_value, sol_value_in_usd)?;
+
+    // Create the client
+    let client = kraken_client();
+
+    // Construct the request payload
+    let payload = json!({
+        "nonce": get_nonce(),
+        "pair": pair,
+        "type": side.to_string(),
+        "ordertype": "limit",
+        "volume": format_volume(pair, volume),
+        "price": format_price(pair, limit_price, &side),
+        // Kraken expires the order if it is still open once it is no longer waited on
+        "expiretm": format!("+{}", FILL_TIMEOUT_SECS)
+    });
+    println!("Payload: {}", redact_json(&payload)); // Debug print
+
+    // Send the order request
+    let mut response: Value = client
+        .send_private_json("/0/private/AddOrder", payload)
+        .await
+        .map_err(|e| {
+            println!("Error sending order: {:?}", e); // Debug print
+            AppError::KrakenOrderFailed(e.to_string())
+        })?;
+
+    println!("Response: {}", redact_json(&response)); // Debug print
+    response["notional_usd_value"] = json!(notional_usd_value);
+    response["notional_sol_value"] = json!(notional_sol_value);
+    Ok(response)
+}
+
+// Function to compute the worst acceptable limit price for an order side
+pub fn limit_price(ticker_price: Decimal, side: &OrderSide, max_deviation_bps: u32) -> Decimal {
+    let deviation = Decimal::new(max_deviation_bps as i64, 4);
+    match side {
+        OrderSide::Buy => ticker_price * (Decimal::ONE + deviation),
+        OrderSide::Sell => ticker_price * (Decimal::ONE - deviation),
+    }
+}
+
+// Function to scale a buy sized at the ticker price down to what the same USD buys at the limit price
+pub fn buy_volume_at_limit(volume: Decimal, ticker_price: Decimal, limit_price: Decimal) -> Result<Decimal, AppError> {
+    (volume * ticker_price)
+        .checked_div(limit_price)
+        .ok_or_else(|| AppError::PriceError("Limit price is zero".to_string()))
+}
+
+// Function to format a price with the precision Kraken accepts for the pair, rounded towards the
+// ticker so the limit never deviates further than allowed
+pub fn format_price(pair: &str, price: Decimal, side: &OrderSide) -> String {
+    let strategy = match side {
+        OrderSide::Buy => RoundingStrategy::ToNegativeInfinity,
+        OrderSide::Sell => RoundingStrategy::ToPositiveInfinity,
+    };
+    price.round_dp_with_strategy(price_decimals(pair), strategy).to_string()
+}
+
+// Function to convert a notional USD value to SOL at the SOL ticker price
+fn notional_sol_value(notional_usd_value: Decimal, sol_value_in_usd: Decimal) -> Result<Decimal, AppError> {
+    notional_usd_value
+        .checked_div(sol_value_in_usd)
+        .ok_or_else(|| AppError::PriceError("SOL price is zero".to_string()))
+}
+
+// How orders are placed on Kraken, configured through KRAKEN_ORDER_TYPE
+pub enum OrderExecution {
+    Market,
+    Limit { max_deviation_bps: u32 },
+}
+
+impl OrderExecution {
+    pub fn from_config() -> Self {
+        let config = config();
+        match config.kraken_order_type {
+            OrderType::Limit => OrderExecution::Limit {
+                max_deviation_bps: config.kraken_limit_max_deviation_bps,
+            },
+            OrderType::Market => OrderExecution::Market,
+        }
+    }
+}
+
+// Function to place an order using the configured execution mode
+pub async fn execute_order(pair: &str, side: OrderSide, volume: Decimal) -> Result<Value, AppError> {
+    match OrderExecution::from_config() {
+        OrderExecution::Market => execute_swap(pair, side, volume).await,
+        OrderExecution::Limit { max_deviation_bps } => {
+            execute_limit(pair, side, volume, max_deviation_bps).await
+        }
+    }
+}
+
+// Function to place an order and wait for it to fill, only pricing it in dry-run mode and on devnet
+#[tracing::instrument(name = "kraken.order", skip_all, fields(pair = pair, side = %side, volume = %volume))]
+pub async fn place_order_and_wait(
+    pair: &str,
+    side: OrderSide,
+    volume: Decimal,
+) -> Result<(String, KrakenOrder), AppError> {
+    if dry_run::simulates_exchange() {
+        let asset = base_asset(pair);
+        let price = get_asset_value(asset).await?;
+        dry_run::record_action(
+            "kraken_order",
+            json!({ "pair": pair, "side": side.to_string(), "volume": volume, "price": price }),
+        )
+        .await;
+        let order = KrakenOrder {
+            status: "closed".to_string(),
+            vol: format_volume(pair, volume),
+            vol_exec: format_volume(pair, volume),
+            cost: (volume * price).to_string(),
+            fee: Decimal::ZERO.to_string(),
+            price: price.to_string(),
+        };
+        return Ok(("DRY-RUN".to_string(), order));
+    }
+
+    // Orders aren't retried, but aren't placed while Kraken is down either
+    circuit_breaker::KRAKEN.check()?;
+    let response = execute_order(pair, side, volume).await?;
+    println!("{} order response: {}", pair, redact_json(&response));
+    let txid = order_txid(&response)?;
+    let order = wait_for_order_fill(&txid).await?;
+    Ok((txid, order))
+}
+
+// Function to create a new wallet for deposit using BTC Lightning in Kraken
+// pub async fn deposit_btc_lightning(asset: &str, amount: f64) -> Result<Value, AppError> {
+//     dotenv().ok(); // Load environment variables from the ".env" file
+
+//     // Read Kraken API key and secret stored in environment variables
+//     let api_key = std::env::var("KRAKEN_API_KEY")?;
+//     let api_secret = std::env::var("KRAKEN_API_SECRET")?;
+
+//     // Create the client
+//     let client = kraken_client();
+
+//     // Construct the request payload
+//     let payload = json!({
+//         "nonce": get_nonce(),
+//         "asset": asset, // Ticker in Kraken
+//         "method": "Bitcoin Lightning", // Method
+//         "new": true, // Always use a new wallet for deposit
+//         "amount": amount // Amount to deposit
+//     });
+
+//     // Send the request
+//     let response: Value = client
+//         .send_private_json("/0/private/DepositAddresses", payload)
+//         .await?;
+
+//     Ok(response)
+// }
+
+// Function to Get Kraken BTC deposit status
+#[tracing::instrument(name = "kraken.deposit_status", skip_all, fields(asset = asset, method = method))]
+pub async fn get_deposit_status(asset: &str, method: &str, start: Option<i64>) -> Result<Value, AppError> {
+    // Construct the request payload
+    let mut payload = json!({
+        "asset": asset, // Asset Ticker in Kraken
+        "method": method, // Name of Method ie "Bitcoin Lightning"
+    });
+    // Only deposits from this unix timestamp on
+    if let Some(start) = start {
+        payload["start"] = json!(start.to_string());
+    }
+
+    // Send the request
+    private_read("/0/private/DepositStatus", payload).await
+}
+
+// Reads every page of a Kraken history endpoint between two unix timestamps. Kraken returns 50 entries
+// a page under `field`, keyed by id, along with the total `count`.
+async fn read_history(path: &str, field: &str, start: i64, end: i64) -> Result<HashMap<String, Value>, AppError> {
+    let mut entries = HashMap::new();
+    loop {
+        let payload = json!({ "start": start.to_string(), "end": end.to_string(), "ofs": entries.len() });
+        let response: Value = private_read(path, payload).await?;
+        let page = response[field].as_object().cloned().unwrap_or_default();
+        let count = response["count"].as_u64().unwrap_or_default() as usize;
+        if page.is_empty() {
+            break;
+        }
+        entries.extend(page);
+        if entries.len() >= count {
+            break;
+        }
+    }
+    Ok(entries)
+}
+
+// Function to get the ledger entries of the Kraken account between two unix timestamps, keyed by ledger id
+#[tracing::instrument(name = "kraken.ledgers", skip_all, fields(start = start, end = end))]
+pub async fn get_ledgers(start: i64, end: i64) -> Result<HashMap<String, Value>, AppError> {
+    read_history("/0/private/Ledgers", "ledger", start, end).await
+}
+
+// Function to get the trades made on Kraken between two unix timestamps, keyed by trade id
+#[tracing::instrument(name = "kraken.trades_history", skip_all, fields(start = start, end = end))]
+pub async fn get_trades_history(start: i64, end: i64) -> Result<HashMap<String, Value>, AppError> {
+    read_history("/0/private/TradesHistory", "trades", start, end).await
+}
+
+// Function to get the account balances held on Kraken
+#[tracing::instrument(name = "kraken.balance", skip_all)]
+pub async fn get_balances() -> Result<KrakenBalances, AppError> {
+    private_read("/0/private/Balance", json!({})).await
+}
+
+// Function to get a token for Kraken's authenticated WebSocket feeds
+pub async fn get_websockets_token() -> Result<String, AppError> {
+    let response: Value = private_read("/0/private/GetWebSocketsToken", json!({})).await?;
+
+    response["token"]
+        .as_str()
+        .map(|token| token.to_string())
+        .ok_or_else(|| AppError::CustomError("WebSocket token missing from Kraken response".to_string()))
+}
+
+// Function to withdraw assets from Kraken
+#[tracing::instrument(name = "kraken.withdraw", skip_all, fields(asset = asset, amount = %amount))]
+pub async fn withdraw_assets(
+    asset: &str,
+    key: &str,
+    address: &str,
+    amount: Decimal,
+) -> Result<Value, AppError> {
+    if dry_run::simulates_exchange() {
+        let details = json!({ "asset": asset, "key": key, "address": address, "amount": amount });
+        dry_run::record_action("kraken_withdrawal", details).await;
+        return Ok(json!({ "dry_run": true }));
+    }
+
+    circuit_breaker::KRAKEN.check()?;
+
+    // Create the client
+    let client = kraken_client();
+
+    // Construct the request payload
+    let payload = json!({
+        "nonce": get_nonce(),
+        "asset": asset, // Ticker in Kraken
+        "key": key, // Name of Wallet in Kraken
+        "address": address, // Address of Wallet in kraken
+        "amount": amount // Amount to withdraw
+    });
+
+    // Send the withdrawal request
+    let response: Value = client
+        .send_private_json("/0/private/Withdraw", payload)
+        .await?;
+
+    Ok(response)
+}
+
+// Function to get withdrawal limits and fees for a withdrawal key on Kraken
+pub async fn get_withdrawal_info(asset: &str, key: &str, amount: Decimal) -> Result<Value, AppError> {
+    // Construct the request payload
+    let payload = json!({
+        "asset": asset, // Ticker in Kraken
+        "key": key, // Name of Wallet in Kraken
+        "amount": amount // Amount to withdraw
+    });
+
+    // Send the request
+    private_read("/0/private/WithdrawInfo", payload).await
+}
+
+// Function to quote a withdrawal on Kraken from its withdrawal info. Kraken takes the fee out of the
+// withdrawn amount and reports what will be sent as `amount`.
+pub async fn quote_withdrawal(asset: &str, key: &str, amount: Decimal) -> Result<WithdrawalQuote, AppError> {
+    // Simulated withdrawals are free, the amount may not even be on the account
+    if dry_run::simulates_exchange() {
+        return Ok(WithdrawalQuote { fee: Decimal::ZERO, limit: None, net_amount: amount });
+    }
+    let info = match get_withdrawal_info(asset, key, amount).await {
+        Ok(info) => info,
+        Err(AppError::KrakenError(Error::Api(message)))
+            if message.contains("EFunding:Invalid amount") || message.contains("EFunding:Too small") =>
+        {
+            return Err(AppError::InsufficientVolume(format!(
+                "{} {} is below Kraken's withdrawal minimum for `{}`: {}",
+                amount, asset, key, message
+            )));
+        }
+        Err(e) => return Err(e),
+    };
+    let decimal = |field: &str| info[field].as_str().and_then(|value| value.parse::<Decimal>().ok());
+    let fee = decimal("fee")
+        .ok_or_else(|| AppError::CustomError(format!("No fee in Kraken withdrawal info: {}", info)))?;
+    Ok(WithdrawalQuote {
+        fee,
+        limit: decimal("limit"),
+        net_amount: decimal("amount").unwrap_or(amount - fee),
+    })
+}
+
+// Function to validate an order on Kraken without placing it
+pub async fn validate_order(pair: &str, side: OrderSide, volume: Decimal) -> Result<Value, AppError> {
+    // Create the client
+    let client = kraken_client();
+
+    // Construct the request payload
+    let payload = json!({
+        "nonce": get_nonce(),
+        "pair": pair,
+        "type": side.to_string(),
+        "ordertype": "market",
+        "volume": format_volume(pair, volume),
+        "validate": true // Kraken checks the order without submitting it
+    });
+
+    // Send the order request
+    let response: Value = client
+        .send_private_json("/0/private/AddOrder", payload)
+        .await?;
+
+    Ok(response)
+}
+
+// Function to get the status of a withdrawal on Kraken by its refid
+#[tracing::instrument(name = "kraken.withdraw_status", skip_all, fields(asset = asset, refid = refid))]
+pub async fn get_withdrawal_status(asset: &str, refid: &str) -> Result<Option<Value>, AppError> {
+    // Construct the request payload
+    let payload = json!({
+        "asset": asset, // Ticker in Kraken
+    });
+
+    // Send the request
+    let response: Value = private_read("/0/private/WithdrawStatus", payload).await?;
+
+    Ok(response
+        .as_array()
+        .and_then(|withdrawals| withdrawals.iter().find(|withdrawal| withdrawal["refid"] == refid))
+        .cloned())
+}
+
+// The live Kraken REST API, backed by the functions above
+pub struct KrakenExchange;
+
+#[async_trait]
+impl Exchange for KrakenExchange {
+    fn name(&self) -> &'static str {
+        "Kraken"
+    }
+
+    async fn get_asset_value(&self, asset: &str) -> Result<Decimal, AppError> {
+        get_asset_value(asset).await
+    }
+
+    async fn get_deposit_status(&self, asset: &str, method: &str, start: Option<i64>) -> Result<Value, AppError> {
+        get_deposit_status(asset, method, start).await
+    }
+
+    async fn get_balance(&self, asset: &str) -> Result<Decimal, AppError> {
+        Ok(get_balances().await?.get(asset))
+    }
+
+    async fn place_order_and_wait(
+        &self,
+        pair: &str,
+        side: OrderSide,
+        volume: Decimal,
+    ) -> Result<(String, OrderFill), AppError> {
+        let (txid, order) = place_order_and_wait(pair, side, volume).await?;
+        Ok((txid, order.into()))
+    }
+
+    async fn withdraw_assets(&self, asset: &str, key: &str, address: &str, amount: Decimal) -> Result<Value, AppError> {
+        withdraw_assets(asset, key, address, amount).await
+    }
+
+    async fn quote_withdrawal(&self, asset: &str, key: &str, amount: Decimal) -> Result<WithdrawalQuote, AppError> {
+        quote_withdrawal(asset, key, amount).await
+    }
+
+    async fn get_withdrawal_status(&self, asset: &str, refid: &str) -> Result<Option<Value>, AppError> {
+        get_withdrawal_status(asset, refid).await
+    }
+}
+
+// // Function to fetch SPL token price from Raydium
+// pub async fn fetch_token_price(token_mint: &str, api_url: &str) -> Result<f64, AppError> {
+//     let client = SimpleClient::new();
+//     let url = format!("{}/mint/price?mints={}", api_url, token_mint);
+
+//     let response = client
+//         .get(&url)
+//         .header("accept", "application/json")
+//         .send()
+//         .await?;
+
+//     // Check if the response status is success
+//     if !response.status().is_success() {
+//         return Err(AppError::InternalServerError);
+//     }
+
+//     let response_text = response.text().await?;
+
+//     let response_json: ApiResponse = serde_json::from_str(&response_text)?;
+
+//     if let Some(price_str) = response_json.data.get(token_mint) {
+//         let price: f64 = price_str.parse()?;
+//         Ok(price)
+//     } else {
+//         Err(AppError::InternalServerError)
+//     }
+// }
+
+// // Combined function to get the SOL to SPL token price
+// pub async fn get_sol_to_spl_token_price(
+//     sol_asset: &str,
+//     spl_token_address: &str,
+//     raydium_api_url: &str,
+// ) -> Result<f64, AppError> {
+//     // Get the SOL value in USD from Kraken
+//     let sol_usd_price = get_asset_value(sol_asset).await?;
+
+//     // Get the SPL token price from Raydium
+//     let spl_token_price = fetch_token_price(spl_token_address, raydium_api_url).await?;
+
+//     // Calculate the SOL to SPL token price
+//     let sol_to_spl_token_price = sol_usd_price / spl_token_price;
+
+//     Ok(sol_to_spl_token_price)
+// }
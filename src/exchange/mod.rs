@@ -0,0 +1,170 @@
+// exchange/mod.rs
+pub mod coinbase;
+pub mod kraken;
+
+use crate::config::{config, ExchangeKind};
+use crate::dry_run;
+use crate::error_handling::AppError;
+use async_trait::async_trait;
+use kraken_rest_client::OrderSide;
+use rust_decimal::prelude::{FromPrimitive, ToPrimitive};
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+// A filled market order, amounts in the quote currency unless noted. Amounts are exact decimals as
+// reported by the exchange.
+#[derive(Debug, Clone)]
+pub struct OrderFill {
+    pub status: String,
+    // Executed volume in the base currency
+    pub volume_executed: Decimal,
+    pub cost: Decimal,
+    pub fee: Decimal,
+    pub average_price: Decimal,
+}
+
+// A deposit's status as the exchange reports it. Kraken flags deposits it holds for review with
+// `status-prop: "onhold"` on top of their status, and reports "Initial" and "Partial" for deposits
+// that are still on their way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DepositStatus {
+    Pending,
+    Settled,
+    Success,
+    Failure,
+    OnHold,
+}
+
+impl DepositStatus {
+    pub fn of_deposit(deposit: &Value) -> Self {
+        let status = match deposit["status"].as_str().unwrap_or_default() {
+            "Success" => DepositStatus::Success,
+            "Failure" => return DepositStatus::Failure,
+            "Settled" => DepositStatus::Settled,
+            _ => DepositStatus::Pending,
+        };
+        if deposit["status-prop"] == "onhold" {
+            DepositStatus::OnHold
+        } else {
+            status
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DepositStatus::Pending => "Pending",
+            DepositStatus::Settled => "Settled",
+            DepositStatus::Success => "Success",
+            DepositStatus::Failure => "Failure",
+            DepositStatus::OnHold => "OnHold",
+        }
+    }
+
+    // Whether the exchange is done with the deposit
+    pub fn is_final(&self) -> bool {
+        matches!(self, DepositStatus::Success | DepositStatus::Failure)
+    }
+}
+
+// What a withdrawal will cost before it is made, as quoted by the exchange
+#[derive(Debug, Clone)]
+pub struct WithdrawalQuote {
+    pub fee: Decimal,
+    // The most that can be withdrawn right now, when the exchange says
+    pub limit: Option<Decimal>,
+    // What arrives at the address once the fee is taken
+    pub net_amount: Decimal,
+}
+
+// What the deposit pipeline needs from an exchange: deposit status, market orders and withdrawals.
+// Pairs and assets use Kraken's names ("BTCUSD", "XBT"), other backends map them to their own.
+// Deposits are returned in the shape of Kraken's DepositStatus entries: `refid`, `txid`, `amount`,
+// `status` ("Success" once credited), `time` and `info` (the deposit address). With `start` only
+// deposits made at or after that unix timestamp are returned.
+#[async_trait]
+pub trait Exchange: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn get_asset_value(&self, asset: &str) -> Result<Decimal, AppError>;
+
+    async fn get_deposit_status(&self, asset: &str, method: &str, start: Option<i64>) -> Result<Value, AppError>;
+
+    async fn get_balance(&self, asset: &str) -> Result<Decimal, AppError>;
+
+    // Places a market order and waits for it to fill, returning the order id and fill
+    async fn place_order_and_wait(
+        &self,
+        pair: &str,
+        side: OrderSide,
+        volume: Decimal,
+    ) -> Result<(String, OrderFill), AppError>;
+
+    // Withdraws to `address`, or to the withdrawal key on exchanges that whitelist by name.
+    // Returns the withdrawal with its `refid`.
+    async fn withdraw_assets(&self, asset: &str, key: &str, address: &str, amount: Decimal) -> Result<Value, AppError>;
+
+    // Quotes a withdrawal of `amount` through the withdrawal key. Amounts under the exchange's withdrawal
+    // minimum are an InsufficientVolume error.
+    async fn quote_withdrawal(&self, asset: &str, key: &str, amount: Decimal) -> Result<WithdrawalQuote, AppError>;
+
+    // Looks up a withdrawal by refid in the shape of Kraken's WithdrawStatus entries: `status` ("Success"
+    // once sent, "Failure"), the on-chain `txid` and the `fee` as a string
+    async fn get_withdrawal_status(&self, asset: &str, refid: &str) -> Result<Option<Value>, AppError>;
+
+    // Checks that the exchange holds at least the required amount of an asset
+    async fn ensure_balance(&self, asset: &str, required: Decimal) -> Result<(), AppError> {
+        let available = self.get_balance(asset).await?;
+        if available < required {
+            println!("Insufficient {} balance on {}: {} < {}", asset, self.name(), available, required);
+            // Earlier steps were never executed in dry-run mode or on devnet, so shortfalls are expected
+            if dry_run::simulates_exchange() {
+                dry_run::record_action(
+                    "insufficient_balance",
+                    json!({ "asset": asset, "available": available, "required": required }),
+                )
+                .await;
+                return Ok(());
+            }
+            return Err(AppError::CustomError(format!(
+                "Insufficient {} balance on {}",
+                asset,
+                self.name()
+            )));
+        }
+        Ok(())
+    }
+}
+
+// The asset bought or sold in a "<ASSET>USD" pair
+pub fn base_asset(pair: &str) -> &str {
+    pair.strip_suffix("USD").unwrap_or(pair)
+}
+
+// Smallest deposit the pipeline will sell, by deposited asset
+pub fn minimum_sell_volume(asset: &str) -> Decimal {
+    match asset {
+        "USDC" | "USDT" => dec!(1),
+        _ => dec!(0.0001),
+    }
+}
+
+// An amount read from MongoDB or a price source as a decimal, at the shortest decimal representation
+// of the float, so 0.1 is 0.1
+pub fn to_decimal(amount: f64) -> Result<Decimal, AppError> {
+    Decimal::from_f64(amount).ok_or_else(|| AppError::CustomError(format!("{} is not a valid amount", amount)))
+}
+
+// A decimal amount as stored in MongoDB, which keeps amounts as doubles
+pub fn to_stored(amount: Decimal) -> f64 {
+    amount.to_f64().unwrap_or(0.0)
+}
+
+// The exchange selected by EXCHANGE for this deployment
+pub fn from_config() -> Arc<dyn Exchange> {
+    match config().exchange {
+        ExchangeKind::Kraken => Arc::new(kraken::KrakenExchange),
+        ExchangeKind::Coinbase => Arc::new(coinbase::CoinbaseExchange::from_config()),
+    }
+}
@@ -0,0 +1,829 @@
+// admin.rs
+// Import necessary modules and libraries
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use mongodb::bson::{doc, oid::ObjectId, Bson, DateTime as BsonDateTime, Document};
+use mongodb::options::{FindOptions, UpdateOptions};
+use serde::Deserialize;
+use serde_json::json;
+use solana_sdk::pubkey::Pubkey;
+use rust_decimal::Decimal;
+use chrono::{NaiveDate, NaiveTime};
+use std::str::FromStr;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+use crate::balance_watchdog;
+use crate::circuit_breaker;
+use crate::config::{config, ExchangeKind};
+use crate::error_handling::AppError;
+use crate::exchange::kraken::get_withdrawal_info;
+use crate::mongo::{get_spend_approvals_collection, AppState, FailedSwap, Refund, User, WithdrawalKey};
+use crate::poller::{
+    is_poller_paused, poller_consecutive_failures, poller_restarts, process_successful_transaction,
+    redrive_swap, run_lockin_swap, set_poller_paused, settled_amount, PipelineClients,
+};
+use crate::lockin::DEFAULT_SLIPPAGE_BPS;
+use crate::ledger::{self, Account};
+use crate::maintenance;
+use crate::reconciliation;
+use crate::transaction_state::{TransactionState, TransactionStateMachine};
+use crate::units::sol_to_lamports;
+use crate::validation::solana_address;
+use crate::withdrawal_addresses;
+
+// Struct for deserializing the refunds listing query
+#[derive(Debug, Deserialize)]
+pub struct RefundQuery {
+    status: Option<String>,
+}
+
+// Struct for deserializing the spend approvals listing query
+#[derive(Debug, Deserialize)]
+pub struct SpendApprovalQuery {
+    status: Option<String>,
+}
+
+// Struct for deserializing pagination of admin listings
+#[derive(Debug, Deserialize)]
+pub struct PageQuery {
+    skip: Option<u64>,
+    limit: Option<i64>,
+}
+
+impl PageQuery {
+    fn find_options(&self, sort: Document) -> FindOptions {
+        FindOptions::builder()
+            .sort(sort)
+            .skip(self.skip)
+            .limit(self.limit.unwrap_or(100).clamp(1, 1000))
+            .build()
+    }
+}
+
+// Checks the admin key header against the ADMIN_API_KEY environment variable. The SHA-256 digests are
+// compared in constant time, so the time taken doesn't tell how much of the key a guess got right.
+fn authorize_admin(headers: &HeaderMap) -> Result<(), AppError> {
+    let admin_key = config().admin_api_key.as_deref().ok_or(AppError::Unauthorized)?;
+    match headers.get("x-admin-key").and_then(|value| value.to_str().ok()) {
+        Some(provided) if digests_match(provided, admin_key) => Ok(()),
+        _ => Err(AppError::Unauthorized),
+    }
+}
+
+fn digests_match(provided: &str, expected: &str) -> bool {
+    let (provided, expected) = (Sha256::digest(provided.as_bytes()), Sha256::digest(expected.as_bytes()));
+    provided.iter().zip(expected.iter()).fold(0u8, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+// Middleware guarding every route of the admin router
+pub async fn require_admin<B>(request: Request<B>, next: Next<B>) -> Result<Response, AppError> {
+    authorize_admin(request.headers())?;
+    Ok(next.run(request).await)
+}
+
+// Asynchronous handler function for listing users, without their API keys or encrypted secrets
+pub async fn list_users(
+    State(state): State<Arc<AppState>>,
+    Query(page): Query<PageQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let mut options = page.find_options(doc! { "user_id": 1 });
+    options.projection = Some(doc! {
+        "api_key": 0,
+        "solana_private_key": 0,
+        "bitcoin_private_key": 0,
+        "bitcoin_mnemonic": 0,
+        "ethereum_private_key": 0,
+    });
+    let mut cursor = state.db.collection::<Document>("users").find(doc! {}, options).await?;
+
+    let mut users = Vec::new();
+    while cursor.advance().await? {
+        users.push(cursor.deserialize_current()?);
+    }
+
+    Ok((StatusCode::OK, Json(json!({ "users": users }))))
+}
+
+// Asynchronous handler function for viewing a user's transactions, newest first
+pub async fn list_user_transactions(
+    State(state): State<Arc<AppState>>,
+    Path(user_id): Path<i64>,
+    Query(page): Query<PageQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    // The bot stores user_id as either an int32 or an int64
+    let filter = doc! { "$or": [ { "user_id": user_id }, { "user_id": user_id as i32 } ] };
+    let mut cursor = state
+        .db
+        .collection::<Document>("transactions")
+        .find(filter, page.find_options(doc! { "timestamp": -1 }))
+        .await?;
+
+    let mut transactions = Vec::new();
+    while cursor.advance().await? {
+        transactions.push(cursor.deserialize_current()?);
+    }
+
+    Ok((StatusCode::OK, Json(json!({ "transactions": transactions }))))
+}
+
+// Asynchronous handler function for getting a single transaction with its fills, fees and Jupiter route plan
+pub async fn get_transaction(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let tx_id = ObjectId::parse_str(&id)
+        .map_err(|_| AppError::BadRequest("Invalid transaction id".to_string()))?;
+    let tx = state
+        .db
+        .collection::<Document>("transactions")
+        .find_one(doc! { "_id": tx_id }, None)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    Ok((StatusCode::OK, Json(json!({ "transaction": tx }))))
+}
+
+// Asynchronous handler function for force-retrying a stuck or failed transaction. Only transactions stuck
+// before the BTC sale or after the SOL withdrawal can be retried, anything in between is partially
+// traded on Kraken and needs manual reconciliation.
+pub async fn retry_transaction(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let tx_id = ObjectId::parse_str(&id)
+        .map_err(|_| AppError::BadRequest("Invalid transaction id".to_string()))?;
+
+    let transactions_collection = state.db.collection::<Document>("transactions");
+    let tx = transactions_collection
+        .find_one(doc! { "_id": tx_id }, None)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    let current = TransactionState::of_document(&tx);
+    let stuck_in = if current == TransactionState::Failed {
+        tx.get_str("failed_in_state")
+            .ok()
+            .and_then(TransactionState::parse)
+            .unwrap_or(TransactionState::Failed)
+    } else {
+        current
+    };
+    if stuck_in != TransactionState::DepositSettled && stuck_in != TransactionState::Withdrawn {
+        return Err(AppError::BadRequest(format!(
+            "Transaction stuck in {} needs manual reconciliation",
+            stuck_in
+        )));
+    }
+
+    let user_id = match tx.get("user_id") {
+        Some(Bson::Int32(user_id)) => *user_id as i64,
+        Some(Bson::Int64(user_id)) => *user_id,
+        _ => return Err(AppError::BadRequest("Transaction has no user_id".to_string())),
+    };
+    let user = state
+        .db
+        .collection::<User>("users")
+        .find_one(doc! { "user_id": user_id }, None)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    let user_sol_address = Pubkey::from_str(user.solana_public_key.as_deref().unwrap_or_default())
+        .map_err(|_| AppError::BadRequest("Invalid user Solana address".to_string()))?;
+    let slippage_bps = user.slippage_bps.unwrap_or(DEFAULT_SLIPPAGE_BPS);
+
+    // A retry of the swap needs the SOL that arrived after the withdrawal fee (the SOL bought, for
+    // transactions withdrawn before that was recorded), a retry of the deposit needs what was credited
+    // for it. The bot's `amount` is never used, a retry by it could sell other users' funds.
+    let amount = if stuck_in == TransactionState::Withdrawn {
+        tx.get_document("withdrawal")
+            .and_then(|withdrawal| withdrawal.get_f64("amount_received"))
+            .or_else(|_| {
+                tx.get_document("kraken_orders")
+                    .and_then(|orders| orders.get_document("buy"))
+                    .and_then(|buy| buy.get_f64("volume"))
+            })
+            .ok()
+    } else {
+        settled_amount(&tx)
+    }
+    .ok_or_else(|| AppError::BadRequest("Transaction has no amount to retry".to_string()))?;
+    let deposit_asset = tx.get_str("deposit_asset").unwrap_or("BTC").to_string();
+
+    // Route the retry through Failed so a worker still holding the transaction can't advance it further
+    let state_machine = TransactionStateMachine::new(transactions_collection.clone(), tx_id);
+    if current != TransactionState::Failed {
+        state_machine.fail("Force retried by admin").await?;
+    }
+    if !state_machine
+        .try_transition(TransactionState::Failed, stuck_in, Document::new())
+        .await?
+    {
+        return Err(AppError::BadRequest("Transaction is already being retried".to_string()));
+    }
+
+    let users_collection = state.db.collection::<User>("users");
+    tokio::spawn(async move {
+        let clients = PipelineClients::live();
+        if stuck_in == TransactionState::Withdrawn {
+            run_lockin_swap(
+                &clients,
+                Some(tx_id),
+                user_id,
+                user_sol_address,
+                sol_to_lamports(amount),
+                slippage_bps,
+            )
+            .await;
+        } else if let Err(e) = process_successful_transaction(
+            &clients,
+            amount,
+            &deposit_asset,
+            user_sol_address,
+            user_id,
+            &users_collection,
+            &transactions_collection,
+            &state_machine,
+            user.deposited_sats(),
+            slippage_bps,
+        )
+        .await
+        {
+            eprintln!("Retry of transaction {} failed: {:?}", tx_id, e);
+            if let Err(e) = state_machine.fail(&e.report()).await {
+                eprintln!("Error marking transaction {} failed: {:?}", tx_id, e);
+            }
+        }
+    });
+
+    Ok((StatusCode::ACCEPTED, Json(json!({ "retrying": tx_id.to_hex(), "from_state": stuck_in }))))
+}
+
+// Asynchronous handler function for approving a deposit held for review because it is over the deposit
+// limits. The approved deposit is processed without checking the limits again.
+pub async fn approve_transaction(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let tx_id = ObjectId::parse_str(&id)
+        .map_err(|_| AppError::BadRequest("Invalid transaction id".to_string()))?;
+    let transactions_collection = state.db.collection::<Document>("transactions");
+    let tx = transactions_collection
+        .find_one(doc! { "_id": tx_id }, None)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    let current = TransactionState::of_document(&tx);
+    if current != TransactionState::UnderReview {
+        return Err(AppError::BadRequest(format!("Transaction is {}, not under review", current)));
+    }
+
+    let user_id = match tx.get("user_id") {
+        Some(Bson::Int32(user_id)) => *user_id as i64,
+        Some(Bson::Int64(user_id)) => *user_id,
+        _ => return Err(AppError::BadRequest("Transaction has no user_id".to_string())),
+    };
+    let user = state
+        .db
+        .collection::<User>("users")
+        .find_one(doc! { "user_id": user_id }, None)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    let user_sol_address = Pubkey::from_str(user.solana_public_key.as_deref().unwrap_or_default())
+        .map_err(|_| AppError::BadRequest("Invalid user Solana address".to_string()))?;
+    let amount = settled_amount(&tx)
+        .ok_or_else(|| AppError::BadRequest("Transaction has no settled amount".to_string()))?;
+    let deposit_asset = tx.get_str("deposit_asset").unwrap_or("BTC").to_string();
+
+    let state_machine = TransactionStateMachine::new(transactions_collection.clone(), tx_id);
+    if !state_machine
+        .try_transition(TransactionState::UnderReview, TransactionState::DepositSettled, doc! { "review_approved": true })
+        .await?
+    {
+        return Err(AppError::BadRequest("Transaction is already being approved".to_string()));
+    }
+
+    let users_collection = state.db.collection::<User>("users");
+    tokio::spawn(async move {
+        if let Err(e) = process_successful_transaction(
+            &PipelineClients::live(),
+            amount,
+            &deposit_asset,
+            user_sol_address,
+            user_id,
+            &users_collection,
+            &transactions_collection,
+            &state_machine,
+            user.deposited_sats(),
+            user.slippage_bps.unwrap_or(DEFAULT_SLIPPAGE_BPS),
+        )
+        .await
+        {
+            eprintln!("Approved transaction {} failed: {:?}", tx_id, e);
+            if let Err(e) = state_machine.fail(&e.report()).await {
+                eprintln!("Error marking transaction {} failed: {:?}", tx_id, e);
+            }
+        }
+    });
+
+    Ok((StatusCode::ACCEPTED, Json(json!({ "approved": tx_id.to_hex() }))))
+}
+
+// Asynchronous handler function for pausing the deposit poller
+pub async fn pause_poller() -> impl IntoResponse {
+    set_poller_paused(true);
+    (StatusCode::OK, Json(json!({ "paused": true })))
+}
+
+// Asynchronous handler function for resuming the deposit poller
+pub async fn resume_poller() -> impl IntoResponse {
+    set_poller_paused(false);
+    (StatusCode::OK, Json(json!({ "paused": false })))
+}
+
+// Asynchronous handler function for the poller's current status
+pub async fn poller_status() -> impl IntoResponse {
+    (
+        StatusCode::OK,
+        Json(json!({
+            "paused": is_poller_paused(),
+            "consecutive_failures": poller_consecutive_failures(),
+            "restarts": poller_restarts(),
+            "circuit_breakers": circuit_breaker::statuses(),
+            "swaps_held": balance_watchdog::swaps_held(),
+            "low_balances": balance_watchdog::low_balances(),
+        })),
+    )
+}
+
+// Struct for deserializing the maintenance mode toggle
+#[derive(Debug, Deserialize)]
+pub struct MaintenancePayload {
+    enabled: bool,
+    message: Option<String>,
+}
+
+// Asynchronous handler function for toggling maintenance mode
+pub async fn set_maintenance(Json(payload): Json<MaintenancePayload>) -> Result<impl IntoResponse, AppError> {
+    maintenance::set(payload.enabled, payload.message).await?;
+    Ok((StatusCode::OK, Json(json!({ "enabled": maintenance::is_enabled(), "message": maintenance::message() }))))
+}
+
+// Asynchronous handler function for the current maintenance mode
+pub async fn maintenance_status() -> Result<impl IntoResponse, AppError> {
+    let enabled = maintenance::refresh().await?;
+    Ok((StatusCode::OK, Json(json!({ "enabled": enabled, "message": maintenance::message() }))))
+}
+
+// Asynchronous handler function for aggregate volume stats, grouped by transaction state
+pub async fn volume_stats(State(state): State<Arc<AppState>>) -> Result<impl IntoResponse, AppError> {
+    let pipeline = vec![
+        doc! { "$group": {
+            "_id": "$state",
+            "count": { "$sum": 1 },
+            "volume_btc": { "$sum": "$amount" },
+            "volume_usd": { "$sum": "$kraken_orders.sell.cost" },
+            "volume_sol": { "$sum": "$kraken_orders.buy.volume" },
+            "trading_fees_usd": { "$sum": "$fees.trading_fee_usd" },
+            "withdrawal_fees_sol": { "$sum": "$fees.withdrawal_fee_sol" },
+        } },
+        doc! { "$sort": { "_id": 1 } },
+    ];
+    let mut cursor = state
+        .db
+        .collection::<Document>("transactions")
+        .aggregate(pipeline, None)
+        .await?;
+
+    let mut by_state = Vec::new();
+    while cursor.advance().await? {
+        by_state.push(cursor.deserialize_current()?);
+    }
+    let users = state
+        .db
+        .collection::<Document>("users")
+        .count_documents(doc! {}, None)
+        .await?;
+
+    Ok((StatusCode::OK, Json(json!({ "users": users, "by_state": by_state }))))
+}
+
+// Struct for deserializing the volume report query, `from` and `to` are YYYY-MM-DD days
+#[derive(Debug, Deserialize)]
+pub struct VolumeReportQuery {
+    period: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+}
+
+// Asynchronous handler function for volume and revenue per day or week (`period=week`, weeks start on
+// Monday) over `from` to `to`, the last 30 days by default. Transactions count towards the period they
+// settled in. Deposit volume leaves out autobuy and DCA purchases, whose BTC was counted when it was
+// deposited. LOCKIN delivered is in base units, from the Jupiter quote of completed swaps. Fees
+// retained are the USD proceeds of a sale left on the exchange after buying the SOL.
+pub async fn volume_report(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<VolumeReportQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let unit = match query.period.as_deref() {
+        None | Some("day") => "day",
+        Some("week") => "week",
+        Some(period) => {
+            return Err(AppError::BadRequest(format!("Invalid period `{}`, expected day or week", period)));
+        }
+    };
+    let parse_day = |day: &str| {
+        NaiveDate::from_str(day)
+            .map_err(|_| AppError::BadRequest(format!("Invalid day `{}`, expected YYYY-MM-DD", day)))
+    };
+    let today = chrono::Utc::now().date_naive();
+    let to = query.to.as_deref().map(parse_day).transpose()?.unwrap_or(today);
+    let from = match query.from.as_deref() {
+        Some(from) => parse_day(from)?,
+        None => to - chrono::Duration::days(29),
+    };
+    if from > to {
+        return Err(AppError::BadRequest("`from` is after `to`".to_string()));
+    }
+    let day_start = |day: NaiveDate| BsonDateTime::from_millis(day.and_time(NaiveTime::default()).and_utc().timestamp_millis());
+
+    let pipeline = vec![
+        doc! { "$addFields": {
+            "report_at": { "$ifNull": ["$settled_at", "$timestamp", { "$toDate": "$_id" }] },
+        } },
+        doc! { "$match": {
+            "report_at": { "$gte": day_start(from), "$lt": day_start(to + chrono::Duration::days(1)) },
+            "state": { "$nin": ["DepositPending", "Expired", "DepositFailed"] },
+        } },
+        doc! { "$group": {
+            "_id": { "$dateTrunc": { "date": "$report_at", "unit": unit, "startOfWeek": "monday" } },
+            "transactions": { "$sum": 1 },
+            "deposits": { "$sum": { "$cond": [{ "$ifNull": ["$source", false] }, 0, 1] } },
+            "deposit_volume_btc": { "$sum": { "$cond": [
+                { "$and": [
+                    { "$not": [{ "$ifNull": ["$source", false] }] },
+                    { "$eq": [{ "$ifNull": ["$deposit_asset", "BTC"] }, "BTC"] },
+                ] },
+                "$amount",
+                0,
+            ] } },
+            "deposit_volume_usd": { "$sum": "$kraken_orders.sell.cost" },
+            "sol_bought": { "$sum": "$kraken_orders.buy.volume" },
+            "lockin_delivered": { "$sum": { "$cond": [
+                { "$eq": ["$state", "Completed"] },
+                { "$convert": { "input": "$route_plan.out_amount", "to": "double", "onError": 0, "onNull": 0 } },
+                0,
+            ] } },
+            "trading_fees_usd": { "$sum": "$fees.trading_fee_usd" },
+            "withdrawal_fees_sol": { "$sum": "$fees.withdrawal_fee_sol" },
+            "network_fees_lamports": { "$sum": "$fees.network_fee_lamports" },
+            "fees_retained_usd": { "$sum": { "$cond": [
+                { "$and": [
+                    { "$ifNull": ["$kraken_orders.sell.cost", false] },
+                    { "$ifNull": ["$kraken_orders.buy.cost", false] },
+                ] },
+                { "$subtract": [
+                    { "$subtract": ["$kraken_orders.sell.cost", "$kraken_orders.sell.fee"] },
+                    { "$add": ["$kraken_orders.buy.cost", "$kraken_orders.buy.fee"] },
+                ] },
+                0,
+            ] } },
+        } },
+        doc! { "$sort": { "_id": 1 } },
+        doc! { "$set": { "period_start": "$_id" } },
+        doc! { "$unset": "_id" },
+    ];
+    let mut cursor = state
+        .db
+        .collection::<Document>("transactions")
+        .aggregate(pipeline, None)
+        .await?;
+
+    let mut periods = Vec::new();
+    while cursor.advance().await? {
+        periods.push(cursor.deserialize_current()?);
+    }
+
+    Ok((
+        StatusCode::OK,
+        Json(json!({ "period": unit, "from": from.to_string(), "to": to.to_string(), "periods": periods })),
+    ))
+}
+
+// Asynchronous handler function for listing swaps waiting in the dead-letter queue
+pub async fn list_failed_swaps(
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let collection = state.db.collection::<FailedSwap>("failed_swaps");
+    let mut cursor = collection.find(doc! { "redriven": false }, None).await?;
+
+    let mut failed_swaps = Vec::new();
+    while cursor.advance().await? {
+        failed_swaps.push(cursor.deserialize_current()?);
+    }
+
+    Ok((StatusCode::OK, Json(json!({ "failed_swaps": failed_swaps }))))
+}
+
+// Asynchronous handler function for listing refund attempts, optionally filtered by status
+pub async fn list_refunds(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<RefundQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let filter = match query.status {
+        Some(status) => doc! { "status": status },
+        None => doc! {},
+    };
+    let collection = state.db.collection::<Refund>("refunds");
+    let mut cursor = collection.find(filter, None).await?;
+
+    let mut refunds = Vec::new();
+    while cursor.advance().await? {
+        refunds.push(cursor.deserialize_current()?);
+    }
+
+    Ok((StatusCode::OK, Json(json!({ "refunds": refunds }))))
+}
+
+// Asynchronous handler function for re-driving a failed swap from the dead-letter queue
+pub async fn redrive_failed_swap(Path(id): Path<String>) -> Result<impl IntoResponse, AppError> {
+    let id = ObjectId::parse_str(&id)
+        .map_err(|_| AppError::BadRequest("Invalid failed swap id".to_string()))?;
+
+    redrive_swap(doc! { "_id": id }).await?.ok_or(AppError::NotFound)?;
+
+    Ok((StatusCode::ACCEPTED, Json(json!({ "redriven": id.to_hex() }))))
+}
+
+// Asynchronous handler function for listing bot wallet spends blocked by the spending limits, pending
+// ones unless another status is asked for
+pub async fn list_spend_approvals(Query(query): Query<SpendApprovalQuery>) -> Result<impl IntoResponse, AppError> {
+    let status = query.status.unwrap_or_else(|| "pending".to_string());
+    let options = FindOptions::builder().sort(doc! { "created_at": -1 }).build();
+    let mut cursor = get_spend_approvals_collection().await?.find(doc! { "status": &status }, options).await?;
+
+    let mut approvals = Vec::new();
+    while cursor.advance().await? {
+        approvals.push(cursor.deserialize_current()?);
+    }
+
+    Ok((StatusCode::OK, Json(json!({ "spend_approvals": approvals }))))
+}
+
+// Asynchronous handler function for approving a blocked bot wallet spend. The swap it blocked is
+// re-driven and its outflows pass the spending limits until they have been sent once.
+pub async fn approve_spend(Path(id): Path<String>) -> Result<impl IntoResponse, AppError> {
+    let id = decide_spend(&id, "approved").await?;
+    let redriven = redrive_swap(doc! { "spend_approval_id": id }).await?;
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(json!({ "approved": id.to_hex(), "redriven": redriven.map(|swap_id| swap_id.to_hex()) })),
+    ))
+}
+
+// Asynchronous handler function for rejecting a blocked bot wallet spend. The swap it blocked stays
+// in the dead-letter queue.
+pub async fn reject_spend(Path(id): Path<String>) -> Result<impl IntoResponse, AppError> {
+    let id = decide_spend(&id, "rejected").await?;
+    Ok((StatusCode::OK, Json(json!({ "rejected": id.to_hex() }))))
+}
+
+async fn decide_spend(id: &str, status: &str) -> Result<ObjectId, AppError> {
+    let id = ObjectId::parse_str(id).map_err(|_| AppError::BadRequest("Invalid spend approval id".to_string()))?;
+    get_spend_approvals_collection()
+        .await?
+        .find_one_and_update(
+            doc! { "_id": id, "status": "pending" },
+            doc! { "$set": { "status": status, "decided_at": BsonDateTime::now() } },
+            None,
+        )
+        .await?
+        .ok_or_else(|| AppError::BadRequest("No pending spend approval with that id".to_string()))?;
+    Ok(id)
+}
+
+// Struct for deserializing a withdrawal key registration
+#[derive(Debug, Deserialize)]
+pub struct WithdrawalKeyPayload {
+    key: String,
+    address: String,
+    #[serde(default)]
+    default: bool,
+}
+
+// Asynchronous handler function for listing the withdrawal keys, including the configured fallback
+pub async fn list_withdrawal_keys() -> Result<impl IntoResponse, AppError> {
+    let withdrawal_keys = withdrawal_addresses::all_keys().await?;
+    Ok((StatusCode::OK, Json(json!({ "withdrawal_keys": withdrawal_keys }))))
+}
+
+// Asynchronous handler function for registering a Kraken withdrawal key. The key has to match the
+// description the address was added under in Kraken's Funding > Withdraw.
+pub async fn register_withdrawal_key(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<WithdrawalKeyPayload>,
+) -> Result<impl IntoResponse, AppError> {
+    let key = payload.key.trim().to_string();
+    if key.is_empty() {
+        return Err(AppError::BadRequest("Withdrawal key can't be empty".to_string()));
+    }
+    let address = solana_address(&payload.address)?.to_string();
+    // Kraken refuses withdrawals through keys it doesn't know, which would only show after the trades
+    if config().exchange == ExchangeKind::Kraken {
+        get_withdrawal_info("SOL", &key, Decimal::ONE).await.map_err(|e| {
+            AppError::BadRequest(format!("Kraken doesn't accept withdrawal key `{}`: {}", key, e))
+        })?;
+    }
+
+    let collection = state.db.collection::<WithdrawalKey>("withdrawal_keys");
+    // Only one key is the default
+    if payload.default {
+        collection
+            .update_many(doc! { "_id": { "$ne": &key } }, doc! { "$set": { "default": false } }, None)
+            .await?;
+    }
+    collection
+        .update_one(
+            doc! { "_id": &key },
+            doc! {
+                "$set": { "address": &address, "default": payload.default },
+                "$setOnInsert": { "created_at": BsonDateTime::now() },
+            },
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .await?;
+
+    Ok((StatusCode::OK, Json(json!({ "key": key, "address": address, "default": payload.default }))))
+}
+
+// Asynchronous handler function for removing a withdrawal key no user is mapped to
+pub async fn remove_withdrawal_key(
+    State(state): State<Arc<AppState>>,
+    Path(key): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let users = state
+        .db
+        .collection::<User>("users")
+        .count_documents(doc! { "withdrawal_key": &key }, None)
+        .await?;
+    if users > 0 {
+        return Err(AppError::BadRequest(format!("{} users withdraw through `{}`, map them to another key first", users, key)));
+    }
+    let result = state
+        .db
+        .collection::<WithdrawalKey>("withdrawal_keys")
+        .delete_one(doc! { "_id": &key }, None)
+        .await?;
+    if result.deleted_count == 0 {
+        return Err(AppError::NotFound);
+    }
+
+    Ok((StatusCode::OK, Json(json!({ "removed": key }))))
+}
+
+// Struct for deserializing a user's withdrawal key mapping, null maps the user back to the default
+#[derive(Debug, Deserialize)]
+pub struct UserWithdrawalKeyPayload {
+    withdrawal_key: Option<String>,
+}
+
+// Asynchronous handler function for mapping a user to a registered withdrawal key
+pub async fn set_user_withdrawal_key(
+    State(state): State<Arc<AppState>>,
+    Path(user_id): Path<i64>,
+    Json(payload): Json<UserWithdrawalKeyPayload>,
+) -> Result<impl IntoResponse, AppError> {
+    if let Some(key) = &payload.withdrawal_key {
+        let registered = state
+            .db
+            .collection::<WithdrawalKey>("withdrawal_keys")
+            .find_one(doc! { "_id": key }, None)
+            .await?;
+        if registered.is_none() {
+            return Err(AppError::BadRequest(format!("Withdrawal key `{}` is not registered", key)));
+        }
+    }
+    let result = state
+        .db
+        .collection::<User>("users")
+        .update_one(
+            doc! { "user_id": user_id },
+            doc! { "$set": { "withdrawal_key": &payload.withdrawal_key } },
+            None,
+        )
+        .await?;
+    if result.matched_count == 0 {
+        return Err(AppError::NotFound);
+    }
+
+    Ok((StatusCode::OK, Json(json!({ "user_id": user_id, "withdrawal_key": payload.withdrawal_key }))))
+}
+
+// Asynchronous handler function for listing the nightly reconciliation reports, newest first, with the
+// number of findings of each kind
+pub async fn list_reconciliation_reports(
+    State(state): State<Arc<AppState>>,
+    Query(page): Query<PageQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let mut options = page.find_options(doc! { "_id": -1 });
+    options.projection = Some(doc! {
+        "from": 1,
+        "to": 1,
+        "generated_at": 1,
+        "ledger_entries": 1,
+        "clean": 1,
+        "missing_deposits": { "$size": "$missing_deposits" },
+        "unmatched_trades": { "$size": "$unmatched_trades" },
+        "fee_discrepancies": { "$size": "$fee_discrepancies" },
+    });
+    let mut cursor = state
+        .db
+        .collection::<Document>("reconciliation_reports")
+        .find(doc! {}, options)
+        .await?;
+
+    let mut reports = Vec::new();
+    while cursor.advance().await? {
+        reports.push(cursor.deserialize_current()?);
+    }
+
+    Ok((StatusCode::OK, Json(json!({ "reports": reports }))))
+}
+
+// Asynchronous handler function for getting the reconciliation report of a day (YYYY-MM-DD)
+pub async fn get_reconciliation_report(
+    State(state): State<Arc<AppState>>,
+    Path(day): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let report = state
+        .db
+        .collection::<Document>("reconciliation_reports")
+        .find_one(doc! { "_id": &day }, None)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    Ok((StatusCode::OK, Json(json!(report))))
+}
+
+// Asynchronous handler function for reconciling a day (YYYY-MM-DD) again, replacing its report
+pub async fn run_reconciliation(Path(day): Path<String>) -> Result<impl IntoResponse, AppError> {
+    if config().exchange != ExchangeKind::Kraken {
+        return Err(AppError::BadRequest("Reconciliation reads the Kraken ledger".to_string()));
+    }
+    let day = NaiveDate::from_str(&day)
+        .map_err(|_| AppError::BadRequest(format!("Invalid day `{}`, expected YYYY-MM-DD", day)))?;
+    if day >= chrono::Utc::now().date_naive() {
+        return Err(AppError::BadRequest("Only past days can be reconciled".to_string()));
+    }
+    let report = reconciliation::reconcile_day(day).await?;
+
+    Ok((StatusCode::OK, Json(json!(report))))
+}
+
+// Asynchronous handler function for the ledger balances of every account, with the total of each
+// asset across all accounts. Every total is zero while the ledger is consistent.
+pub async fn ledger_balances() -> Result<impl IntoResponse, AppError> {
+    let balances = ledger::balances(None).await?;
+    let (totals, unbalanced) = ledger::totals(&balances);
+
+    Ok((
+        StatusCode::OK,
+        Json(json!({
+            "balances": balances,
+            "totals": totals,
+            "consistent": unbalanced.is_empty(),
+            "unbalanced_assets": unbalanced,
+        })),
+    ))
+}
+
+// Asynchronous handler function for a user's ledger journals, newest first, and the balances of the
+// user's accounts
+pub async fn user_ledger(
+    State(state): State<Arc<AppState>>,
+    Path(user_id): Path<i64>,
+    Query(page): Query<PageQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let options = page.find_options(doc! { "created_at": -1 });
+    let mut cursor = state
+        .db
+        .collection::<Document>("ledger")
+        .find(doc! { "user_id": user_id }, options)
+        .await?;
+
+    let mut journals = Vec::new();
+    while cursor.advance().await? {
+        journals.push(cursor.deserialize_current()?);
+    }
+    let accounts = vec![
+        Account::User(user_id).name(""),
+        Account::UserWallet(user_id).name(""),
+    ];
+    let balances = ledger::balances(Some(accounts)).await?;
+
+    Ok((StatusCode::OK, Json(json!({ "user_id": user_id, "balances": balances, "journals": journals }))))
+}
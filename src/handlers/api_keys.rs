@@ -0,0 +1,84 @@
+// api_keys.rs
+// Import necessary modules and libraries
+use axum::{extract::{Json, Path, State}, http::{HeaderMap, StatusCode}, response::IntoResponse};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::api_keys::{self, ApiKeyAuth, SCOPE_DECRYPT};
+use crate::error_handling::AppError;
+use crate::handlers::sessions::api_key_from_headers;
+use crate::mongo::AppState;
+use crate::totp;
+
+// Struct for deserializing the API key request
+#[derive(Debug, Deserialize)]
+pub struct CreateApiKeyPayload {
+    // Any of "read-only", "trade", "withdraw" and "decrypt", limited to the scopes of the calling key
+    scopes: Vec<String>,
+    name: Option<String>,
+}
+
+// Function to authenticate the API key in the x-api-key header
+async fn authenticate(state: &AppState, headers: &HeaderMap) -> Result<ApiKeyAuth, AppError> {
+    let api_key = api_key_from_headers(headers)?;
+    api_keys::authenticate(&state.db, &api_key)
+        .await?
+        .ok_or(AppError::Unauthorized)
+}
+
+// Asynchronous handler function for adding an API key with some of the calling key's scopes. The new
+// key is only returned in this response.
+pub async fn create_api_key(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<CreateApiKeyPayload>,
+) -> Result<impl IntoResponse, AppError> {
+    let auth = authenticate(&state, &headers).await?;
+    let (id, api_key) = api_keys::create_api_key(&state.db, &auth, payload.name.as_deref(), &payload.scopes).await?;
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({
+            "id": id,
+            "api_key": api_key,
+            "name": payload.name,
+            "scopes": payload.scopes,
+        })),
+    ))
+}
+
+// Asynchronous handler function for listing the caller's API keys, without the keys themselves
+pub async fn list_api_keys(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    let auth = authenticate(&state, &headers).await?;
+    let keys = api_keys::list_api_keys(&state.db, &auth.user).await?;
+    Ok((StatusCode::OK, Json(json!({ "current": auth.key_id, "api_keys": keys }))))
+}
+
+// Asynchronous handler function for revoking one of the caller's API keys, "primary" being the key
+// from registration
+pub async fn revoke_api_key(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let auth = authenticate(&state, &headers).await?;
+    api_keys::revoke_api_key(&state.db, &auth, &id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Asynchronous handler function for replacing the registration key with a new one. The wallet secrets
+// are re-encrypted under the new key, which is only returned in this response, and every other key
+// and session is revoked. Needs a key with the decrypt scope, and a TOTP code when enabled.
+pub async fn rotate_key(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    let auth = authenticate(&state, &headers).await?;
+    auth.require(SCOPE_DECRYPT)?;
+    totp::require_code(&state.db, &auth.user, &auth.secrets_key, &headers).await?;
+    let api_key = api_keys::rotate_primary_key(&state.db, &auth).await?;
+    Ok((StatusCode::OK, Json(json!({ "api_key": api_key }))))
+}
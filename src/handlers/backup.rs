@@ -0,0 +1,217 @@
+// backup.rs
+// Import necessary modules and libraries
+use axum::{extract::{Json, State}, http::{HeaderMap, StatusCode}, response::IntoResponse};
+use mongodb::bson::doc;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use solana_sdk::bs58;
+use solana_sdk::signer::{keypair::Keypair, Signer};
+use std::sync::Arc;
+use tokio::task::spawn_blocking;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::backup::{open, seal, EncryptedBackup};
+use crate::error_handling::AppError;
+use crate::encryption::{decrypt_field, SecretField, UserCipher};
+use crate::mongo::{AppState, User};
+use crate::sessions::{self, Session, SCOPE_EXPORT_BACKUP, SCOPE_IMPORT_BACKUP};
+use crate::totp;
+use crate::wallets::ethereum::{derive_keypair, DERIVATION_PATH as ETHEREUM_DERIVATION_PATH};
+use crate::wallets::solana::{derivation_path_label, derive_keypair as derive_solana_keypair, user_keypair};
+
+// Struct for deserializing the backup export payload, not Debug so the password can't be logged
+#[derive(Deserialize)]
+pub struct ExportBackupPayload {
+    password: String,
+}
+
+// Struct for deserializing the backup import payload, not Debug so the password can't be logged
+#[derive(Deserialize)]
+pub struct ImportBackupPayload {
+    password: String,
+    backup: EncryptedBackup,
+}
+
+// The plaintext of a backup, every secret in the clear so it can be restored anywhere. Not Debug, and
+// the secrets are zeroed when it's dropped.
+#[derive(Serialize, Deserialize)]
+struct WalletSecrets {
+    solana: SolanaSecrets,
+    bitcoin: BitcoinSecrets,
+    ethereum: EthereumSecrets,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SolanaSecrets {
+    public_key: String,
+    private_key: String,
+    derivation_path: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BitcoinSecrets {
+    mnemonic: Option<String>,
+    descriptor: Option<String>,
+    private_key: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct EthereumSecrets {
+    public_key: Option<String>,
+    private_key: Option<String>,
+    derivation_path: Option<String>,
+}
+
+impl Drop for WalletSecrets {
+    fn drop(&mut self) {
+        self.solana.private_key.zeroize();
+        self.bitcoin.mnemonic.zeroize();
+        self.bitcoin.private_key.zeroize();
+        self.ethereum.private_key.zeroize();
+    }
+}
+
+// Decrypts an optional stored secret, empty values count as missing
+async fn decrypt_optional(user: &User, field: SecretField, api_key: &str) -> Result<Option<String>, AppError> {
+    match field.value(user) {
+        Some(_) => decrypt_field(user, field, api_key).await.map(|secret| Some(secret.to_string())),
+        None => Ok(None),
+    }
+}
+
+// Asynchronous handler function for exporting a user's keys and mnemonics as a password protected
+// backup they can store offline. Needs a session with the export-backup scope.
+pub async fn export_backup(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<ExportBackupPayload>,
+) -> Result<impl IntoResponse, AppError> {
+    let Session { user, secrets_key } = sessions::authorize(&state.db, &headers, SCOPE_EXPORT_BACKUP).await?;
+    totp::require_code(&state.db, &user, &secrets_key, &headers).await?;
+    let api_key = secrets_key.as_str();
+
+    let solana_keypair = user_keypair(&user, Some(api_key)).await?;
+    let bitcoin_mnemonic = decrypt_optional(&user, SecretField::BitcoinMnemonic, api_key).await?;
+    let ethereum_private_key = decrypt_optional(&user, SecretField::EthereumPrivateKey, api_key).await?;
+
+    // Only keys derived from the mnemonic have a path, older random keys are exported as they are
+    let ethereum_derivation_path = bitcoin_mnemonic
+        .as_deref()
+        .and_then(|mnemonic| derive_keypair(mnemonic).ok())
+        .filter(|(_, public_key, _)| Some(public_key.to_string()) == user.ethereum_public_key)
+        .map(|_| ETHEREUM_DERIVATION_PATH.to_string());
+
+    let secrets = WalletSecrets {
+        solana: SolanaSecrets {
+            public_key: solana_keypair.pubkey().to_string(),
+            private_key: bs58::encode(Zeroizing::new(solana_keypair.to_bytes()).as_slice()).into_string(),
+            derivation_path: user
+                .solana_derivation_index
+                .and_then(|index| u32::try_from(index).ok())
+                .map(derivation_path_label),
+        },
+        bitcoin: BitcoinSecrets {
+            mnemonic: bitcoin_mnemonic,
+            descriptor: user.bitcoin_public_key.clone(),
+            private_key: decrypt_optional(&user, SecretField::BitcoinPrivateKey, api_key).await?,
+        },
+        ethereum: EthereumSecrets {
+            public_key: user.ethereum_public_key.clone(),
+            private_key: ethereum_private_key,
+            derivation_path: ethereum_derivation_path,
+        },
+    };
+    let plaintext = Zeroizing::new(serde_json::to_vec(&secrets)?);
+
+    // Argon2 is deliberately slow, keep it off the async workers
+    let user_id = user.user_id;
+    let backup = spawn_blocking(move || seal(user_id, &plaintext, &payload.password))
+        .await
+        .map_err(|e| AppError::CustomError(format!("Backup encryption panicked: {}", e)))??;
+
+    Ok((StatusCode::OK, Json(backup)))
+}
+
+// Asynchronous handler function for restoring a user's keys from a backup made with POST /export/backup.
+// Needs a session with the import-backup scope.
+pub async fn import_backup(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<ImportBackupPayload>,
+) -> Result<impl IntoResponse, AppError> {
+    let Session { user, secrets_key } = sessions::authorize(&state.db, &headers, SCOPE_IMPORT_BACKUP).await?;
+    let cipher = UserCipher::for_user(&user, &secrets_key).await?;
+    if payload.backup.user_id != user.user_id {
+        return Err(AppError::BadRequest("Backup belongs to another user".to_string()));
+    }
+
+    let backup = payload.backup;
+    let password = payload.password;
+    let plaintext = spawn_blocking(move || open(&backup, &password).map(Zeroizing::new))
+        .await
+        .map_err(|e| AppError::CustomError(format!("Backup decryption panicked: {}", e)))??;
+    let secrets: WalletSecrets = serde_json::from_slice(&plaintext)
+        .map_err(|_| AppError::BadRequest("Backup contents are not a wallet backup".to_string()))?;
+
+    // The Solana key must match the public key it was exported with
+    let solana_keypair = bs58::decode(&secrets.solana.private_key)
+        .into_vec()
+        .ok()
+        .and_then(|bytes| Keypair::from_bytes(&bytes).ok())
+        .filter(|keypair| keypair.pubkey().to_string() == secrets.solana.public_key)
+        .ok_or_else(|| AppError::BadRequest("Backup Solana key doesn't match its public key".to_string()))?;
+
+    let mut set = doc! {};
+    let mut unset = doc! {};
+    // A derived wallet that still derives the same key keeps deriving it, anything else is stored
+    let still_derived = user
+        .solana_derivation_index
+        .and_then(|index| u32::try_from(index).ok())
+        .filter(|index| secrets.solana.derivation_path.as_deref() == Some(derivation_path_label(*index).as_str()))
+        .is_some_and(|index| {
+            derive_solana_keypair(index).is_ok_and(|keypair| keypair.pubkey() == solana_keypair.pubkey())
+        });
+    if !still_derived {
+        set.insert("solana_public_key", solana_keypair.pubkey().to_string());
+        set.insert("solana_private_key", cipher.encrypt(SecretField::SolanaPrivateKey, &secrets.solana.private_key)?);
+        unset.insert("solana_derivation_index", "");
+    }
+    if let Some(mnemonic) = &secrets.bitcoin.mnemonic {
+        set.insert("bitcoin_mnemonic", cipher.encrypt(SecretField::BitcoinMnemonic, mnemonic)?);
+    }
+    if let Some(descriptor) = &secrets.bitcoin.descriptor {
+        set.insert("bitcoin_public_key", descriptor);
+    }
+    if let Some(private_key) = &secrets.bitcoin.private_key {
+        set.insert("bitcoin_private_key", cipher.encrypt(SecretField::BitcoinPrivateKey, private_key)?);
+    }
+    if let Some(public_key) = &secrets.ethereum.public_key {
+        set.insert("ethereum_public_key", public_key);
+    }
+    if let Some(private_key) = &secrets.ethereum.private_key {
+        set.insert("ethereum_private_key", cipher.encrypt(SecretField::EthereumPrivateKey, private_key)?);
+    }
+
+    if let Some(data_key) = cipher.new_data_key() {
+        set.insert("data_key", data_key);
+    }
+    let mut update = doc! { "$set": set };
+    if !unset.is_empty() {
+        update.insert("$unset", unset);
+    }
+    state
+        .db
+        .collection::<User>("users")
+        .update_one(doc! { "_id": user.id }, update, None)
+        .await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(json!({
+            "restored": true,
+            "solana_public_key": solana_keypair.pubkey().to_string(),
+            "bitcoin_public_key": secrets.bitcoin.descriptor,
+            "ethereum_public_key": secrets.ethereum.public_key,
+        })),
+    ))
+}
@@ -0,0 +1,57 @@
+// bitcoin.rs
+// Import necessary modules and libraries
+use axum::{extract::{Json, State}, http::StatusCode, response::IntoResponse};
+use mongodb::bson::doc;
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::config::config;
+use crate::error_handling::AppError;
+use crate::handlers::decrypt::get_user_by_api_key;
+use crate::api_keys::SCOPE_TRADE;
+use crate::redact::Redacted;
+use crate::mongo::{AppState, User};
+use crate::wallets::bitcoin::receive_address;
+
+// Struct for deserializing a payload that only identifies the user
+#[derive(Debug, Deserialize)]
+pub struct ApiKeyPayload {
+    api_key: Redacted<String>,
+}
+
+// Asynchronous handler function for handing out a fresh receive address of the user's Bitcoin wallet,
+// so deposits don't all land on (and link) the same address
+pub async fn create_deposit_address(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<ApiKeyPayload>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = get_user_by_api_key(&state.db, &payload.api_key, SCOPE_TRADE)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    let descriptor = user
+        .bitcoin_public_key
+        .filter(|descriptor| !descriptor.is_empty())
+        .ok_or_else(|| AppError::BadRequest("User has no Bitcoin wallet".to_string()))?;
+
+    // Reserve the next index atomically so concurrent requests never get the same address
+    let options = FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build();
+    let updated = state
+        .db
+        .collection::<User>("users")
+        .find_one_and_update(
+            doc! { "_id": user.id },
+            doc! { "$inc": { "bitcoin_address_index": 1_i64 } },
+            options,
+        )
+        .await?
+        .ok_or(AppError::NotFound)?;
+    let index = u32::try_from(updated.bitcoin_address_index)
+        .map_err(|_| AppError::CustomError("Bitcoin address index out of range".to_string()))?;
+    let address = receive_address(&descriptor, config().bitcoin_network, index)?;
+
+    Ok((StatusCode::OK, Json(json!({ "address": address, "index": index }))))
+}
@@ -0,0 +1,133 @@
+// dca.rs
+// Import necessary modules and libraries
+use axum::{extract::{Json, State}, http::StatusCode, response::IntoResponse};
+use mongodb::bson::{doc, DateTime as BsonDateTime};
+use mongodb::options::FindOptions;
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::error_handling::AppError;
+use crate::handlers::decrypt::get_user_by_api_key;
+use crate::api_keys::{SCOPE_READ_ONLY, SCOPE_TRADE};
+use crate::redact::Redacted;
+use crate::mongo::{AppState, DcaExecution, DcaPlan, User};
+
+// Struct for deserializing the DCA plan payload
+#[derive(Debug, Deserialize)]
+pub struct DcaPlanPayload {
+    api_key: Redacted<String>,
+    amount_usd: f64,
+    interval_hours: i64,
+}
+
+// Struct for deserializing a payload that only identifies the user
+#[derive(Debug, Deserialize)]
+pub struct ApiKeyPayload {
+    api_key: Redacted<String>,
+}
+
+// Asynchronous handler function for creating or replacing a user's DCA plan
+pub async fn set_dca_plan(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<DcaPlanPayload>,
+) -> Result<impl IntoResponse, AppError> {
+    if !payload.amount_usd.is_finite() || payload.amount_usd <= 0.0 {
+        return Err(AppError::BadRequest("amount_usd must be positive".to_string()));
+    }
+    if payload.interval_hours < 1 {
+        return Err(AppError::BadRequest("interval_hours must be at least 1".to_string()));
+    }
+
+    let user = get_user_by_api_key(&state.db, &payload.api_key, SCOPE_TRADE)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    // A user has a single plan, the first run happens on the next scheduler tick
+    let plans = state.db.collection::<DcaPlan>("dca_plans");
+    plans
+        .update_many(
+            doc! { "user_id": user.user_id, "active": true },
+            doc! { "$set": { "active": false } },
+            None,
+        )
+        .await?;
+    let now = BsonDateTime::now();
+    let plan = DcaPlan {
+        id: None,
+        user_id: user.user_id,
+        amount_usd: payload.amount_usd,
+        interval_hours: payload.interval_hours,
+        active: true,
+        next_run_at: now,
+        created_at: now,
+    };
+    plans.insert_one(&plan, None).await?;
+
+    state
+        .db
+        .collection::<User>("users")
+        .update_one(doc! { "_id": user.id }, doc! { "$set": { "dca_active": true } }, None)
+        .await?;
+
+    Ok((StatusCode::OK, Json(json!({ "plan": plan }))))
+}
+
+// Asynchronous handler function for stopping a user's DCA plan, held funds go back to the autobuy flow
+pub async fn stop_dca_plan(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<ApiKeyPayload>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = get_user_by_api_key(&state.db, &payload.api_key, SCOPE_TRADE)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    state
+        .db
+        .collection::<DcaPlan>("dca_plans")
+        .update_many(
+            doc! { "user_id": user.user_id, "active": true },
+            doc! { "$set": { "active": false } },
+            None,
+        )
+        .await?;
+    state
+        .db
+        .collection::<User>("users")
+        .update_one(doc! { "_id": user.id }, doc! { "$set": { "dca_active": false } }, None)
+        .await?;
+
+    Ok((StatusCode::OK, Json(json!({ "active": false }))))
+}
+
+// Asynchronous handler function for getting a user's DCA plan and execution history
+pub async fn get_dca_plan(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<ApiKeyPayload>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = get_user_by_api_key(&state.db, &payload.api_key, SCOPE_READ_ONLY)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    let plan = state
+        .db
+        .collection::<DcaPlan>("dca_plans")
+        .find_one(doc! { "user_id": user.user_id, "active": true }, None)
+        .await?;
+
+    let options = FindOptions::builder()
+        .sort(doc! { "executed_at": -1 })
+        .limit(100)
+        .build();
+    let mut cursor = state
+        .db
+        .collection::<DcaExecution>("dca_executions")
+        .find(doc! { "user_id": user.user_id }, options)
+        .await?;
+    let mut executions = Vec::new();
+    while cursor.advance().await? {
+        executions.push(cursor.deserialize_current()?);
+    }
+
+    Ok((StatusCode::OK, Json(json!({ "plan": plan, "executions": executions }))))
+}
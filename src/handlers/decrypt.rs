@@ -1,122 +1,110 @@
 // Deecrypt.rs
 // Import necessary modules and libraries
-use axum::{extract::{State, Json}, http::StatusCode, response::IntoResponse, Json as ResponseJson};
-use mongodb::bson::doc;
+use axum::{extract::{ConnectInfo, State, Json}, http::{HeaderMap, StatusCode}, response::IntoResponse, Json as ResponseJson};
 use serde::Deserialize;
 use serde_json::json;
 use tracing::error;
-use aes_gcm::{Aes256Gcm, Key, Nonce};
-use aes_gcm::aead::{Aead, KeyInit};
-use hex;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use zeroize::Zeroizing;
 
 use crate::mongo::{AppState, User};
+use crate::wallets::solana::user_keypair;
+use solana_sdk::bs58;
+use crate::api_keys;
+use crate::encryption::{decrypt_field, SecretField};
 use crate::error_handling::AppError;
-
-// Struct for deserializing API key payload from the request body
-#[derive(Debug, Deserialize)]
-pub struct ApiKeyPayload {
-    api_key: String,
+use crate::notifications::notify_user;
+use crate::totp;
+use crate::sessions::{self, Session, SCOPE_DECRYPT_KEYS};
+
+// Struct for deserializing the key selection from the request body
+#[derive(Debug, Default, Deserialize)]
+pub struct DecryptKeysPayload {
+    // Any of "solana", "bitcoin" and "ethereum", all three when left out
+    keys: Option<Vec<String>>,
 }
 
-// Asynchronous handler function for decrypting user keys
+const KEY_NAMES: [&str; 3] = ["solana", "bitcoin", "ethereum"];
+
+// Asynchronous handler function for decrypting user keys. Needs a session with the decrypt-keys scope
+// and only the keys listed in `keys` are decrypted and returned. The user is notified of every
+// successful access along with the caller's IP address.
 pub async fn decrypt_keys_handler(
     State(state): State<Arc<AppState>>, // Extract shared application state
-    Json(payload): Json<ApiKeyPayload>,  // Extract JSON payload from request body
-) -> impl IntoResponse {
-    let api_key = payload.api_key;
-
-    // Fetch user from the database by API key
-    let user = match get_user_by_api_key(&state.db, &api_key).await {
-        Ok(Some(user)) => user,
-        Ok(None) => {
-            // If no user is found, respond with 404 status code
-            return (StatusCode::NOT_FOUND, "User not found").into_response();
-        }
-        Err(err) => {
-            // If database query fails, log the error and respond accordingly
-            error!("Failed to query database: {}", err);
-            return err.into_response();
-        }
-    };
-
-    // Ensure the API key is 32 bytes long for AES-256 encryption
-    let key_bytes = {
-        let mut key_bytes = vec![0; 32];
-        let api_key_bytes = api_key.as_bytes();
-        let len = std::cmp::min(api_key_bytes.len(), 32);
-        key_bytes[..len].copy_from_slice(&api_key_bytes[..len]);
-        key_bytes
-    };
-
-    let key = Key::<Aes256Gcm>::from_slice(&key_bytes);
-
-    // Decrypt Solana private key
-    let solana_private_key = match decrypt_data(&user.solana_private_key.unwrap_or_default(), key) {
-        Ok(key) => key,
-        Err(_) => {
-            error!("Failed to decrypt Solana private key");
-            return AppError::DecryptionError.into_response();
-        }
-    };
-
-    // Decrypt Bitcoin private key
-    let bitcoin_private_key = match decrypt_data(&user.bitcoin_private_key.unwrap_or_default(), key) {
-        Ok(key) => key,
-        Err(_) => {
-            error!("Failed to decrypt Bitcoin private key");
-            return AppError::DecryptionError.into_response();
-        }
+    ConnectInfo(peer): ConnectInfo<SocketAddr>, // Extract the caller's address for the notification
+    headers: HeaderMap,
+    payload: Option<Json<DecryptKeysPayload>>, // Extract the optional key selection
+) -> Result<impl IntoResponse, AppError> {
+    let requested = payload.map(|Json(payload)| payload).unwrap_or_default().keys;
+    let requested: Vec<String> = match requested {
+        Some(keys) if !keys.is_empty() => keys.iter().map(|key| key.to_lowercase()).collect(),
+        _ => KEY_NAMES.iter().map(|key| key.to_string()).collect(),
     };
+    if let Some(unknown) = requested.iter().find(|key| !KEY_NAMES.contains(&key.as_str())) {
+        return Err(AppError::BadRequest(format!(
+            "Unknown key {}, expected solana, bitcoin or ethereum",
+            unknown
+        )));
+    }
 
-    // Decrypt Ethereum private key
-    let ethereum_private_key = match decrypt_data(&user.ethereum_private_key.unwrap_or_default(), key) {
-        Ok(key) => key,
-        Err(_) => {
-            error!("Failed to decrypt Ethereum private key");
-            return AppError::DecryptionError.into_response();
+    let Session { user, secrets_key } = sessions::authorize(&state.db, &headers, SCOPE_DECRYPT_KEYS).await?;
+    totp::require_code(&state.db, &user, &secrets_key, &headers).await?;
+    let api_key = secrets_key.as_str();
+
+    let mut response = serde_json::Map::new();
+    for key in KEY_NAMES.iter().filter(|key| requested.iter().any(|requested| requested == *key)) {
+        let private_key = match *key {
+            // Derive or decrypt the Solana private key
+            "solana" => user_keypair(&user, Some(api_key))
+                .await
+                .map(|keypair| Zeroizing::new(bs58::encode(Zeroizing::new(keypair.to_bytes()).as_slice()).into_string())),
+            "bitcoin" => decrypt_field(&user, SecretField::BitcoinPrivateKey, api_key).await,
+            _ => decrypt_field(&user, SecretField::EthereumPrivateKey, api_key).await,
         }
-    };
+        .inspect_err(|_| error!("Failed to decrypt {} private key", key))?;
+        response.insert(key.to_string(), json!({ "private_key": private_key.as_str() }));
+    }
 
-    // Create JSON response with decrypted keys
-    let response = json!({
-        "solana": {
-            "private_key": solana_private_key,
-        },
-        "bitcoin": {
-            "private_key": bitcoin_private_key,
-        },
-        "ethereum": {
-            "private_key": ethereum_private_key,
-        }
-    });
+    let keys: Vec<&str> = response.keys().map(String::as_str).collect();
+    notify_user(
+        user.user_id,
+        format!(
+            "Your {} private key{} {} just accessed from IP {}. If this wasn't you, rotate your API key right away.",
+            keys.join(", "),
+            if keys.len() == 1 { "" } else { "s" },
+            if keys.len() == 1 { "was" } else { "were" },
+            client_ip(&headers, peer),
+        ),
+    );
 
     // Respond with 200 status code and JSON payload
-    (StatusCode::OK, ResponseJson(response)).into_response()
+    Ok((StatusCode::OK, ResponseJson(serde_json::Value::Object(response))))
 }
 
-// Asynchronous function to get a user from the database by API key
-async fn get_user_by_api_key(db: &mongodb::Database, api_key: &str) -> Result<Option<User>, AppError> {
-    let collection = db.collection::<User>("users");
-    let filter = doc! { "api_key": api_key };
-    let user = collection.find_one(filter, None).await.map_err(AppError::DatabaseError)?;
-    Ok(user)
+// Function to describe where a request came from, the connecting address plus the client a proxy
+// reports in X-Forwarded-For, which the client could have set itself
+fn client_ip(headers: &HeaderMap, peer: SocketAddr) -> String {
+    let forwarded = headers
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .map(str::trim)
+        .filter(|forwarded| !forwarded.is_empty());
+    match forwarded {
+        Some(forwarded) => format!("{} (forwarded for {})", peer.ip(), forwarded),
+        None => peer.ip().to_string(),
+    }
 }
 
-// Function to decrypt data using AES-256-GCM
-fn decrypt_data(data: &str, key: &Key<Aes256Gcm>) -> Result<String, AppError> {
-    let cipher = Aes256Gcm::new(key);
-    let decoded_data = hex::decode(data).map_err(|_| AppError::DecryptionError)?;
-
-    // Ensure there is enough data for a nonce and ciphertext
-    if decoded_data.len() < 12 {
-        return Err(AppError::DecryptionError);
+// Asynchronous function to get a user from the database by any of their API keys, failing with
+// Forbidden when the key doesn't have `scope`
+pub(crate) async fn get_user_by_api_key(db: &mongodb::Database, api_key: &str, scope: &str) -> Result<Option<User>, AppError> {
+    match api_keys::authenticate(db, api_key).await? {
+        Some(auth) => {
+            auth.require(scope)?;
+            Ok(Some(auth.user))
+        }
+        None => Ok(None),
     }
-
-    let (nonce_bytes, ciphertext) = decoded_data.split_at(12);
-    let nonce = Nonce::from_slice(nonce_bytes);
-
-    // Decrypt the data and convert to a UTF-8 string
-    let plaintext = cipher.decrypt(nonce, ciphertext).map_err(|_| AppError::DecryptionError)?;
-    String::from_utf8(plaintext).map_err(|_| AppError::DecryptionError)
 }
@@ -0,0 +1,269 @@
+// export.rs
+// Import necessary modules and libraries
+use axum::{
+    body::StreamBody,
+    extract::{Json, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures_util::stream::{self, StreamExt};
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::options::FindOptions;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::task::spawn_blocking;
+
+use crate::backup::MIN_PASSWORD_LENGTH;
+use crate::config::config;
+use crate::error_handling::AppError;
+use crate::encryption::{decrypt_field, SecretField};
+use crate::handlers::decrypt::get_user_by_api_key;
+use crate::handlers::sessions::api_key_from_headers;
+use crate::api_keys::SCOPE_READ_ONLY;
+use crate::redact::Redacted;
+use crate::mongo::AppState;
+use crate::sessions::{self, Session, SCOPE_EXPORT_KEYSTORE};
+use crate::totp;
+use crate::wallets::bitcoin::watch_only_wallet;
+use crate::wallets::ethereum::{address_from_public_key, keystore_v3};
+
+// Struct for deserializing a payload that only identifies the user
+#[derive(Debug, Deserialize)]
+pub struct ApiKeyPayload {
+    api_key: Redacted<String>,
+}
+
+// Asynchronous handler function for exporting a user's public wallet data, so the wallets can be
+// imported as watch-only into Sparrow/Electrum (descriptors), Phantom (public key) or any Ethereum wallet
+pub async fn export_watch_only(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<ApiKeyPayload>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = get_user_by_api_key(&state.db, &payload.api_key, SCOPE_READ_ONLY)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    let network = config().bitcoin_network;
+    let bitcoin = match user.bitcoin_public_key.as_deref().filter(|descriptor| !descriptor.is_empty()) {
+        Some(descriptor) => {
+            let wallet = watch_only_wallet(descriptor, network)?;
+            Some(json!({
+                "network": network.to_string(),
+                "receive_descriptor": wallet.receive_descriptor,
+                "change_descriptor": wallet.change_descriptor,
+                "xpub": wallet.xpub,
+                "first_address": wallet.first_address,
+            }))
+        }
+        None => None,
+    };
+    let ethereum_address = user.ethereum_public_key.as_deref().and_then(address_from_public_key);
+
+    Ok((
+        StatusCode::OK,
+        Json(json!({
+            "bitcoin": bitcoin,
+            "solana": { "public_key": user.solana_public_key },
+            "ethereum": { "address": ethereum_address },
+        })),
+    ))
+}
+
+// Struct for deserializing the keystore export payload, not Debug so the password can't be logged
+#[derive(Deserialize)]
+pub struct KeystorePayload {
+    password: String,
+}
+
+// Asynchronous handler function for exporting a user's Ethereum key as a V3 keystore JSON, encrypted
+// with their password, that MetaMask and geth can import directly. Needs a session with the
+// export-keystore scope.
+pub async fn export_ethereum_keystore(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<KeystorePayload>,
+) -> Result<impl IntoResponse, AppError> {
+    if payload.password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(AppError::BadRequest(format!(
+            "Keystore password must be at least {} characters",
+            MIN_PASSWORD_LENGTH
+        )));
+    }
+    let Session { user, secrets_key } = sessions::authorize(&state.db, &headers, SCOPE_EXPORT_KEYSTORE).await?;
+    totp::require_code(&state.db, &user, &secrets_key, &headers).await?;
+    if SecretField::EthereumPrivateKey.value(&user).is_none() {
+        return Err(AppError::BadRequest("User has no Ethereum wallet".to_string()));
+    }
+    let secret_key = decrypt_field(&user, SecretField::EthereumPrivateKey, &secrets_key).await?;
+
+    // scrypt is deliberately slow, keep it off the async workers
+    let keystore = spawn_blocking(move || keystore_v3(&secret_key, &payload.password))
+        .await
+        .map_err(|e| AppError::CustomError(format!("Keystore encryption panicked: {}", e)))??;
+
+    Ok((StatusCode::OK, Json(keystore)))
+}
+
+// Struct for deserializing the history export query, `format` is "json" (the default) or "csv"
+#[derive(Debug, Deserialize)]
+pub struct HistoryExportQuery {
+    format: Option<String>,
+}
+
+// The columns of a history export and the transaction fields they are read from
+const HISTORY_COLUMNS: [(&str, &str); 28] = [
+    ("id", "_id"),
+    ("timestamp", "timestamp"),
+    ("settled_at", "settled_at"),
+    ("state", "state"),
+    ("source", "source"),
+    ("deposit_asset", "deposit_asset"),
+    ("amount", "amount"),
+    ("deposit_refid", "refid"),
+    ("sell_txid", "kraken_orders.sell.txid"),
+    ("sell_volume", "kraken_orders.sell.volume"),
+    ("sell_price_usd", "kraken_orders.sell.price"),
+    ("sell_proceeds_usd", "kraken_orders.sell.cost"),
+    ("sell_fee_usd", "kraken_orders.sell.fee"),
+    ("buy_txid", "kraken_orders.buy.txid"),
+    ("buy_volume_sol", "kraken_orders.buy.volume"),
+    ("buy_price_usd", "kraken_orders.buy.price"),
+    ("buy_cost_usd", "kraken_orders.buy.cost"),
+    ("buy_fee_usd", "kraken_orders.buy.fee"),
+    ("withdrawal_amount_sol", "withdrawal.amount"),
+    ("withdrawal_fee_sol", "fees.withdrawal_fee_sol"),
+    ("withdrawal_txid", "withdrawal.txid"),
+    ("network_fee_lamports", "fees.network_fee_lamports"),
+    ("platform_fee_lamports", "fees.platform_fee_lamports"),
+    ("lockin_out_amount", "route_plan.out_amount"),
+    ("lockin_signature", "lockin_signature"),
+    ("btc_price_usd", "cost_basis.btc_price_usd"),
+    ("lockin_amount", "cost_basis.lockin_amount"),
+    ("lockin_price_usd", "cost_basis.lockin_price_usd"),
+];
+
+// Reads a dotted field path of a transaction as JSON, dates as RFC 3339 and ids as hex
+fn history_field(tx: &Document, path: &str) -> Value {
+    let mut fields = path.split('.');
+    let mut value = fields.next().and_then(|field| tx.get(field));
+    for field in fields {
+        value = match value {
+            Some(Bson::Document(document)) => document.get(field),
+            _ => None,
+        };
+    }
+    match value {
+        Some(Bson::String(value)) => json!(value),
+        Some(Bson::Double(value)) => json!(value),
+        Some(Bson::Int32(value)) => json!(value),
+        Some(Bson::Int64(value)) => json!(value),
+        Some(Bson::Boolean(value)) => json!(value),
+        Some(Bson::ObjectId(id)) => json!(id.to_hex()),
+        Some(Bson::DateTime(date)) => json!(date.try_to_rfc3339_string().ok()),
+        _ => Value::Null,
+    }
+}
+
+fn history_row(tx: &Document) -> Vec<(&'static str, Value)> {
+    HISTORY_COLUMNS
+        .iter()
+        .map(|(column, path)| {
+            let mut value = history_field(tx, path);
+            // Transactions without a timestamp were created when their id was
+            if *column == "timestamp" && value.is_null() {
+                value = tx
+                    .get_object_id("_id")
+                    .ok()
+                    .and_then(|id| id.timestamp().try_to_rfc3339_string().ok())
+                    .map_or(Value::Null, Value::String);
+            }
+            (*column, value)
+        })
+        .collect()
+}
+
+// Quotes a CSV value when it holds a separator, quote or line break
+fn csv_value(value: &Value) -> String {
+    let text = match value {
+        Value::Null => String::new(),
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    };
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text
+    }
+}
+
+// Asynchronous handler function for exporting the user's whole history, oldest first, with the
+// deposit, the exchange fills, the withdrawal and the swap of every transaction, for tax tools. The
+// API key goes in the x-api-key header. The rows are streamed as they are read.
+pub async fn export_history(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<HistoryExportQuery>,
+) -> Result<Response, AppError> {
+    let csv = match query.format.as_deref() {
+        None | Some("json") => false,
+        Some("csv") => true,
+        Some(format) => {
+            return Err(AppError::BadRequest(format!("Invalid format `{}`, expected json or csv", format)));
+        }
+    };
+    let api_key = api_key_from_headers(&headers)?;
+    let user = get_user_by_api_key(&state.db, &api_key, SCOPE_READ_ONLY)
+        .await?
+        .ok_or(AppError::Unauthorized)?;
+
+    // The bot stores user_id as either an int32 or an int64
+    let filter = doc! { "$or": [ { "user_id": user.user_id }, { "user_id": user.user_id as i32 } ] };
+    let options = FindOptions::builder().sort(doc! { "_id": 1 }).build();
+    let cursor = state
+        .db
+        .collection::<Document>("transactions")
+        .find(filter, options)
+        .await?;
+
+    if csv {
+        let header_line = HISTORY_COLUMNS.iter().map(|(column, _)| *column).collect::<Vec<_>>().join(",") + "\n";
+        let rows = cursor.map(|tx| {
+            tx.map(|tx| {
+                let values: Vec<String> = history_row(&tx).iter().map(|(_, value)| csv_value(value)).collect();
+                values.join(",") + "\n"
+            })
+        });
+        let body = StreamBody::new(stream::once(async { Ok(header_line) }).chain(rows));
+        return Ok((
+            [
+                (header::CONTENT_TYPE, "text/csv"),
+                (header::CONTENT_DISPOSITION, "attachment; filename=\"transactions.csv\""),
+            ],
+            body,
+        )
+            .into_response());
+    }
+
+    let rows = cursor.enumerate().map(|(index, tx)| {
+        tx.map(|tx| {
+            let row: serde_json::Map<String, Value> =
+                history_row(&tx).into_iter().map(|(column, value)| (column.to_string(), value)).collect();
+            let separator = if index == 0 { "" } else { "," };
+            format!("{}{}", separator, Value::Object(row))
+        })
+    });
+    let body = StreamBody::new(
+        stream::once(async { Ok("[".to_string()) })
+            .chain(rows)
+            .chain(stream::once(async { Ok("]".to_string()) })),
+    );
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/json"),
+            (header::CONTENT_DISPOSITION, "attachment; filename=\"transactions.json\""),
+        ],
+        body,
+    )
+        .into_response())
+}
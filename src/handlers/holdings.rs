@@ -0,0 +1,61 @@
+// holdings.rs
+// Import necessary modules and libraries
+use axum::{extract::{Json, State}, http::StatusCode, response::IntoResponse};
+use serde::Deserialize;
+use serde_json::json;
+use solana_sdk::pubkey::Pubkey;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::config::config;
+use crate::cost_basis;
+use crate::error_handling::AppError;
+use crate::handlers::decrypt::get_user_by_api_key;
+use crate::api_keys::SCOPE_READ_ONLY;
+use crate::redact::Redacted;
+use crate::holdings::get_holdings;
+use crate::mongo::AppState;
+
+// Struct for deserializing a payload that only identifies the user
+#[derive(Debug, Deserialize)]
+pub struct ApiKeyPayload {
+    api_key: Redacted<String>,
+}
+
+// Asynchronous handler function for listing the SPL tokens held by a user's Solana address
+pub async fn get_user_holdings(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<ApiKeyPayload>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = get_user_by_api_key(&state.db, &payload.api_key, SCOPE_READ_ONLY)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    let address = Pubkey::from_str(user.solana_public_key.as_deref().unwrap_or_default())
+        .map_err(|_| AppError::BadRequest("User has no valid Solana address".to_string()))?;
+
+    let holdings = get_holdings(&address).await?;
+    let lockin = holdings.iter().find(|holding| holding.mint == config().lockin_mint);
+    let total_value_usd: f64 = holdings.iter().filter_map(|holding| holding.value_usd).sum();
+    Ok((
+        StatusCode::OK,
+        Json(json!({
+            "address": address.to_string(),
+            "lockin": lockin,
+            "total_value_usd": total_value_usd,
+            "holdings": holdings,
+        })),
+    ))
+}
+
+// Asynchronous handler function for the cost basis and unrealized P&L of each LOCKIN lot the user acquired
+pub async fn get_cost_basis(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<ApiKeyPayload>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = get_user_by_api_key(&state.db, &payload.api_key, SCOPE_READ_ONLY)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    let lots = cost_basis::lots(user.user_id).await?;
+    Ok((StatusCode::OK, Json(lots)))
+}
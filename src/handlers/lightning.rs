@@ -0,0 +1,45 @@
+// lightning.rs
+// Import necessary modules and libraries
+use axum::{extract::{Json, State}, http::StatusCode, response::IntoResponse};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::error_handling::AppError;
+use crate::handlers::decrypt::get_user_by_api_key;
+use crate::api_keys::SCOPE_TRADE;
+use crate::redact::Redacted;
+use crate::lightning::create_invoice;
+use crate::mongo::AppState;
+
+// Struct for deserializing the invoice request payload
+#[derive(Debug, Deserialize)]
+pub struct InvoicePayload {
+    api_key: Redacted<String>,
+    amount_sats: i64,
+}
+
+// Asynchronous handler function for issuing a Lightning invoice that deposits straight to the LND node
+pub async fn create_lightning_invoice(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<InvoicePayload>,
+) -> Result<impl IntoResponse, AppError> {
+    if payload.amount_sats <= 0 {
+        return Err(AppError::BadRequest("amount_sats must be positive".to_string()));
+    }
+
+    let user = get_user_by_api_key(&state.db, &payload.api_key, SCOPE_TRADE)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    let invoice = create_invoice(&user, payload.amount_sats).await?;
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({
+            "payment_request": invoice.payment_request,
+            "payment_hash": invoice.payment_hash,
+            "amount_sats": invoice.amount_sats,
+            "expires_at": invoice.expires_at.try_to_rfc3339_string().unwrap_or_default(),
+        })),
+    ))
+}
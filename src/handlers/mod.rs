@@ -1,3 +1,19 @@
 // handlers/mod.rs
 pub mod register;
-pub mod decrypt;
\ No newline at end of file
+pub mod decrypt;
+pub mod admin;
+pub mod preferences;
+pub mod dca;
+pub mod lightning;
+pub mod tokens;
+pub mod holdings;
+pub mod transactions;
+pub mod export;
+pub mod validate;
+pub mod bitcoin;
+pub mod backup;
+pub mod sign;
+pub mod sweep;
+pub mod sessions;
+pub mod api_keys;
+pub mod two_factor;
\ No newline at end of file
@@ -0,0 +1,251 @@
+// preferences.rs
+// Import necessary modules and libraries
+use axum::{extract::{Json, State}, http::StatusCode, response::IntoResponse};
+use mongodb::bson::{doc, Document};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::allocation;
+use crate::config::config;
+use crate::error_handling::AppError;
+use crate::handlers::decrypt::get_user_by_api_key;
+use crate::api_keys::{SCOPE_TRADE, SCOPE_WITHDRAW};
+use crate::redact::Redacted;
+use crate::token_metadata::token_info;
+use crate::autobuy::MIN_AUTOBUY_AMOUNT;
+use crate::lockin::MAX_SLIPPAGE_BPS;
+use crate::mongo::{AllocationLeg, AppState, User};
+use crate::poller::release_held_transactions;
+use crate::validation::solana_address;
+
+// Struct for deserializing the slippage update payload
+#[derive(Debug, Deserialize)]
+pub struct SlippagePayload {
+    api_key: Redacted<String>,
+    // None resets the user to the default slippage
+    slippage_bps: Option<u16>,
+}
+
+// Struct for deserializing the autobuy threshold payload
+#[derive(Debug, Deserialize)]
+pub struct AutobuyPayload {
+    api_key: Redacted<String>,
+    autobuy_amount: f64,
+}
+
+// Struct for deserializing the allocation update payload
+#[derive(Debug, Deserialize)]
+pub struct AllocationPayload {
+    api_key: Redacted<String>,
+    allocation: Vec<AllocationLeg>,
+}
+
+// Struct for deserializing the staking preference payload
+#[derive(Debug, Deserialize)]
+pub struct StakePayload {
+    api_key: Redacted<String>,
+    enabled: bool,
+}
+
+// Struct for deserializing the Solana address replacement payload
+#[derive(Debug, Deserialize)]
+pub struct SolanaAddressPayload {
+    api_key: Redacted<String>,
+    address: String,
+}
+
+// Struct for deserializing a payload that only identifies the user
+#[derive(Debug, Deserialize)]
+pub struct ApiKeyPayload {
+    api_key: Redacted<String>,
+}
+
+// Asynchronous handler function for setting the BTC amount deposits accumulate to before buying
+pub async fn set_autobuy(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<AutobuyPayload>,
+) -> Result<impl IntoResponse, AppError> {
+    if !payload.autobuy_amount.is_finite() || payload.autobuy_amount < MIN_AUTOBUY_AMOUNT {
+        return Err(AppError::BadRequest(format!(
+            "autobuy_amount must be at least {} BTC",
+            MIN_AUTOBUY_AMOUNT
+        )));
+    }
+
+    let user = get_user_by_api_key(&state.db, &payload.api_key, SCOPE_TRADE)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    let collection = state.db.collection::<User>("users");
+    collection
+        .update_one(
+            doc! { "_id": user.id },
+            doc! { "$set": { "autobuy_amount": payload.autobuy_amount } },
+            None,
+        )
+        .await?;
+
+    Ok((StatusCode::OK, Json(json!({ "autobuy_amount": payload.autobuy_amount }))))
+}
+
+// Asynchronous handler function for clearing the autobuy threshold, pending funds are bought on the next run
+pub async fn clear_autobuy(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<ApiKeyPayload>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = get_user_by_api_key(&state.db, &payload.api_key, SCOPE_TRADE)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    let collection = state.db.collection::<User>("users");
+    collection
+        .update_one(
+            doc! { "_id": user.id },
+            doc! { "$set": { "autobuy_amount": null } },
+            None,
+        )
+        .await?;
+
+    Ok((StatusCode::OK, Json(json!({ "autobuy_amount": null, "pending_autobuy": user.pending_autobuy }))))
+}
+
+// Asynchronous handler function for updating a user's slippage tolerance
+pub async fn update_slippage(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<SlippagePayload>,
+) -> Result<impl IntoResponse, AppError> {
+    if let Some(slippage_bps) = payload.slippage_bps {
+        if slippage_bps == 0 || slippage_bps > MAX_SLIPPAGE_BPS {
+            return Err(AppError::BadRequest(format!(
+                "slippage_bps must be between 1 and {}",
+                MAX_SLIPPAGE_BPS
+            )));
+        }
+    }
+
+    let user = get_user_by_api_key(&state.db, &payload.api_key, SCOPE_TRADE)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    let collection = state.db.collection::<User>("users");
+    collection
+        .update_one(
+            doc! { "_id": user.id },
+            doc! { "$set": { "slippage_bps": payload.slippage_bps.map(|bps| bps as i32) } },
+            None,
+        )
+        .await?;
+
+    Ok((StatusCode::OK, Json(json!({ "slippage_bps": payload.slippage_bps }))))
+}
+
+// Asynchronous handler function for setting how the user's SOL is split across output mints
+pub async fn set_allocation(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<AllocationPayload>,
+) -> Result<impl IntoResponse, AppError> {
+    let allocation = allocation::validate(payload.allocation)?;
+    let user = get_user_by_api_key(&state.db, &payload.api_key, SCOPE_TRADE)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    let stored: Vec<Document> = allocation
+        .iter()
+        .map(|leg| doc! { "mint": &leg.mint, "bps": leg.bps as i32 })
+        .collect();
+    let collection = state.db.collection::<User>("users");
+    collection
+        .update_one(doc! { "_id": user.id }, doc! { "$set": { "allocation": stored } }, None)
+        .await?;
+
+    let mints: Vec<String> = allocation.iter().map(|leg| leg.mint.clone()).collect();
+    let tokens = match token_info(&mints).await {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            eprintln!("Failed to look up token metadata of the allocation: {:?}", e);
+            Default::default()
+        }
+    };
+    let allocation: Vec<_> = allocation
+        .iter()
+        .map(|leg| json!({ "mint": leg.mint, "bps": leg.bps, "token": tokens.get(&leg.mint) }))
+        .collect();
+
+    Ok((StatusCode::OK, Json(json!({ "allocation": allocation }))))
+}
+
+// Asynchronous handler function for resetting the user's allocation to all LOCKIN
+pub async fn clear_allocation(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<ApiKeyPayload>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = get_user_by_api_key(&state.db, &payload.api_key, SCOPE_TRADE)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    let collection = state.db.collection::<User>("users");
+    collection
+        .update_one(doc! { "_id": user.id }, doc! { "$set": { "allocation": [] } }, None)
+        .await?;
+
+    Ok((StatusCode::OK, Json(json!({ "allocation": [] }))))
+}
+
+// Asynchronous handler function for choosing whether the SOL share of the user's allocation is staked
+pub async fn set_stake(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<StakePayload>,
+) -> Result<impl IntoResponse, AppError> {
+    if payload.enabled && config().stake_pool_address.is_none() {
+        return Err(AppError::BadRequest("Staking is not available".to_string()));
+    }
+    let user = get_user_by_api_key(&state.db, &payload.api_key, SCOPE_TRADE)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    let collection = state.db.collection::<User>("users");
+    collection
+        .update_one(doc! { "_id": user.id }, doc! { "$set": { "stake_sol": payload.enabled } }, None)
+        .await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(json!({ "stake_sol": payload.enabled, "stake_pool": config().stake_pool_address })),
+    ))
+}
+
+// Asynchronous handler function for replacing a Solana address that can't receive funds. Only invalid
+// addresses can be replaced, once it is set the deposits held for it are released.
+pub async fn set_solana_address(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<SolanaAddressPayload>,
+) -> Result<impl IntoResponse, AppError> {
+    let address = solana_address(&payload.address)?;
+    let user = get_user_by_api_key(&state.db, &payload.api_key, SCOPE_WITHDRAW)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    if solana_address(user.solana_public_key.as_deref().unwrap_or_default()).is_ok() {
+        return Err(AppError::BadRequest("The current Solana address is valid and can't be replaced".to_string()));
+    }
+
+    let users_collection = state.db.collection::<User>("users");
+    users_collection
+        .update_one(
+            doc! { "_id": user.id },
+            doc! { "$set": { "solana_public_key": address.to_string(), "address_notice": null } },
+            None,
+        )
+        .await?;
+
+    let transactions_collection = state.db.collection::<Document>("transactions");
+    let released = release_held_transactions(&users_collection, &transactions_collection, &user, address).await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(json!({
+            "solana_public_key": address.to_string(),
+            "released": released.iter().map(|tx_id| tx_id.to_hex()).collect::<Vec<_>>(),
+        })),
+    ))
+}
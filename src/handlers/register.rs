@@ -1,42 +1,43 @@
 // register.rs
 // Import necessary modules and libraries
 use axum::{extract::Json, http::StatusCode, response::IntoResponse};
-use mongodb::bson::doc;
+use mongodb::bson::{doc, DateTime as BsonDateTime};
+use sha2::{Digest, Sha256};
+use solana_sdk::bs58;
 use serde::Deserialize;
 use serde_json::json;
 use tracing::error;
-use uuid::Uuid as UuidGenerator;
-use aes_gcm::{Aes256Gcm, Key, Nonce};
-use aes_gcm::aead::{Aead, KeyInit};
-use rand::RngCore;
 use hex;
-use typenum::U12;
+use zeroize::Zeroizing;
 
-use crate::mongo::{get_users_collection, User};
-use crate::wallets::solana::SolWalletResponse;
+use crate::config::config;
+use crate::encryption::{api_key_hash, decrypt_field, open_for_token, seal_for_token, SecretField, UserCipher};
+use crate::entropy;
+use crate::mongo::{get_secret_tokens_collection, get_users_collection, User};
+use crate::wallets::solana::{user_keypair, SolWalletResponse};
 use crate::wallets::bitcoin::WalletResponse;
 use crate::wallets::ethereum::EthereumWallet;
-use crate::wallets::{bitcoin::generate_bitcoin_wallet, ethereum::generate_keypair, solana::generate_solana_wallet};
+use crate::wallets::{bitcoin::generate_bitcoin_wallet, ethereum::{derive_keypair, DERIVATION_PATH as ETHEREUM_DERIVATION_PATH}, solana::generate_solana_wallet};
 use crate::error_handling::AppError;
 
 // Struct for deserializing the register request payload
 #[derive(Deserialize)]
 pub struct RegisterRequest {
     user_id: i64,
+    // Leave private keys out of the response, defaults to REDACT_REGISTER_SECRETS
+    #[serde(default)]
+    redact_secrets: Option<bool>,
 }
 
+// Struct for deserializing the secret retrieval payload
+#[derive(Deserialize)]
+pub struct RetrieveSecretsRequest {
+    token: String,
+}
 
-// Function to encrypt data using AES-256-GCM
-fn encrypt(data: &str, key: &Key<Aes256Gcm>, nonce: &Nonce<U12>) -> Result<String, AppError> {
-    let cipher = Aes256Gcm::new(key);
-    let mut ciphertext = cipher.encrypt(nonce, data.as_bytes())
-        .map_err(|_| AppError::InternalServerError)?;
+// How long the one-time token of a redacted registration can be redeemed
+const SECRETS_TOKEN_TTL_SECS: i64 = 15 * 60;
 
-    // Prepend the nonce to the ciphertext
-    let mut result = nonce.to_vec();
-    result.append(&mut ciphertext);
-    Ok(hex::encode(result))
-}
 
 // Asynchronous handler function for registering a user and generating wallets
 pub async fn register(Json(payload): Json<RegisterRequest>) -> impl IntoResponse {
@@ -82,63 +83,148 @@ pub async fn register(Json(payload): Json<RegisterRequest>) -> impl IntoResponse
         return AppError::InternalServerError.into_response();
     }
 
+    // A redacted response only carries public data and a one-time token for the secrets
+    if payload.redact_secrets.unwrap_or(config().redact_register_secrets) {
+        let (token, expires_at) = match issue_secrets_token(payload.user_id, &api_key).await {
+            Ok(issued) => issued,
+            Err(err) => {
+                error!("Failed to issue secrets token: {}", err);
+                return AppError::InternalServerError.into_response();
+            }
+        };
+        let response = json!({
+            "api_key": api_key,
+            "solana_public_key": solana_wallet.public_key,
+            "solana_derivation_path": solana_wallet.derivation_path,
+            "bitcoin_public_key": bitcoin_wallet.public_key,
+            "bitcoin_address": bitcoin_wallet.address,
+            "ethereum_public_key": ethereum_wallet.public_key,
+            "ethereum_derivation_path": ethereum_wallet.derivation_path,
+            "secrets_token": token,
+            "secrets_token_expires_at": expires_at.try_to_rfc3339_string().unwrap_or_default(),
+        });
+        return (StatusCode::OK, Json(response)).into_response();
+    }
+
     // Create JSON response with generated API key and wallet information
     let response = json!({
         "api_key": api_key,
         "solana_public_key": solana_wallet.public_key,
         "solana_private_key": solana_wallet.private_key,
+        "solana_derivation_path": solana_wallet.derivation_path,
         "bitcoin_mnemonic": bitcoin_wallet.mnemonic,
         "bitcoin_public_key": bitcoin_wallet.public_key,
+        "bitcoin_address": bitcoin_wallet.address,
         "bitcoin_private_key": bitcoin_wallet.private_key,
         "ethereum_public_key": ethereum_wallet.public_key,
         "ethereum_private_key": ethereum_wallet.secret_key,
+        "ethereum_derivation_path": ethereum_wallet.derivation_path,
     });
 
     // Respond with 200 status code and JSON payload
     (StatusCode::OK, Json(response)).into_response()
 }
 
+// Function to store a one-time secrets token, only its SHA-256 hash is kept along with the API key
+// sealed under the token
+async fn issue_secrets_token(user_id: i64, api_key: &str) -> Result<(String, BsonDateTime), AppError> {
+    let token = hex::encode(entropy::random_bytes::<32>()?);
+    let expires_at = BsonDateTime::from_millis(BsonDateTime::now().timestamp_millis() + SECRETS_TOKEN_TTL_SECS * 1000);
+    get_secret_tokens_collection()
+        .await?
+        .insert_one(
+            doc! {
+                "token_hash": hex::encode(Sha256::digest(token.as_bytes())),
+                "user_id": user_id,
+                "secrets_key": seal_for_token(&token, api_key)?,
+                "expires_at": expires_at,
+            },
+            None,
+        )
+        .await?;
+    Ok((token, expires_at))
+}
+
+// Asynchronous handler function for redeeming the one-time token of a redacted registration for the
+// private keys and mnemonic. The token is deleted as it is read, so it works exactly once.
+pub async fn retrieve_secrets(Json(payload): Json<RetrieveSecretsRequest>) -> Result<impl IntoResponse, AppError> {
+    let filter = doc! {
+        "token_hash": hex::encode(Sha256::digest(payload.token.as_bytes())),
+        "expires_at": { "$gt": BsonDateTime::now() },
+    };
+    let token = get_secret_tokens_collection()
+        .await?
+        .find_one_and_delete(filter, None)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    let user_id = token
+        .get_i64("user_id")
+        .map_err(|_| AppError::CustomError("Secrets token has no user_id".to_string()))?;
+    let user = get_users_collection()
+        .await?
+        .find_one(doc! { "user_id": user_id }, None)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    let api_key = token
+        .get_str("secrets_key")
+        .map_err(|_| AppError::DecryptionError)
+        .and_then(|sealed| open_for_token(&payload.token, sealed))?;
+
+    let solana_keypair = user_keypair(&user, Some(api_key.as_str())).await?;
+    let solana_private_key = Zeroizing::new(bs58::encode(Zeroizing::new(solana_keypair.to_bytes()).as_slice()).into_string());
+    let bitcoin_mnemonic = decrypt_field(&user, SecretField::BitcoinMnemonic, &api_key).await?;
+    let bitcoin_private_key = decrypt_field(&user, SecretField::BitcoinPrivateKey, &api_key).await?;
+    let ethereum_private_key = decrypt_field(&user, SecretField::EthereumPrivateKey, &api_key).await?;
+    Ok((
+        StatusCode::OK,
+        Json(json!({
+            "solana_private_key": solana_private_key.as_str(),
+            "bitcoin_mnemonic": bitcoin_mnemonic.as_str(),
+            "bitcoin_private_key": bitcoin_private_key.as_str(),
+            "ethereum_private_key": ethereum_private_key.as_str(),
+        })),
+    ))
+}
+
 // Function to check if a user already has wallets
 fn user_has_wallets(user: &User) -> bool {
-    user.solana_public_key.is_some() && user.solana_private_key.is_some() &&
-    !user.solana_public_key.as_ref().unwrap().is_empty() && !user.solana_private_key.as_ref().unwrap().is_empty()
+    user.solana_public_key.as_deref().is_some_and(|public_key| !public_key.is_empty())
 }
 
 // Asynchronous function to generate and save wallets for a user
 async fn generate_and_save_wallets(user: &mut User) -> Result<(SolWalletResponse, WalletResponse, EthereumWallet, String), AppError> {
-    // Generate a new API key
-    let api_key = UuidGenerator::new_v4().to_string();
-    user.api_key = Some(api_key.clone());
-
-    // Ensure the key is 32 bytes for AES-256
-    let key = Key::<Aes256Gcm>::from_slice(&api_key.as_bytes()[..32]);
+    // Generate a new API key, only its hash is stored
+    let api_key = entropy::uuid()?.to_string();
+    user.api_key = None;
+    user.api_key_hash = Some(api_key_hash(&api_key));
 
-    // Generate a random nonce of exactly 12 bytes
-    let mut nonce_bytes = [0u8; 12];
-    rand::thread_rng().fill_bytes(&mut nonce_bytes);
-    let nonce = Nonce::from_slice(&nonce_bytes);
+    // Stretch the API key with a fresh per-user salt, every secret below is sealed with it
+    let cipher = UserCipher::new(&api_key).await?;
+    user.data_key = cipher.new_data_key().map(str::to_string);
 
-    // Generate Solana wallet and encrypt the private key
-    let solana_wallet = generate_solana_wallet().await?;
+    // Derive the Solana wallet from the master mnemonic, only its index is stored
+    let (solana_index, solana_wallet) = generate_solana_wallet().await?;
     user.solana_public_key = Some(solana_wallet.public_key.clone());
-    user.solana_private_key = Some(encrypt(&solana_wallet.private_key, key, nonce)?);
+    user.solana_private_key = None;
+    user.solana_derivation_index = Some(solana_index as i64);
 
     // Generate Bitcoin wallet and encrypt the mnemonic and private key
-    let bitcoin_wallet = generate_bitcoin_wallet().await?;
-    user.bitcoin_mnemonic = Some(encrypt(&bitcoin_wallet.mnemonic, key, nonce)?);
+    let bitcoin_wallet = generate_bitcoin_wallet(config().bitcoin_network).await?;
+    user.bitcoin_mnemonic = Some(cipher.encrypt(SecretField::BitcoinMnemonic, &bitcoin_wallet.mnemonic)?);
     user.bitcoin_public_key = Some(bitcoin_wallet.public_key.clone());
-    user.bitcoin_private_key = Some(encrypt(&bitcoin_wallet.private_key, key, nonce)?);
+    user.bitcoin_private_key = Some(cipher.encrypt(SecretField::BitcoinPrivateKey, &bitcoin_wallet.private_key)?);
 
-    // Generate Ethereum wallet and encrypt the private key
-    let (secret_key, pub_key, pub_address) = generate_keypair();
-    let secret_key_str = hex::encode(secret_key.secret_bytes());
+    // Derive the Ethereum wallet from the Bitcoin mnemonic and encrypt the private key
+    let (secret_key, pub_key, pub_address) = derive_keypair(&bitcoin_wallet.mnemonic)?;
+    let secret_key_str = Zeroizing::new(hex::encode(Zeroizing::new(secret_key.secret_bytes()).as_slice()));
 
     user.ethereum_public_key = Some(pub_key.to_string());
-    user.ethereum_private_key = Some(encrypt(&secret_key_str, key, nonce)?);
+    user.ethereum_private_key = Some(cipher.encrypt(SecretField::EthereumPrivateKey, &secret_key_str)?);
     // Return generated wallets and API key
     Ok((solana_wallet, bitcoin_wallet, EthereumWallet {
         public_key: pub_key,
-        secret_key: secret_key,
+        secret_key,
         public_address: pub_address.to_string(),
+        derivation_path: ETHEREUM_DERIVATION_PATH.to_string(),
     }, api_key))
 }
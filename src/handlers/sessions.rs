@@ -0,0 +1,68 @@
+// sessions.rs
+// Import necessary modules and libraries
+use axum::{extract::{Json, State}, http::{HeaderMap, StatusCode}, response::IntoResponse};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::config::config;
+use crate::api_keys;
+use crate::error_handling::AppError;
+use crate::mongo::AppState;
+use crate::sessions;
+
+// Struct for deserializing the session request
+#[derive(Debug, Deserialize)]
+pub struct CreateSessionPayload {
+    scopes: Vec<String>,
+    // Lifetime in seconds, capped at SESSION_TTL_SECS
+    ttl_secs: Option<u64>,
+}
+
+// Function to read the caller's API key from the x-api-key header
+pub(crate) fn api_key_from_headers(headers: &HeaderMap) -> Result<String, AppError> {
+    headers
+        .get("x-api-key")
+        .and_then(|value| value.to_str().ok())
+        .filter(|api_key| !api_key.is_empty())
+        .map(|api_key| api_key.to_string())
+        .ok_or(AppError::Unauthorized)
+}
+
+// Asynchronous handler function for exchanging the API key in the x-api-key header for a short-lived
+// session token limited to the requested scopes
+pub async fn create_session(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<CreateSessionPayload>,
+) -> Result<impl IntoResponse, AppError> {
+    let api_key = api_key_from_headers(&headers)?;
+    // An unknown API key is reported the same way as a missing one
+    let auth = api_keys::authenticate(&state.db, &api_key)
+        .await?
+        .ok_or(AppError::Unauthorized)?;
+
+    let max_ttl = config().session_ttl_secs;
+    let ttl_secs = payload.ttl_secs.unwrap_or(max_ttl).clamp(1, max_ttl);
+    let (token, expires_at) = sessions::create_session(&state.db, &auth, &payload.scopes, ttl_secs).await?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({
+            "token": token,
+            "scopes": payload.scopes,
+            "expires_at": expires_at.try_to_rfc3339_string().unwrap_or_default(),
+        })),
+    ))
+}
+
+// Asynchronous handler function for ending the session in the Authorization header early
+pub async fn revoke_session(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    if !sessions::revoke_session(&state.db, &headers).await? {
+        return Err(AppError::NotFound);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
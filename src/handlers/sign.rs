@@ -0,0 +1,99 @@
+// sign.rs
+// Import necessary modules and libraries
+use axum::{extract::{Json, State}, http::{HeaderMap, StatusCode}, response::IntoResponse};
+use serde::Deserialize;
+use serde_json::json;
+use solana_sdk::signer::Signer;
+use std::sync::Arc;
+
+use crate::config::config;
+use crate::error_handling::AppError;
+use crate::encryption::{decrypt_field, SecretField};
+use crate::mongo::AppState;
+use crate::sessions::{self, Session, SCOPE_SIGN_BITCOIN, SCOPE_SIGN_ETHEREUM, SCOPE_SIGN_SOLANA};
+use crate::totp;
+use crate::wallets::bitcoin;
+use crate::wallets::ethereum::{address_from_public_key, sign_personal_message};
+use crate::wallets::solana::user_keypair;
+
+// Messages are proofs of ownership, not transactions, so they are kept short
+const MAX_MESSAGE_LENGTH: usize = 1024;
+
+// Struct for deserializing the message signing payload
+#[derive(Debug, Deserialize)]
+pub struct SignMessagePayload {
+    chain: String,
+    message: String,
+    // Bitcoin receive address index to sign with, 0 is the registration address
+    #[serde(default)]
+    index: u32,
+}
+
+// Asynchronous handler function for signing a message with one of the user's keys, so they can prove
+// they own a wallet without exporting its key. Solana messages are signed with ed25519, Ethereum ones
+// with personal_sign (EIP-191) and Bitcoin ones in the BIP-137 format. Needs a session with the
+// chain's sign scope.
+pub async fn sign_message(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<SignMessagePayload>,
+) -> Result<impl IntoResponse, AppError> {
+    if payload.message.is_empty() || payload.message.len() > MAX_MESSAGE_LENGTH {
+        return Err(AppError::BadRequest(format!(
+            "message must be between 1 and {} bytes",
+            MAX_MESSAGE_LENGTH
+        )));
+    }
+    let chain = payload.chain.to_lowercase();
+    let scope = match chain.as_str() {
+        "solana" | "sol" => SCOPE_SIGN_SOLANA,
+        "ethereum" | "eth" => SCOPE_SIGN_ETHEREUM,
+        "bitcoin" | "btc" => SCOPE_SIGN_BITCOIN,
+        other => {
+            return Err(AppError::BadRequest(format!(
+                "Unsupported chain {}, expected solana, bitcoin or ethereum",
+                other
+            )))
+        }
+    };
+    let Session { user, secrets_key } = sessions::authorize(&state.db, &headers, scope).await?;
+    totp::require_code(&state.db, &user, &secrets_key, &headers).await?;
+
+    let (address, signature) = match chain.as_str() {
+        "solana" | "sol" => {
+            let keypair = user_keypair(&user, Some(secrets_key.as_str())).await?;
+            let signature = keypair.sign_message(payload.message.as_bytes());
+            (keypair.pubkey().to_string(), signature.to_string())
+        }
+        "ethereum" | "eth" => {
+            if SecretField::EthereumPrivateKey.value(&user).is_none() {
+                return Err(AppError::BadRequest("User has no Ethereum wallet".to_string()));
+            }
+            let secret_key = decrypt_field(&user, SecretField::EthereumPrivateKey, &secrets_key).await?;
+            let address = user
+                .ethereum_public_key
+                .as_deref()
+                .and_then(address_from_public_key)
+                .ok_or(AppError::DecryptionError)?;
+            (address, sign_personal_message(&secret_key, payload.message.as_bytes())?)
+        }
+        "bitcoin" | "btc" => {
+            if SecretField::BitcoinPrivateKey.value(&user).is_none() {
+                return Err(AppError::BadRequest("User has no Bitcoin wallet".to_string()));
+            }
+            let xprv = decrypt_field(&user, SecretField::BitcoinPrivateKey, &secrets_key).await?;
+            bitcoin::sign_message(&xprv, config().bitcoin_network, payload.index, &payload.message)?
+        }
+        _ => unreachable!("chain was matched against its scope above"),
+    };
+
+    Ok((
+        StatusCode::OK,
+        Json(json!({
+            "chain": payload.chain,
+            "address": address,
+            "message": payload.message,
+            "signature": signature,
+        })),
+    ))
+}
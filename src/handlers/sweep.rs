@@ -0,0 +1,48 @@
+// sweep.rs
+// Import necessary modules and libraries
+use axum::{extract::{Json, State}, http::{HeaderMap, StatusCode}, response::IntoResponse};
+use serde::Deserialize;
+use solana_sdk::signer::Signer;
+use std::sync::Arc;
+
+use crate::error_handling::AppError;
+use crate::lockin::LockinClient;
+use crate::mongo::AppState;
+use crate::sessions::{self, Session, SCOPE_SWEEP};
+use crate::totp;
+use crate::validation::solana_address;
+use crate::wallets::solana::user_keypair;
+
+// Struct for deserializing the sweep payload
+#[derive(Debug, Deserialize)]
+pub struct SweepPayload {
+    destination: String,
+}
+
+// Asynchronous handler function for moving everything in the user's generated Solana wallet, SOL and
+// SPL tokens, to a destination address of their choosing. Needs a session with the sweep scope.
+pub async fn sweep_wallet(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<SweepPayload>,
+) -> Result<impl IntoResponse, AppError> {
+    let destination = solana_address(&payload.destination)?;
+    let Session { user, secrets_key } = sessions::authorize(&state.db, &headers, SCOPE_SWEEP).await?;
+    totp::require_code(&state.db, &user, &secrets_key, &headers).await?;
+    let owner = user_keypair(&user, Some(secrets_key.as_str())).await?;
+    if owner.pubkey() == destination {
+        return Err(AppError::BadRequest("destination is the wallet being swept".to_string()));
+    }
+
+    let client = LockinClient::new().await?;
+    let sweep = client
+        .sweep_wallet(&owner, destination)
+        .await
+        .map_err(|e| AppError::CustomError(format!("Sweep of {} failed: {:#}", owner.pubkey(), e)))?;
+    println!(
+        "Swept {} token accounts and {} lamports from user {} to {}",
+        sweep.closed_accounts, sweep.lamports, user.user_id, destination
+    );
+
+    Ok((StatusCode::OK, Json(sweep)))
+}
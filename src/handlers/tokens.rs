@@ -0,0 +1,114 @@
+// tokens.rs
+// Import necessary modules and libraries
+use axum::{extract::{Json, State}, http::StatusCode, response::IntoResponse};
+use mongodb::bson::{doc, Document};
+use mongodb::options::FindOptions;
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::autobuy::run_purchase;
+use crate::error_handling::AppError;
+use crate::handlers::decrypt::get_user_by_api_key;
+use crate::api_keys::{SCOPE_READ_ONLY, SCOPE_TRADE};
+use crate::redact::Redacted;
+use crate::mongo::{AppState, TokenDeposit, User};
+use crate::wallets::ethereum::{address_from_public_key, find_token};
+
+// Struct for deserializing a payload that only identifies the user
+#[derive(Debug, Deserialize)]
+pub struct ApiKeyPayload {
+    api_key: Redacted<String>,
+}
+
+// Struct for deserializing the token conversion payload
+#[derive(Debug, Deserialize)]
+pub struct ConvertPayload {
+    api_key: Redacted<String>,
+    symbol: String,
+    amount: f64,
+}
+
+// Asynchronous handler function for getting a user's ERC-20 deposit address, balances and recent deposits
+pub async fn get_token_balances(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<ApiKeyPayload>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = get_user_by_api_key(&state.db, &payload.api_key, SCOPE_READ_ONLY)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    let options = FindOptions::builder()
+        .sort(doc! { "block_number": -1 })
+        .limit(100)
+        .build();
+    let mut cursor = state
+        .db
+        .collection::<TokenDeposit>("token_deposits")
+        .find(doc! { "user_id": user.user_id }, options)
+        .await?;
+    let mut deposits = Vec::new();
+    while cursor.advance().await? {
+        deposits.push(cursor.deserialize_current()?);
+    }
+
+    let address = user.ethereum_public_key.as_deref().and_then(address_from_public_key);
+    Ok((
+        StatusCode::OK,
+        Json(json!({ "address": address, "balances": user.token_balances, "deposits": deposits })),
+    ))
+}
+
+// Asynchronous handler function for converting part of a user's stablecoin balance into LOCKIN
+pub async fn convert_tokens(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<ConvertPayload>,
+) -> Result<impl IntoResponse, AppError> {
+    let token = find_token(&payload.symbol)
+        .ok_or_else(|| AppError::BadRequest(format!("Unsupported token {}", payload.symbol)))?;
+    if !payload.amount.is_finite() || payload.amount <= 0.0 {
+        return Err(AppError::BadRequest("amount must be positive".to_string()));
+    }
+
+    let user = get_user_by_api_key(&state.db, &payload.api_key, SCOPE_TRADE)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    // Take the amount out of the balance up front so two requests can't convert the same funds
+    let balance_field = format!("token_balances.{}", token.symbol);
+    let users_collection = state.db.collection::<User>("users");
+    let result = users_collection
+        .update_one(
+            doc! { "_id": user.id, balance_field.as_str(): { "$gte": payload.amount } },
+            doc! { "$inc": { balance_field.as_str(): -payload.amount } },
+            None,
+        )
+        .await?;
+    if result.modified_count == 0 {
+        return Err(AppError::BadRequest(format!("Insufficient {} balance", token.symbol)));
+    }
+
+    // The sale and swap wait on order fills, so they run in the background like any other deposit
+    let transactions_collection = state.db.collection::<Document>("transactions");
+    let amount = payload.amount;
+    tokio::spawn(async move {
+        let source = format!("ERC-20 {}", token.symbol);
+        if let Err(e) = run_purchase(
+            &users_collection,
+            &transactions_collection,
+            &user,
+            amount,
+            token.symbol,
+            &source,
+        )
+        .await
+        {
+            eprintln!("{} conversion failed for user {}: {:?}", token.symbol, user.user_id, e);
+        }
+    });
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(json!({ "symbol": token.symbol, "amount": payload.amount, "status": "converting" })),
+    ))
+}
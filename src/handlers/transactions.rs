@@ -0,0 +1,99 @@
+// transactions.rs
+// Import necessary modules and libraries
+use axum::{extract::{Json, Path, State}, http::StatusCode, response::IntoResponse};
+use mongodb::bson::{doc, oid::ObjectId, Document};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::error_handling::AppError;
+use crate::handlers::decrypt::get_user_by_api_key;
+use crate::api_keys::SCOPE_READ_ONLY;
+use crate::redact::Redacted;
+use crate::mongo::AppState;
+use crate::solana_txs;
+use crate::token_metadata::token_info;
+
+// Struct for deserializing a payload that only identifies the user
+#[derive(Debug, Deserialize)]
+pub struct ApiKeyPayload {
+    api_key: Redacted<String>,
+}
+
+// Asynchronous handler function for getting one of the user's transactions, including the Jupiter
+// route plan its swap took and the token info of the mints it delivered
+pub async fn get_user_transaction(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Json(payload): Json<ApiKeyPayload>,
+) -> Result<impl IntoResponse, AppError> {
+    let tx_id = ObjectId::parse_str(&id)
+        .map_err(|_| AppError::BadRequest("Invalid transaction id".to_string()))?;
+    let user = get_user_by_api_key(&state.db, &payload.api_key, SCOPE_READ_ONLY)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    // The bot stores user_id as either an int32 or an int64
+    let filter = doc! {
+        "_id": tx_id,
+        "$or": [ { "user_id": user.user_id }, { "user_id": user.user_id as i32 } ],
+    };
+    let tx = state
+        .db
+        .collection::<Document>("transactions")
+        .find_one(filter, None)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    // The output mints of the swap and of any other allocation legs
+    let mut mints: Vec<String> = tx
+        .get_document("allocation_legs")
+        .map(|legs| legs.keys().cloned().collect())
+        .unwrap_or_default();
+    if let Some(output_mint) =
+        tx.get_document("route_plan").ok().and_then(|route_plan| route_plan.get_str("output_mint").ok())
+    {
+        mints.push(output_mint.to_string());
+    }
+    let tokens = if mints.is_empty() {
+        Default::default()
+    } else {
+        match token_info(&mints).await {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                eprintln!("Failed to look up token metadata of transaction {}: {:?}", tx_id, e);
+                Default::default()
+            }
+        }
+    };
+
+    let solana_txs = match solana_txs::receipts(tx_id).await {
+        Ok(receipts) => receipts,
+        Err(e) => {
+            eprintln!("Failed to look up the Solana receipts of transaction {}: {:?}", tx_id, e);
+            Vec::new()
+        }
+    };
+
+    Ok((
+        StatusCode::OK,
+        Json(json!({
+            "id": tx_id.to_hex(),
+            "state": tx.get_str("state").ok(),
+            "source": tx.get_str("source").ok(),
+            "amount": tx.get("amount"),
+            "deposit_asset": tx.get_str("deposit_asset").unwrap_or("BTC"),
+            "timestamp": tx.get("timestamp"),
+            "state_history": tx.get("state_history"),
+            "attention_reason": tx.get_str("attention_reason").ok(),
+            "failed_request_id": tx.get_str("failed_request_id").ok(),
+            "fees": tx.get("fees"),
+            "lockin_signature": tx.get_str("lockin_signature").ok(),
+            "lockin_explorer_url": tx.get_str("lockin_signature").ok().map(solana_txs::explorer_url),
+            "route_plan": tx.get("route_plan"),
+            "allocation_legs": tx.get("allocation_legs"),
+            "tokens": tokens,
+            "solana_txs": solana_txs,
+        })),
+    ))
+}
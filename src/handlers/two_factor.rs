@@ -0,0 +1,79 @@
+// two_factor.rs
+// Import necessary modules and libraries
+use axum::{extract::{Json, State}, http::{HeaderMap, StatusCode}, response::IntoResponse};
+use mongodb::bson::doc;
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::api_keys::{self, SCOPE_DECRYPT};
+use crate::error_handling::AppError;
+use crate::handlers::sessions::api_key_from_headers;
+use crate::mongo::{AppState, User};
+use crate::totp;
+
+// Struct for deserializing the code confirming an enrollment
+#[derive(Debug, Deserialize)]
+pub struct VerifyTotpPayload {
+    code: String,
+}
+
+// Asynchronous handler function for starting TOTP enrollment. Returns a new secret and its otpauth://
+// URI for an authenticator app, which only takes effect once a code from it is verified. Needs a key
+// with the decrypt scope.
+pub async fn enroll_totp(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    let api_key = api_key_from_headers(&headers)?;
+    let auth = api_keys::authenticate(&state.db, &api_key)
+        .await?
+        .ok_or(AppError::Unauthorized)?;
+    auth.require(SCOPE_DECRYPT)?;
+    if auth.user.totp_enabled {
+        return Err(AppError::BadRequest("Two-factor authentication is already enabled".to_string()));
+    }
+
+    let (sealed, secret, uri) = totp::new_secret(&auth.user, &auth.secrets_key)?;
+    state
+        .db
+        .collection::<User>("users")
+        .update_one(
+            doc! { "_id": auth.user.id, "totp_enabled": { "$ne": true } },
+            doc! { "$set": { "totp_secret": sealed }, "$unset": { "totp_last_step": "" } },
+            None,
+        )
+        .await?;
+    Ok((StatusCode::OK, Json(json!({ "secret": secret, "otpauth_uri": uri }))))
+}
+
+// Asynchronous handler function for confirming TOTP enrollment with a code from the authenticator app.
+// From then on the code is required in the x-totp-code header of /decrypt_keys, /sweep and /rotate_key.
+pub async fn verify_totp(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<VerifyTotpPayload>,
+) -> Result<impl IntoResponse, AppError> {
+    let api_key = api_key_from_headers(&headers)?;
+    let auth = api_keys::authenticate(&state.db, &api_key)
+        .await?
+        .ok_or(AppError::Unauthorized)?;
+    auth.require(SCOPE_DECRYPT)?;
+    if auth.user.totp_enabled {
+        return Err(AppError::BadRequest("Two-factor authentication is already enabled".to_string()));
+    }
+    if !totp::check_code(&state.db, &auth.user, &auth.secrets_key, &payload.code).await? {
+        return Err(AppError::BadRequest("Invalid code".to_string()));
+    }
+
+    state
+        .db
+        .collection::<User>("users")
+        .update_one(
+            doc! { "_id": auth.user.id, "totp_secret": &auth.user.totp_secret },
+            doc! { "$set": { "totp_enabled": true } },
+            None,
+        )
+        .await?;
+    Ok((StatusCode::OK, Json(json!({ "enabled": true }))))
+}
@@ -0,0 +1,33 @@
+// validate.rs
+// Import necessary modules and libraries
+use axum::{extract::Json, http::StatusCode, response::IntoResponse};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::config::config;
+use crate::error_handling::AppError;
+use crate::validation::{bitcoin_address, ethereum_address, solana_address};
+
+// Struct for deserializing the address validation payload
+#[derive(Debug, Deserialize)]
+pub struct ValidateAddressPayload {
+    chain: String,
+    address: String,
+}
+
+// Asynchronous handler function for checking an address before it is used. Bitcoin addresses are
+// checked against the configured BITCOIN_NETWORK and Ethereum addresses are returned checksummed.
+pub async fn validate_address(Json(payload): Json<ValidateAddressPayload>) -> Result<impl IntoResponse, AppError> {
+    let result = match payload.chain.to_lowercase().as_str() {
+        "solana" | "sol" => solana_address(&payload.address).map(|pubkey| pubkey.to_string()),
+        "bitcoin" | "btc" => bitcoin_address(&payload.address, config().bitcoin_network).map(|address| address.to_string()),
+        "ethereum" | "eth" => ethereum_address(&payload.address),
+        other => return Err(AppError::BadRequest(format!("Unsupported chain {}, expected solana, bitcoin or ethereum", other))),
+    };
+
+    let response = match result {
+        Ok(address) => json!({ "chain": payload.chain, "valid": true, "address": address }),
+        Err(e) => json!({ "chain": payload.chain, "valid": false, "error": e.to_string() }),
+    };
+    Ok((StatusCode::OK, Json(response)))
+}
@@ -0,0 +1,117 @@
+// holdings.rs
+use crate::config::config;
+use crate::error_handling::AppError;
+use crate::lockin::{PooledRpc, RpcPool, SolanaRpc, TOKEN_2022_PROGRAM_ID};
+use crate::token_metadata::token_info;
+use reqwest::Client;
+use serde::Serialize;
+use serde_json::{json, Value};
+use solana_sdk::pubkey::Pubkey;
+use std::collections::HashMap;
+
+// The Jupiter price API caps the number of ids per call
+const BATCH_SIZE: usize = 100;
+
+// One SPL token position of a wallet
+#[derive(Debug, Clone, Serialize)]
+pub struct Holding {
+    pub mint: String,
+    pub symbol: Option<String>,
+    pub name: Option<String>,
+    pub amount: f64,
+    pub decimals: u8,
+    pub logo_uri: Option<String>,
+    pub price_usd: Option<f64>,
+    pub value_usd: Option<f64>,
+}
+
+// Lists the non-empty token accounts of `owner` with their token metadata and USD value
+pub async fn get_holdings(owner: &Pubkey) -> Result<Vec<Holding>, AppError> {
+    let rpc = PooledRpc::new(RpcPool::shared());
+
+    // Token accounts are grouped by mint, a wallet can hold the same token in several accounts
+    let mut balances: HashMap<String, (f64, u8)> = HashMap::new();
+    for program_id in [spl_token::id().to_string(), TOKEN_2022_PROGRAM_ID.to_string()] {
+        let response = rpc
+            .send_request(
+                "getTokenAccountsByOwner",
+                json!([owner.to_string(), { "programId": program_id }, { "encoding": "jsonParsed" }]),
+            )
+            .await?;
+        for account in response["result"]["value"].as_array().into_iter().flatten() {
+            let info = &account["account"]["data"]["parsed"]["info"];
+            let mint = match info["mint"].as_str() {
+                Some(mint) => mint.to_string(),
+                None => continue,
+            };
+            let amount = info["tokenAmount"]["uiAmountString"]
+                .as_str()
+                .and_then(|amount| amount.parse::<f64>().ok())
+                .unwrap_or(0.0);
+            let decimals = info["tokenAmount"]["decimals"].as_u64().unwrap_or(0) as u8;
+            let entry = balances.entry(mint).or_insert((0.0, decimals));
+            entry.0 += amount;
+        }
+    }
+    balances.retain(|_, (amount, _)| *amount > 0.0);
+
+    let mints: Vec<String> = balances.keys().cloned().collect();
+    let metadata = token_info(&mints).await?;
+    let prices = match get_prices(&mints).await {
+        Ok(prices) => prices,
+        Err(e) => {
+            eprintln!("Failed to fetch token prices: {:?}", e);
+            HashMap::new()
+        }
+    };
+
+    let mut holdings: Vec<Holding> = balances
+        .into_iter()
+        .map(|(mint, (amount, decimals))| {
+            let info = metadata.get(&mint).cloned().unwrap_or_default();
+            let price_usd = prices.get(&mint).copied();
+            Holding {
+                symbol: info.symbol,
+                name: info.name,
+                logo_uri: info.logo_uri,
+                amount,
+                decimals,
+                price_usd,
+                value_usd: price_usd.map(|price| price * amount),
+                mint,
+            }
+        })
+        .collect();
+    holdings.sort_by(|a, b| {
+        b.value_usd
+            .unwrap_or(0.0)
+            .partial_cmp(&a.value_usd.unwrap_or(0.0))
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    Ok(holdings)
+}
+
+// Looks up USD prices for the mints on the Jupiter price API
+pub(crate) async fn get_prices(mints: &[String]) -> Result<HashMap<String, f64>, AppError> {
+    let client = Client::new();
+    let mut prices = HashMap::new();
+    for chunk in mints.chunks(BATCH_SIZE) {
+        let response: Value = client
+            .get(&config().jupiter_price_api_url)
+            .query(&[("ids", chunk.join(","))])
+            .send()
+            .await?
+            .json()
+            .await?;
+        for mint in chunk {
+            let price = response["data"][mint]["price"]
+                .as_str()
+                .and_then(|price| price.parse::<f64>().ok())
+                .or_else(|| response["data"][mint]["price"].as_f64());
+            if let Some(price) = price {
+                prices.insert(mint.clone(), price);
+            }
+        }
+    }
+    Ok(prices)
+}
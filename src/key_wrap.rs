@@ -0,0 +1,489 @@
+// key_wrap.rs
+// Wrapping of the per-user data keys stored secrets are encrypted with. The master key never leaves
+// its backend (a local key, HashiCorp Vault's transit engine, GCP KMS or AWS KMS), so a database dump
+// and the users' API keys aren't enough to recover their private keys without access to it as well.
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD as base64_engine;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use mongodb::bson::{doc, DateTime as BsonDateTime, Document};
+use reqwest::Client;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use zeroize::Zeroizing;
+
+use crate::config::{config, KeyWrapperKind};
+use crate::entropy;
+use crate::error_handling::AppError;
+use crate::mongo::get_settings_collection;
+
+const GCP_METADATA_TOKEN_URL: &str =
+    "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+
+// A master key that wraps and unwraps data keys
+#[async_trait]
+pub trait KeyWrapper: Send + Sync {
+    // Stored in front of every wrapped key, so a key is only ever handed to the backend that wrapped it
+    fn name(&self) -> &'static str;
+
+    async fn wrap(&self, data_key: &[u8]) -> Result<String, AppError>;
+
+    async fn unwrap(&self, wrapped: &str) -> Result<Vec<u8>, AppError>;
+}
+
+fn wrap_error(backend: &str, message: impl std::fmt::Display) -> AppError {
+    AppError::CustomError(format!("{} key wrapping failed: {}", backend, message))
+}
+
+// Fails with the backend's response body when the request wasn't successful
+async fn json_response(backend: &str, response: reqwest::Response) -> Result<Value, AppError> {
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(wrap_error(backend, format!("{} {}", status, body)));
+    }
+    Ok(response.json().await?)
+}
+
+fn decode_base64(backend: &str, value: &Value) -> Result<Vec<u8>, AppError> {
+    value
+        .as_str()
+        .and_then(|value| base64_engine.decode(value).ok())
+        .ok_or_else(|| wrap_error(backend, "response has no base64 key"))
+}
+
+// A master key from MASTER_KEY, for deployments without a KMS. It sits next to the database
+// credentials, so it only helps against leaked dumps.
+pub struct LocalMasterKey {
+    key: Key<Aes256Gcm>,
+}
+
+#[async_trait]
+impl KeyWrapper for LocalMasterKey {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    async fn wrap(&self, data_key: &[u8]) -> Result<String, AppError> {
+        let nonce = entropy::nonce()?;
+        let mut wrapped = nonce.to_vec();
+        wrapped.extend(
+            Aes256Gcm::new(&self.key)
+                .encrypt(Nonce::from_slice(&nonce), data_key)
+                .map_err(|_| AppError::InternalServerError)?,
+        );
+        Ok(hex::encode(wrapped))
+    }
+
+    async fn unwrap(&self, wrapped: &str) -> Result<Vec<u8>, AppError> {
+        let wrapped = hex::decode(wrapped)
+            .ok()
+            .filter(|wrapped| wrapped.len() > 12)
+            .ok_or(AppError::DecryptionError)?;
+        let (nonce, ciphertext) = wrapped.split_at(12);
+        Aes256Gcm::new(&self.key)
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| AppError::DecryptionError)
+    }
+}
+
+// HashiCorp Vault's transit secrets engine
+pub struct VaultTransit {
+    client: Client,
+    addr: String,
+    token: String,
+    key_name: String,
+}
+
+#[async_trait]
+impl KeyWrapper for VaultTransit {
+    fn name(&self) -> &'static str {
+        "vault"
+    }
+
+    async fn wrap(&self, data_key: &[u8]) -> Result<String, AppError> {
+        let response = self
+            .client
+            .post(format!("{}/v1/transit/encrypt/{}", self.addr.trim_end_matches('/'), self.key_name))
+            .header("X-Vault-Token", &self.token)
+            .json(&json!({ "plaintext": base64_engine.encode(data_key) }))
+            .send()
+            .await?;
+        let response = json_response("Vault", response).await?;
+        response["data"]["ciphertext"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| wrap_error("Vault", "response has no ciphertext"))
+    }
+
+    async fn unwrap(&self, wrapped: &str) -> Result<Vec<u8>, AppError> {
+        let response = self
+            .client
+            .post(format!("{}/v1/transit/decrypt/{}", self.addr.trim_end_matches('/'), self.key_name))
+            .header("X-Vault-Token", &self.token)
+            .json(&json!({ "ciphertext": wrapped }))
+            .send()
+            .await?;
+        let response = json_response("Vault", response).await?;
+        decode_base64("Vault", &response["data"]["plaintext"])
+    }
+}
+
+// Google Cloud KMS, authenticated as the instance's service account through the metadata server
+pub struct GcpKms {
+    client: Client,
+    key_name: String,
+    token: Mutex<Option<(String, Instant)>>,
+}
+
+impl GcpKms {
+    async fn access_token(&self) -> Result<String, AppError> {
+        if let Some((token, expires_at)) = self.token.lock().unwrap().as_ref() {
+            if Instant::now() < *expires_at {
+                return Ok(token.clone());
+            }
+        }
+        let response = self
+            .client
+            .get(GCP_METADATA_TOKEN_URL)
+            .header("Metadata-Flavor", "Google")
+            .send()
+            .await?;
+        let response = json_response("GCP KMS", response).await?;
+        let token = response["access_token"]
+            .as_str()
+            .ok_or_else(|| wrap_error("GCP KMS", "metadata server returned no access token"))?
+            .to_string();
+        // Refresh a minute before the token runs out
+        let expires_in = response["expires_in"].as_u64().unwrap_or(0).saturating_sub(60);
+        *self.token.lock().unwrap() = Some((token.clone(), Instant::now() + Duration::from_secs(expires_in)));
+        Ok(token)
+    }
+
+    async fn call(&self, method: &str, body: Value) -> Result<Value, AppError> {
+        let response = self
+            .client
+            .post(format!("https://cloudkms.googleapis.com/v1/{}:{}", self.key_name, method))
+            .bearer_auth(self.access_token().await?)
+            .json(&body)
+            .send()
+            .await?;
+        json_response("GCP KMS", response).await
+    }
+}
+
+#[async_trait]
+impl KeyWrapper for GcpKms {
+    fn name(&self) -> &'static str {
+        "gcp-kms"
+    }
+
+    async fn wrap(&self, data_key: &[u8]) -> Result<String, AppError> {
+        let response = self.call("encrypt", json!({ "plaintext": base64_engine.encode(data_key) })).await?;
+        response["ciphertext"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| wrap_error("GCP KMS", "response has no ciphertext"))
+    }
+
+    async fn unwrap(&self, wrapped: &str) -> Result<Vec<u8>, AppError> {
+        let response = self.call("decrypt", json!({ "ciphertext": wrapped })).await?;
+        decode_base64("GCP KMS", &response["plaintext"])
+    }
+}
+
+// AWS KMS through its JSON API, requests are signed with Signature Version 4
+pub struct AwsKms {
+    client: Client,
+    key_id: String,
+    region: String,
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+// The SigV4 key for one day, region and service
+fn signing_key(secret_access_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let date_key = hmac_sha256(format!("AWS4{}", secret_access_key).as_bytes(), date);
+    let region_key = hmac_sha256(&date_key, region);
+    let service_key = hmac_sha256(&region_key, service);
+    hmac_sha256(&service_key, "aws4_request")
+}
+
+impl AwsKms {
+    // The headers to send and the authorization header for a KMS call at `now`
+    fn sign(
+        &self,
+        host: &str,
+        target: &str,
+        payload: &str,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> (Vec<(&'static str, String)>, String) {
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+
+        // Canonical headers are lowercase and sorted by name
+        let mut headers = vec![
+            ("content-type", "application/x-amz-json-1.1".to_string()),
+            ("host", host.to_string()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        headers.push(("x-amz-target", target.to_string()));
+        let canonical_headers: String = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value)).collect();
+        let signed_headers = headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";");
+        let canonical_request = format!(
+            "POST\n/\n\n{}\n{}\n{}",
+            canonical_headers,
+            signed_headers,
+            hex::encode(Sha256::digest(payload.as_bytes()))
+        );
+        let scope = format!("{}/{}/kms/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let signing_key = signing_key(&self.secret_access_key, &date, &self.region, "kms");
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key_id,
+            scope,
+            signed_headers,
+            hex::encode(hmac_sha256(&signing_key, &string_to_sign))
+        );
+        (headers, authorization)
+    }
+
+    async fn call(&self, action: &str, body: Value) -> Result<Value, AppError> {
+        let host = format!("kms.{}.amazonaws.com", self.region);
+        let target = format!("TrentService.{}", action);
+        let payload = body.to_string();
+        let (headers, authorization) = self.sign(&host, &target, &payload, chrono::Utc::now());
+
+        let mut request = self.client.post(format!("https://{}/", host)).header("authorization", authorization);
+        for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+            request = request.header(*name, value);
+        }
+        let response = request.body(payload).send().await?;
+        json_response("AWS KMS", response).await
+    }
+}
+
+#[async_trait]
+impl KeyWrapper for AwsKms {
+    fn name(&self) -> &'static str {
+        "aws-kms"
+    }
+
+    async fn wrap(&self, data_key: &[u8]) -> Result<String, AppError> {
+        let response = self
+            .call("Encrypt", json!({ "KeyId": self.key_id, "Plaintext": base64_engine.encode(data_key) }))
+            .await?;
+        response["CiphertextBlob"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| wrap_error("AWS KMS", "response has no CiphertextBlob"))
+    }
+
+    async fn unwrap(&self, wrapped: &str) -> Result<Vec<u8>, AppError> {
+        let response = self
+            .call("Decrypt", json!({ "KeyId": self.key_id, "CiphertextBlob": wrapped }))
+            .await?;
+        decode_base64("AWS KMS", &response["Plaintext"])
+    }
+}
+
+// The configured key wrapper, None when KEY_WRAPPER is none
+pub fn key_wrapper() -> Option<Arc<dyn KeyWrapper>> {
+    static WRAPPER: OnceLock<Option<Arc<dyn KeyWrapper>>> = OnceLock::new();
+    WRAPPER
+        .get_or_init(|| {
+            let config = config();
+            let client = Client::new();
+            let wrapper: Arc<dyn KeyWrapper> = match config.key_wrapper {
+                KeyWrapperKind::None => return None,
+                // MASTER_KEY is checked to be 32 hex encoded bytes when the config is loaded
+                KeyWrapperKind::Local => Arc::new(LocalMasterKey {
+                    key: Key::<Aes256Gcm>::clone_from_slice(&hex::decode(&config.master_key).unwrap_or_default()),
+                }),
+                KeyWrapperKind::Vault => Arc::new(VaultTransit {
+                    client,
+                    addr: config.vault_addr.clone(),
+                    token: config.vault_token.clone(),
+                    key_name: config.vault_transit_key.clone(),
+                }),
+                KeyWrapperKind::GcpKms => Arc::new(GcpKms {
+                    client,
+                    key_name: config.gcp_kms_key_name.clone(),
+                    token: Mutex::new(None),
+                }),
+                KeyWrapperKind::AwsKms => Arc::new(AwsKms {
+                    client,
+                    key_id: config.aws_kms_key_id.clone(),
+                    region: config.aws_region.clone(),
+                    access_key_id: config.aws_access_key_id.clone(),
+                    secret_access_key: config.aws_secret_access_key.clone(),
+                    session_token: config.aws_session_token.clone(),
+                }),
+            };
+            Some(wrapper)
+        })
+        .clone()
+}
+
+// Creates a data key and wraps it, returning the key and its stored form "<backend>:<wrapped key>"
+pub async fn new_data_key(wrapper: &dyn KeyWrapper) -> Result<(Zeroizing<[u8; 32]>, String), AppError> {
+    let data_key = Zeroizing::new(entropy::random_bytes::<32>()?);
+    let wrapped = wrapper.wrap(data_key.as_slice()).await?;
+    Ok((data_key, format!("{}:{}", wrapper.name(), wrapped)))
+}
+
+// Unwraps a stored data key with the backend that wrapped it
+pub async fn unwrap_data_key(stored: &str) -> Result<Zeroizing<Vec<u8>>, AppError> {
+    let (backend, wrapped) = stored.split_once(':').ok_or(AppError::DecryptionError)?;
+    let wrapper = key_wrapper().ok_or_else(|| {
+        AppError::CustomError(format!("Data key is wrapped by {} but KEY_WRAPPER is none", backend))
+    })?;
+    if wrapper.name() != backend {
+        return Err(AppError::CustomError(format!(
+            "Data key is wrapped by {} but KEY_WRAPPER is {}",
+            backend,
+            wrapper.name()
+        )));
+    }
+    let data_key = Zeroizing::new(wrapper.unwrap(wrapped).await?);
+    if data_key.len() != 32 {
+        return Err(AppError::DecryptionError);
+    }
+    Ok(data_key)
+}
+
+// The service's own secrets kept in the settings collection: the base64 of a nonce and the AES-256-GCM
+// ciphertext, next to the data key they are sealed with, wrapped by KEY_WRAPPER. The document id is
+// the associated data, so a sealed value can't be moved to another setting. Returns None when the
+// setting isn't stored.
+pub async fn open_setting(id: &str) -> Result<Option<Zeroizing<Vec<u8>>>, AppError> {
+    let Some(stored) = get_settings_collection().await?.find_one(doc! { "_id": id }, None).await? else {
+        return Ok(None);
+    };
+    let data_key = unwrap_data_key(stored.get_str("data_key").map_err(|_| AppError::DecryptionError)?).await?;
+    let sealed = base64_engine
+        .decode(stored.get_str("ciphertext").map_err(|_| AppError::DecryptionError)?)
+        .map_err(|_| AppError::DecryptionError)?;
+    if sealed.len() < 12 {
+        return Err(AppError::DecryptionError);
+    }
+    let (nonce, ciphertext) = sealed.split_at(12);
+    let secret = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(data_key.as_slice()))
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: id.as_bytes() })
+        .map_err(|_| AppError::DecryptionError)?;
+    Ok(Some(Zeroizing::new(secret)))
+}
+
+// Seals `secret` under a new data key and stores it as setting `id` along with `fields`, failing when
+// the setting is already stored
+pub async fn seal_setting(id: &str, secret: &[u8], fields: Document) -> Result<(), AppError> {
+    let wrapper = key_wrapper()
+        .ok_or_else(|| AppError::CustomError(format!("Storing {} needs a KEY_WRAPPER", id)))?;
+    let (data_key, wrapped_data_key) = new_data_key(wrapper.as_ref()).await?;
+    let nonce = entropy::nonce()?;
+    let ciphertext = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(data_key.as_slice()))
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: secret, aad: id.as_bytes() })
+        .map_err(|_| AppError::InternalServerError)?;
+    let mut sealed = nonce.to_vec();
+    sealed.extend_from_slice(&ciphertext);
+    let mut setting = doc! {
+        "_id": id,
+        "data_key": wrapped_data_key,
+        "ciphertext": base64_engine.encode(&sealed),
+        "created_at": BsonDateTime::now(),
+    };
+    setting.extend(fields);
+    get_settings_collection().await?.insert_one(setting, None).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The example credentials from the AWS SigV4 documentation
+    const SECRET_ACCESS_KEY: &str = "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY";
+
+    fn kms(session_token: Option<&str>) -> AwsKms {
+        AwsKms {
+            client: Client::new(),
+            key_id: "alias/test".to_string(),
+            region: "us-east-1".to_string(),
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: SECRET_ACCESS_KEY.to_string(),
+            session_token: session_token.map(str::to_string),
+        }
+    }
+
+    fn signed_at(kms: &AwsKms) -> (Vec<(&'static str, String)>, String) {
+        let now = "2015-08-30T12:36:00Z".parse().unwrap();
+        kms.sign("kms.us-east-1.amazonaws.com", "TrentService.Encrypt", r#"{"KeyId":"alias/test"}"#, now)
+    }
+
+    #[test]
+    fn signing_key_matches_the_aws_example() {
+        // "Examples of how to derive a signing key for Signature Version 4" in the AWS docs
+        assert_eq!(
+            hex::encode(signing_key(SECRET_ACCESS_KEY, "20120215", "us-east-1", "iam")),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+
+    #[test]
+    fn requests_are_signed_over_the_sorted_headers() {
+        let (headers, authorization) = signed_at(&kms(None));
+        let names: Vec<_> = headers.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, ["content-type", "host", "x-amz-date", "x-amz-target"]);
+        assert!(headers.contains(&("x-amz-date", "20150830T123600Z".to_string())));
+        assert_eq!(
+            authorization,
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/kms/aws4_request, \
+             SignedHeaders=content-type;host;x-amz-date;x-amz-target, \
+             Signature=aeb70ac9c507950a88e04d5258d129b788086ab40738b03c7cd56eeee0b75af2"
+        );
+    }
+
+    #[test]
+    fn session_tokens_are_signed_in_order() {
+        let (headers, authorization) = signed_at(&kms(Some("session")));
+        let names: Vec<_> = headers.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, ["content-type", "host", "x-amz-date", "x-amz-security-token", "x-amz-target"]);
+        assert!(authorization.ends_with(
+            "SignedHeaders=content-type;host;x-amz-date;x-amz-security-token;x-amz-target, \
+             Signature=8e03f91da36c2bda31691604f2bd0b6ee3707934d0c41a9f5ce764320ef47460"
+        ));
+    }
+
+    #[tokio::test]
+    async fn local_master_key_round_trips_and_rejects_tampering() {
+        let wrapper = LocalMasterKey { key: Key::<Aes256Gcm>::clone_from_slice(&[7u8; 32]) };
+        let wrapped = wrapper.wrap(b"data key").await.unwrap();
+        assert_eq!(wrapper.unwrap(&wrapped).await.unwrap(), b"data key");
+        assert_ne!(wrapper.wrap(b"data key").await.unwrap(), wrapped, "nonces must not repeat");
+
+        let mut tampered = hex::decode(&wrapped).unwrap();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(matches!(wrapper.unwrap(&hex::encode(tampered)).await, Err(AppError::DecryptionError)));
+        assert!(matches!(wrapper.unwrap("00").await, Err(AppError::DecryptionError)));
+    }
+}
@@ -0,0 +1,84 @@
+// kraken_ws.rs
+use crate::config::{config, ExchangeKind};
+use crate::error_handling::AppError;
+use crate::exchange::kraken::get_websockets_token;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::sync::Notify;
+use tokio::time::{sleep, Duration};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+const KRAKEN_WS_AUTH_URL: &str = "wss://ws-auth.kraken.com/v2";
+const MAX_RECONNECT_BACKOFF_SECS: u64 = 60;
+
+// WebSocket streaming is opt-in, REST polling alone is used otherwise
+pub fn kraken_ws_enabled() -> bool {
+    config().kraken_ws_enabled && config().exchange == ExchangeKind::Kraken
+}
+
+// Keeps a subscription to Kraken's private balances feed open, notifying the poller on every deposit.
+// While disconnected the poller's REST interval keeps picking up deposits.
+pub async fn stream_deposit_events(deposit_events: Arc<Notify>) {
+    let mut backoff = 1;
+    loop {
+        match subscribe_balances(&deposit_events).await {
+            Ok(_) => {
+                eprintln!("Kraken WebSocket closed, falling back to REST polling until reconnected.");
+                backoff = 1;
+            }
+            Err(e) => {
+                eprintln!(
+                    "Kraken WebSocket error: {:?}, falling back to REST polling until reconnected.",
+                    e
+                );
+            }
+        }
+        sleep(Duration::from_secs(backoff)).await;
+        backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF_SECS);
+    }
+}
+
+// Subscribes to the balances channel and returns once the connection closes
+async fn subscribe_balances(deposit_events: &Notify) -> Result<(), AppError> {
+    let token = get_websockets_token().await?;
+    let (mut ws_stream, _) = connect_async(KRAKEN_WS_AUTH_URL).await?;
+
+    let subscribe = json!({
+        "method": "subscribe",
+        "params": {
+            "channel": "balances",
+            "token": token,
+            "snapshot": false,
+        }
+    });
+    ws_stream.send(Message::Text(subscribe.to_string())).await?;
+    println!("Subscribed to Kraken balances feed.");
+
+    while let Some(message) = ws_stream.next().await {
+        match message? {
+            Message::Text(text) => {
+                let value: Value = serde_json::from_str(&text)?;
+                if is_deposit_event(&value) {
+                    println!("Kraken deposit event received: {}", value);
+                    deposit_events.notify_one();
+                }
+            }
+            Message::Close(_) => break,
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+// Checks whether a balances update contains a deposit ledger entry
+fn is_deposit_event(value: &Value) -> bool {
+    if value["channel"] != "balances" || value["type"] != "update" {
+        return false;
+    }
+    value["data"]
+        .as_array()
+        .map(|entries| entries.iter().any(|entry| entry["type"] == "deposit"))
+        .unwrap_or(false)
+}
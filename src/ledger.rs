@@ -0,0 +1,187 @@
+// ledger.rs
+// Double-entry ledger of the funds moving through the pipeline. Every step that moves funds posts a
+// journal of entries, debits positive and credits negative, that sum to zero for each asset.
+// `user:<id>` accounts hold what the service owes each user and go negative while it holds their
+// funds. The system accounts hold where those funds are: on the exchange (`kraken:btc`, `kraken:usd`,
+// `kraken:sol`), in the bot hot wallet (`bot_wallet`), in the users' deposit wallets
+// (`user_wallet:<id>`), swept from the hot wallet to cold storage (`cold_wallet`), or spent on network
+// fees the service pays itself (`fees`). `platform_fees` holds the platform fees charged to users and
+// goes negative while they are kept in the bot wallet rather than sent to the treasury. As every
+// journal balances, all balances of an asset sum to zero at any time. A journal is keyed by its
+// transaction and step, so a step that runs again is only recorded once.
+use crate::config::{config, ExchangeKind};
+use crate::dry_run;
+use crate::error_handling::AppError;
+use crate::exchange::to_stored;
+use crate::mongo::{get_ledger_collection, AtomicWrites};
+use mongodb::bson::{doc, oid::ObjectId, DateTime as BsonDateTime, Document};
+use rust_decimal::Decimal;
+use std::collections::BTreeMap;
+
+// Balances of an asset whose absolute sum is under this are float rounding
+const BALANCE_TOLERANCE: f64 = 1e-9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Account {
+    // What the service owes the user
+    User(i64),
+    // Held on the exchange
+    Exchange,
+    BotWallet,
+    // The user's deposit wallets
+    UserWallet(i64),
+    // The cold storage address the bot wallet is swept to
+    ColdWallet,
+    // Network fees the service paid
+    Fees,
+    // Platform fees charged to users and not yet sent to the treasury
+    PlatformFees,
+}
+
+impl Account {
+    // The account's name for `asset`, exchange accounts are kept per asset
+    pub fn name(&self, asset: &str) -> String {
+        match self {
+            Account::User(user_id) => format!("user:{}", user_id),
+            Account::Exchange => {
+                let exchange = match config().exchange {
+                    ExchangeKind::Kraken => "kraken",
+                    ExchangeKind::Coinbase => "coinbase",
+                };
+                format!("{}:{}", exchange, asset.to_lowercase())
+            }
+            Account::BotWallet => "bot_wallet".to_string(),
+            Account::UserWallet(user_id) => format!("user_wallet:{}", user_id),
+            Account::ColdWallet => "cold_wallet".to_string(),
+            Account::Fees => "fees".to_string(),
+            Account::PlatformFees => "platform_fees".to_string(),
+        }
+    }
+}
+
+// The entries of one pipeline step, posted together
+pub struct Journal {
+    id: String,
+    kind: &'static str,
+    tx_id: Option<ObjectId>,
+    user_id: i64,
+    entries: Vec<(Account, String, Decimal)>,
+}
+
+impl Journal {
+    // The journal of step `kind` of a transaction
+    pub fn for_transaction(tx_id: ObjectId, kind: &'static str, user_id: i64) -> Self {
+        Journal { id: format!("{}:{}", tx_id, kind), kind, tx_id: Some(tx_id), user_id, entries: Vec::new() }
+    }
+
+    // A journal without a transaction, keyed by something unique to the step such as its signature
+    pub fn new(reference: &str, kind: &'static str, user_id: i64) -> Self {
+        Journal { id: format!("{}:{}", kind, reference), kind, tx_id: None, user_id, entries: Vec::new() }
+    }
+
+    // Keys one of several parts of a step, such as the legs of a split allocation. The first part keeps
+    // the step's key.
+    pub fn part(mut self, index: usize) -> Self {
+        if index > 0 {
+            self.id = format!("{}:{}", self.id, index);
+        }
+        self
+    }
+
+    // Moves `amount` of `asset` from one account to another
+    pub fn transfer(mut self, from: Account, to: Account, asset: &str, amount: Decimal) -> Self {
+        if !amount.is_zero() {
+            self.entries.push((from, asset.to_string(), -amount));
+            self.entries.push((to, asset.to_string(), amount));
+        }
+        self
+    }
+
+    // Records the journal, in `writes` when it belongs with other writes
+    pub async fn post(self, writes: &mut AtomicWrites) -> Result<(), AppError> {
+        if dry_run::is_enabled() || self.entries.is_empty() {
+            return Ok(());
+        }
+        let mut totals: BTreeMap<&str, Decimal> = BTreeMap::new();
+        for (_, asset, amount) in &self.entries {
+            *totals.entry(asset.as_str()).or_default() += amount;
+        }
+        if let Some((asset, total)) = totals.iter().find(|(_, total)| !total.is_zero()) {
+            return Err(AppError::CustomError(format!(
+                "Ledger journal {} doesn't balance, its {} entries sum to {}",
+                self.id, asset, total
+            )));
+        }
+
+        let entries: Vec<Document> = self
+            .entries
+            .iter()
+            .map(|(account, asset, amount)| {
+                doc! { "account": account.name(asset), "asset": asset, "amount": to_stored(*amount) }
+            })
+            .collect();
+        let journal = doc! {
+            "kind": self.kind,
+            "tx_id": self.tx_id,
+            "user_id": self.user_id,
+            "entries": entries,
+            "created_at": BsonDateTime::now(),
+        };
+        writes
+            .insert_once(&get_ledger_collection().await?, doc! { "_id": &self.id }, journal)
+            .await?;
+        Ok(())
+    }
+
+    // Records the journal of a step that already happened on its own. The step can't be undone, so a
+    // failure is logged instead of failing it.
+    pub async fn record(self) {
+        let id = self.id.clone();
+        if let Err(e) = self.post(&mut AtomicWrites::none()).await {
+            eprintln!("Failed to record ledger journal {}: {:?}", id, e);
+        }
+    }
+}
+
+// The balance of every account per asset, optionally only of the accounts in `accounts`
+pub async fn balances(accounts: Option<Vec<String>>) -> Result<Vec<Document>, AppError> {
+    let mut pipeline = vec![doc! { "$unwind": "$entries" }];
+    if let Some(accounts) = accounts {
+        pipeline.push(doc! { "$match": { "entries.account": { "$in": accounts } } });
+    }
+    pipeline.push(doc! { "$group": {
+        "_id": { "account": "$entries.account", "asset": "$entries.asset" },
+        "balance": { "$sum": "$entries.amount" },
+    } });
+    pipeline.push(doc! { "$project": {
+        "_id": 0,
+        "account": "$_id.account",
+        "asset": "$_id.asset",
+        "balance": 1,
+    } });
+    pipeline.push(doc! { "$sort": { "account": 1, "asset": 1 } });
+
+    let mut cursor = get_ledger_collection().await?.aggregate(pipeline, None).await?;
+    let mut balances = Vec::new();
+    while cursor.advance().await? {
+        balances.push(cursor.deserialize_current()?);
+    }
+    Ok(balances)
+}
+
+// Sums the balances per asset. Every total is zero while the ledger is consistent, the assets
+// returned second are those that aren't.
+pub fn totals(balances: &[Document]) -> (BTreeMap<String, f64>, Vec<String>) {
+    let mut totals: BTreeMap<String, f64> = BTreeMap::new();
+    for balance in balances {
+        if let (Ok(asset), Ok(amount)) = (balance.get_str("asset"), balance.get_f64("balance")) {
+            *totals.entry(asset.to_string()).or_default() += amount;
+        }
+    }
+    let unbalanced = totals
+        .iter()
+        .filter(|(_, total)| total.abs() > BALANCE_TOLERANCE)
+        .map(|(asset, _)| asset.clone())
+        .collect();
+    (totals, unbalanced)
+}
@@ -0,0 +1,281 @@
+// lightning.rs
+use crate::config::config;
+use crate::dry_run;
+use crate::error_handling::AppError;
+use crate::maintenance;
+use crate::mongo::{
+    get_lightning_invoices_collection, get_transactions_collection, get_users_collection, LightningInvoice, User,
+};
+use crate::poller::{claim_and_process_deposit, is_poller_paused, process_user_transaction, PipelineClients};
+use crate::transaction_state::{initial_state_fields, TransactionState, TransactionStateMachine};
+use crate::units::sats_to_btc;
+use base64::engine::general_purpose::STANDARD as base64_engine;
+use base64::Engine;
+use mongodb::bson::{doc, oid::ObjectId, DateTime as BsonDateTime};
+use reqwest::{Certificate, Client, Method};
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio::time::interval;
+
+const LND_SOURCE: &str = "Lightning (LND)";
+
+// Talks to an LND node over its REST API, authenticated with an invoice macaroon
+pub struct LndClient {
+    client: Client,
+    rest_url: String,
+    macaroon: String,
+}
+
+impl LndClient {
+    // None when LND_REST_URL is not set
+    pub fn from_config() -> Result<Option<Self>, AppError> {
+        let config = config();
+        let rest_url = match &config.lnd_rest_url {
+            Some(rest_url) => rest_url.trim_end_matches('/').to_string(),
+            None => return Ok(None),
+        };
+
+        // LND serves its REST API with a self-signed certificate
+        let mut builder = Client::builder();
+        if let Some(path) = &config.lnd_tls_cert_path {
+            let pem = std::fs::read(path)
+                .map_err(|e| AppError::LightningError(format!("Failed to read {}: {}", path, e)))?;
+            builder = builder.add_root_certificate(Certificate::from_pem(&pem)?);
+        }
+
+        Ok(Some(LndClient {
+            client: builder.build()?,
+            rest_url,
+            macaroon: config.lnd_macaroon.clone().unwrap_or_default(),
+        }))
+    }
+
+    async fn request(&self, method: Method, path: &str, body: Option<Value>) -> Result<Value, AppError> {
+        let mut request = self
+            .client
+            .request(method, format!("{}{}", self.rest_url, path))
+            .header("Grpc-Metadata-macaroon", &self.macaroon);
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        let text = response.text().await?;
+        if !status.is_success() {
+            return Err(AppError::LightningError(format!("{} {}: {}", status, path, text)));
+        }
+        Ok(serde_json::from_str(&text)?)
+    }
+
+    // Creates an invoice and returns its hex payment hash and BOLT11 payment request
+    pub async fn add_invoice(&self, amount_sats: i64, memo: &str, expiry_secs: u64) -> Result<(String, String), AppError> {
+        let response = self
+            .request(
+                Method::POST,
+                "/v1/invoices",
+                Some(json!({ "value": amount_sats.to_string(), "memo": memo, "expiry": expiry_secs.to_string() })),
+            )
+            .await?;
+        let payment_hash = response["r_hash"]
+            .as_str()
+            .and_then(|r_hash| base64_engine.decode(r_hash).ok())
+            .map(hex::encode)
+            .ok_or_else(|| AppError::LightningError("r_hash missing from LND response".to_string()))?;
+        let payment_request = response["payment_request"]
+            .as_str()
+            .map(|payment_request| payment_request.to_string())
+            .ok_or_else(|| AppError::LightningError("payment_request missing from LND response".to_string()))?;
+        Ok((payment_hash, payment_request))
+    }
+
+    pub async fn lookup_invoice(&self, payment_hash: &str) -> Result<Value, AppError> {
+        self.request(Method::GET, &format!("/v1/invoice/{}", payment_hash), None).await
+    }
+}
+
+// Creates an LND invoice for the user and stores it for the settlement watcher
+pub async fn create_invoice(user: &User, amount_sats: i64) -> Result<LightningInvoice, AppError> {
+    let lnd = LndClient::from_config()?
+        .ok_or_else(|| AppError::ServiceUnavailable("Lightning invoices are not enabled".to_string()))?;
+    let expiry_secs = config().lnd_invoice_expiry_secs;
+    let (payment_hash, payment_request) = lnd
+        .add_invoice(amount_sats, &format!("LOCKIN deposit for user {}", user.user_id), expiry_secs)
+        .await?;
+
+    let now = BsonDateTime::now();
+    let mut invoice = LightningInvoice {
+        id: None,
+        user_id: user.user_id,
+        payment_hash,
+        payment_request,
+        amount_sats,
+        state: "OPEN".to_string(),
+        transaction_id: None,
+        created_at: now,
+        expires_at: BsonDateTime::from_millis(now.timestamp_millis() + expiry_secs as i64 * 1000),
+        settled_at: None,
+    };
+    let inserted = get_lightning_invoices_collection().await?.insert_one(&invoice, None).await?;
+    invoice.id = inserted.inserted_id.as_object_id();
+    Ok(invoice)
+}
+
+// Checks open invoices against LND every LND_POLL_INTERVAL_SECS and feeds settled ones into the
+// same processing pipeline as Kraken deposits
+pub async fn start_invoice_watcher() -> Result<(), AppError> {
+    let lnd = match LndClient::from_config()? {
+        Some(lnd) => lnd,
+        None => return Ok(()),
+    };
+    let clients = PipelineClients::live();
+    let mut interval = interval(Duration::from_secs(config().lnd_poll_interval_secs));
+    loop {
+        interval.tick().await;
+        if maintenance::is_enabled() || is_poller_paused() {
+            continue;
+        }
+        if let Err(e) = check_open_invoices(&lnd, &clients).await {
+            eprintln!("Lightning invoice watcher failed: {:?}", e);
+        }
+    }
+}
+
+async fn check_open_invoices(lnd: &LndClient, clients: &PipelineClients) -> Result<(), AppError> {
+    let invoices_collection = get_lightning_invoices_collection().await?;
+    let mut cursor = invoices_collection.find(doc! { "state": "OPEN" }, None).await?;
+    while cursor.advance().await? {
+        let invoice = cursor.deserialize_current()?;
+        let lookup = match lnd.lookup_invoice(&invoice.payment_hash).await {
+            Ok(lookup) => lookup,
+            Err(e) => {
+                eprintln!("Failed to look up invoice {}: {:?}", invoice.payment_hash, e);
+                continue;
+            }
+        };
+        match lookup["state"].as_str().unwrap_or_default() {
+            "SETTLED" => {
+                if let Err(e) = settle_invoice(clients, &invoice, &lookup).await {
+                    eprintln!("Error processing settled invoice {}: {:?}", invoice.payment_hash, e);
+                }
+            }
+            "CANCELED" => set_invoice_state(&invoice, "CANCELED").await?,
+            _ if invoice.expires_at < BsonDateTime::now() => set_invoice_state(&invoice, "EXPIRED").await?,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+async fn set_invoice_state(invoice: &LightningInvoice, state: &str) -> Result<(), AppError> {
+    if dry_run::is_enabled() {
+        return Ok(());
+    }
+    get_lightning_invoices_collection()
+        .await?
+        .update_one(
+            doc! { "payment_hash": &invoice.payment_hash, "state": "OPEN" },
+            doc! { "$set": { "state": state } },
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+// Records the payment as a deposit transaction and runs it through the pipeline
+async fn settle_invoice(clients: &PipelineClients, invoice: &LightningInvoice, lookup: &Value) -> Result<(), AppError> {
+    let users_collection = get_users_collection().await?;
+    let transactions_collection = get_transactions_collection().await?;
+    let invoices_collection = get_lightning_invoices_collection().await?;
+
+    let amount_paid_sats = lookup["amt_paid_sat"]
+        .as_str()
+        .and_then(|amount| amount.parse::<i64>().ok())
+        .unwrap_or(invoice.amount_sats);
+    let amount_sats = amount_paid_sats.max(0) as u64;
+    let amount = sats_to_btc(amount_sats);
+    let time = lookup["settle_date"]
+        .as_str()
+        .and_then(|settle_date| settle_date.parse::<i64>().ok())
+        .unwrap_or_else(|| BsonDateTime::now().timestamp_millis() / 1000);
+
+    // In dry-run mode the invoice stays open and each payment is only run through once
+    if dry_run::is_enabled() {
+        if dry_run::has_processed_deposit(&invoice.payment_hash).await? {
+            return Ok(());
+        }
+        let user = match users_collection.find_one(doc! { "user_id": invoice.user_id }, None).await? {
+            Some(user) => user,
+            None => return Ok(()),
+        };
+        dry_run::record_action(
+            "process_deposit",
+            json!({ "refid": invoice.payment_hash, "user_id": invoice.user_id, "amount": amount, "address": invoice.payment_request }),
+        )
+        .await;
+        let state_machine = TransactionStateMachine::new(transactions_collection.clone(), ObjectId::new());
+        return process_user_transaction(
+            clients,
+            amount_sats,
+            invoice.user_id,
+            &invoice.payment_request,
+            "Success",
+            time,
+            user,
+            &users_collection,
+            &transactions_collection,
+            &state_machine,
+        )
+        .await;
+    }
+
+    // Moving the invoice out of OPEN claims the payment, so it is only recorded once
+    let claimed = invoices_collection
+        .update_one(
+            doc! { "payment_hash": &invoice.payment_hash, "state": "OPEN" },
+            doc! { "$set": { "state": "SETTLED", "settled_at": BsonDateTime::from_millis(time * 1000) } },
+            None,
+        )
+        .await?;
+    if claimed.modified_count != 1 {
+        return Ok(());
+    }
+
+    let mut tx = doc! {
+        "user_id": invoice.user_id,
+        "amount": amount,
+        "address": &invoice.payment_request,
+        "refid": &invoice.payment_hash,
+        "source": LND_SOURCE,
+        "timestamp": BsonDateTime::from_millis(time * 1000),
+    };
+    tx.extend(initial_state_fields(TransactionState::DepositPending));
+    let tx_id = transactions_collection
+        .insert_one(tx, None)
+        .await?
+        .inserted_id
+        .as_object_id()
+        .ok_or_else(|| AppError::CustomError("Inserted transaction has no ObjectId".to_string()))?;
+    invoices_collection
+        .update_one(
+            doc! { "payment_hash": &invoice.payment_hash },
+            doc! { "$set": { "transaction_id": tx_id } },
+            None,
+        )
+        .await?;
+
+    println!(
+        "Processing Lightning payment {} of {} sats for user {}",
+        invoice.payment_hash, amount_paid_sats, invoice.user_id
+    );
+    claim_and_process_deposit(
+        clients,
+        &users_collection,
+        &transactions_collection,
+        tx_id,
+        invoice.user_id,
+        amount_sats,
+        &invoice.payment_request,
+        time,
+    )
+    .await
+}
@@ -1,34 +1,75 @@
 // lockin.rs
 use anyhow::{Context, Result};
+use async_trait::async_trait;
 use base64::engine::general_purpose::STANDARD as base64_engine;
 use base64::Engine;
-use bs58;
-use dotenv::dotenv;
 use jupiter_swap_api_client::{
     quote::{QuoteRequest, QuoteResponse},
     swap::{SwapInstructionsResponse, SwapRequest, SwapResponse},
     transaction_config::TransactionConfig,
     JupiterSwapApiClient,
 };
+use mongodb::bson::oid::ObjectId;
+use rand::Rng;
 use reqwest::Client;
+use serde::Serialize;
 use serde_json::json;
 use solana_client::rpc_client::RpcClient;
 use solana_program::{
-    instruction::Instruction,
-    native_token::LAMPORTS_PER_SOL,
+    address_lookup_table::{state::AddressLookupTable, AddressLookupTableAccount},
+    instruction::{AccountMeta, Instruction},
+    message::{v0, Message, VersionedMessage},
     pubkey::Pubkey,
-    system_instruction,
+    system_instruction, system_program,
 };
 use solana_sdk::{
-    signature::{Keypair, Signer},
-    transaction::Transaction,
+    account::Account,
+    compute_budget::ComputeBudgetInstruction,
+    hash::Hash,
+    signature::{Keypair, Signature, Signer},
+    transaction::{Transaction, VersionedTransaction},
 };
 use spl_associated_token_account::{
-    instruction::create_associated_token_account, get_associated_token_address,
+    instruction::create_associated_token_account, get_associated_token_address_with_program_id,
 };
 use spl_token::id as token_program_id;
 use thiserror::Error;
-use tokio::time::{sleep, Duration};
+
+use crate::circuit_breaker;
+use crate::config::{config, Network, SubmissionBackendKind};
+use crate::dry_run;
+use crate::quote_check;
+use crate::refunds;
+use crate::signer::{self, WalletSigner};
+use crate::spend_policy::{self, TokenOutflow};
+use crate::retry::{self, retry_with_backoff, Backoff};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock};
+use futures_util::{SinkExt, StreamExt};
+use tokio::time::{timeout, Duration, Instant};
+use tokio_tungstenite::{connect_async, tungstenite::Message as WsMessage};
+
+pub const DEFAULT_SLIPPAGE_BPS: u16 = 1500;
+pub const MAX_SLIPPAGE_BPS: u16 = 2500;
+const BLOCKHASH_REBUILD_LIMIT: usize = 3;
+// Smallest raise of the compute unit price on a rebuild, so a transaction priced at 0 still climbs
+const MIN_PRIORITY_FEE_STEP_MICRO_LAMPORTS: u64 = 1_000;
+const SIGNATURE_SUBSCRIBE_TIMEOUT_SECS: u64 = 60;
+
+// Derives the PubSub endpoint from an RPC URL unless RPC_WS_URL is set
+pub fn solana_ws_url(rpc_url: &str) -> String {
+    if let Some(ws_url) = &config().rpc_ws_url {
+        return ws_url.clone();
+    }
+    if let Some(rest) = rpc_url.strip_prefix("https://") {
+        format!("wss://{}", rest)
+    } else if let Some(rest) = rpc_url.strip_prefix("http://") {
+        format!("ws://{}", rest)
+    } else {
+        rpc_url.to_string()
+    }
+}
 
 #[derive(Error, Debug)]
 pub enum LockinClientError {
@@ -44,61 +85,574 @@ pub enum LockinClientError {
     SwapInstructionsError(String),
     #[error("Failed to create transaction: {0}")]
     TransactionError(String),
-    #[error("Failed to check transaction confirmation: {0}")]
-    TransactionConfirmationError(String),
+    #[error("Swap refunded: {reason}")]
+    SwapRefunded {
+        reason: String,
+        // None in dry-run mode, where nothing is sent
+        refund_signature: Option<String>,
+    },
     #[error("Failed to process refund: {0}")]
     RefundError(String),
+    #[error("Swap failed after {attempts} attempts: {reason}")]
+    RetriesExhausted {
+        attempts: usize,
+        reason: String,
+        quote: Option<serde_json::Value>,
+        // Category of the last failed simulation, see SimulationFailure::category
+        failure: Option<&'static str>,
+    },
+    #[error("Quote rejected: {reason}")]
+    QuoteRejected {
+        reason: String,
+        quote: Option<serde_json::Value>,
+    },
+}
+
+// Rolling health of a single RPC endpoint
+#[derive(Debug, Default)]
+struct EndpointHealth {
+    successes: u64,
+    failures: u64,
+    latency_ms: f64,
+}
+
+// Pool of Solana RPC endpoints, ranked by error rate and latency so requests go to the healthiest one
+pub struct RpcPool {
+    endpoints: Vec<(String, Mutex<EndpointHealth>)>,
+}
+
+static RPC_POOL: OnceLock<Arc<RpcPool>> = OnceLock::new();
+
+impl RpcPool {
+    pub fn new(rpc_urls: Vec<String>) -> Self {
+        Self {
+            endpoints: rpc_urls
+                .into_iter()
+                .map(|url| (url, Mutex::new(EndpointHealth::default())))
+                .collect(),
+        }
+    }
+
+    // Returns the process-wide pool so health scores survive across swaps
+    pub fn shared() -> Arc<RpcPool> {
+        RPC_POOL
+            .get_or_init(|| Arc::new(RpcPool::new(config().rpc_urls.clone())))
+            .clone()
+    }
+
+    // Endpoint URLs ordered from healthiest to least healthy
+    pub fn ranked_urls(&self) -> Vec<String> {
+        let mut scored: Vec<(f64, &String)> = self
+            .endpoints
+            .iter()
+            .map(|(url, health)| (Self::score(&health.lock().unwrap()), url))
+            .collect();
+        scored.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        scored.into_iter().map(|(_, url)| url.clone()).collect()
+    }
+
+    pub fn best_url(&self) -> String {
+        self.ranked_urls().remove(0)
+    }
+
+    // Lower is better: each percent of errors weighs like 100ms of latency
+    fn score(health: &EndpointHealth) -> f64 {
+        let total = health.successes + health.failures;
+        let error_rate = if total == 0 { 0.0 } else { health.failures as f64 / total as f64 };
+        error_rate * 10_000.0 + health.latency_ms
+    }
+
+    pub fn record_success(&self, url: &str, latency: Duration) {
+        if let Some((_, health)) = self.endpoints.iter().find(|(endpoint, _)| endpoint == url) {
+            let mut health = health.lock().unwrap();
+            let latency_ms = latency.as_millis() as f64;
+            health.latency_ms = if health.successes == 0 {
+                latency_ms
+            } else {
+                health.latency_ms * 0.8 + latency_ms * 0.2
+            };
+            health.successes += 1;
+        }
+    }
+
+    pub fn record_failure(&self, url: &str) {
+        if let Some((_, health)) = self.endpoints.iter().find(|(endpoint, _)| endpoint == url) {
+            health.lock().unwrap().failures += 1;
+        }
+    }
+}
+
+// Whether an RPC error points at the endpoint itself (unhealthy, behind, stale blockhash) rather than the request
+fn is_endpoint_fault(response: &serde_json::Value) -> bool {
+    let error = &response["error"];
+    if error.is_null() {
+        return false;
+    }
+    let code = error["code"].as_i64().unwrap_or(0);
+    let message = error["message"].as_str().unwrap_or_default();
+    matches!(code, -32004 | -32005 | -32007 | -32014)
+        || message.contains("Blockhash not found")
+        || message.contains("Node is behind")
+}
+
+// The Solana RPC surface the lockin client uses, so swaps can run against an in-memory cluster
+#[async_trait]
+pub trait SolanaRpc: Send + Sync {
+    // Sends a raw JSON-RPC request and returns the full response body
+    async fn send_request(&self, method: &str, params: serde_json::Value) -> Result<serde_json::Value>;
+
+    async fn get_account(&self, address: &Pubkey) -> Result<Account>;
+
+    async fn get_multiple_accounts(&self, addresses: &[Pubkey]) -> Result<Vec<Option<Account>>>;
+
+    async fn send_and_confirm_transaction(&self, transaction: &Transaction) -> Result<Signature>;
+
+    // PubSub endpoint used to wait for signature notifications
+    fn ws_url(&self) -> String;
+}
+
+// The live cluster, reached through the shared endpoint pool
+pub struct PooledRpc {
+    client: Client,
+    rpc_pool: Arc<RpcPool>,
+}
+
+impl PooledRpc {
+    pub fn new(rpc_pool: Arc<RpcPool>) -> Self {
+        Self {
+            client: Client::new(),
+            rpc_pool,
+        }
+    }
+
+    // RPC client bound to the currently healthiest endpoint
+    fn rpc_client(&self) -> RpcClient {
+        RpcClient::new(self.rpc_pool.best_url())
+    }
+}
+
+#[async_trait]
+impl SolanaRpc for PooledRpc {
+    // Fails over to the next healthiest endpoint on endpoint faults
+    async fn send_request(&self, method: &str, params: serde_json::Value) -> Result<serde_json::Value> {
+        let mut last_error = None;
+        for rpc_url in self.rpc_pool.ranked_urls() {
+            let started = Instant::now();
+            let response = self
+                .client
+                .post(&rpc_url)
+                .json(&json!({
+                    "jsonrpc": "2.0",
+                    "id": 1,
+                    "method": method,
+                    "params": params
+                }))
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            let response = match response {
+                Ok(response) => response.json::<serde_json::Value>().await,
+                Err(e) => Err(e),
+            };
+
+            match response {
+                Ok(value) if !is_endpoint_fault(&value) => {
+                    self.rpc_pool.record_success(&rpc_url, started.elapsed());
+                    return Ok(value);
+                }
+                Ok(value) => {
+                    eprintln!("RPC endpoint {} failed {}: {}, failing over", rpc_url, method, value["error"]);
+                    self.rpc_pool.record_failure(&rpc_url);
+                    last_error = Some(anyhow::anyhow!("RPC error for {}: {}", method, value["error"]));
+                }
+                Err(e) => {
+                    eprintln!("RPC endpoint {} unreachable for {}: {:?}, failing over", rpc_url, method, e);
+                    self.rpc_pool.record_failure(&rpc_url);
+                    last_error = Some(anyhow::Error::new(e).context(format!("Failed to send request for {}", method)));
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow::anyhow!("No RPC endpoints configured")))
+    }
+
+    async fn get_account(&self, address: &Pubkey) -> Result<Account> {
+        self.rpc_client()
+            .get_account(address)
+            .with_context(|| format!("Failed to fetch account {}", address))
+    }
+
+    async fn get_multiple_accounts(&self, addresses: &[Pubkey]) -> Result<Vec<Option<Account>>> {
+        self.rpc_client()
+            .get_multiple_accounts(addresses)
+            .context("Failed to fetch accounts")
+    }
+
+    async fn send_and_confirm_transaction(&self, transaction: &Transaction) -> Result<Signature> {
+        self.rpc_client()
+            .send_and_confirm_transaction(transaction)
+            .context("Failed to send and confirm transaction")
+    }
+
+    fn ws_url(&self) -> String {
+        solana_ws_url(&self.rpc_pool.best_url())
+    }
+}
+
+// The Jupiter swap API calls the lockin client uses
+#[async_trait]
+pub trait JupiterApi: Send + Sync {
+    async fn quote(&self, request: &QuoteRequest) -> Result<QuoteResponse>;
+
+    async fn swap(&self, request: &SwapRequest) -> Result<SwapResponse>;
+
+    async fn swap_instructions(&self, request: &SwapRequest) -> Result<SwapInstructionsResponse>;
+}
+
+#[async_trait]
+impl JupiterApi for JupiterSwapApiClient {
+    async fn quote(&self, request: &QuoteRequest) -> Result<QuoteResponse> {
+        Ok(JupiterSwapApiClient::quote(self, request).await?)
+    }
+
+    async fn swap(&self, request: &SwapRequest) -> Result<SwapResponse> {
+        Ok(JupiterSwapApiClient::swap(self, request).await?)
+    }
+
+    async fn swap_instructions(&self, request: &SwapRequest) -> Result<SwapInstructionsResponse> {
+        Ok(JupiterSwapApiClient::swap_instructions(self, request).await?)
+    }
+}
+
+// Fetching the details of a transaction the WebSocket reported as confirmed
+const CONFIRMATION_DETAILS_BACKOFF: Backoff = Backoff::new(3, Duration::from_secs(1), Duration::from_secs(1));
+// Polling for confirmation when the WebSocket subscription is unavailable
+const CONFIRMATION_POLL_BACKOFF: Backoff = Backoff::new(5, Duration::from_secs(5), Duration::from_secs(80));
+// Keeps polling a transaction that is still unconfirmed until its blockhash expires, it can land until then
+const CONFIRMATION_EXPIRY_BACKOFF: Backoff = Backoff::new(30, Duration::from_secs(5), Duration::from_secs(5));
+
+// Outcome of waiting for a submitted transaction. Only Expired and Failed mean it can no longer land,
+// a TimedOut transaction may still confirm.
+enum ConfirmationStatus {
+    Confirmed(serde_json::Value),
+    // Landed but failed on-chain, with the transaction error. Nothing but the fee was spent.
+    Failed(serde_json::Value),
+    Expired,
+    TimedOut,
+}
+
+impl ConfirmationStatus {
+    // A landed transaction, confirmed or failed by its `meta.err`
+    fn landed(response: serde_json::Value) -> Self {
+        let err = &response["result"]["meta"]["err"];
+        if err.is_null() {
+            ConfirmationStatus::Confirmed(response)
+        } else {
+            ConfirmationStatus::Failed(err.clone())
+        }
+    }
+}
+
+pub const TOKEN_2022_PROGRAM_ID: &str = "TokenzQdBNbLqP5VEhdkAS6EPFLC1PHnBqCXEpPxuEb";
+// Base fee of a transaction signature, sweeps don't add a priority fee
+const LAMPORTS_PER_SIGNATURE: u64 = 5_000;
+// Token accounts moved per sweep transaction, each needs an ATA creation, a transfer and a close
+const TOKEN_ACCOUNTS_PER_SWEEP: usize = 4;
+// Size of an SPL token account, for the rent of destination ATAs
+const TOKEN_ACCOUNT_LEN: usize = 165;
+// A Token-2022 associated token account also carries the ImmutableOwner extension. Mints with extensions
+// that add to their accounts, such as transfer fees, make them slightly larger still.
+const TOKEN_2022_ACCOUNT_LEN: usize = 170;
+
+// The token program of every mint looked up, a mint never changes program
+static TOKEN_PROGRAMS: OnceLock<Mutex<HashMap<Pubkey, Pubkey>>> = OnceLock::new();
+
+// The size of a token account of `program_id`
+fn token_account_len(program_id: &Pubkey) -> usize {
+    if program_id.to_string() == TOKEN_2022_PROGRAM_ID {
+        TOKEN_2022_ACCOUNT_LEN
+    } else {
+        TOKEN_ACCOUNT_LEN
+    }
+}
+
+// A token account found in a wallet being swept
+#[derive(Debug, Clone)]
+struct SweptTokenAccount {
+    address: Pubkey,
+    mint: Pubkey,
+    program_id: Pubkey,
+    amount: u64,
+    decimals: u8,
+}
+
+// What a wallet sweep moved
+#[derive(Debug, Clone, Serialize)]
+pub struct WalletSweep {
+    pub signatures: Vec<String>,
+    pub tokens: Vec<serde_json::Value>,
+    pub closed_accounts: usize,
+    pub lamports: u64,
+}
+
+// Why a swap simulation failed, read from the transaction error and the program logs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SimulationFailure {
+    // The route delivered less than the quote's minimum out amount
+    SlippageExceeded,
+    // The bot wallet can't cover the amount, the fee or the rent of a new account
+    InsufficientFunds,
+    // An account the swap reads, usually the receiving token account, doesn't exist
+    AccountMissing,
+    // Any other instruction error, with the failing program and its custom error code when known
+    ProgramError { program: Option<String>, code: Option<u64> },
+    Other(String),
+}
+
+// Jupiter's SlippageToleranceExceeded error code
+const JUPITER_SLIPPAGE_ERROR_CODE: u64 = 6001;
+// Anchor's AccountNotInitialized error code
+const ACCOUNT_NOT_INITIALIZED_ERROR_CODE: u64 = 3012;
+
+impl SimulationFailure {
+    // Classifies the `result` of a simulateTransaction response
+    pub fn parse(result: &serde_json::Value) -> Self {
+        let err = &result["err"];
+        let logs: Vec<&str> = result["logs"].as_array().into_iter().flatten().filter_map(|log| log.as_str()).collect();
+        let logged = |needle: &str| logs.iter().any(|log| log.to_lowercase().contains(needle));
+        let code = err["InstructionError"][1]["Custom"].as_u64();
+
+        if logged("slippagetoleranceexceeded") || logged("slippage tolerance exceeded") || code == Some(JUPITER_SLIPPAGE_ERROR_CODE) {
+            return SimulationFailure::SlippageExceeded;
+        }
+        if matches!(err.as_str(), Some("InsufficientFundsForFee") | Some("InsufficientFundsForRent"))
+            || err["InsufficientFundsForRent"].is_object()
+            || logged("insufficient lamports")
+            || logged("insufficient funds")
+        {
+            return SimulationFailure::InsufficientFunds;
+        }
+        if matches!(err.as_str(), Some("AccountNotFound") | Some("ProgramAccountNotFound"))
+            || code == Some(ACCOUNT_NOT_INITIALIZED_ERROR_CODE)
+            || logged("accountnotinitialized")
+            || logged("account not found")
+        {
+            return SimulationFailure::AccountMissing;
+        }
+        if !err["InstructionError"].is_null() {
+            // The runtime logs "Program <id> failed: <reason>" for the instruction that failed
+            let program = logs
+                .iter()
+                .find(|log| log.starts_with("Program ") && log.contains(" failed: "))
+                .and_then(|log| log.split_whitespace().nth(1).map(str::to_string));
+            return SimulationFailure::ProgramError { program, code };
+        }
+        SimulationFailure::Other(err.to_string())
+    }
+
+    // Stable name of the failure, recorded on the transaction as `simulation_failure`
+    pub fn category(&self) -> &'static str {
+        match self {
+            SimulationFailure::SlippageExceeded => "slippage_exceeded",
+            SimulationFailure::InsufficientFunds => "insufficient_funds",
+            SimulationFailure::AccountMissing => "account_missing",
+            SimulationFailure::ProgramError { .. } => "program_error",
+            SimulationFailure::Other(_) => "unknown",
+        }
+    }
+}
+
+impl std::fmt::Display for SimulationFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SimulationFailure::SlippageExceeded => write!(f, "slippage tolerance exceeded"),
+            SimulationFailure::InsufficientFunds => write!(f, "insufficient funds in the bot wallet"),
+            SimulationFailure::AccountMissing => write!(f, "a required account is missing"),
+            SimulationFailure::ProgramError { program, code } => write!(
+                f,
+                "program {} failed with error {}",
+                program.as_deref().unwrap_or("unknown"),
+                code.map(|code| code.to_string()).unwrap_or_else(|| "unknown".to_string())
+            ),
+            SimulationFailure::Other(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+// How a swap or transfer ended short of an error. Failures are errors, a LockinClientError telling
+// whether the input is still in the bot wallet or was refunded.
+#[derive(Debug, Clone)]
+pub enum SwapResult {
+    // The output reached the receiving address
+    Delivered(Box<SwapOutcome>),
+    // Dry-run mode, the transaction was simulated and recorded but not sent
+    DryRun,
+    // Nothing was sent, the input is still in the bot wallet
+    NotSent(NotSentReason),
+}
+
+#[derive(Debug, Clone)]
+pub enum NotSentReason {
+    // The amount left after the platform fee doesn't cover the rent and network fees
+    InsufficientAmount { amount_lamports: u64, costs_lamports: u64 },
+    // Jupiter doesn't route on devnet
+    DevnetUnroutable,
+}
+
+impl std::fmt::Display for NotSentReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NotSentReason::InsufficientAmount { amount_lamports, costs_lamports } => write!(
+                f,
+                "{} lamports don't cover the {} lamports of rent and network fees",
+                amount_lamports, costs_lamports
+            ),
+            NotSentReason::DevnetUnroutable => write!(f, "Jupiter does not route on devnet"),
+        }
+    }
+}
+
+// What a successful lockin swap cost on-chain and what it delivered
+#[derive(Debug, Clone)]
+pub struct SwapOutcome {
+    pub signature: String,
+    // Base units of the input mint sent into the swap, lamports for SOL
+    pub in_amount: u64,
+    // Base units of the output the receiving address gained, read from the confirmed transaction's token
+    // balances and falling back to the quoted amount. Lamports for SOL transfers, pool tokens for stake
+    // deposits.
+    pub out_amount: Option<u64>,
+    pub network_fee_lamports: u64,
+    pub price_impact_pct: f64,
+    // The Jupiter route the swap took, see `route_plan_summary`
+    pub route_plan: Option<serde_json::Value>,
+    pub platform_fee_lamports: u64,
+    // Whether the platform fee was sent to the treasury rather than kept in the bot wallet
+    pub platform_fee_to_treasury: bool,
+    // Set when the SOL was deposited into the stake pool rather than sent
+    pub stake_deposit: Option<StakeDeposit>,
+}
+
+// Where a staked SOL leg went
+#[derive(Debug, Clone, Serialize)]
+pub struct StakeDeposit {
+    pub stake_pool: String,
+    pub pool_mint: String,
+    // The user's token account holding the pool tokens, their share of the pool's stake
+    pub pool_token_account: String,
+    pub lamports: u64,
+}
+
+// SPL stake pool program, and the index of DepositSol in its instruction enum
+const STAKE_POOL_PROGRAM_ID: &str = "SPoo1Ku8WFXoNDMHPsrGSTSG1Y47rzgn41SLUNakuHy";
+const DEPOSIT_SOL_INSTRUCTION: u8 = 14;
+
+// The accounts of an SPL stake pool a SOL deposit goes through
+#[derive(Debug, Clone)]
+struct StakePool {
+    address: Pubkey,
+    withdraw_authority: Pubkey,
+    reserve_stake: Pubkey,
+    pool_mint: Pubkey,
+    manager_fee_account: Pubkey,
+    token_program_id: Pubkey,
+}
+
+// Mainnet tip accounts published by Jito, one is picked at random per bundle
+const JITO_TIP_ACCOUNTS: [&str; 8] = [
+    "96gYZGLnJYVFmbjzopPSU6QiEV5fGqZNyN9nmNhvrZU5",
+    "HFqU5x63VTqvQss8hp11i4wVV8bD44PvwucfZ2bU7gRe",
+    "Cw8CFyM9FkoMi7K7Crf6HNQqf4uEMzpKw6QNghXLvLkY",
+    "ADaUMid9yfUytqMBgopwjb2DTLSokTSzL1zt6iGPaS49",
+    "DfXygSm4jCyNCybVYYK6DwvWqjKee8pbDmJGcLWNDXjh",
+    "ADuUkR4vqLUMWXxW9gh6D6L8pMSawimctcNZ5pGwDcEt",
+    "DttWaMuVvTiduZRnguLF7jNxTgiMBZ1hyAumKUiL2KRL",
+    "3AVi9Tg9Uo68tJfuvoKvqKNWKkC5wPdSSdeBnizKZ6jT",
+];
+
+// Where signed swap transactions are sent, configured through SUBMISSION_BACKEND
+pub enum SubmissionBackend {
+    Rpc,
+    Jito {
+        block_engine_url: String,
+        tip_lamports: u64,
+    },
+}
+
+impl SubmissionBackend {
+    pub fn from_config() -> Self {
+        let config = config();
+        match config.submission_backend {
+            SubmissionBackendKind::Jito => SubmissionBackend::Jito {
+                block_engine_url: config.jito_block_engine_url.clone(),
+                tip_lamports: config.jito_tip_lamports,
+            },
+            SubmissionBackendKind::Rpc => SubmissionBackend::Rpc,
+        }
+    }
+
+    // Lamports paid on top of the network fee for each submitted transaction
+    pub fn tip_lamports(&self) -> u64 {
+        match self {
+            SubmissionBackend::Rpc => 0,
+            SubmissionBackend::Jito { tip_lamports, .. } => *tip_lamports,
+        }
+    }
+
+    // Jito only includes bundles that pay a tip to one of its tip accounts
+    pub fn tip_instruction(&self, payer: &Pubkey) -> Option<Instruction> {
+        match self {
+            SubmissionBackend::Rpc => None,
+            SubmissionBackend::Jito { tip_lamports, .. } => {
+                let tip_account = JITO_TIP_ACCOUNTS[rand::thread_rng().gen_range(0..JITO_TIP_ACCOUNTS.len())];
+                let tip_account = Pubkey::from_str(tip_account).ok()?;
+                Some(system_instruction::transfer(payer, &tip_account, *tip_lamports))
+            }
+        }
+    }
 }
 
 pub struct LockinClient {
     client: Client,
-    rpc_url: String,
-    keypair: Keypair,
-    jupiter_swap_api_client: JupiterSwapApiClient,
-    rpc_client: RpcClient,
+    rpc: Arc<dyn SolanaRpc>,
+    submission_backend: SubmissionBackend,
+    // The bot wallet swaps are sent from
+    signer: Arc<dyn WalletSigner>,
+    jupiter: Arc<dyn JupiterApi>,
+}
+
+// Address of the bot wallet, SOL withdrawn anywhere else can't be swapped
+pub fn bot_wallet_address() -> Result<Pubkey> {
+    Ok(signer::shared()?.pubkey())
 }
 
 impl LockinClient {
     pub async fn new() -> Result<Self> {
-        dotenv().ok();
-        let rpc_url = std::env::var("RPC_URL").context("RPC URL not set")?;
-        let base58privatekey = std::env::var("PRIVATE_KEY").context("PRIVATE_KEY not set")?;
-        let private_key_bytes = bs58::decode(base58privatekey)
-            .into_vec()
-            .context("Invalid base58 string")?;
-        let keypair = Keypair::from_bytes(&private_key_bytes).context("Invalid keypair bytes")?;
-        let rpc_url_string = rpc_url.to_string();
-        let jupiter_swap_api_client = JupiterSwapApiClient::new("https://quote-api.jup.ag/v6".to_string());
-        let rpc_client = RpcClient::new(rpc_url_string.clone());
+        Self::with_clients(
+            Arc::new(PooledRpc::new(RpcPool::shared())),
+            Arc::new(JupiterSwapApiClient::new(config().jupiter_api_url.clone())),
+        )
+    }
 
+    // Builds a client with the configured signer on top of the given RPC and Jupiter implementations
+    pub fn with_clients(rpc: Arc<dyn SolanaRpc>, jupiter: Arc<dyn JupiterApi>) -> Result<Self> {
         Ok(Self {
             client: Client::new(),
-            rpc_url,
-            keypair,
-            jupiter_swap_api_client,
-            rpc_client,
+            rpc,
+            submission_backend: SubmissionBackend::from_config(),
+            signer: signer::shared()?,
+            jupiter,
         })
     }
 
+    #[tracing::instrument(name = "solana.rpc", skip_all, fields(rpc.method = method))]
     async fn send_rpc_request(
         &self,
         method: &str,
         params: serde_json::Value,
     ) -> Result<serde_json::Value> {
-        self.client
-            .post(&self.rpc_url)
-            .json(&json!({
-                "jsonrpc": "2.0",
-                "id": 1,
-                "method": method,
-                "params": params
-            }))
-            .send()
-            .await
-            .context(format!("Failed to send request for {}", method))?
-            .json::<serde_json::Value>()
-            .await
-            .context(format!("Failed to parse response for {}", method))
+        let request =
+            retry_with_backoff(&retry::SOLANA_RPC, method, |_| true, || self.rpc.send_request(method, params.clone()));
+        circuit_breaker::SOLANA_RPC.call(|_| true, request).await
     }
 
     pub async fn get_minimum_balance_for_rent_exemption(&self, data_length: usize) -> Result<u64> {
@@ -112,6 +666,156 @@ impl LockinClient {
         })
     }
 
+    // Whether a transaction landed: None until it is confirmed, then whether it succeeded
+    pub async fn signature_status(&self, signature: &str) -> Result<Option<bool>> {
+        let response = self
+            .send_rpc_request(
+                "getSignatureStatuses",
+                json!([[signature], { "searchTransactionHistory": true }]),
+            )
+            .await?;
+        let status = &response["result"]["value"][0];
+        match status["confirmationStatus"].as_str() {
+            Some("confirmed") | Some("finalized") => Ok(Some(status["err"].is_null())),
+            _ => Ok(None),
+        }
+    }
+
+    // The fee the network charges for a message, priority fee included
+    pub async fn get_fee_for_message(&self, message: &VersionedMessage) -> Result<u64> {
+        let base64_message = base64_engine.encode(message.serialize());
+        let response = self
+            .send_rpc_request("getFeeForMessage", json!([base64_message, { "commitment": "confirmed" }]))
+            .await?;
+        // The value is null when the message's blockhash has already expired
+        response["result"]["value"].as_u64().ok_or_else(|| {
+            LockinClientError::TransactionError(format!("getFeeForMessage returned no fee: {}", response)).into()
+        })
+    }
+
+    // The fee of a transaction made of `instructions`, compiled the way it would be sent
+    async fn estimate_fee(&self, instructions: Vec<Instruction>, lookup_table_addresses: &[Pubkey]) -> Result<u64> {
+        let (recent_blockhash, _) = self.get_latest_blockhash().await?;
+        let message = self.compile_message(&instructions, lookup_table_addresses, recent_blockhash).await?;
+        self.get_fee_for_message(&message).await
+    }
+
+    // The token program owning `mint`, the legacy token program or Token-2022. A mint's accounts are
+    // created and moved through its program.
+    pub async fn token_program_of(&self, mint: Pubkey) -> Result<Pubkey> {
+        let programs = TOKEN_PROGRAMS.get_or_init(|| Mutex::new(HashMap::new()));
+        if let Some(program_id) = programs.lock().unwrap().get(&mint) {
+            return Ok(*program_id);
+        }
+        let owner = self.rpc.get_account(&mint).await?.owner;
+        if owner != token_program_id() && owner != Pubkey::from_str(TOKEN_2022_PROGRAM_ID)? {
+            anyhow::bail!("{} is not a token mint, it is owned by {}", mint, owner);
+        }
+        programs.lock().unwrap().insert(mint, owner);
+        Ok(owner)
+    }
+
+    // The associated token account of `wallet_address` for `token_mint_address`, and the mint's token program
+    async fn associated_token_address(&self, wallet_address: Pubkey, token_mint_address: Pubkey) -> Result<(Pubkey, Pubkey)> {
+        let program_id = self.token_program_of(token_mint_address).await?;
+        let address = get_associated_token_address_with_program_id(&wallet_address, &token_mint_address, &program_id);
+        Ok((address, program_id))
+    }
+
+    // What the receiving token account costs the bot wallet when it doesn't exist yet: its rent
+    // exemption and the fee of the transaction creating it
+    async fn token_account_creation_cost(&self, wallet_address: Pubkey, token_mint_address: Pubkey) -> Result<u64> {
+        let (associated_token_address, program_id) =
+            self.associated_token_address(wallet_address, token_mint_address).await?;
+        if self.rpc.get_account(&associated_token_address).await.is_ok() {
+            return Ok(0);
+        }
+        let rent_exemption = self
+            .get_minimum_balance_for_rent_exemption(token_account_len(&program_id))
+            .await?;
+        let create_ata_instruction = create_associated_token_account(
+            &self.signer.pubkey(),
+            &wallet_address,
+            &token_mint_address,
+            &program_id,
+        );
+        let creation_fee = self.estimate_fee(vec![create_ata_instruction], &[]).await?;
+        Ok(rent_exemption + creation_fee)
+    }
+
+    // The network fee of swapping `amount`, priced on the message the swap would be sent as
+    async fn estimate_swap_fee(
+        &self,
+        input_mint: Pubkey,
+        output_mint: Pubkey,
+        amount: u64,
+        receiving_address: Pubkey,
+        slippage_bps: u16,
+    ) -> Result<u64> {
+        let quote_response = self.get_quote(amount, input_mint, output_mint, slippage_bps).await?;
+        let (receiving_token_address, _) = self.associated_token_address(receiving_address, output_mint).await?;
+        let swap_instructions_response = self
+            .get_swap_instructions(self.signer.pubkey(), receiving_token_address, quote_response)
+            .await?;
+        let lookup_table_addresses = swap_instructions_response.address_lookup_table_addresses.clone();
+        let instructions = self.collect_swap_instructions(swap_instructions_response).await?;
+        self.estimate_fee(instructions, &lookup_table_addresses).await
+    }
+
+    // The network fee of sending `instruction` on its own, such as the plain transfer devnet sends instead
+    // of a swap
+    async fn estimate_instruction_fee(&self, instruction: Instruction) -> Result<u64> {
+        let mut instructions = self.compute_budget_instructions(std::slice::from_ref(&instruction)).await?;
+        instructions.push(instruction);
+        self.estimate_fee(instructions, &[]).await
+    }
+
+    // Reads the accounts of STAKE_POOL_ADDRESS from its state. The ones used sit at fixed offsets, ahead
+    // of the pool's first variable length field.
+    async fn stake_pool(&self) -> Result<StakePool> {
+        let address = config().stake_pool_address.as_deref().context("STAKE_POOL_ADDRESS is not set")?;
+        let address = Pubkey::from_str(address).context("Invalid STAKE_POOL_ADDRESS")?;
+        let program_id = Pubkey::from_str(STAKE_POOL_PROGRAM_ID)?;
+        let account = self.rpc.get_account(&address).await?;
+        if account.owner != program_id || account.data.len() < 258 {
+            anyhow::bail!("{} is not an SPL stake pool", address);
+        }
+        let key_at = |offset: usize| Pubkey::try_from(&account.data[offset..offset + 32]);
+        let (withdraw_authority, _) = Pubkey::find_program_address(&[address.as_ref(), b"withdraw"], &program_id);
+        Ok(StakePool {
+            address,
+            withdraw_authority,
+            reserve_stake: key_at(130)?,
+            pool_mint: key_at(162)?,
+            manager_fee_account: key_at(194)?,
+            token_program_id: key_at(226)?,
+        })
+    }
+
+    // DepositSol of `lamports` from the bot wallet into the pool, minting the pool tokens to
+    // `pool_token_account`. Pools restricting SOL deposits to an authority are not supported.
+    fn deposit_sol_instruction(&self, pool: &StakePool, pool_token_account: Pubkey, lamports: u64) -> Result<Instruction> {
+        let mut data = vec![DEPOSIT_SOL_INSTRUCTION];
+        data.extend_from_slice(&lamports.to_le_bytes());
+        Ok(Instruction {
+            program_id: Pubkey::from_str(STAKE_POOL_PROGRAM_ID)?,
+            accounts: vec![
+                AccountMeta::new(pool.address, false),
+                AccountMeta::new_readonly(pool.withdraw_authority, false),
+                AccountMeta::new(pool.reserve_stake, false),
+                AccountMeta::new(self.signer.pubkey(), true),
+                AccountMeta::new(pool_token_account, false),
+                AccountMeta::new(pool.manager_fee_account, false),
+                // Without a referrer the referral share goes to the manager as well
+                AccountMeta::new(pool.manager_fee_account, false),
+                AccountMeta::new(pool.pool_mint, false),
+                AccountMeta::new_readonly(system_program::id(), false),
+                AccountMeta::new_readonly(pool.token_program_id, false),
+            ],
+            data,
+        })
+    }
+
     pub async fn get_balance(&self, wallet_pubkey: &Pubkey) -> Result<u64> {
         let response = self.send_rpc_request(
             "getBalance",
@@ -123,6 +827,7 @@ impl LockinClient {
         })
     }
 
+    #[tracing::instrument(name = "jupiter.quote", skip_all, fields(amount = amount, slippage_bps = slippage_bps))]
     pub async fn get_quote(
         &self,
         amount: u64,
@@ -137,13 +842,16 @@ impl LockinClient {
             slippage_bps,
             ..QuoteRequest::default()
         };
-        self.jupiter_swap_api_client
-            .quote(&quote_request)
-            .await
-            .context("Failed to get quote from Jupiter swap API")
-            .map_err(|e| LockinClientError::QuoteError(e.to_string()).into())
+        let request = async {
+            retry_with_backoff(&retry::JUPITER, "Jupiter quote", |_| true, || self.jupiter.quote(&quote_request))
+                .await
+                .context("Failed to get quote from Jupiter swap API")
+                .map_err(|e| LockinClientError::QuoteError(e.to_string()).into())
+        };
+        circuit_breaker::JUPITER.call(|_| true, request).await
     }
 
+    #[tracing::instrument(name = "jupiter.swap", skip_all)]
     pub async fn perform_swap(
         &self,
         test_wallet: Pubkey,
@@ -154,17 +862,21 @@ impl LockinClient {
             destination_token_account: Some(receiving_address),
             ..TransactionConfig::default()
         };
-        self.jupiter_swap_api_client
-            .swap(&SwapRequest {
-                user_public_key: test_wallet,
-                quote_response: quote_response.clone(),
-                config,
-            })
-            .await
-            .context("Failed to perform swap with Jupiter swap API")
-            .map_err(|e| LockinClientError::SwapError(e.to_string()).into())
+        let request = async {
+            self.jupiter
+                .swap(&SwapRequest {
+                    user_public_key: test_wallet,
+                    quote_response: quote_response.clone(),
+                    config,
+                })
+                .await
+                .context("Failed to perform swap with Jupiter swap API")
+                .map_err(|e| LockinClientError::SwapError(e.to_string()).into())
+        };
+        circuit_breaker::JUPITER.call(|_| true, request).await
     }
 
+    #[tracing::instrument(name = "jupiter.swap_instructions", skip_all)]
     pub async fn get_swap_instructions(
         &self,
         test_wallet: Pubkey,
@@ -175,32 +887,165 @@ impl LockinClient {
             destination_token_account: Some(receiving_address),
             ..TransactionConfig::default()
         };
-        self.jupiter_swap_api_client
-            .swap_instructions(&SwapRequest {
-                user_public_key: test_wallet,
-                quote_response,
-                config,
-            })
-            .await
-            .context("Failed to get swap instructions from Jupiter swap API")
-            .map_err(|e| LockinClientError::SwapInstructionsError(e.to_string()).into())
+        let request = async {
+            self.jupiter
+                .swap_instructions(&SwapRequest {
+                    user_public_key: test_wallet,
+                    quote_response,
+                    config,
+                })
+                .await
+                .context("Failed to get swap instructions from Jupiter swap API")
+                .map_err(|e| LockinClientError::SwapInstructionsError(e.to_string()).into())
+        };
+        circuit_breaker::JUPITER.call(|_| true, request).await
+    }
+
+    #[tracing::instrument(name = "solana.build_transaction", skip_all)]
+    pub async fn create_transaction(
+        &self,
+        instructions: Vec<Instruction>,
+        lookup_table_addresses: &[Pubkey],
+        tokens: &[TokenOutflow],
+    ) -> Result<(VersionedTransaction, u64)> {
+        let payer = self.signer.pubkey();
+        spend_policy::authorize(&instructions, &payer, tokens).await?;
+        let signed = self.sign_versioned_transaction(&instructions, lookup_table_addresses).await;
+        if signed.is_err() {
+            spend_policy::release(&instructions, &payer, tokens).await;
+        }
+        signed
+    }
+
+    async fn sign_versioned_transaction(
+        &self,
+        instructions: &[Instruction],
+        lookup_table_addresses: &[Pubkey],
+    ) -> Result<(VersionedTransaction, u64)> {
+        let (recent_blockhash, last_valid_block_height) = self.get_latest_blockhash().await?;
+        let message = self.compile_message(instructions, lookup_table_addresses, recent_blockhash).await?;
+
+        // Only the bot wallet signs swaps and transfers, as the fee payer
+        let required_signatures = message.header().num_required_signatures;
+        if required_signatures != 1 {
+            return Err(anyhow::anyhow!(
+                "Expected the bot wallet to be the only signer, the message needs {} signatures",
+                required_signatures
+            ));
+        }
+        let signature = self.signer.sign_message(&message.serialize()).await.context("Failed to sign transaction")?;
+        Ok((VersionedTransaction { signatures: vec![signature], message }, last_valid_block_height))
+    }
+
+    // Compiles a message paid for by the bot wallet
+    async fn compile_message(
+        &self,
+        instructions: &[Instruction],
+        lookup_table_addresses: &[Pubkey],
+        recent_blockhash: Hash,
+    ) -> Result<VersionedMessage> {
+        let payer = self.signer.pubkey();
+
+        // Compile against Jupiter's lookup tables when present, legacy messages can't fit most routes
+        if lookup_table_addresses.is_empty() {
+            return Ok(VersionedMessage::Legacy(Message::new_with_blockhash(instructions, Some(&payer), &recent_blockhash)));
+        }
+        let lookup_tables = self.get_address_lookup_tables(lookup_table_addresses).await?;
+        Ok(VersionedMessage::V0(
+            v0::Message::try_compile(&payer, instructions, &lookup_tables, recent_blockhash)
+                .context("Failed to compile v0 message")?,
+        ))
+    }
+
+    // Builds a legacy transaction paid for and signed by the bot wallet, and by `co_signers` when other
+    // accounts have to sign too
+    async fn sign_legacy_transaction(&self, instructions: &[Instruction], co_signers: &[&Keypair]) -> Result<Transaction> {
+        Ok(self.sign_expiring_legacy_transaction(instructions, co_signers).await?.0)
+    }
+
+    // Like sign_legacy_transaction, also returning the last block height at which the transaction can land
+    async fn sign_expiring_legacy_transaction(
+        &self,
+        instructions: &[Instruction],
+        co_signers: &[&Keypair],
+    ) -> Result<(Transaction, u64)> {
+        let payer = self.signer.pubkey();
+        spend_policy::authorize(instructions, &payer, &[]).await?;
+        let signed = self.sign_legacy_message(instructions, co_signers).await;
+        if signed.is_err() {
+            spend_policy::release(instructions, &payer, &[]).await;
+        }
+        signed
     }
 
-    pub async fn create_transaction(&self, instructions: Vec<Instruction>) -> Result<Transaction> {
-        let recent_blockhash = self.send_rpc_request("getRecentBlockhash", json!([]))
-            .await?["result"]["value"]["blockhash"]
+    async fn sign_legacy_message(&self, instructions: &[Instruction], co_signers: &[&Keypair]) -> Result<(Transaction, u64)> {
+        let payer = self.signer.pubkey();
+        let (recent_blockhash, last_valid_block_height) = self.get_latest_blockhash().await?;
+        let mut transaction =
+            Transaction::new_unsigned(Message::new_with_blockhash(instructions, Some(&payer), &recent_blockhash));
+        if !co_signers.is_empty() {
+            transaction
+                .try_partial_sign(co_signers, recent_blockhash)
+                .context("Failed to sign transaction")?;
+        }
+        // The fee payer's signature always comes first
+        transaction.signatures[0] =
+            self.signer.sign_message(&transaction.message_data()).await.context("Failed to sign transaction")?;
+        Ok((transaction, last_valid_block_height))
+    }
+
+    // Returns the latest blockhash together with the last block height at which it is still valid
+    pub async fn get_latest_blockhash(&self) -> Result<(Hash, u64)> {
+        let response = self
+            .send_rpc_request("getLatestBlockhash", json!([{ "commitment": "confirmed" }]))
+            .await?;
+        let value = &response["result"]["value"];
+        let blockhash = value["blockhash"]
             .as_str()
             .ok_or_else(|| {
                 LockinClientError::TransactionError("Invalid response format for blockhash".to_string())
             })?
             .parse()
             .context("Failed to parse blockhash")?;
-        let mut transaction = Transaction::new_with_payer(&instructions, Some(&self.keypair.pubkey()));
-        transaction.sign(&[&self.keypair], recent_blockhash);
-        Ok(transaction)
+        let last_valid_block_height = value["lastValidBlockHeight"].as_u64().ok_or_else(|| {
+            LockinClientError::TransactionError("Invalid response format for lastValidBlockHeight".to_string())
+        })?;
+        Ok((blockhash, last_valid_block_height))
     }
 
-    pub async fn send_transaction(&self, transaction: &Transaction) -> Result<serde_json::Value> {
+    pub async fn get_block_height(&self) -> Result<u64> {
+        let response = self
+            .send_rpc_request("getBlockHeight", json!([{ "commitment": "confirmed" }]))
+            .await?;
+        response["result"].as_u64().ok_or_else(|| {
+            LockinClientError::TransactionError("Invalid response format for block height".to_string()).into()
+        })
+    }
+
+    async fn get_address_lookup_tables(&self, addresses: &[Pubkey]) -> Result<Vec<AddressLookupTableAccount>> {
+        let accounts = self
+            .rpc
+            .get_multiple_accounts(addresses)
+            .await
+            .context("Failed to fetch address lookup tables")?;
+        addresses
+            .iter()
+            .zip(accounts)
+            .map(|(key, account)| {
+                let account = account.ok_or_else(|| {
+                    LockinClientError::TransactionError(format!("Address lookup table {} not found", key))
+                })?;
+                let table = AddressLookupTable::deserialize(&account.data)
+                    .context("Failed to deserialize address lookup table")?;
+                Ok(AddressLookupTableAccount {
+                    key: *key,
+                    addresses: table.addresses.to_vec(),
+                })
+            })
+            .collect()
+    }
+
+    pub async fn send_transaction(&self, transaction: &VersionedTransaction) -> Result<serde_json::Value> {
         let serialized_transaction = bincode::serialize(transaction).context("Failed to serialize transaction")?;
         let base64_transaction = base64_engine.encode(&serialized_transaction);
         self.send_rpc_request(
@@ -211,21 +1056,86 @@ impl LockinClient {
         .context("Failed to send transaction")
     }
 
+    // Submits through the configured backend and returns the transaction signature
+    #[tracing::instrument(name = "solana.submit", skip_all)]
+    pub async fn submit_transaction(&self, transaction: &VersionedTransaction) -> Result<String> {
+        match &self.submission_backend {
+            SubmissionBackend::Rpc => {
+                let send_transaction_response = self.send_transaction(transaction).await?;
+                println!(
+                    "Send Transaction Response: {:#?}",
+                    send_transaction_response
+                );
+                send_transaction_response["result"]
+                    .as_str()
+                    .map(|signature| signature.to_string())
+                    .ok_or_else(|| {
+                        LockinClientError::TransactionError(format!(
+                            "sendTransaction failed: {}",
+                            send_transaction_response["error"]
+                        ))
+                        .into()
+                    })
+            }
+            SubmissionBackend::Jito { block_engine_url, .. } => {
+                let bundle_id = self.send_bundle(block_engine_url, transaction).await?;
+                println!("Jito Bundle ID: {}", bundle_id);
+                transaction
+                    .signatures
+                    .first()
+                    .map(|signature| signature.to_string())
+                    .ok_or_else(|| LockinClientError::TransactionError("Transaction is not signed".to_string()).into())
+            }
+        }
+    }
+
+    // Sends a single-transaction bundle to the Jito block engine
+    pub async fn send_bundle(&self, block_engine_url: &str, transaction: &VersionedTransaction) -> Result<String> {
+        let serialized_transaction = bincode::serialize(transaction).context("Failed to serialize transaction")?;
+        let base58_transaction = bs58::encode(serialized_transaction).into_string();
+        let response = self
+            .client
+            .post(format!("{}/api/v1/bundles", block_engine_url))
+            .json(&json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": "sendBundle",
+                "params": [[base58_transaction]]
+            }))
+            .send()
+            .await
+            .context("Failed to send bundle to Jito block engine")?
+            .json::<serde_json::Value>()
+            .await
+            .context("Failed to parse Jito block engine response")?;
+        response["result"]
+            .as_str()
+            .map(|bundle_id| bundle_id.to_string())
+            .ok_or_else(|| {
+                LockinClientError::TransactionError(format!("sendBundle failed: {}", response["error"])).into()
+            })
+    }
+
     pub async fn check_transaction_confirmation(
         &self,
         transaction_signature: &str,
     ) -> Result<serde_json::Value> {
         self.send_rpc_request(
             "getTransaction",
-            json!([transaction_signature, { "encoding": "json" }]),
+            json!([transaction_signature, {
+                "encoding": "json",
+                "commitment": "confirmed",
+                "maxSupportedTransactionVersion": 0
+            }]),
         )
         .await
         .context("Failed to send request for transaction confirmation")
     }
 
+    #[tracing::instrument(name = "solana.simulate", skip_all)]
     pub async fn simulate_transaction(
         &self,
-        transaction: &Transaction,
+        transaction: &VersionedTransaction,
     ) -> Result<serde_json::Value> {
         let serialized_transaction = bincode::serialize(transaction).context("Failed to serialize transaction")?;
         let base64_transaction = base64_engine.encode(&serialized_transaction);
@@ -242,24 +1152,34 @@ impl LockinClient {
         wallet_address: Pubkey,
         token_mint_address: Pubkey,
     ) -> Result<Pubkey> {
-        let associated_token_address = get_associated_token_address(&wallet_address, &token_mint_address);
-        match self.rpc_client.get_account(&associated_token_address) {
+        let (associated_token_address, program_id) =
+            self.associated_token_address(wallet_address, token_mint_address).await?;
+        match self.rpc.get_account(&associated_token_address).await {
             Ok(_) => Ok(associated_token_address),
+            Err(_) if dry_run::is_enabled() => {
+                dry_run::record_action(
+                    "create_associated_token_account",
+                    json!({
+                        "wallet": wallet_address.to_string(),
+                        "mint": token_mint_address.to_string(),
+                        "token_program": program_id.to_string(),
+                        "address": associated_token_address.to_string(),
+                    }),
+                )
+                .await;
+                Ok(associated_token_address)
+            }
             Err(_) => {
                 let create_ata_instruction = create_associated_token_account(
-                    &self.keypair.pubkey(),
+                    &self.signer.pubkey(),
                     &wallet_address,
                     &token_mint_address,
-                    &token_program_id(),
-                );
-                let transaction = Transaction::new_signed_with_payer(
-                    &[create_ata_instruction],
-                    Some(&self.keypair.pubkey()),
-                    &[&self.keypair],
-                    self.rpc_client.get_latest_blockhash().context("Failed to get latest blockhash")?,
+                    &program_id,
                 );
-                self.rpc_client
+                let transaction = self.sign_legacy_transaction(&[create_ata_instruction], &[]).await?;
+                self.rpc
                     .send_and_confirm_transaction(&transaction)
+                    .await
                     .context("Failed to create associated token account")?;
                 Ok(associated_token_address)
             }
@@ -270,47 +1190,180 @@ impl LockinClient {
         &self,
         input_mint: Pubkey,
         output_mint: Pubkey,
-        amount: f64,
+        amount_lamports: u64,
         receiving_address: Pubkey,
         initial_slippage_bps: u16,
-    ) -> Result<()> {
-        const SMALL_FEE: f64 = 0.0001;
-        const RETRY_LIMIT: usize = 3;
-        const _CONFIRMATION_RETRIES: usize = 5;
-        const MAX_SLIPPAGE_BPS: u16 = 2500;
+        stake: bool,
+    ) -> Result<SwapResult> {
+        let sending_wallet = self.signer.pubkey();
+        let sol_balance = self.get_balance(&sending_wallet).await?;
+        println!("SOL balance in Bot Wallet: {} lamports", sol_balance);
 
-        let sending_wallet = self.keypair.pubkey();
-        let sol_balance = self.get_balance(&sending_wallet).await? as f64 / LAMPORTS_PER_SOL as f64;
-        println!("SOL balance in Bot Wallet: {} SOL", sol_balance);
+        let platform_fee = platform_fee_lamports(amount_lamports);
+        let max_spendable_amount = amount_lamports.saturating_sub(platform_fee);
+        // Jupiter only routes on mainnet, so devnet exercises signing, submission and confirmation with a plain
+        // transfer. SOL kept as SOL is a plain transfer everywhere.
+        let direct_transfer =
+            (config().network == Network::Devnet && !dry_run::is_enabled()) || output_mint == input_mint;
+        // SOL kept as SOL can be deposited into the stake pool instead
+        let stake_pool = if stake && output_mint == input_mint { Some(self.stake_pool().await?) } else { None };
 
-        let max_spendable_amount = (amount * 0.9) - SMALL_FEE;
-        let gas_fees = 0.004 * LAMPORTS_PER_SOL as f64;
-        let rent_exemption_fee = self.get_minimum_balance_for_rent_exemption(165).await? as f64;
-        let total_fees = gas_fees + rent_exemption_fee + SMALL_FEE * LAMPORTS_PER_SOL as f64;
-        let max_swap_amount = (max_spendable_amount * LAMPORTS_PER_SOL as f64 - total_fees) as u64;
+        // The receiving token account is created by the bot wallet ahead of the swap when missing
+        let rent_exemption_fee = match &stake_pool {
+            Some(pool) => self.token_account_creation_cost(receiving_address, pool.pool_mint).await?,
+            None if direct_transfer => 0,
+            None => self.token_account_creation_cost(receiving_address, output_mint).await?,
+        };
+        // The network fee is priced on the actual message, built for the amount left after the other costs.
+        // It doesn't depend on the amount, only on the signatures and the priority fee.
+        let provisional_amount = max_spendable_amount.saturating_sub(rent_exemption_fee);
+        if provisional_amount == 0 {
+            eprintln!(
+                "Insufficient balance for swap after accounting for rent. Swap Amount: {} lamports, Rent: {} lamports",
+                max_spendable_amount, rent_exemption_fee
+            );
+            return Ok(SwapResult::NotSent(NotSentReason::InsufficientAmount {
+                amount_lamports: max_spendable_amount,
+                costs_lamports: rent_exemption_fee,
+            }));
+        }
+        let network_fee = if let Some(pool) = &stake_pool {
+            let pool_token_account =
+                get_associated_token_address_with_program_id(&receiving_address, &pool.pool_mint, &pool.token_program_id);
+            self.estimate_instruction_fee(self.deposit_sol_instruction(pool, pool_token_account, provisional_amount)?)
+                .await?
+        } else if direct_transfer {
+            self.estimate_instruction_fee(system_instruction::transfer(
+                &sending_wallet,
+                &receiving_address,
+                provisional_amount,
+            ))
+            .await?
+        } else {
+            self.estimate_swap_fee(input_mint, output_mint, provisional_amount, receiving_address, initial_slippage_bps)
+                .await?
+        };
+        let gas_fees = network_fee.saturating_add(self.submission_backend.tip_lamports());
+        let total_fees = gas_fees.saturating_add(rent_exemption_fee);
+        let max_swap_amount = max_spendable_amount.saturating_sub(total_fees);
 
-        if max_swap_amount <= 0 {
+        if max_swap_amount == 0 {
             eprintln!(
                 "Insufficient balance for swap after accounting for fees. Swap Amount: {} lamports, Total fees: {} lamports",
-                max_spendable_amount * LAMPORTS_PER_SOL as f64,
-                total_fees as u64
+                max_spendable_amount, total_fees
             );
-            return Ok(());
+            return Ok(SwapResult::NotSent(NotSentReason::InsufficientAmount {
+                amount_lamports: max_spendable_amount,
+                costs_lamports: total_fees,
+            }));
         }
 
-        println!("SOL Swap Amount: {}", max_spendable_amount);
-        println!("Estimated Gas Fees: {}", gas_fees as u64);
-        println!("Estimated Rent Exemption Fees: {}", rent_exemption_fee as u64);
-        println!("Small Fee: {}", SMALL_FEE * LAMPORTS_PER_SOL as f64);
+        println!("SOL Swap Amount: {} lamports", max_spendable_amount);
+        println!("Estimated Gas Fees: {}", gas_fees);
+        println!("Estimated Rent Exemption Fees: {}", rent_exemption_fee);
+        println!("Platform Fee: {} ({} bps)", platform_fee, config().platform_fee_bps);
         println!("Max Swap Amount: {}", max_swap_amount);
 
+        if direct_transfer {
+            return self
+                .execute_transfer(receiving_address, max_swap_amount, platform_fee, stake_pool.as_ref())
+                .await;
+        }
+
+        self.swap_with_retries(
+            input_mint,
+            output_mint,
+            max_swap_amount,
+            receiving_address,
+            initial_slippage_bps,
+            Some(platform_fee),
+        )
+        .await
+    }
+
+    // The transfer of the platform fee to the treasury, sent in the same transaction as the swap
+    fn platform_fee_instruction(&self, platform_fee: u64) -> Result<Option<Instruction>> {
+        let Some(treasury_address) = config().treasury_address.as_deref() else {
+            return Ok(None);
+        };
+        if platform_fee == 0 {
+            return Ok(None);
+        }
+        let treasury = Pubkey::from_str(treasury_address).context("Invalid TREASURY_ADDRESS")?;
+        Ok(Some(system_instruction::transfer(&self.signer.pubkey(), &treasury, platform_fee)))
+    }
+
+    // Swaps `amount` base units of an SPL token held by the bot wallet, network fees are paid in SOL on top
+    pub async fn execute_token(
+        &self,
+        input_mint: Pubkey,
+        output_mint: Pubkey,
+        amount: u64,
+        receiving_address: Pubkey,
+        initial_slippage_bps: u16,
+    ) -> Result<SwapResult> {
+        if config().network == Network::Devnet && !dry_run::is_enabled() {
+            println!("Devnet: Jupiter does not route on devnet, leaving {} of {} in the bot wallet", amount, input_mint);
+            return Ok(SwapResult::NotSent(NotSentReason::DevnetUnroutable));
+        }
+        self.swap_with_retries(input_mint, output_mint, amount, receiving_address, initial_slippage_bps, None)
+            .await
+    }
+
+    // Values `amount` base units of `mint` in lamports for the spending limits, with a quote into SOL
+    async fn token_outflow(&self, mint: Pubkey, amount: u64) -> Result<TokenOutflow> {
+        let lamports = match spend_policy::is_enabled() {
+            true => {
+                self.get_quote(amount, mint, spl_token::native_mint::id(), DEFAULT_SLIPPAGE_BPS)
+                    .await?
+                    .out_amount
+            }
+            false => 0,
+        };
+        Ok(TokenOutflow { mint, amount, lamports })
+    }
+
+    // Quotes, simulates and submits the swap, doubling the slippage after each failed simulation. A swap
+    // that never confirms refunds the receiving address when the input is SOL. SOL swaps pass their
+    // platform fee, which goes to the treasury in the swap transaction and so is refunded with the amount.
+    #[tracing::instrument(name = "solana.swap", skip_all, fields(input_mint = %input_mint, output_mint = %output_mint, amount = swap_amount))]
+    async fn swap_with_retries(
+        &self,
+        input_mint: Pubkey,
+        output_mint: Pubkey,
+        swap_amount: u64,
+        receiving_address: Pubkey,
+        initial_slippage_bps: u16,
+        sol_platform_fee: Option<u64>,
+    ) -> Result<SwapResult> {
+        const RETRY_LIMIT: usize = 3;
+
+        let sending_wallet = self.signer.pubkey();
+        let tokens = match input_mint == spl_token::native_mint::id() {
+            true => Vec::new(),
+            false => vec![self.token_outflow(input_mint, swap_amount).await?],
+        };
+        let platform_fee = sol_platform_fee.unwrap_or(0);
+        let platform_fee_instruction = self.platform_fee_instruction(platform_fee)?;
         let mut slippage_bps = initial_slippage_bps;
+        let mut last_quote = None;
+        let mut last_error = String::from("Unknown error");
+        let mut last_failure = None;
+        let mut attempts = 0;
 
         for attempt in 0..RETRY_LIMIT {
+            attempts = attempt + 1;
             let quote_response = self
-                .get_quote(max_swap_amount, input_mint, output_mint, slippage_bps)
+                .get_quote(swap_amount, input_mint, output_mint, slippage_bps)
                 .await?;
             println!("Quote Response: {:#?}", quote_response);
+            last_quote = serde_json::to_value(&quote_response).ok();
+            if let Some(quote) = &last_quote {
+                if let Err(reason) = quote_check::check(&input_mint, &output_mint, quote).await {
+                    eprintln!("Aborting swap: {}", reason);
+                    return Err(LockinClientError::QuoteRejected { reason, quote: last_quote }.into());
+                }
+            }
 
             let receiving_token_address = self
                 .get_or_create_associated_token_address(receiving_address, output_mint)
@@ -333,110 +1386,900 @@ impl LockinClient {
                         swap_instructions_response
                     );
 
-                    let instructions = self.collect_swap_instructions(swap_instructions_response);
+                    let lookup_table_addresses = swap_instructions_response.address_lookup_table_addresses.clone();
+                    let mut instructions = self.collect_swap_instructions(swap_instructions_response).await?;
+                    if let Some(tip_instruction) = self.submission_backend.tip_instruction(&sending_wallet) {
+                        instructions.push(tip_instruction);
+                    }
+                    instructions.extend(platform_fee_instruction.clone());
 
-                    let transaction = self.create_transaction(instructions).await?;
+                    let (mut transaction, mut last_valid_block_height) =
+                        self.create_transaction(instructions.clone(), &lookup_table_addresses, &tokens).await?;
                     println!("Transaction: {:#?}", transaction);
 
-                    let simulation_response = self.simulate_transaction(&transaction).await?;
+                    let simulation_response = match self.simulate_transaction(&transaction).await {
+                        Ok(simulation_response) => simulation_response,
+                        Err(e) => {
+                            spend_policy::release(&instructions, &sending_wallet, &tokens).await;
+                            return Err(e);
+                        }
+                    };
                     println!("Simulation Response: {:#?}", simulation_response);
 
                     if simulation_response["result"]["err"].is_null() {
-                        let send_transaction_response = self.send_transaction(&transaction).await?;
-                        println!(
-                            "Send Transaction Response: {:#?}",
-                            send_transaction_response
-                        );
-
-                        if self
-                            .confirm_transaction(&send_transaction_response["result"].as_str().unwrap())
-                            .await
-                        {
-                            return Ok(());
+                        if dry_run::is_enabled() {
+                            dry_run::record_action(
+                                "solana_swap",
+                                json!({
+                                    "input_mint": input_mint.to_string(),
+                                    "output_mint": output_mint.to_string(),
+                                    "amount": swap_amount,
+                                    "receiving_address": receiving_address.to_string(),
+                                    "slippage_bps": slippage_bps,
+                                    "platform_fee_lamports": platform_fee,
+                                    "treasury_address": config().treasury_address,
+                                    "simulation": simulation_response["result"].clone(),
+                                }),
+                            )
+                            .await;
+                            return Ok(SwapResult::DryRun);
                         }
 
-                        self.initiate_refund(receiving_address, max_swap_amount).await?;
-                        return Err(LockinClientError::TransactionConfirmationError(
-                            "Transaction failed or not yet confirmed.".to_string(),
-                        )
+                        // An expired blockhash means the transaction can no longer land, so it is safe to rebuild and re-sign
+                        let mut unsettled_reason = "Transaction expired without confirming.".to_string();
+                        for rebuild in 0..=BLOCKHASH_REBUILD_LIMIT {
+                            let signature = self.submit_transaction(&transaction).await?;
+                            match self.confirm_transaction(&signature, last_valid_block_height).await {
+                                ConfirmationStatus::Confirmed(confirmation) => {
+                                    spend_policy::record_spend(&signature, &instructions, &sending_wallet, &tokens).await;
+                                    let quoted_out = last_quote
+                                        .as_ref()
+                                        .and_then(|quote| quote["outAmount"].as_str()?.parse::<u64>().ok());
+                                    return Ok(SwapResult::Delivered(Box::new(SwapOutcome {
+                                        signature,
+                                        in_amount: swap_amount,
+                                        out_amount: token_balance_change(&confirmation, &receiving_address, &output_mint)
+                                            .or(quoted_out),
+                                        network_fee_lamports: confirmation["result"]["meta"]["fee"]
+                                            .as_u64()
+                                            .unwrap_or(0),
+                                        price_impact_pct: last_quote
+                                            .as_ref()
+                                            .map(quote_price_impact_pct)
+                                            .unwrap_or(0.0),
+                                        route_plan: last_quote.as_ref().map(route_plan_summary),
+                                        platform_fee_lamports: platform_fee,
+                                        platform_fee_to_treasury: platform_fee_instruction.is_some(),
+                                        stake_deposit: None,
+                                    })));
+                                }
+                                ConfirmationStatus::Expired if rebuild < BLOCKHASH_REBUILD_LIMIT => {
+                                    spend_policy::release(&instructions, &sending_wallet, &tokens).await;
+                                    escalate_priority_fee(&mut instructions);
+                                    (transaction, last_valid_block_height) = self
+                                        .create_transaction(instructions.clone(), &lookup_table_addresses, &tokens)
+                                        .await?;
+                                }
+                                ConfirmationStatus::Expired => {
+                                    spend_policy::release(&instructions, &sending_wallet, &tokens).await;
+                                    break;
+                                }
+                                ConfirmationStatus::Failed(err) => {
+                                    spend_policy::release(&instructions, &sending_wallet, &tokens).await;
+                                    eprintln!("Swap transaction {} failed on-chain: {}", signature, err);
+                                    unsettled_reason = format!("Transaction {} failed on-chain: {}", signature, err);
+                                    break;
+                                }
+                                // Refunding now could pay the user twice if the swap still lands
+                                ConfirmationStatus::TimedOut => {
+                                    return Err(LockinClientError::RetriesExhausted {
+                                        attempts: attempt + 1,
+                                        reason: format!(
+                                            "Transaction {} is unconfirmed and may still land, check it on-chain before re-driving",
+                                            signature
+                                        ),
+                                        quote: last_quote,
+                                        failure: None,
+                                    }
+                                    .into());
+                                }
+                            }
+                        }
+
+                        // The transaction failed on-chain, or its last blockhash expired without it landing, so
+                        // the swap input is still in the bot wallet
+                        let Some(refund_lamports) = sol_platform_fee.map(|fee| swap_amount.saturating_add(fee)) else {
+                            // Token inputs stay in the bot wallet for manual reconciliation
+                            return Err(LockinClientError::RetriesExhausted {
+                                attempts: attempt + 1,
+                                reason: unsettled_reason,
+                                quote: last_quote,
+                                failure: None,
+                            }
+                            .into());
+                        };
+                        let refund_signature = self.initiate_refund(receiving_address, refund_lamports).await?;
+                        return Err(LockinClientError::SwapRefunded {
+                            reason: unsettled_reason,
+                            refund_signature,
+                        }
                         .into());
                     } else {
-                        eprintln!("Simulation failed: {:#?}", simulation_response);
-                        slippage_bps = (slippage_bps * 2).min(MAX_SLIPPAGE_BPS);
+                        spend_policy::release(&instructions, &sending_wallet, &tokens).await;
+                        let failure = SimulationFailure::parse(&simulation_response["result"]);
+                        eprintln!("Simulation failed ({}): {:#?}", failure, simulation_response);
+                        last_error = format!("Simulation failed: {}", failure);
+                        last_failure = Some(failure.category());
+                        match failure {
+                            // Retrying can't make up for funds the bot wallet doesn't have
+                            SimulationFailure::InsufficientFunds => break,
+                            // The receiving token account is created again on the next attempt, at the same slippage
+                            SimulationFailure::AccountMissing => {}
+                            // AMM program errors are often a slippage check under another name
+                            SimulationFailure::SlippageExceeded
+                            | SimulationFailure::ProgramError { .. }
+                            | SimulationFailure::Other(_) => {
+                                slippage_bps = (slippage_bps * 2).min(MAX_SLIPPAGE_BPS);
+                            }
+                        }
                     }
                 }
                 Err(e) => {
-                    eprintln!("Error performing swap: {:?}", e);
-                    if attempt == RETRY_LIMIT - 1 {
-                        self.initiate_refund(receiving_address, max_swap_amount).await?;
-                        return Err(e);
+                    eprintln!("Error performing swap: {:?} (attempt {})", e, attempt + 1);
+                    last_error = e.to_string();
+                }
+            }
+        }
+
+        // Leave the funds in the bot wallet so the swap can be re-driven from the dead-letter queue
+        eprintln!("Failed to execute swap after {} attempts", attempts);
+        Err(LockinClientError::RetriesExhausted {
+            attempts,
+            reason: last_error,
+            quote: last_quote,
+            failure: last_failure,
+        }
+        .into())
+    }
+
+    // Sends the swap amount as SOL to the receiving address, or deposits it into `stake_pool` for them, for
+    // SOL kept as SOL and on devnet standing in for the Jupiter swap
+    async fn execute_transfer(
+        &self,
+        receiving_address: Pubkey,
+        lamports: u64,
+        platform_fee: u64,
+        stake_pool: Option<&StakePool>,
+    ) -> Result<SwapResult> {
+        if dry_run::is_enabled() {
+            dry_run::record_action(
+                "solana_transfer",
+                json!({
+                    "amount": lamports,
+                    "receiving_address": receiving_address.to_string(),
+                    "stake_pool": stake_pool.map(|pool| pool.address.to_string()),
+                    "platform_fee_lamports": platform_fee,
+                    "treasury_address": config().treasury_address,
+                }),
+            )
+            .await;
+            return Ok(SwapResult::DryRun);
+        }
+        let (transfer_instruction, stake_deposit) = match stake_pool {
+            Some(pool) => {
+                println!("Depositing {} lamports into stake pool {} for {}", lamports, pool.address, receiving_address);
+                let pool_token_account = self
+                    .get_or_create_associated_token_address(receiving_address, pool.pool_mint)
+                    .await?;
+                let stake_deposit = StakeDeposit {
+                    stake_pool: pool.address.to_string(),
+                    pool_mint: pool.pool_mint.to_string(),
+                    pool_token_account: pool_token_account.to_string(),
+                    lamports,
+                };
+                (self.deposit_sol_instruction(pool, pool_token_account, lamports)?, Some(stake_deposit))
+            }
+            None => {
+                println!("Transferring {} lamports to {} instead of swapping", lamports, receiving_address);
+                (system_instruction::transfer(&self.signer.pubkey(), &receiving_address, lamports), None)
+            }
+        };
+        let platform_fee_instruction = self.platform_fee_instruction(platform_fee)?;
+        let mut instructions = self.compute_budget_instructions(std::slice::from_ref(&transfer_instruction)).await?;
+        instructions.push(transfer_instruction);
+        instructions.extend(platform_fee_instruction.clone());
+
+        let (mut transaction, mut last_valid_block_height) =
+            self.create_transaction(instructions.clone(), &[], &[]).await?;
+        let mut unsettled_reason = "Transfer failed or not yet confirmed.".to_string();
+        for rebuild in 0..=BLOCKHASH_REBUILD_LIMIT {
+            let signature = self.submit_transaction(&transaction).await?;
+            match self.confirm_transaction(&signature, last_valid_block_height).await {
+                ConfirmationStatus::Confirmed(confirmation) => {
+                    spend_policy::record_spend(&signature, &instructions, &self.signer.pubkey(), &[]).await;
+                    let out_amount = match &stake_deposit {
+                        Some(stake_deposit) => Pubkey::from_str(&stake_deposit.pool_mint)
+                            .ok()
+                            .and_then(|pool_mint| token_balance_change(&confirmation, &receiving_address, &pool_mint)),
+                        None => Some(lamports),
+                    };
+                    return Ok(SwapResult::Delivered(Box::new(SwapOutcome {
+                        signature,
+                        in_amount: lamports,
+                        out_amount,
+                        network_fee_lamports: confirmation["result"]["meta"]["fee"].as_u64().unwrap_or(0),
+                        price_impact_pct: 0.0,
+                        route_plan: None,
+                        platform_fee_lamports: platform_fee,
+                        platform_fee_to_treasury: platform_fee_instruction.is_some(),
+                        stake_deposit,
+                    })));
+                }
+                ConfirmationStatus::Expired if rebuild < BLOCKHASH_REBUILD_LIMIT => {
+                    spend_policy::release(&instructions, &self.signer.pubkey(), &[]).await;
+                    escalate_priority_fee(&mut instructions);
+                    (transaction, last_valid_block_height) =
+                        self.create_transaction(instructions.clone(), &[], &[]).await?;
+                }
+                ConfirmationStatus::Failed(err) => {
+                    spend_policy::release(&instructions, &self.signer.pubkey(), &[]).await;
+                    eprintln!("Transfer transaction {} failed on-chain: {}", signature, err);
+                    unsettled_reason = format!("Transfer {} failed on-chain: {}", signature, err);
+                    break;
+                }
+                ConfirmationStatus::Expired => {
+                    spend_policy::release(&instructions, &self.signer.pubkey(), &[]).await;
+                    break;
+                }
+                // It may still land, so its reservation is kept
+                ConfirmationStatus::TimedOut => break,
+            }
+        }
+        // Nothing was sent, so the transfer goes to the dead-letter queue rather than being refunded
+        Err(LockinClientError::RetriesExhausted {
+            attempts: 1,
+            reason: unsettled_reason,
+            quote: None,
+            failure: None,
+        }
+        .into())
+    }
+
+    // Waits for a signature notification over WebSocket, falling back to polling if the subscription is unavailable
+    #[tracing::instrument(name = "solana.confirm", skip_all, fields(signature = transaction_signature))]
+    async fn confirm_transaction(
+        &self,
+        transaction_signature: &str,
+        last_valid_block_height: u64,
+    ) -> ConfirmationStatus {
+        match self.wait_for_signature(transaction_signature).await {
+            Ok(err) => {
+                // The notification carries no transaction details, fetch them for the fee breakdown
+                let fetch_details = || async move {
+                    match self.check_transaction_confirmation(transaction_signature).await {
+                        Ok(response) if !response["result"].is_null() => Ok(response),
+                        _ => Err(()),
                     }
+                };
+                let details =
+                    retry_with_backoff(&CONFIRMATION_DETAILS_BACKOFF, "getTransaction", |_| true, fetch_details).await;
+                if let Ok(response) = details {
+                    println!("Confirmation Response: {:#?}", response);
+                    return ConfirmationStatus::landed(response);
+                }
+                if !err.is_null() {
+                    return ConfirmationStatus::Failed(err);
                 }
             }
+            Err(e) => eprintln!("WebSocket confirmation unavailable, falling back to polling: {:?}", e),
         }
+        self.poll_confirmation(transaction_signature, last_valid_block_height).await
+    }
+
+    // Subscribes to a signature and returns once the cluster reports it as confirmed, with the
+    // transaction error, null when it succeeded
+    async fn wait_for_signature(&self, transaction_signature: &str) -> Result<serde_json::Value> {
+        let ws_url = self.rpc.ws_url();
+        let (mut ws_stream, _) = connect_async(ws_url.as_str())
+            .await
+            .context("Failed to connect to RPC WebSocket")?;
+        let subscribe = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "signatureSubscribe",
+            "params": [transaction_signature, { "commitment": "confirmed" }]
+        });
+        ws_stream
+            .send(WsMessage::Text(subscribe.to_string()))
+            .await
+            .context("Failed to send signatureSubscribe")?;
 
-        eprintln!("Failed to execute swap after {} attempts", RETRY_LIMIT);
-        Ok(())
+        let notification = timeout(Duration::from_secs(SIGNATURE_SUBSCRIBE_TIMEOUT_SECS), async {
+            while let Some(message) = ws_stream.next().await {
+                if let WsMessage::Text(text) = message.context("RPC WebSocket error")? {
+                    let value: serde_json::Value = serde_json::from_str(&text).context("Invalid RPC WebSocket message")?;
+                    if value["method"] == "signatureNotification" {
+                        return Ok(value);
+                    }
+                }
+            }
+            Err(anyhow::anyhow!("RPC WebSocket closed before confirmation"))
+        })
+        .await
+        .context("Timed out waiting for signature notification")??;
+
+        let _ = ws_stream.close(None).await;
+        let err = notification["params"]["result"]["value"]["err"].clone();
+        if !err.is_null() {
+            eprintln!("Transaction {} failed on-chain: {}", transaction_signature, err);
+        }
+        Ok(err)
+    }
+
+    // Polls for the transaction. Once the usual backoff is used up it keeps polling until the blockhash
+    // expires, so a transaction only comes back TimedOut when it could still land after all of that.
+    async fn poll_confirmation(
+        &self,
+        transaction_signature: &str,
+        last_valid_block_height: u64,
+    ) -> ConfirmationStatus {
+        // Stop waiting once the blockhash can no longer be included in a block
+        let unexpired = |status: &ConfirmationStatus| !matches!(status, ConfirmationStatus::Expired);
+        let check = || self.check_confirmation_once(transaction_signature, last_valid_block_height);
+        let mut confirmation =
+            retry_with_backoff(&CONFIRMATION_POLL_BACKOFF, "Transaction confirmation", unexpired, check).await;
+        if matches!(confirmation, Err(ConfirmationStatus::TimedOut)) {
+            confirmation =
+                retry_with_backoff(&CONFIRMATION_EXPIRY_BACKOFF, "Transaction confirmation", unexpired, check).await;
+        }
+        match confirmation {
+            Ok(response) => {
+                println!("Confirmation Response: {:#?}", response);
+                ConfirmationStatus::landed(response)
+            }
+            Err(status) => status,
+        }
     }
 
-    async fn confirm_transaction(&self, transaction_signature: &str) -> bool {
-        const CONFIRMATION_RETRIES: usize = 5;
-        let mut backoff = 5;
-        for _ in 0..CONFIRMATION_RETRIES {
-            match self.check_transaction_confirmation(transaction_signature).await {
-                Ok(response) => {
-                    if !response["result"].is_null() {
-                        println!("Confirmation Response: {:#?}", response);
-                        return true;
+    async fn check_confirmation_once(
+        &self,
+        transaction_signature: &str,
+        last_valid_block_height: u64,
+    ) -> std::result::Result<serde_json::Value, ConfirmationStatus> {
+        match self.check_transaction_confirmation(transaction_signature).await {
+            Ok(response) if !response["result"].is_null() => return Ok(response),
+            Ok(_) => eprintln!("Transaction not yet confirmed. Retrying..."),
+            Err(e) => eprintln!("Error checking transaction confirmation: {:?}", e),
+        }
+        match self.get_block_height().await {
+            Ok(block_height) if block_height > last_valid_block_height => {
+                eprintln!(
+                    "Blockhash expired: block height {} > last valid block height {}",
+                    block_height, last_valid_block_height
+                );
+                // It may have landed between the two calls, it is only expired if it still isn't found
+                match self.check_transaction_confirmation(transaction_signature).await {
+                    Ok(response) if !response["result"].is_null() => Ok(response),
+                    Ok(_) => Err(ConfirmationStatus::Expired),
+                    Err(e) => {
+                        eprintln!("Error checking transaction confirmation: {:?}", e);
+                        Err(ConfirmationStatus::TimedOut)
                     }
-                    eprintln!("Transaction not yet confirmed. Retrying...");
                 }
-                Err(e) => {
-                    eprintln!("Error checking transaction confirmation: {:?}", e);
+            }
+            Ok(_) => Err(ConfirmationStatus::TimedOut),
+            Err(e) => {
+                eprintln!("Error getting block height: {:?}", e);
+                Err(ConfirmationStatus::TimedOut)
+            }
+        }
+    }
+
+    pub fn pubkey(&self) -> Pubkey {
+        self.signer.pubkey()
+    }
+
+    // Moves lamports deposited to a user's wallet into the bot wallet, the bot pays the network fee
+    pub async fn sweep_sol(&self, owner: &Keypair, lamports: u64) -> Result<String> {
+        let instruction = system_instruction::transfer(&owner.pubkey(), &self.signer.pubkey(), lamports);
+        self.send_sweep(owner, vec![instruction], json!({ "lamports": lamports })).await
+    }
+
+    // Moves `amount` base units of `mint` out of a user's token account into the bot's associated token account
+    pub async fn sweep_token(&self, owner: &Keypair, source: Pubkey, mint: Pubkey, amount: u64, decimals: u8) -> Result<String> {
+        let destination = self
+            .get_or_create_associated_token_address(self.signer.pubkey(), mint)
+            .await?;
+        let program_id = self.token_program_of(mint).await?;
+        let instruction = spl_token::instruction::transfer_checked(
+            &program_id,
+            &source,
+            &mint,
+            &destination,
+            &owner.pubkey(),
+            &[],
+            amount,
+            decimals,
+        )
+        .context("Failed to build token transfer")?;
+        self.send_sweep(owner, vec![instruction], json!({ "mint": mint.to_string(), "amount": amount }))
+            .await
+    }
+
+    async fn send_sweep(&self, owner: &Keypair, instructions: Vec<Instruction>, details: serde_json::Value) -> Result<String> {
+        if dry_run::is_enabled() {
+            let mut details = details;
+            details["owner"] = json!(owner.pubkey().to_string());
+            dry_run::record_action("sweep_deposit", details).await;
+            return Ok("DRY-RUN".to_string());
+        }
+        let transaction = self.sign_legacy_transaction(&instructions, &[owner]).await?;
+        let signature = self
+            .rpc
+            .send_and_confirm_transaction(&transaction)
+            .await
+            .context("Failed to sweep deposit into the bot wallet")?;
+        Ok(signature.to_string())
+    }
+
+    // Moves everything in a user's wallet to `destination`: every token balance into the destination's
+    // associated token accounts, closing the emptied accounts so their rent goes along, then the SOL
+    // left after fees. The user's wallet pays its own fees and the rent of any destination ATA it creates.
+    pub async fn sweep_wallet(&self, owner: &Keypair, destination: Pubkey) -> Result<WalletSweep> {
+        let accounts = self.list_token_accounts(&owner.pubkey()).await?;
+        let destination_atas = accounts
+            .iter()
+            .filter(|account| account.amount > 0)
+            .map(|account| {
+                spl_associated_token_account::get_associated_token_address_with_program_id(
+                    &destination,
+                    &account.mint,
+                    &account.program_id,
+                )
+            })
+            .collect::<Vec<_>>();
+        let existing_atas = self.rpc.get_multiple_accounts(&destination_atas).await?;
+        let mut missing_ata_rent = 0;
+        for (account, existing) in accounts.iter().filter(|account| account.amount > 0).zip(&existing_atas) {
+            if existing.is_none() {
+                missing_ata_rent += self
+                    .get_minimum_balance_for_rent_exemption(token_account_len(&account.program_id))
+                    .await?;
+            }
+        }
+
+        // Reserve the fees of every transaction and the rent of the ATAs created up front, so the
+        // sweep doesn't stop half way with tokens moved but no SOL left to finish
+        let token_transactions = accounts.chunks(TOKEN_ACCOUNTS_PER_SWEEP).count() as u64;
+        let reserved = (token_transactions + 1) * LAMPORTS_PER_SIGNATURE + missing_ata_rent;
+        let balance = self.get_balance(&owner.pubkey()).await?;
+        if !accounts.is_empty() && balance < reserved {
+            return Err(anyhow::anyhow!(
+                "Wallet needs {} lamports for fees and token account rent but holds {}",
+                reserved,
+                balance
+            ));
+        }
+
+        let mut sweep = WalletSweep {
+            signatures: Vec::new(),
+            tokens: Vec::new(),
+            closed_accounts: 0,
+            lamports: 0,
+        };
+        for chunk in accounts.chunks(TOKEN_ACCOUNTS_PER_SWEEP) {
+            let mut instructions = Vec::new();
+            for account in chunk {
+                if account.amount > 0 {
+                    instructions.push(
+                        spl_associated_token_account::instruction::create_associated_token_account_idempotent(
+                            &owner.pubkey(),
+                            &destination,
+                            &account.mint,
+                            &account.program_id,
+                        ),
+                    );
+                    let destination_ata = spl_associated_token_account::get_associated_token_address_with_program_id(
+                        &destination,
+                        &account.mint,
+                        &account.program_id,
+                    );
+                    instructions.push(
+                        spl_token::instruction::transfer_checked(
+                            &account.program_id,
+                            &account.address,
+                            &account.mint,
+                            &destination_ata,
+                            &owner.pubkey(),
+                            &[],
+                            account.amount,
+                            account.decimals,
+                        )
+                        .context("Failed to build token transfer")?,
+                    );
+                    sweep.tokens.push(json!({
+                        "mint": account.mint.to_string(),
+                        "amount": account.amount,
+                        "decimals": account.decimals,
+                    }));
                 }
+                // The emptied account's rent goes straight to the destination
+                instructions.push(
+                    spl_token::instruction::close_account(
+                        &account.program_id,
+                        &account.address,
+                        &destination,
+                        &owner.pubkey(),
+                        &[],
+                    )
+                    .context("Failed to build close account")?,
+                );
+                sweep.closed_accounts += 1;
             }
-            sleep(Duration::from_secs(backoff)).await;
-            backoff *= 2;
+            sweep.signatures.push(self.send_owner_transaction(owner, instructions, "sweep_tokens").await?);
+        }
+
+        // Everything but the last fee goes, an empty system account is simply removed. A transfer that
+        // would create the destination account has to cover its rent exemption.
+        let balance = self.get_balance(&owner.pubkey()).await?;
+        let lamports = balance.saturating_sub(LAMPORTS_PER_SIGNATURE);
+        let destination_exists = self.get_balance(&destination).await? > 0;
+        let rent_exempt_minimum = self.get_minimum_balance_for_rent_exemption(0).await?;
+        if lamports > 0 && (destination_exists || lamports >= rent_exempt_minimum) {
+            let instruction = system_instruction::transfer(&owner.pubkey(), &destination, lamports);
+            sweep.signatures.push(self.send_owner_transaction(owner, vec![instruction], "sweep_sol").await?);
+            sweep.lamports = lamports;
+        } else if lamports > 0 {
+            println!(
+                "Leaving {} lamports in {}, below the {} lamports a new destination account needs",
+                lamports,
+                owner.pubkey(),
+                rent_exempt_minimum
+            );
         }
-        false
+        Ok(sweep)
     }
 
-    pub async fn initiate_refund(&self, recipient: Pubkey, amount: u64) -> Result<()> {
-        let recent_blockhash = self.rpc_client.get_latest_blockhash().context("Failed to get latest blockhash")?;
+    // Lists the SPL token and Token-2022 accounts of `owner`
+    async fn list_token_accounts(&self, owner: &Pubkey) -> Result<Vec<SweptTokenAccount>> {
+        let mut accounts = Vec::new();
+        for program_id in [token_program_id(), Pubkey::from_str(TOKEN_2022_PROGRAM_ID)?] {
+            let response = self
+                .send_rpc_request(
+                    "getTokenAccountsByOwner",
+                    json!([owner.to_string(), { "programId": program_id.to_string() }, { "encoding": "jsonParsed" }]),
+                )
+                .await?;
+            for account in response["result"]["value"].as_array().into_iter().flatten() {
+                let info = &account["account"]["data"]["parsed"]["info"];
+                // Frozen accounts can neither be emptied nor closed
+                if info["state"] == "frozen" {
+                    continue;
+                }
+                let parsed = (|| {
+                    Some(SweptTokenAccount {
+                        address: Pubkey::from_str(account["pubkey"].as_str()?).ok()?,
+                        mint: Pubkey::from_str(info["mint"].as_str()?).ok()?,
+                        program_id,
+                        amount: info["tokenAmount"]["amount"].as_str()?.parse().ok()?,
+                        decimals: info["tokenAmount"]["decimals"].as_u64()? as u8,
+                    })
+                })();
+                if let Some(parsed) = parsed {
+                    accounts.push(parsed);
+                }
+            }
+        }
+        Ok(accounts)
+    }
+
+    // Sends a transaction paid and signed by the user's wallet alone
+    async fn send_owner_transaction(&self, owner: &Keypair, instructions: Vec<Instruction>, action: &str) -> Result<String> {
+        if dry_run::is_enabled() {
+            dry_run::record_action(action, json!({ "owner": owner.pubkey().to_string(), "instructions": instructions.len() }))
+                .await;
+            return Ok("DRY-RUN".to_string());
+        }
+        let transaction = Transaction::new_signed_with_payer(
+            &instructions,
+            Some(&owner.pubkey()),
+            &[owner],
+            self.get_latest_blockhash().await?.0,
+        );
+        let signature = self
+            .rpc
+            .send_and_confirm_transaction(&transaction)
+            .await
+            .with_context(|| format!("Failed to send {} transaction", action))?;
+        Ok(signature.to_string())
+    }
+
+    // Sends a refund recorded in the refunds collection, failed refunds are retried by the poller
+    pub async fn initiate_refund(&self, recipient: Pubkey, amount: u64) -> Result<Option<String>> {
+        if dry_run::is_enabled() {
+            dry_run::record_action(
+                "refund",
+                json!({ "recipient": recipient.to_string(), "lamports": amount }),
+            )
+            .await;
+            return Ok(None);
+        }
+        let refund_id = refunds::start_refund(&recipient, amount).await?;
+        let result = self.send_refund(refund_id, recipient, amount).await;
+        if let Err(e) = refunds::finish_refund(refund_id, &result).await {
+            eprintln!("Failed to record refund {}: {:?}", refund_id, e);
+        }
+        result.map(Some)
+    }
+
+    // Transfers `amount` lamports back to the recipient and returns the signature. The signature is
+    // stored on the refund before it is sent, so a retry can find out whether this attempt landed.
+    pub async fn send_refund(&self, refund_id: ObjectId, recipient: Pubkey, amount: u64) -> Result<String> {
+        if recipient == Pubkey::default() {
+            return Err(anyhow::anyhow!("Refusing to refund to the system program"));
+        }
         let refund_instruction = system_instruction::transfer(
-            &self.keypair.pubkey(),
+            &self.signer.pubkey(),
             &recipient,
             amount,
         );
-        let refund_transaction = Transaction::new_signed_with_payer(
-            &[refund_instruction],
-            Some(&self.keypair.pubkey()),
-            &[&self.keypair],
-            recent_blockhash,
-        );
-        let send_refund_response = self.rpc_client.send_and_confirm_transaction(&refund_transaction);
+        let (refund_transaction, last_valid_block_height) =
+            self.sign_expiring_legacy_transaction(std::slice::from_ref(&refund_instruction), &[]).await?;
+        refunds::record_attempt(refund_id, &refund_transaction.signatures[0].to_string(), last_valid_block_height)
+            .await?;
+        let send_refund_response = self.rpc.send_and_confirm_transaction(&refund_transaction).await;
         match send_refund_response {
             Ok(signature) => {
                 println!("Refund Transaction ID: {}", signature);
-                Ok(())
+                spend_policy::record_spend(&signature.to_string(), &[refund_instruction], &self.signer.pubkey(), &[])
+                    .await;
+                Ok(signature.to_string())
             }
+            // It may still have landed, so its spend reservation is kept
             Err(e) => {
                 eprintln!("Failed to send refund transaction: {:?}", e);
-                Err(LockinClientError::RefundError(e.to_string()).into())
+                Err(LockinClientError::RefundError(format!("{:#}", e)).into())
             }
         }
     }
 
-    fn collect_swap_instructions(
+    // Moves SOL from the bot wallet to the cold storage address, the bot pays the network fee
+    pub async fn send_to_cold_wallet(&self, destination: Pubkey, lamports: u64) -> Result<String> {
+        let instruction = system_instruction::transfer(&self.signer.pubkey(), &destination, lamports);
+        let transaction = self.sign_legacy_transaction(&[instruction], &[]).await?;
+        let signature = self
+            .rpc
+            .send_and_confirm_transaction(&transaction)
+            .await
+            .context("Failed to sweep the bot wallet to cold storage")?;
+        Ok(signature.to_string())
+    }
+
+    // Estimates a priority fee from recent prioritization fees paid for the accounts the swap writes to
+    pub async fn estimate_priority_fee(&self, writable_accounts: &[Pubkey]) -> Result<u64> {
+        let accounts: Vec<String> = writable_accounts.iter().map(|account| account.to_string()).collect();
+        let response = self
+            .send_rpc_request("getRecentPrioritizationFees", json!([accounts]))
+            .await?;
+        let mut fees: Vec<u64> = response["result"]
+            .as_array()
+            .map(|entries| {
+                entries
+                    .iter()
+                    .filter_map(|entry| entry["prioritizationFee"].as_u64())
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(fee_at_percentile(&mut fees, config().priority_fee_percentile))
+    }
+
+    // Builds the compute budget instructions, using a fixed price from PRIORITY_FEE_MICRO_LAMPORTS
+    // when set and a capped estimate from recent fees otherwise
+    async fn compute_budget_instructions(&self, swap_instructions: &[Instruction]) -> Result<Vec<Instruction>> {
+        let unit_limit = config().compute_unit_limit;
+        let unit_price = match config().priority_fee_micro_lamports {
+            Some(fixed_price) => fixed_price,
+            None => {
+                // getRecentPrioritizationFees accepts at most 128 accounts
+                let mut writable_accounts: Vec<Pubkey> = swap_instructions
+                    .iter()
+                    .flat_map(|instruction| instruction.accounts.iter())
+                    .filter(|account| account.is_writable)
+                    .map(|account| account.pubkey)
+                    .collect();
+                writable_accounts.sort();
+                writable_accounts.dedup();
+                writable_accounts.truncate(128);
+                let estimate = self.estimate_priority_fee(&writable_accounts).await.unwrap_or_else(|e| {
+                    eprintln!("Failed to estimate priority fee: {:?}", e);
+                    0
+                });
+                estimate.min(config().max_priority_fee_micro_lamports)
+            }
+        };
+        println!("Compute unit limit: {}, priority fee: {} micro-lamports", unit_limit, unit_price);
+
+        Ok(vec![
+            ComputeBudgetInstruction::set_compute_unit_limit(unit_limit),
+            ComputeBudgetInstruction::set_compute_unit_price(unit_price),
+        ])
+    }
+
+    async fn collect_swap_instructions(
         &self,
         response: SwapInstructionsResponse,
-    ) -> Vec<Instruction> {
-        let mut instructions = Vec::new();
-        instructions.extend(response.setup_instructions);
-        instructions.push(response.swap_instruction);
+    ) -> Result<Vec<Instruction>> {
+        let mut swap_instructions = response.setup_instructions;
+        swap_instructions.push(response.swap_instruction);
         if let Some(cleanup_instruction) = response.cleanup_instruction {
-            instructions.push(cleanup_instruction);
+            swap_instructions.push(cleanup_instruction);
         }
-        instructions
+
+        let mut instructions = self.compute_budget_instructions(&swap_instructions).await?;
+        instructions.extend(swap_instructions);
+        Ok(instructions)
+    }
+}
+
+// What `owner` gained of `mint` in a confirmed transaction, from its pre and post token balances
+fn token_balance_change(confirmation: &serde_json::Value, owner: &Pubkey, mint: &Pubkey) -> Option<u64> {
+    let (owner, mint) = (owner.to_string(), mint.to_string());
+    let meta = &confirmation["result"]["meta"];
+    let total = |balances: &serde_json::Value| -> Option<u64> {
+        let balances = balances.as_array()?;
+        Some(
+            balances
+                .iter()
+                .filter(|balance| balance["owner"] == owner.as_str() && balance["mint"] == mint.as_str())
+                .filter_map(|balance| balance["uiTokenAmount"]["amount"].as_str()?.parse::<u64>().ok())
+                .sum(),
+        )
+    };
+    let post = total(&meta["postTokenBalances"])?;
+    let pre = total(&meta["preTokenBalances"]).unwrap_or(0);
+    post.checked_sub(pre).filter(|gained| *gained > 0)
+}
+
+// Raises the compute unit price of an expired transaction's instructions before it is rebuilt, by
+// PRIORITY_FEE_ESCALATION_PCT and at least MIN_PRIORITY_FEE_STEP_MICRO_LAMPORTS, up to
+// MAX_ESCALATED_PRIORITY_FEE_MICRO_LAMPORTS. An expired blockhash usually means the price was too low
+// to get the transaction into a block.
+fn escalate_priority_fee(instructions: &mut [Instruction]) {
+    let escalation_pct = config().priority_fee_escalation_pct;
+    let price_tag = ComputeBudgetInstruction::set_compute_unit_price(0).data[0];
+    let price_instruction = instructions.iter_mut().find(|instruction| {
+        instruction.program_id == solana_sdk::compute_budget::id() && instruction.data.first() == Some(&price_tag)
+    });
+    let current = price_instruction
+        .as_ref()
+        .and_then(|instruction| instruction.data.get(1..9)?.try_into().ok())
+        .map(u64::from_le_bytes);
+    let (Some(price_instruction), Some(current), true) = (price_instruction, current, escalation_pct > 0) else {
+        eprintln!("Blockhash expired before confirmation, rebuilding transaction...");
+        return;
+    };
+    let escalated = escalated_unit_price(current, escalation_pct, config().max_escalated_priority_fee_micro_lamports);
+    eprintln!(
+        "Blockhash expired before confirmation, rebuilding transaction at {} micro-lamports per compute unit (was {})...",
+        escalated, current
+    );
+    *price_instruction = ComputeBudgetInstruction::set_compute_unit_price(escalated);
+}
+
+// `current` raised by `escalation_pct` and at least MIN_PRIORITY_FEE_STEP_MICRO_LAMPORTS, capped at `max`
+// but never lowered
+fn escalated_unit_price(current: u64, escalation_pct: u64, max: u64) -> u64 {
+    let step = (current.saturating_mul(escalation_pct) / 100).max(MIN_PRIORITY_FEE_STEP_MICRO_LAMPORTS);
+    current.saturating_add(step).min(max).max(current)
+}
+
+// The fee at `percentile` (capped at 100) of recently paid fees, 0 when there are none
+fn fee_at_percentile(fees: &mut [u64], percentile: u64) -> u64 {
+    if fees.is_empty() {
+        return 0;
+    }
+    fees.sort_unstable();
+    fees[((fees.len() - 1) * percentile.min(100) as usize) / 100]
+}
+
+// The platform fee of a swap of `amount_lamports`, PLATFORM_FEE_BPS of it rounded up
+fn platform_fee_lamports(amount_lamports: u64) -> u64 {
+    fee_at_bps(amount_lamports, config().platform_fee_bps)
+}
+
+// `bps` basis points of `amount` rounded up, so a non-zero fee never rounds away
+fn fee_at_bps(amount: u64, bps: u64) -> u64 {
+    (amount as u128 * bps as u128).div_ceil(10_000) as u64
+}
+
+// Reads Jupiter's price impact from a serialized quote, which reports it as a decimal string
+fn quote_price_impact_pct(quote: &serde_json::Value) -> f64 {
+    match &quote["priceImpactPct"] {
+        serde_json::Value::String(pct) => pct.parse().unwrap_or(0.0),
+        other => other.as_f64().unwrap_or(0.0),
+    }
+}
+
+// Condenses a Jupiter quote into the amounts, slippage, price impact and the AMM hops it routes
+// through, each hop carrying the share of the input it takes
+pub fn route_plan_summary(quote: &serde_json::Value) -> serde_json::Value {
+    let hops: Vec<serde_json::Value> = quote["routePlan"]
+        .as_array()
+        .map(|steps| {
+            steps
+                .iter()
+                .map(|step| {
+                    let swap_info = &step["swapInfo"];
+                    json!({
+                        "amm_key": swap_info["ammKey"],
+                        "label": swap_info["label"],
+                        "input_mint": swap_info["inputMint"],
+                        "output_mint": swap_info["outputMint"],
+                        "in_amount": swap_info["inAmount"],
+                        "out_amount": swap_info["outAmount"],
+                        "fee_amount": swap_info["feeAmount"],
+                        "fee_mint": swap_info["feeMint"],
+                        "percent": step["percent"],
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    json!({
+        "input_mint": quote["inputMint"],
+        "output_mint": quote["outputMint"],
+        "in_amount": quote["inAmount"],
+        "out_amount": quote["outAmount"],
+        "other_amount_threshold": quote["otherAmountThreshold"],
+        "slippage_bps": quote["slippageBps"],
+        "price_impact_pct": quote_price_impact_pct(quote),
+        "hops": hops,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn platform_fees_round_up() {
+        assert_eq!(fee_at_bps(1_000_000_000, 1000), 100_000_000);
+        assert_eq!(fee_at_bps(1, 1000), 1);
+        assert_eq!(fee_at_bps(9_999, 1), 1);
+        assert_eq!(fee_at_bps(10_000, 1), 1);
+        assert_eq!(fee_at_bps(10_001, 1), 2);
+        assert_eq!(fee_at_bps(0, 1000), 0);
+        assert_eq!(fee_at_bps(1_000_000, 0), 0);
+        // PLATFORM_FEE_BPS is at most 10000, where the fee is the whole amount without overflowing
+        assert_eq!(fee_at_bps(u64::MAX, 10_000), u64::MAX);
+    }
+
+    #[test]
+    fn escalation_steps_at_least_the_minimum_and_stops_at_the_cap() {
+        assert_eq!(escalated_unit_price(100_000, 50, 1_000_000), 150_000);
+        assert_eq!(escalated_unit_price(100, 50, 1_000_000), 100 + MIN_PRIORITY_FEE_STEP_MICRO_LAMPORTS);
+        assert_eq!(escalated_unit_price(900_000, 50, 1_000_000), 1_000_000);
+        // A price already over the cap is kept rather than lowered
+        assert_eq!(escalated_unit_price(2_000_000, 50, 1_000_000), 2_000_000);
+        assert_eq!(escalated_unit_price(u64::MAX, 50, u64::MAX), u64::MAX);
+    }
+
+    #[test]
+    fn priority_fees_are_read_at_the_percentile() {
+        let mut fees = vec![50, 10, 40, 20, 30];
+        assert_eq!(fee_at_percentile(&mut fees, 0), 10);
+        assert_eq!(fee_at_percentile(&mut fees, 50), 30);
+        assert_eq!(fee_at_percentile(&mut fees, 75), 40);
+        assert_eq!(fee_at_percentile(&mut fees, 100), 50);
+        assert_eq!(fee_at_percentile(&mut fees, 250), 50);
+        assert_eq!(fee_at_percentile(&mut [], 75), 0);
+    }
+
+    #[test]
+    fn price_impact_is_read_from_strings_and_numbers() {
+        assert_eq!(quote_price_impact_pct(&json!({ "priceImpactPct": "0.0125" })), 0.0125);
+        assert_eq!(quote_price_impact_pct(&json!({ "priceImpactPct": 0.5 })), 0.5);
+        assert_eq!(quote_price_impact_pct(&json!({ "priceImpactPct": "n/a" })), 0.0);
+        assert_eq!(quote_price_impact_pct(&json!({})), 0.0);
     }
 }
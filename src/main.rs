@@ -1,40 +1,176 @@
 // main.rs
 use dotenv::dotenv;
-use mongo::get_database;
-use tracing_subscriber;
-use poller::start_poller;
+use mongo::{detect_transaction_support, ensure_indexes, get_database};
+use poller::supervise_poller;
+use autobuy::start_autobuy_scheduler;
+use dca::start_dca_scheduler;
+use btc_watcher::start_btc_watcher;
+use lightning::start_invoice_watcher;
+use erc20_watcher::start_erc20_watcher;
+use sol_watcher::start_sol_watcher;
+use reconciliation::start_reconciliation_scheduler;
+use balance_watchdog::start_balance_watchdog;
+use treasury_sweep::start_treasury_sweep_scheduler;
+use crate::config::{config, ExchangeKind};
+use crate::preflight::run_preflight_checks;
 use crate::server::{create_app, shutdown_signal};
 
 mod error_handling;
+mod config;
+mod alerts;
 mod mongo;
 mod server;
 mod handlers;
 mod wallets;
+mod entropy;
+mod validation;
+mod backup;
+mod encryption;
+mod key_wrap;
+mod sessions;
+mod api_keys;
+mod totp;
+mod notifications;
 mod poller;
-mod kraken;
+mod exchange;
+mod kraken_ws;
 mod lockin;
+mod autobuy;
+mod dca;
+mod btc_watcher;
+mod lightning;
+mod erc20_watcher;
+mod sol_watcher;
+mod holdings;
+mod price;
+mod refunds;
+mod transaction_state;
+mod dry_run;
+mod maintenance;
+mod preflight;
+mod telemetry;
+mod redact;
+mod request_id;
+mod retry;
+mod circuit_breaker;
+mod units;
+mod settlement;
+mod withdrawal_addresses;
+mod withdrawal_tracker;
+mod reconciliation;
+mod ledger;
+mod cost_basis;
+mod allocation;
+mod token_metadata;
+mod quote_check;
+mod solana_txs;
+mod signer;
+mod balance_watchdog;
+mod treasury_sweep;
+mod spend_policy;
+#[cfg(test)]
+mod test_support;
 
 
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::fmt::init();
     dotenv().ok();
+    // Load and validate the configuration up front, exiting with every problem listed if it is invalid
+    let config = config();
+    telemetry::init();
+    if let Err(problems) = run_preflight_checks().await {
+        eprintln!("Preflight checks failed:");
+        for problem in problems {
+            eprintln!("  - {}", problem);
+        }
+        std::process::exit(1);
+    }
     let db = get_database().await.unwrap();
-    let app = create_app(db);
+    ensure_indexes(&db).await;
+    detect_transaction_support(&db).await;
+    if let Err(e) = maintenance::refresh().await {
+        eprintln!("Failed to load maintenance mode: {:?}", e);
+    }
+    let app = create_app(db.clone());
+
+    let bind_address = config.bind_address.parse().expect("BIND_ADDRESS must be a socket address");
+    let server = axum::Server::bind(&bind_address)
+        .serve(app.into_make_service_with_connect_info::<std::net::SocketAddr>());
+
+    // Start the polling in a separate async task, restarted by its supervisor if it ever stops
+    tokio::spawn(supervise_poller(db.clone()));
+
+    tokio::spawn(async {
+        if let Err(e) = start_autobuy_scheduler().await {
+            eprintln!("Autobuy scheduler error: {}", e);
+        }
+    });
 
-    let server = axum::Server::bind(&"0.0.0.0:8080".parse().unwrap())
-        .serve(app.into_make_service());
+    tokio::spawn(async {
+        if let Err(e) = start_dca_scheduler().await {
+            eprintln!("DCA scheduler error: {}", e);
+        }
+    });
 
-    // Start the polling in a separate async task
     tokio::spawn(async {
-        if let Err(e) = start_poller().await {
-            eprintln!("Polling error: {}", e);
+        if let Err(e) = start_balance_watchdog().await {
+            eprintln!("Balance watchdog error: {}", e);
         }
     });
 
+    if config.cold_wallet_address.is_some() {
+        tokio::spawn(async {
+            if let Err(e) = start_treasury_sweep_scheduler().await {
+                eprintln!("Treasury sweep scheduler error: {}", e);
+            }
+        });
+    }
+
+    // The ledger reconciled is Kraken's
+    if config.exchange == ExchangeKind::Kraken {
+        tokio::spawn(async {
+            if let Err(e) = start_reconciliation_scheduler().await {
+                eprintln!("Reconciliation scheduler error: {}", e);
+            }
+        });
+    }
+
+    if config.btc_watcher_enabled {
+        tokio::spawn(async {
+            if let Err(e) = start_btc_watcher().await {
+                eprintln!("Bitcoin watcher error: {}", e);
+            }
+        });
+    }
+
+    if config.lnd_rest_url.is_some() {
+        tokio::spawn(async {
+            if let Err(e) = start_invoice_watcher().await {
+                eprintln!("Lightning invoice watcher error: {}", e);
+            }
+        });
+    }
+
+    if config.sol_watcher_enabled {
+        tokio::spawn(async {
+            if let Err(e) = start_sol_watcher().await {
+                eprintln!("Solana watcher error: {}", e);
+            }
+        });
+    }
+
+    if config.eth_watcher_enabled {
+        tokio::spawn(async {
+            if let Err(e) = start_erc20_watcher().await {
+                eprintln!("ERC-20 watcher error: {}", e);
+            }
+        });
+    }
+
     let graceful = server.with_graceful_shutdown(shutdown_signal());
 
     if let Err(err) = graceful.await {
         tracing::error!("Server error: {}", err);
     }
+    telemetry::shutdown().await;
 }
@@ -0,0 +1,78 @@
+// maintenance.rs
+use crate::error_handling::AppError;
+use crate::mongo::get_settings_collection;
+use axum::http::{Method, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use mongodb::bson::{doc, DateTime as BsonDateTime};
+use mongodb::options::UpdateOptions;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+
+const SETTINGS_ID: &str = "maintenance";
+const DEFAULT_MESSAGE: &str = "Coinlocker is down for maintenance, please try again shortly.";
+
+// Cached copy of the flag persisted in the settings collection, refreshed by the poller every tick
+static MAINTENANCE_ENABLED: AtomicBool = AtomicBool::new(false);
+static MAINTENANCE_MESSAGE: RwLock<String> = RwLock::new(String::new());
+
+pub fn is_enabled() -> bool {
+    MAINTENANCE_ENABLED.load(Ordering::SeqCst)
+}
+
+pub fn message() -> String {
+    let message = MAINTENANCE_MESSAGE.read().map(|message| message.clone()).unwrap_or_default();
+    if message.is_empty() {
+        DEFAULT_MESSAGE.to_string()
+    } else {
+        message
+    }
+}
+
+fn cache(enabled: bool, message: Option<String>) {
+    MAINTENANCE_ENABLED.store(enabled, Ordering::SeqCst);
+    if let Ok(mut cached) = MAINTENANCE_MESSAGE.write() {
+        *cached = message.unwrap_or_default();
+    }
+}
+
+// Reloads the flag from Mongo so a toggle on one instance reaches every instance
+pub async fn refresh() -> Result<bool, AppError> {
+    let settings = get_settings_collection().await?;
+    let (enabled, message) = match settings.find_one(doc! { "_id": SETTINGS_ID }, None).await? {
+        Some(setting) => (
+            setting.get_bool("enabled").unwrap_or(false),
+            setting.get_str("message").ok().map(str::to_string),
+        ),
+        None => (false, None),
+    };
+    cache(enabled, message);
+    Ok(enabled)
+}
+
+// Persists the flag and applies it to this instance straight away
+pub async fn set(enabled: bool, message: Option<String>) -> Result<(), AppError> {
+    let settings = get_settings_collection().await?;
+    settings
+        .update_one(
+            doc! { "_id": SETTINGS_ID },
+            doc! { "$set": {
+                "enabled": enabled,
+                "message": message.clone(),
+                "updated_at": BsonDateTime::now(),
+            } },
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .await?;
+    cache(enabled, message);
+    println!("Maintenance mode {}.", if enabled { "enabled" } else { "disabled" });
+    Ok(())
+}
+
+// Middleware rejecting user-facing writes with a 503 while maintenance mode is on, reads still go through
+pub async fn reject_writes<B>(request: Request<B>, next: Next<B>) -> Result<Response, AppError> {
+    if is_enabled() && request.method() != Method::GET && request.method() != Method::HEAD {
+        return Err(AppError::ServiceUnavailable(message()));
+    }
+    Ok(next.run(request).await)
+}
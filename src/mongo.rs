@@ -1,30 +1,29 @@
 // mongo.rs
 use mongodb::{
     bson::{doc, DateTime as BsonDateTime, Document},
-    Client, Collection, Database,
+    error::{ErrorKind, TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT},
+    options::{ClientOptions, IndexOptions, UpdateModifications, UpdateOptions},
+    results::UpdateResult,
+    Client, ClientSession, Collection, Database, IndexModel,
 };
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use tokio::sync::OnceCell;
+use std::time::Duration;
 use serde::{Deserialize, Serialize};
+use crate::config::config;
 use crate::error_handling::AppError;
+use crate::units::btc_to_sats;
 use mongodb::bson::oid::ObjectId;
+use std::collections::HashMap;
 
 #[derive(Clone)]
 pub struct AppState {
     pub db: mongodb::Database,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Transaction {
-    pub user_id: i32,
-    pub amount: f64,
-    pub processed: bool,
-    pub status: String, // New field for transaction status
-    pub address: String,
-    pub timestamp: BsonDateTime,
-    // pub kraken_result: serde_json::Value,
-    // pub kraken_error: serde_json::Value,
-}
-
-#[derive(Debug, Deserialize, Serialize)]
+// Debug is implemented by hand below, so the API key and encrypted secrets never end up in logs
+#[derive(Deserialize, Serialize)]
 pub struct User {
     #[serde(rename = "_id")]
     pub id: ObjectId,
@@ -32,23 +31,479 @@ pub struct User {
     pub username: Option<String>,
     pub first_name: Option<String>,
     pub last_name: Option<String>,
+    // Plaintext API key of users registered before keys were hashed, replaced by api_key_hash once
+    // they next authenticate
     pub api_key: Option<String>,
+    // SHA-256 of the API key, users are looked up by it
+    #[serde(default)]
+    pub api_key_hash: Option<String>,
+    // Total deposited in BTC, kept for the bot to display. total_deposit_sats is the exact total, users
+    // who haven't deposited since it was added only have this one.
     pub total_deposit: f64,
+    #[serde(default)]
+    pub total_deposit_sats: Option<i64>,
     pub lockin_total: f64,
     pub autobuy_amount: Option<f64>,
+    #[serde(default)]
+    pub pending_autobuy: f64,
+    #[serde(default)]
+    pub dca_active: bool,
+    #[serde(default)]
+    pub slippage_bps: Option<u16>,
     pub solana_public_key: Option<String>,
     pub solana_private_key: Option<String>,
+    // Index under SOLANA_MASTER_MNEMONIC, wallets created before HD derivation keep their private key instead
+    #[serde(default)]
+    pub solana_derivation_index: Option<i64>,
     pub bitcoin_public_key: Option<String>,
     pub bitcoin_private_key: Option<String>,
     pub bitcoin_mnemonic: Option<String>,
     pub ethereum_public_key: Option<String>,
     pub ethereum_private_key: Option<String>,
+    // Data key the secrets above are encrypted with, wrapped by KEY_WRAPPER's master key
+    #[serde(default)]
+    pub data_key: Option<String>,
+    // TOTP secret sealed under the API key, enabled once a code from it was verified
+    #[serde(default)]
+    pub totp_secret: Option<String>,
+    #[serde(default)]
+    pub totp_enabled: bool,
+    // Last TOTP time step a code was accepted for, so codes can't be replayed
+    #[serde(default)]
+    pub totp_last_step: Option<i64>,
+    // Wrong TOTP codes since totp_failures_since, too many lock code checks until totp_locked_until
+    #[serde(default)]
+    pub totp_failures: i64,
+    #[serde(default)]
+    pub totp_failures_since: Option<BsonDateTime>,
+    #[serde(default)]
+    pub totp_locked_until: Option<BsonDateTime>,
+    // ERC-20 deposits not yet converted, by token symbol
+    #[serde(default)]
+    pub token_balances: HashMap<String, f64>,
+    // Newest signature on the user's Solana address already checked for deposits
+    #[serde(default)]
+    pub solana_deposit_cursor: Option<String>,
+    // Highest receive address index of the Bitcoin wallet handed out, 0 is the registration address
+    #[serde(default)]
+    pub bitcoin_address_index: i64,
+    // Set while deposits are held because the user's Solana address can't receive funds
+    #[serde(default)]
+    pub address_notice: Option<String>,
+    // Registered Kraken withdrawal key the user's SOL is withdrawn through, the default one when unset
+    #[serde(default)]
+    pub withdrawal_key: Option<String>,
+    // How the lockin stage splits the user's SOL across output mints, all LOCKIN when empty
+    #[serde(default)]
+    pub allocation: Vec<AllocationLeg>,
+    // Deposit the SOL share of the allocation into STAKE_POOL_ADDRESS instead of sending it
+    #[serde(default)]
+    pub stake_sol: bool,
+}
+
+impl User {
+    // The exact total deposited in satoshis, from the BTC total for users not credited since it was kept
+    pub fn deposited_sats(&self) -> u64 {
+        match self.total_deposit_sats {
+            Some(sats) => sats.max(0) as u64,
+            None => btc_to_sats(self.total_deposit),
+        }
+    }
+}
+
+// A share of a user's allocation, see allocation.rs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AllocationLeg {
+    pub mint: String,
+    pub bps: u16,
+}
+
+impl std::fmt::Debug for User {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("User")
+            .field("id", &self.id)
+            .field("user_id", &self.user_id)
+            .field("username", &self.username)
+            .field("solana_public_key", &self.solana_public_key)
+            .field("bitcoin_public_key", &self.bitcoin_public_key)
+            .field("ethereum_public_key", &self.ethereum_public_key)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FailedSwap {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    #[serde(default)]
+    pub transaction_id: Option<ObjectId>,
+    pub user_id: i64,
+    pub input_mint: String,
+    pub output_mint: String,
+    pub amount: f64,
+    pub receiving_address: String,
+    pub slippage_bps: u16,
+    // The allocation leg the swap was, 0 without a split allocation
+    #[serde(default)]
+    pub leg: i32,
+    #[serde(default)]
+    pub stake: bool,
+    pub attempts: i32,
+    pub error: String,
+    // SimulationFailure category of the last failed simulation, when the swap got that far
+    #[serde(default)]
+    pub simulation_failure: Option<String>,
+    pub quote: Option<serde_json::Value>,
+    pub redriven: bool,
+    // Set when the swap was never sent because Jupiter or Solana RPC was down, the poller re-drives
+    // it once they recover
+    #[serde(default)]
+    pub deferred: bool,
+    // Set when the bot wallet's spending limits blocked the swap, approving the spend re-drives it
+    #[serde(default)]
+    pub spend_approval_id: Option<ObjectId>,
+    pub created_at: BsonDateTime,
+}
+
+// A Kraken withdrawal key: the description an address was added under in Funding > Withdraw
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WithdrawalKey {
+    #[serde(rename = "_id")]
+    pub key: String,
+    pub address: String,
+    // The key used for users without one of their own
+    #[serde(default)]
+    pub default: bool,
+    pub created_at: BsonDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Refund {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub recipient: String,
+    pub lamports: i64,
+    pub signature: Option<String>,
+    // Every signature sent for this refund, in order, and the last block height the latest can land at
+    #[serde(default)]
+    pub signatures: Vec<String>,
+    #[serde(default)]
+    pub last_valid_block_height: Option<i64>,
+    pub status: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub created_at: BsonDateTime,
+    pub updated_at: BsonDateTime,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DcaPlan {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: i64,
+    pub amount_usd: f64,
+    pub interval_hours: i64,
+    pub active: bool,
+    pub next_run_at: BsonDateTime,
+    pub created_at: BsonDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DcaExecution {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub plan_id: ObjectId,
+    pub user_id: i64,
+    pub amount_usd: f64,
+    pub amount_btc: f64,
+    pub transaction_id: Option<ObjectId>,
+    pub status: String,
+    pub error: Option<String>,
+    pub executed_at: BsonDateTime,
+}
+
+// An invoice issued by the LND node, settled invoices point at the transaction they created
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LightningInvoice {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: i64,
+    pub payment_hash: String,
+    pub payment_request: String,
+    pub amount_sats: i64,
+    pub state: String,
+    pub transaction_id: Option<ObjectId>,
+    pub created_at: BsonDateTime,
+    pub expires_at: BsonDateTime,
+    pub settled_at: Option<BsonDateTime>,
+}
+
+// An ERC-20 transfer into a user's Ethereum address, unique by transaction hash and log index
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TokenDeposit {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: i64,
+    pub symbol: String,
+    pub contract: String,
+    pub tx_hash: String,
+    pub log_index: i64,
+    pub block_number: i64,
+    pub from_address: String,
+    pub amount: f64,
+    pub credited: bool,
+    pub created_at: BsonDateTime,
+}
+
+// The database handle shared by the whole process
+static DATABASE: OnceLock<OnceCell<Database>> = OnceLock::new();
+
+// Returns the shared database handle. Its client, and with it the connection pool, is created on first
+// use with the MONGO_* pool size and timeout settings, every later call reuses it.
 pub async fn get_database() -> Result<Database, AppError> {
-    let url = std::env::var("MONGO_URL")?;
-    let client = Client::with_uri_str(&url).await?;
-    Ok(client.database("telegram_bot"))
+    let database = DATABASE
+        .get_or_init(OnceCell::new)
+        .get_or_try_init(|| async {
+            let settings = config();
+            let mut options = ClientOptions::parse(&settings.mongo_url).await?;
+            options.app_name = Some("coinlockerapi".to_string());
+            options.max_pool_size = Some(settings.mongo_max_pool_size);
+            options.min_pool_size = Some(settings.mongo_min_pool_size);
+            options.connect_timeout = Some(Duration::from_secs(settings.mongo_connect_timeout_secs));
+            options.server_selection_timeout = Some(Duration::from_secs(settings.mongo_server_selection_timeout_secs));
+            let client = Client::with_options(options)?;
+            Ok::<_, AppError>(client.database("telegram_bot"))
+        })
+        .await?;
+    Ok(database.clone())
+}
+
+fn index(keys: Document, options: Option<IndexOptions>) -> IndexModel {
+    IndexModel::builder().keys(keys).options(options).build()
+}
+
+// Creates the indexes the poller, watchers and API lookups rely on, so they don't scan whole
+// collections. Creating an index that already exists is a no-op, so this runs on every start. A
+// failure is logged and startup continues, e.g. when duplicate user_ids or API key hashes keep a
+// unique index from being built.
+pub async fn ensure_indexes(db: &Database) {
+    let unique = || Some(IndexOptions::builder().unique(true).build());
+    let sparse = || Some(IndexOptions::builder().sparse(true).build());
+    let expire_at = || Some(IndexOptions::builder().expire_after(Duration::ZERO).build());
+    let indexes: Vec<(&str, Vec<IndexModel>)> = vec![
+        (
+            "users",
+            vec![
+                index(doc! { "user_id": 1 }, unique()),
+                index(doc! { "api_key_hash": 1 }, sparse()),
+                index(doc! { "api_key": 1 }, sparse()),
+            ],
+        ),
+        (
+            "transactions",
+            vec![
+                index(doc! { "address": 1 }, None),
+                index(doc! { "txid": 1 }, None),
+                index(doc! { "refid": 1 }, None),
+                index(doc! { "state": 1 }, None),
+                index(doc! { "user_id": 1 }, None),
+            ],
+        ),
+        // Keys are authenticated by their hash, so two keys can never share one
+        ("api_keys", vec![index(doc! { "key_hash": 1 }, unique()), index(doc! { "user_id": 1 }, None)]),
+        ("ledger", vec![index(doc! { "user_id": 1 }, None), index(doc! { "entries.account": 1 }, None)]),
+        ("stake_deposits", vec![index(doc! { "user_id": 1 }, None)]),
+        ("solana_txs", vec![index(doc! { "tx_id": 1 }, None)]),
+        ("spend_approvals", vec![index(doc! { "fingerprint": 1, "status": 1 }, None)]),
+        (
+            "bot_wallet_spends",
+            vec![index(doc! { "created_at": 1 }, None), index(doc! { "fingerprint": 1, "reserved": 1 }, None)],
+        ),
+        // Expired sessions and secrets tokens are removed by Mongo once their expires_at passes
+        ("sessions", vec![index(doc! { "token_hash": 1 }, None), index(doc! { "expires_at": 1 }, expire_at())]),
+        ("secret_tokens", vec![index(doc! { "token_hash": 1 }, None), index(doc! { "expires_at": 1 }, expire_at())]),
+    ];
+    for (collection, models) in indexes {
+        if let Err(e) = db.collection::<Document>(collection).create_indexes(models, None).await {
+            eprintln!("Failed to create indexes on {}: {}", collection, e);
+        }
+    }
+
+    // Indexes nothing queries anymore, which only slow down writes. Transactions are found by `state`
+    // since the state machine, not by `processed`.
+    let retired = [("transactions", "processed_1_status_1")];
+    for (collection, name) in retired {
+        if let Err(e) = db.collection::<Document>(collection).drop_index(name, None).await {
+            // IndexNotFound, already dropped or never built
+            if !matches!(*e.kind, ErrorKind::Command(ref error) if error.code == 27) {
+                eprintln!("Failed to drop index {} on {}: {}", name, collection, e);
+            }
+        }
+    }
+}
+
+// Whether the deployment runs multi-document transactions, only replica sets and sharded clusters do
+static TRANSACTIONS_SUPPORTED: AtomicBool = AtomicBool::new(false);
+
+// Checks whether the deployment supports multi-document transactions. Without them, writes that
+// belong together are still made, one after the other.
+pub async fn detect_transaction_support(db: &Database) {
+    let supported = match db.run_command(doc! { "hello": 1 }, None).await {
+        Ok(hello) => hello.contains_key("setName") || matches!(hello.get_str("msg"), Ok("isdbgrid")),
+        Err(e) => {
+            eprintln!("Failed to check for transaction support: {}", e);
+            false
+        }
+    };
+    if !supported {
+        eprintln!(
+            "WARNING: MongoDB is a standalone server, writes that belong together are made one after the other \
+             without a transaction, and a crash between them can leave a deposit claimed but not credited. \
+             Run a replica set in production."
+        );
+    }
+    TRANSACTIONS_SUPPORTED.store(supported, Ordering::Relaxed);
+}
+
+// Times a transaction is run before a transient error is returned
+const MAX_TRANSACTION_ATTEMPTS: usize = 3;
+
+// Writes that have to land together: a multi-document transaction when the deployment supports them,
+// otherwise plain writes in order. Dropped without `commit`, the transaction is aborted. A transaction
+// aborted by a transient error (a failover, a write conflict) is started over with the writes made so
+// far, and a commit with an unknown result is committed again.
+pub struct AtomicWrites {
+    session: Option<ClientSession>,
+    writes: Vec<TransactionWrite>,
+}
+
+// A write made in the transaction, kept to make it again if the transaction starts over
+struct TransactionWrite {
+    collection: Collection<Document>,
+    filter: Document,
+    update: UpdateModifications,
+    options: Option<UpdateOptions>,
+    matched_count: u64,
+}
+
+impl AtomicWrites {
+    pub async fn start(client: &Client) -> Result<Self, AppError> {
+        if !TRANSACTIONS_SUPPORTED.load(Ordering::Relaxed) {
+            return Ok(AtomicWrites::none());
+        }
+        let mut session = client.start_session(None).await?;
+        session.start_transaction(None).await?;
+        Ok(AtomicWrites { session: Some(session), writes: Vec::new() })
+    }
+
+    // Writes made one at a time, for callers without anything to group
+    pub fn none() -> Self {
+        AtomicWrites { session: None, writes: Vec::new() }
+    }
+
+    pub async fn update_one<T: Send + Sync>(
+        &mut self,
+        collection: &Collection<T>,
+        filter: Document,
+        update: impl Into<UpdateModifications>,
+    ) -> Result<UpdateResult, AppError> {
+        self.write(collection.clone_with_type(), filter, update.into(), None).await
+    }
+
+    // Inserts `document` unless a document matching `filter` already exists
+    pub async fn insert_once<T: Send + Sync>(
+        &mut self,
+        collection: &Collection<T>,
+        filter: Document,
+        document: Document,
+    ) -> Result<UpdateResult, AppError> {
+        let update = doc! { "$setOnInsert": document };
+        let options = UpdateOptions::builder().upsert(true).build();
+        self.write(collection.clone_with_type(), filter, update.into(), Some(options)).await
+    }
+
+    async fn write(
+        &mut self,
+        collection: Collection<Document>,
+        filter: Document,
+        update: UpdateModifications,
+        options: Option<UpdateOptions>,
+    ) -> Result<UpdateResult, AppError> {
+        if self.session.is_none() {
+            return Ok(collection.update_one(filter, update, options).await?);
+        }
+        let mut attempt = 1;
+        loop {
+            let session = self.session.as_mut().ok_or(AppError::InternalServerError)?;
+            let result = collection
+                .update_one_with_session(filter.clone(), update.clone(), options.clone(), session)
+                .await;
+            match result {
+                Ok(result) => {
+                    self.writes.push(TransactionWrite {
+                        collection,
+                        filter,
+                        update,
+                        options,
+                        matched_count: result.matched_count,
+                    });
+                    return Ok(result);
+                }
+                Err(e) if e.contains_label(TRANSIENT_TRANSACTION_ERROR) && attempt < MAX_TRANSACTION_ATTEMPTS => {
+                    eprintln!("Transaction write failed with a transient error, starting over: {}", e);
+                    attempt += 1;
+                    self.restart().await?;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    // Starts the transaction over and makes the writes made so far again. A write matching a different
+    // number of documents than the first time means its data changed in the meantime, and the caller
+    // decided on what it saw then, so that fails the transaction.
+    async fn restart(&mut self) -> Result<(), AppError> {
+        let session = self.session.as_mut().ok_or(AppError::InternalServerError)?;
+        // The server may have aborted it already
+        let _ = session.abort_transaction().await;
+        session.start_transaction(None).await?;
+        for write in &self.writes {
+            let result = write
+                .collection
+                .update_one_with_session(write.filter.clone(), write.update.clone(), write.options.clone(), session)
+                .await?;
+            if result.matched_count != write.matched_count {
+                return Err(AppError::CustomError(format!(
+                    "A write to {} matched differently when its transaction was retried",
+                    write.collection.name()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn commit(mut self) -> Result<(), AppError> {
+        if self.session.is_none() {
+            return Ok(());
+        }
+        let mut attempt = 1;
+        loop {
+            let session = self.session.as_mut().ok_or(AppError::InternalServerError)?;
+            match session.commit_transaction().await {
+                Ok(()) => return Ok(()),
+                // The commit may or may not have applied, committing again is safe either way
+                Err(e) if e.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT) && attempt < MAX_TRANSACTION_ATTEMPTS => {
+                    eprintln!("Transaction commit result unknown, committing again: {}", e);
+                }
+                Err(e) if e.contains_label(TRANSIENT_TRANSACTION_ERROR) && attempt < MAX_TRANSACTION_ATTEMPTS => {
+                    eprintln!("Transaction commit failed with a transient error, starting over: {}", e);
+                    self.restart().await?;
+                }
+                Err(e) => return Err(e.into()),
+            }
+            attempt += 1;
+        }
+    }
 }
 
 pub async fn get_users_collection() -> Result<Collection<User>, AppError> {
@@ -59,4 +514,94 @@ pub async fn get_users_collection() -> Result<Collection<User>, AppError> {
 pub async fn get_transactions_collection() -> Result<Collection<Document>, AppError> {
     let db = get_database().await?;
     Ok(db.collection("transactions"))
-}
\ No newline at end of file
+}
+
+pub async fn get_dry_run_actions_collection() -> Result<Collection<Document>, AppError> {
+    let db = get_database().await?;
+    Ok(db.collection("dry_run_actions"))
+}
+
+pub async fn get_failed_swaps_collection() -> Result<Collection<FailedSwap>, AppError> {
+    let db = get_database().await?;
+    Ok(db.collection("failed_swaps"))
+}
+
+pub async fn get_refunds_collection() -> Result<Collection<Refund>, AppError> {
+    let db = get_database().await?;
+    Ok(db.collection("refunds"))
+}
+
+pub async fn get_dca_plans_collection() -> Result<Collection<DcaPlan>, AppError> {
+    let db = get_database().await?;
+    Ok(db.collection("dca_plans"))
+}
+
+pub async fn get_dca_executions_collection() -> Result<Collection<DcaExecution>, AppError> {
+    let db = get_database().await?;
+    Ok(db.collection("dca_executions"))
+}
+
+pub async fn get_lightning_invoices_collection() -> Result<Collection<LightningInvoice>, AppError> {
+    let db = get_database().await?;
+    Ok(db.collection("lightning_invoices"))
+}
+
+pub async fn get_token_deposits_collection() -> Result<Collection<TokenDeposit>, AppError> {
+    let db = get_database().await?;
+    Ok(db.collection("token_deposits"))
+}
+
+pub async fn get_secret_tokens_collection() -> Result<Collection<Document>, AppError> {
+    let db = get_database().await?;
+    Ok(db.collection("secret_tokens"))
+}
+
+pub async fn get_withdrawal_keys_collection() -> Result<Collection<WithdrawalKey>, AppError> {
+    let db = get_database().await?;
+    Ok(db.collection("withdrawal_keys"))
+}
+
+pub async fn get_settings_collection() -> Result<Collection<Document>, AppError> {
+    let db = get_database().await?;
+    Ok(db.collection("settings"))
+}
+
+pub async fn get_ledger_collection() -> Result<Collection<Document>, AppError> {
+    let db = get_database().await?;
+    Ok(db.collection("ledger"))
+}
+
+pub async fn get_token_metadata_collection() -> Result<Collection<Document>, AppError> {
+    let db = get_database().await?;
+    Ok(db.collection("token_metadata"))
+}
+
+pub async fn get_stake_deposits_collection() -> Result<Collection<Document>, AppError> {
+    let db = get_database().await?;
+    Ok(db.collection("stake_deposits"))
+}
+
+pub async fn get_treasury_sweeps_collection() -> Result<Collection<Document>, AppError> {
+    let db = get_database().await?;
+    Ok(db.collection("treasury_sweeps"))
+}
+
+pub async fn get_spend_approvals_collection() -> Result<Collection<Document>, AppError> {
+    let db = get_database().await?;
+    Ok(db.collection("spend_approvals"))
+}
+
+pub async fn get_bot_wallet_spends_collection() -> Result<Collection<Document>, AppError> {
+    let db = get_database().await?;
+    Ok(db.collection("bot_wallet_spends"))
+}
+
+pub async fn get_solana_txs_collection() -> Result<Collection<Document>, AppError> {
+    let db = get_database().await?;
+    Ok(db.collection("solana_txs"))
+}
+
+pub async fn get_reconciliation_reports_collection() -> Result<Collection<Document>, AppError> {
+    let db = get_database().await?;
+    Ok(db.collection("reconciliation_reports"))
+}
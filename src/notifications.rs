@@ -0,0 +1,45 @@
+// notifications.rs
+use crate::config::config;
+use serde_json::json;
+
+// Tells a user about something on their account, as a Telegram message from TELEGRAM_BOT_TOKEN's bot
+// (users are identified by their Telegram user id) and posted to USER_NOTIFICATION_WEBHOOK_URL when
+// configured. Runs in the background, a failed notification is only logged.
+pub fn notify_user(user_id: i64, message: String) {
+    let telegram_bot_token = config().telegram_bot_token.clone();
+    let webhook_url = config().user_notification_webhook_url.clone();
+    if telegram_bot_token.is_none() && webhook_url.is_none() {
+        return;
+    }
+    tokio::spawn(async move {
+        let client = reqwest::Client::new();
+        if let Some(token) = telegram_bot_token {
+            let response = client
+                .post(format!("https://api.telegram.org/bot{}/sendMessage", token))
+                .json(&json!({ "chat_id": user_id, "text": message }))
+                .send()
+                .await;
+            match response {
+                Ok(response) if !response.status().is_success() => {
+                    eprintln!("Telegram notification for user {} returned {}", user_id, response.status())
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("Failed to notify user {} on Telegram: {}", user_id, e.without_url()),
+            }
+        }
+        if let Some(url) = webhook_url {
+            let response = client
+                .post(&url)
+                .json(&json!({ "user_id": user_id, "text": message }))
+                .send()
+                .await;
+            match response {
+                Ok(response) if !response.status().is_success() => {
+                    eprintln!("Notification webhook for user {} returned {}", user_id, response.status())
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("Failed to notify user {} through the webhook: {:?}", user_id, e),
+            }
+        }
+    });
+}
@@ -1,16 +1,60 @@
 // poller.rs
 use crate::error_handling::AppError;
-use crate::kraken::{execute_swap, get_deposit_status, withdraw_assets};
-use crate::lockin::LockinClient;
-use crate::mongo::{get_transactions_collection, get_users_collection, User};
+use crate::autobuy::{accumulate_deposit, hold_deposit};
+use crate::alerts::send_alert;
+use crate::allocation::{self, SwapLeg, NATIVE_SOL_MINT};
+use crate::balance_watchdog::{self, SwapsHeld};
+use crate::circuit_breaker::{self, CircuitOpen};
+use crate::config::config;
+use crate::cost_basis;
+use crate::dry_run;
+use crate::ledger::{Account, Journal};
+use crate::maintenance;
+use crate::notifications::notify_user;
+use crate::refunds::retry_failed_refunds;
+use crate::request_id;
+use crate::retry::{self, retry_with_backoff};
+use crate::settlement;
+use crate::solana_txs;
+use crate::spend_policy::SpendBlocked;
+use crate::token_metadata;
+use crate::withdrawal_addresses;
+use crate::withdrawal_tracker;
+use crate::transaction_state::{TransactionState, TransactionStateMachine};
+use crate::units::{
+    btc_decimal_to_sats, lamports_to_sol, sats_to_btc, sats_to_btc_decimal, sol_decimal_to_lamports, sol_to_lamports,
+    SATS_PER_BTC,
+};
+use crate::validation::solana_address;
+use crate::exchange::{self, to_decimal, to_stored, DepositStatus, Exchange, OrderFill};
+use crate::kraken_ws::{kraken_ws_enabled, stream_deposit_events};
+use crate::lockin::{
+    bot_wallet_address, JupiterApi, LockinClient, LockinClientError, PooledRpc, RpcPool, SolanaRpc, SwapOutcome,
+    SwapResult, DEFAULT_SLIPPAGE_BPS,
+};
+use crate::price::{PriceOracle, PriceSource};
+use crate::mongo::{
+    AtomicWrites, get_failed_swaps_collection, get_solana_txs_collection, get_stake_deposits_collection,
+    get_transactions_collection, FailedSwap, User,
+};
+use jupiter_swap_api_client::JupiterSwapApiClient;
 use kraken_rest_client::OrderSide;
 use log::info;
-use mongodb::bson::{doc, Bson, Document};
-use mongodb::Collection;
+use mongodb::bson::{doc, oid::ObjectId, to_bson, Bson, DateTime as BsonDateTime, Document};
+use futures_util::stream::{self, StreamExt};
+use mongodb::options::UpdateOptions;
+use mongodb::{Collection, Database};
+use rust_decimal::Decimal;
+use serde_json::json;
 use solana_sdk::pubkey::Pubkey;
+use std::collections::{BTreeMap, HashSet};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::time::interval;
+use tokio::sync::Notify;
+use tokio::time::{interval, sleep, Instant};
+use tracing::Instrument;
 
 // Converts a Unix timestamp (in seconds) to a BSON DateTime format
 // fn convert_timestamp(unix_timestamp: i64) -> BsonDateTime {
@@ -19,234 +63,785 @@ use tokio::time::interval;
 //     BsonDateTime::from_millis(datetime.timestamp_millis())
 // }
 
-// Starts a poller that runs every 60 seconds
-pub async fn start_poller() -> Result<(), AppError> {
+// The exchange, price and chain clients the deposit pipeline runs against. The live set talks to the
+// configured exchange, the shared price oracle, the Solana RPC pool and Jupiter; integration tests
+// can swap in in-memory mocks.
+#[derive(Clone)]
+pub struct PipelineClients {
+    pub exchange: Arc<dyn Exchange>,
+    pub prices: Arc<dyn PriceSource>,
+    pub solana_rpc: Arc<dyn SolanaRpc>,
+    pub jupiter: Arc<dyn JupiterApi>,
+}
+
+impl PipelineClients {
+    pub fn live() -> Self {
+        PipelineClients {
+            exchange: exchange::from_config(),
+            prices: PriceOracle::shared(),
+            solana_rpc: Arc::new(PooledRpc::new(RpcPool::shared())),
+            jupiter: Arc::new(JupiterSwapApiClient::new(config().jupiter_api_url.clone())),
+        }
+    }
+
+    pub fn lockin_client(&self) -> anyhow::Result<LockinClient> {
+        LockinClient::with_clients(self.solana_rpc.clone(), self.jupiter.clone())
+    }
+}
+
+// Set from the admin API to stop picking up new deposits without restarting the service
+static POLLER_PAUSED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_poller_paused(paused: bool) {
+    POLLER_PAUSED.store(paused, Ordering::SeqCst);
+    println!("Poller {}.", if paused { "paused" } else { "resumed" });
+}
+
+pub fn is_poller_paused() -> bool {
+    POLLER_PAUSED.load(Ordering::SeqCst)
+}
+
+// Health of the poll loop, exposed through the admin API
+static CONSECUTIVE_FAILURES: AtomicU64 = AtomicU64::new(0);
+static POLLER_RESTARTS: AtomicU64 = AtomicU64::new(0);
+
+const MAX_RESTART_BACKOFF_SECS: u64 = 300;
+// A poll loop that stayed up this long before dying starts over from the shortest backoff
+const HEALTHY_RUN_SECS: u64 = 600;
+
+// Kraken's smallest SOL withdrawal worth swapping, 0.0001 SOL
+const MIN_WITHDRAWAL_LAMPORTS: u64 = 100_000;
+
+pub fn poller_consecutive_failures() -> u64 {
+    CONSECUTIVE_FAILURES.load(Ordering::SeqCst)
+}
+
+pub fn poller_restarts() -> u64 {
+    POLLER_RESTARTS.load(Ordering::SeqCst)
+}
+
+// Counts a failed poll or crashed poll loop and alerts once the threshold is reached
+async fn record_poller_failure(reason: &str) {
+    let failures = CONSECUTIVE_FAILURES.fetch_add(1, Ordering::SeqCst) + 1;
+    if failures == config().poller_alert_threshold {
+        send_alert(&format!("Deposit poller failed {} times in a row: {}", failures, reason)).await;
+    }
+}
+
+// Keeps the poll loop running, restarting it with exponential backoff whenever it errors or panics
+pub async fn supervise_poller(db: Database) {
+    let deposit_events = Arc::new(Notify::new());
+    if kraken_ws_enabled() {
+        let deposit_events = deposit_events.clone();
+        spawn(async move {
+            stream_deposit_events(deposit_events).await;
+        });
+    }
+
+    let mut backoff = 1;
+    loop {
+        let started = Instant::now();
+        let reason = match spawn(start_poller(db.clone(), deposit_events.clone())).await {
+            Ok(Ok(())) => "poll loop exited".to_string(),
+            Ok(Err(e)) => format!("poll loop returned an error: {:?}", e),
+            Err(join_error) => format!("poll loop panicked: {:?}", join_error),
+        };
+        POLLER_RESTARTS.fetch_add(1, Ordering::SeqCst);
+        record_poller_failure(&reason).await;
+
+        if started.elapsed() >= Duration::from_secs(HEALTHY_RUN_SECS) {
+            backoff = 1;
+        }
+        eprintln!("Poller stopped ({}), restarting in {}s.", reason, backoff);
+        sleep(Duration::from_secs(backoff)).await;
+        backoff = (backoff * 2).min(MAX_RESTART_BACKOFF_SECS);
+    }
+}
+
+// Runs the poller every 60 seconds, or immediately when the Kraken WebSocket reports a deposit
+pub async fn start_poller(db: Database, deposit_events: Arc<Notify>) -> Result<(), AppError> {
+    let clients = PipelineClients::live();
     let mut interval = interval(Duration::from_secs(60));
     loop {
-        interval.tick().await;
-        match poll_kraken().await {
-            Ok(_) => println!("Polling successful."),
-            Err(e) => eprintln!("Polling failed: {:?}", e),
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = deposit_events.notified() => println!("Deposit event received, polling immediately."),
+        }
+        if is_poller_paused() {
+            println!("Poller paused, skipping poll.");
+            continue;
+        }
+        match maintenance::refresh().await {
+            Ok(true) => {
+                println!("Maintenance mode enabled, skipping poll.");
+                continue;
+            }
+            Ok(false) => {}
+            Err(e) => eprintln!("Failed to refresh maintenance mode: {:?}", e),
+        }
+        // Each cycle gets a request ID, stored on the transactions that fail in it
+        let cycle_id = request_id::generate();
+        match request_id::scope(cycle_id.clone(), poll_deposits(&clients, &db)).await {
+            Ok(_) => {
+                println!("Polling successful.");
+                CONSECUTIVE_FAILURES.store(0, Ordering::SeqCst);
+            }
+            // A hard-down upstream is already alerted on by its circuit breaker
+            Err(AppError::UpstreamUnavailable(e)) => println!("Skipping poll: {}", e),
+            Err(e) => {
+                eprintln!("Polling cycle {} failed: {}", cycle_id, e.report());
+                record_poller_failure(&format!("{} (request {})", e.report(), cycle_id)).await;
+            }
+        }
+        if !dry_run::is_enabled() {
+            if let Err(e) = retry_failed_refunds().await {
+                eprintln!("Retrying failed refunds failed: {:?}", e);
+            }
+            let resume_id = request_id::generate();
+            if let Err(e) = request_id::scope(resume_id, resume_deferred_transactions(&clients, &db)).await {
+                eprintln!("Resuming deferred transactions failed: {}", e.report());
+            }
+            if let Err(e) = redrive_deferred_swaps().await {
+                eprintln!("Re-driving deferred swaps failed: {}", e.report());
+            }
+            if let Err(e) = expire_stale_deposits(&db).await {
+                eprintln!("Expiring stale deposits failed: {}", e.report());
+            }
+            if let Err(e) = withdrawal_tracker::track_withdrawals(&clients).await {
+                eprintln!("Tracking withdrawals failed: {}", e.report());
+            }
         }
     }
 }
 
-// Polls Kraken for deposit status and processes any new transactions
-async fn poll_kraken() -> Result<(), AppError> {
-    println!("Polling Kraken for deposit status...");
+// Polls the exchange for deposit status and processes any new transactions
+#[tracing::instrument(name = "poll_cycle", skip_all, fields(exchange = clients.exchange.name()))]
+pub async fn poll_deposits(clients: &PipelineClients, db: &Database) -> Result<(), AppError> {
+    println!("Polling {} for deposit status...", clients.exchange.name());
 
-    // Retrieve MongoDB collections for users and transactions
-    let users_collection = get_users_collection().await?;
-    println!("Users collection retrieved.");
-    let transactions_collection = get_transactions_collection().await?;
-    println!("Transactions collection retrieved.");
+    // MongoDB collections for users and transactions, on the shared client
+    let users_collection = db.collection::<User>("users");
+    let transactions_collection = db.collection::<Document>("transactions");
 
-    // Fetch the deposit status from Kraken for Bitcoin Lightning deposits
-    let response = get_deposit_status("XBT", "Bitcoin Lightning").await?;
+    // Fetch the Bitcoin Lightning deposits from the exchange, only those since the persisted cursor
+    // once there is one
+    let settings = db.collection::<Document>("settings");
+    let cursor_id = format!("deposit_cursor_{}", clients.exchange.name().to_lowercase());
+    let start = settings
+        .find_one(doc! { "_id": &cursor_id }, None)
+        .await?
+        .and_then(|cursor| cursor.get_i64("time").ok());
+    let response = clients.exchange.get_deposit_status("XBT", "Bitcoin Lightning", start).await?;
     // println!("Kraken Deposit Response: {:?}", response);
 
-    // Process each transaction from the response
+    // Match each transaction from the response to its transaction document, grouped by user in the
+    // order Kraken reports them
+    let mut deposits_by_user: BTreeMap<i64, Vec<DepositEvent>> = BTreeMap::new();
+    let mut claimed_transactions = HashSet::new();
+    // The cursor moves up to the newest deposit, but no further than the oldest one the exchange
+    // hasn't settled yet so that one is fetched again until it has
+    let mut newest_deposit: Option<(i64, String)> = None;
+    let mut oldest_unsettled: Option<(i64, String)> = None;
     if let Some(transactions) = response.as_array() {
         for transaction in transactions {
-            let amount = transaction["amount"]
-                .as_str()
-                .unwrap_or("0.0")
-                .parse::<f64>()?;
-            let status = transaction["status"].as_str().unwrap_or("Unknown");
+            // Converted to satoshis here, so the deposit is credited exactly what Kraken reported
+            let amount = transaction["amount"].as_str().unwrap_or("0");
+            let amount_sats = Decimal::from_str(amount)
+                .map(btc_decimal_to_sats)
+                .map_err(|e| AppError::CustomError(format!("Invalid deposit amount {}: {}", amount, e)))?;
+            let deposit_status = DepositStatus::of_deposit(transaction);
+            let status = deposit_status.as_str();
             let time = transaction["time"].as_i64().unwrap_or(0);
             let address = transaction["info"].as_str().unwrap_or("Unknown");
+            // Kraken's refid uniquely identifies the deposit, fall back to the on-chain txid
+            let refid = match transaction["refid"]
+                .as_str()
+                .or_else(|| transaction["txid"].as_str())
+            {
+                Some(refid) => refid,
+                None => {
+                    eprintln!("Deposit without refid or txid, skipping: {}", transaction);
+                    continue;
+                }
+            };
+
+            if time > 0 && newest_deposit.as_ref().is_none_or(|(newest, _)| time >= *newest) {
+                newest_deposit = Some((time, refid.to_string()));
+            }
+            if !deposit_status.is_final() {
+                hold_cursor(&mut oldest_unsettled, time, refid);
+            }
 
             // Print the user_id, info, amount, time, and status
             println!(
-                "Transaction info - address: {}, amount: {}, time: {}, status: {}",
-                address, amount, time, status
+                "Transaction info - refid: {}, address: {}, amount: {}, time: {}, status: {}",
+                refid, address, amount, time, status
             );
 
-            // Check if the transaction already exists in the database
+            // Check if the transaction already exists in the database, either already linked
+            // to this deposit's refid or still waiting to be linked through its address
             if let Some(tx) = transactions_collection
-                .find_one(doc! { "address": address }, None)
+                .find_one(
+                    doc! {
+                        "$or": [
+                            { "refid": refid },
+                            { "address": address, "refid": { "$exists": false } },
+                        ]
+                    },
+                    None,
+                )
                 .await?
             {
-                let user_id_result = tx.get("user_id");
-                match user_id_result {
-                    Some(Bson::Int32(user_id)) => {
-                        println!(
-                            "Transaction found for user_id (i32)={}, address: {}, amount: {}, time: {}, status: {}",
-                            user_id, address, amount, time, status
-                        );
-                        handle_transaction(
-                            &users_collection,
-                            &transactions_collection,
-                            *user_id as i64,
-                            amount,
-                            address,
-                            status,
-                            time,
-                            tx.clone(),
-                        )
-                        .await?;
-                    }
-                    Some(Bson::Int64(user_id)) => {
-                        println!(
-                            "Transaction found for user_id (i64)={}, address: {}, amount: {}, time: {}, status: {}",
-                            user_id, address, amount, time, status
-                        );
-                        handle_transaction(
-                            &users_collection,
-                            &transactions_collection,
-                            *user_id,
-                            amount,
-                            address,
-                            status,
-                            time,
-                            tx.clone(),
-                        )
-                        .await?;
+                // A second deposit to a still unlinked address is linked next cycle, once the first
+                // one has taken this transaction document
+                if let Ok(tx_id) = tx.get_object_id("_id") {
+                    if !claimed_transactions.insert(tx_id) {
+                        println!("Transaction for address {} already claimed this cycle, deferring refid {}", address, refid);
+                        hold_cursor(&mut oldest_unsettled, time, refid);
+                        continue;
                     }
+                }
+                // A deposit that hasn't been credited yet, for instance one still waiting out its
+                // settlement delay, is fetched again next cycle
+                if matches!(
+                    TransactionState::of_document(&tx),
+                    TransactionState::DepositPending | TransactionState::Expired
+                ) {
+                    hold_cursor(&mut oldest_unsettled, time, refid);
+                }
+                let user_id = match tx.get("user_id") {
+                    Some(Bson::Int32(user_id)) => *user_id as i64,
+                    Some(Bson::Int64(user_id)) => *user_id,
                     Some(other) => {
                         eprintln!("Unexpected type for user_id: {:?}", other.element_type());
+                        continue;
                     }
                     None => {
                         eprintln!("user_id field is missing");
+                        continue;
                     }
-                }
+                };
+                println!(
+                    "Transaction found for user_id={}, address: {}, amount: {}, time: {}, status: {}",
+                    user_id, address, amount, time, status
+                );
+                deposits_by_user.entry(user_id).or_default().push(DepositEvent {
+                    amount_sats,
+                    refid: refid.to_string(),
+                    address: address.to_string(),
+                    status: deposit_status,
+                    time,
+                    tx,
+                });
             } else {
                 println!("Transaction not found in database. Skipping...");
             }
         }
     }
 
+    // Users are processed DEPOSIT_CONCURRENCY at a time, so one slow deposit doesn't hold up everyone
+    // else's, while each user's deposits still run one after the other in order. A failed deposit
+    // stops the rest of its user's, the cycle fails with the first error once all users are done.
+    let results: Vec<Result<(), AppError>> = stream::iter(deposits_by_user)
+        .map(|(user_id, deposits)| {
+            let users_collection = &users_collection;
+            let transactions_collection = &transactions_collection;
+            async move {
+                for deposit in deposits {
+                    handle_transaction(
+                        clients,
+                        users_collection,
+                        transactions_collection,
+                        user_id,
+                        deposit.amount_sats,
+                        &deposit.refid,
+                        &deposit.address,
+                        deposit.status,
+                        deposit.time,
+                        deposit.tx,
+                    )
+                    .await?;
+                }
+                Ok(())
+            }
+        })
+        .buffer_unordered(config().deposit_concurrency)
+        .collect()
+        .await;
+    results.into_iter().collect::<Result<(), AppError>>()?;
+
+    // Only a cycle that got through every deposit moves the cursor. Its timestamp is inclusive, so
+    // deposits made in the same second are fetched again and skipped by their state.
+    if let Some((time, refid)) = oldest_unsettled.or(newest_deposit) {
+        if start.is_none_or(|start| time > start) {
+            settings
+                .update_one(
+                    doc! { "_id": &cursor_id },
+                    doc! { "$set": { "time": time, "refid": &refid, "updated_at": BsonDateTime::now() } },
+                    UpdateOptions::builder().upsert(true).build(),
+                )
+                .await?;
+            println!("Deposit cursor moved to {} (refid {})", time, refid);
+        }
+    }
     Ok(())
 }
 
-// Handles the processing of a transaction based on user_id type
+// Keeps the deposit cursor at or before the deposit made at `time`
+fn hold_cursor(oldest_unsettled: &mut Option<(i64, String)>, time: i64, refid: &str) {
+    if time > 0 && oldest_unsettled.as_ref().is_none_or(|(oldest, _)| time < *oldest) {
+        *oldest_unsettled = Some((time, refid.to_string()));
+    }
+}
+
+// A deposit reported by the exchange, matched to its transaction document
+struct DepositEvent {
+    amount_sats: u64,
+    refid: String,
+    address: String,
+    status: DepositStatus,
+    time: i64,
+    tx: Document,
+}
+
+// Handles the processing of a transaction based on user_id type, each deposit in its own trace
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(
+    name = "deposit",
+    parent = None,
+    skip_all,
+    fields(
+        request_id = %request_id::current().unwrap_or_default(),
+        refid = refid,
+        user_id = user_id,
+        amount_sats = amount_sats,
+        status = status.as_str()
+    )
+)]
 async fn handle_transaction(
+    clients: &PipelineClients,
     users_collection: &Collection<User>,
     transactions_collection: &Collection<Document>,
     user_id: i64,
-    amount: f64,
+    amount_sats: u64,
+    refid: &str,
     address: &str,
-    status: &str,
+    status: DepositStatus,
     time: i64,
     tx: Document,
 ) -> Result<(), AppError> {
+    let tx_id = tx.get_object_id("_id").map_err(|_| {
+        AppError::CustomError("Transaction document is missing _id".to_string())
+    })?;
+    let state_machine = TransactionStateMachine::new(transactions_collection.clone(), tx_id);
+
     // If the user exists in the database, process their transaction
     if let Some(user_doc) = users_collection
         .find_one(doc! { "user_id": user_id }, None)
         .await?
     {
-        // Update the status of the transaction
-        transactions_collection
-            .update_one(
-                doc! { "address": address },
-                doc! { "$set": { "status": status.to_string() } },
-                None,
+        // In dry-run mode the transaction is left untouched and each deposit is only run through once
+        if dry_run::is_enabled() {
+            if !should_process_transaction(status, TransactionState::of_document(&tx)) || dry_run::has_processed_deposit(refid).await? {
+                return Ok(());
+            }
+            dry_run::record_action(
+                "process_deposit",
+                json!({ "refid": refid, "user_id": user_id, "amount": sats_to_btc(amount_sats), "address": address }),
             )
-            .await?;
-        println!("Transaction status updated to {}", status);
-        if should_process_transaction(&tx) {
-            println!("Processing user transaction...");
-
-            process_user_transaction(
-                amount,
+            .await;
+            return process_user_transaction(
+                clients,
+                amount_sats,
                 user_id,
                 address,
-                status,
+                status.as_str(),
                 time,
                 user_doc,
                 users_collection,
-                // transactions_collection,
+                transactions_collection,
+                &state_machine,
             )
-            .await?;
+            .await;
+        }
 
-            // Mark the transaction as processed
-            transactions_collection
-                .update_one(
-                    doc! { "address": address },
-                    doc! { "$set": { "processed": true } },
-                    None,
-                )
-                .await?;
-            println!("Transaction marked as processed.");
-        } else {
+        // Record Kraken's deposit status, appending it to the status history when it changed, and link
+        // the transaction to the Kraken deposit
+        let now = BsonDateTime::now();
+        let status_changed = tx.get_str("kraken_status").ok() != Some(status.as_str());
+        let mut update = doc! {
+            "$set": { "kraken_status": status.as_str(), "refid": refid },
+            "$min": { "kraken_first_seen_at": now },
+        };
+        if status_changed {
+            update.insert("$push", doc! { "kraken_status_history": { "status": status.as_str(), "at": now } });
+        }
+        transactions_collection.update_one(doc! { "_id": tx_id }, update, None).await?;
+        println!("Kraken deposit status updated to {}", status.as_str());
+
+        let mut state = TransactionState::of_document(&tx);
+        // A hold placed on a deposit Kraken had already reported as Success restarts its settlement delay
+        if status != DepositStatus::Success && state == TransactionState::DepositPending {
+            settlement::is_creditable(transactions_collection, &tx, false).await?;
+        }
+        match status {
+            DepositStatus::Failure => {
+                if state.can_transition_to(TransactionState::DepositFailed)
+                    && state_machine
+                        .try_transition(
+                            state,
+                            TransactionState::DepositFailed,
+                            doc! { "processing_error": "Kraken reported the deposit as failed" },
+                        )
+                        .await?
+                {
+                    notify_user(
+                        user_id,
+                        format!(
+                            "Your deposit of {} BTC failed on the exchange and was not credited. Contact support if the funds left your wallet.",
+                            sats_to_btc_decimal(amount_sats)
+                        ),
+                    );
+                }
+                return Ok(());
+            }
+            DepositStatus::OnHold => {
+                if status_changed && state == TransactionState::DepositPending {
+                    notify_user(
+                        user_id,
+                        format!(
+                            "Your deposit of {} BTC is on hold at the exchange for review. It will be processed once the exchange releases it.",
+                            sats_to_btc_decimal(amount_sats)
+                        ),
+                    );
+                }
+                return Ok(());
+            }
+            DepositStatus::Pending | DepositStatus::Settled => return Ok(()),
+            DepositStatus::Success => {}
+        }
+
+        // A deposit that expired while pending is processed after all once Kraken credits it
+        if state == TransactionState::Expired
+            && state_machine
+                .try_transition(TransactionState::Expired, TransactionState::DepositPending, Document::new())
+                .await?
+        {
+            println!("Expired transaction {} was credited by Kraken, processing it", refid);
+            state = TransactionState::DepositPending;
+        }
+
+        if !should_process_transaction(status, state) {
             println!("Transaction already exists and has been processed.");
+            return Ok(());
+        }
+        if !settlement::is_creditable(transactions_collection, &tx, true).await? {
+            return Ok(());
+        }
+
+        // Claim the deposit with a compare-and-set on its state, so concurrent poll
+        // cycles or other instances can never swap the same deposit twice
+        let claimed = settle_deposit(
+            users_collection,
+            &state_machine,
+            user_id,
+            amount_sats,
+            BsonDateTime::from_millis(time * 1000),
+            clients.prices.as_ref(),
+        )
+        .await?;
+        if !claimed {
+            println!("Transaction {} was claimed by another poller. Skipping...", refid);
+            return Ok(());
         }
+
+        println!("Processing user transaction...");
+        if let Err(e) = process_user_transaction(
+            clients,
+            amount_sats,
+            user_id,
+            address,
+            status.as_str(),
+            time,
+            user_doc,
+            users_collection,
+            transactions_collection,
+            &state_machine,
+        )
+        .await
+        {
+            // Failed is terminal for the poller, part of the swap may already have gone through on Kraken
+            state_machine.fail(&e.report()).await?;
+            return Err(e);
+        }
+        println!("Transaction processed.");
     }
     Ok(())
 }
 
-// Determines if a transaction should be processed based on its status and processed flag
-fn should_process_transaction(tx: &Document) -> bool {
-    println!("Checking if transaction should be processed...");
-    match tx.get_str("status") {
-        Ok(existing_status)
-            if existing_status == "Success" && !(tx.get_bool("processed").unwrap()) =>
-        {
-            println!("\nProcessed is: {}\n", tx.get_bool("processed").unwrap());
-            true
+// Claims a deposit that arrived outside the Kraken poller (on-chain or through LND) into
+// DepositSettled and runs it through the pipeline, so it is processed exactly once
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(
+    name = "deposit",
+    parent = None,
+    skip_all,
+    fields(request_id = %request_id::current().unwrap_or_default(), transaction_id = %tx_id, user_id = user_id, amount_sats = amount_sats)
+)]
+pub(crate) async fn claim_and_process_deposit(
+    clients: &PipelineClients,
+    users_collection: &Collection<User>,
+    transactions_collection: &Collection<Document>,
+    tx_id: ObjectId,
+    user_id: i64,
+    amount_sats: u64,
+    address: &str,
+    time: i64,
+) -> Result<(), AppError> {
+    let state_machine = TransactionStateMachine::new(transactions_collection.clone(), tx_id);
+    let user = users_collection.find_one(doc! { "user_id": user_id }, None).await?;
+    let claimed = settle_deposit(
+        users_collection,
+        &state_machine,
+        user_id,
+        amount_sats,
+        BsonDateTime::now(),
+        clients.prices.as_ref(),
+    )
+    .await?;
+    if !claimed {
+        return Ok(());
+    }
+
+    let user = match user {
+        Some(user) => user,
+        None => {
+            state_machine.fail("User not found").await?;
+            return Ok(());
         }
-        _ => {
-            println!("\nNot Processing tx: {}\n", tx);
-            false
+    };
+    if let Err(e) = process_user_transaction(
+        clients,
+        amount_sats,
+        user_id,
+        address,
+        "Success",
+        time,
+        user,
+        users_collection,
+        transactions_collection,
+        &state_machine,
+    )
+    .await
+    {
+        state_machine.fail(&e.report()).await?;
+        return Err(e);
+    }
+    Ok(())
+}
+
+// Claims a pending deposit into DepositSettled, recording the credited amount on it, and credits it to
+// the user's total deposit in the same transaction, so a crash can't leave one without the other.
+// Returns false when another worker claimed the deposit first.
+async fn settle_deposit(
+    users_collection: &Collection<User>,
+    state_machine: &TransactionStateMachine,
+    user_id: i64,
+    amount_sats: u64,
+    settled_at: BsonDateTime,
+    prices: &dyn PriceSource,
+) -> Result<bool, AppError> {
+    // The BTC price at deposit time is the deposit's cost basis, a deposit is credited without one
+    let btc_price_usd = match prices.usd_price("BTC").await {
+        Ok(price) => Some(price),
+        Err(e) => {
+            eprintln!("Failed to look up the BTC price of transaction {}: {:?}", state_machine.tx_id(), e);
+            None
         }
+    };
+    let mut writes = AtomicWrites::start(users_collection.client()).await?;
+    let claimed = state_machine
+        .try_transition_in(
+            &mut writes,
+            TransactionState::DepositPending,
+            TransactionState::DepositSettled,
+            doc! {
+                "settled_at": settled_at,
+                "deposit_amount_sats": amount_sats as i64,
+                "cost_basis.btc_price_usd": btc_price_usd,
+            },
+        )
+        .await?;
+    if !claimed {
+        return Ok(false);
+    }
+    if !dry_run::is_enabled() {
+        // Summed in satoshis, starting from the BTC total for users credited before it was kept, and
+        // the BTC total follows from it
+        let sats_per_btc = SATS_PER_BTC as i64;
+        let legacy_sats = doc! {
+            "$toLong": { "$round": [{ "$multiply": [{ "$ifNull": ["$total_deposit", 0] }, sats_per_btc] }, 0] }
+        };
+        let update = vec![
+            doc! { "$set": { "total_deposit_sats": {
+                "$add": [{ "$ifNull": ["$total_deposit_sats", legacy_sats] }, amount_sats as i64]
+            } } },
+            doc! { "$set": { "total_deposit": { "$divide": ["$total_deposit_sats", sats_per_btc as f64] } } },
+        ];
+        writes.update_one(users_collection, doc! { "user_id": user_id }, update).await?;
+    }
+    // The deposit is on the exchange and owed to the user
+    Journal::for_transaction(state_machine.tx_id(), "deposit", user_id)
+        .transfer(Account::User(user_id), Account::Exchange, "BTC", sats_to_btc_decimal(amount_sats))
+        .post(&mut writes)
+        .await?;
+    writes.commit().await?;
+    println!("Credited {} sats to the total deposit of user {}", amount_sats, user_id);
+    Ok(true)
+}
+
+// The amount a settled transaction sells, what the exchange credited for a deposit or what a purchase
+// drew from the user's balance. The bot's `amount` is only what the user said they would send, so
+// anything run again after settlement goes by this.
+pub(crate) fn settled_amount(tx: &Document) -> Option<f64> {
+    match (tx.get("deposit_amount_sats"), tx.get("purchase_amount")) {
+        (Some(Bson::Int64(sats)), _) if *sats > 0 => Some(sats_to_btc(*sats as u64)),
+        (_, Some(Bson::Double(amount))) if *amount > 0.0 => Some(*amount),
+        _ => None,
+    }
+}
+
+// Determines if a transaction should be processed based on Kraken's deposit status and its state
+fn should_process_transaction(status: DepositStatus, state: TransactionState) -> bool {
+    println!("Checking if transaction should be processed...");
+    if status == DepositStatus::Success && state == TransactionState::DepositPending {
+        println!("\nTransaction state is: {}\n", state);
+        true
+    } else {
+        println!("\nNot Processing tx in state {}\n", state);
+        false
     }
 }
 
 // Processes a user's transaction, updating their deposit and performing necessary swaps and withdrawals
-async fn process_user_transaction(
-    amount: f64,
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn process_user_transaction(
+    clients: &PipelineClients,
+    amount_sats: u64,
     user_id: i64,
     address: &str,
     status: &str,
     time: i64,
     user_doc: User,
     users_collection: &Collection<User>,
-    // transactions_collection: &Collection<Document>,
+    transactions_collection: &Collection<Document>,
+    state_machine: &TransactionStateMachine,
 ) -> Result<(), AppError> {
     println!(
-        "Processing user transaction: amount={}, user_id={}, address={}, status={}, time={}",
-        amount, user_id, address, status, time
+        "Processing user transaction: amount={} sats, user_id={}, address={}, status={}, time={}",
+        amount_sats, user_id, address, status, time
     );
+    // The deposit limits, the autobuy and DCA balances and the exchange orders are in BTC
+    let amount = sats_to_btc(amount_sats);
 
-    // Calculate the new total deposit for the user
-    let current_total_deposit = user_doc.total_deposit;
-    let new_total_deposit = current_total_deposit + amount;
-    let found_address = user_doc.solana_public_key.unwrap_or(Default::default());
+    // The user document was read before the deposit was credited when it was claimed
+    let current_total_deposit = user_doc.deposited_sats();
+    let new_total_deposit = current_total_deposit.saturating_add(amount_sats);
+    let slippage_bps = user_doc.slippage_bps.unwrap_or(DEFAULT_SLIPPAGE_BPS);
+    let autobuy_amount = user_doc.autobuy_amount;
+    let dca_active = user_doc.dca_active;
+    let found_address = user_doc.solana_public_key.unwrap_or_default();
 
     println!(
-        "User current total deposit: {}, new total deposit: {}",
+        "User current total deposit: {} sats, new total deposit: {} sats",
         current_total_deposit, new_total_deposit
     );
     println!("User Solana address: {}", found_address);
 
-    // Parse the user's Solana public key
-    let user_sol_address = Pubkey::from_str(&found_address).unwrap_or_else(|_| {
-        eprintln!("Invalid user Solana address.");
-        Pubkey::default()
-    });
-
-    // Update the user's total deposit in the users collection
-    users_collection
-        .update_one(
-            doc! { "user_id": user_id },
-            doc! { "$set": { "total_deposit": new_total_deposit } },
-            None,
-        )
-        .await?;
-    println!("Updated total deposit for user: {:?}", user_id);
+    // Parse the user's Solana public key. Nothing is bought or withdrawn for an address that can't
+    // receive it, the deposit is held until the user sets a valid one.
+    let user_sol_address = match solana_address(&found_address) {
+        Ok(address) => address,
+        Err(e) => {
+            hold_for_attention(users_collection, state_machine, user_id, &e.to_string()).await?;
+            return Ok(());
+        }
+    };
 
     // If the transaction status is "Success", process the transaction further
     if status == "Success" {
         println!("Transaction status is Success. Processing further...");
-        process_successful_transaction(
-            amount,
+
+        // Deposits over the deposit limits wait for an admin to approve them
+        if let Some(reason) =
+            deposit_limit_exceeded(transactions_collection, state_machine.tx_id(), user_id, amount_sats).await?
+        {
+            hold_for_review(state_machine, user_id, amount, &reason).await?;
+            return Ok(());
+        }
+
+        // Deposits of users on a DCA plan are held on Kraken for the DCA scheduler to draw from
+        if dca_active && !dry_run::is_enabled() {
+            let mut writes = AtomicWrites::start(users_collection.client()).await?;
+            if !state_machine
+                .try_transition_in(&mut writes, TransactionState::DepositSettled, TransactionState::Completed, Document::new())
+                .await?
+            {
+                return Err(AppError::CustomError(format!(
+                    "Transaction {} is no longer in state {}",
+                    state_machine.tx_id(),
+                    TransactionState::DepositSettled
+                )));
+            }
+            hold_deposit(&mut writes, users_collection, user_id, amount).await?;
+            writes.commit().await?;
+            println!("User has an active DCA plan, deposit held.\n");
+            return Ok(());
+        }
+
+        // With an autobuy threshold set, deposits accumulate until the threshold is reached. Deposits
+        // under MIN_DEPOSIT_BTC accumulate the same way until they add up to it.
+        let threshold = match (autobuy_amount, config().min_deposit_btc) {
+            (Some(autobuy_amount), Some(min_deposit)) => Some(autobuy_amount.max(min_deposit)),
+            (Some(autobuy_amount), None) => Some(autobuy_amount),
+            (None, Some(min_deposit)) if amount < min_deposit => Some(min_deposit),
+            (None, _) => None,
+        };
+        let swap_amount = match threshold {
+            Some(threshold) if !dry_run::is_enabled() => {
+                match accumulate_deposit(users_collection, user_id, amount, threshold).await? {
+                    Some(accumulated) => accumulated,
+                    None => {
+                        state_machine
+                            .transition(TransactionState::DepositSettled, TransactionState::Completed)
+                            .await?;
+                        println!("Threshold of {} not reached, deposit accumulated.\n", threshold);
+                        return Ok(());
+                    }
+                }
+            }
+            _ => amount,
+        };
+
+        if let Err(e) = process_successful_transaction(
+            clients,
+            swap_amount,
+            "BTC",
             user_sol_address,
             user_id,
             users_collection,
-            // transactions_collection,
+            transactions_collection,
+            state_machine,
             new_total_deposit,
+            slippage_bps,
         )
-        .await?;
+        .await
+        {
+            if defer_if_upstream_down(state_machine, &e, swap_amount).await? {
+                return Ok(());
+            }
+            return Err(e);
+        }
     } else {
         println!("Transaction is not ready to be processed.\n");
     }
@@ -256,19 +851,317 @@ async fn process_user_transaction(
 
 use tokio::task::spawn;
 
-// Processes a successful transaction, including swapping BTC to USD, buying SOL, and withdrawing assets
-async fn process_successful_transaction(
+// Parks a settled deposit in NeedsAttention, leaves the user a notice to fix their Solana address
+// and alerts the operator. The deposit stays on the exchange untouched until it is released.
+pub(crate) async fn hold_for_attention(
+    users_collection: &Collection<User>,
+    state_machine: &TransactionStateMachine,
+    user_id: i64,
+    reason: &str,
+) -> Result<(), AppError> {
+    // The hold and the user's notice land together
+    let mut writes = AtomicWrites::start(users_collection.client()).await?;
+    state_machine
+        .try_transition_in(
+            &mut writes,
+            TransactionState::DepositSettled,
+            TransactionState::NeedsAttention,
+            doc! { "attention_reason": reason },
+        )
+        .await?;
+    if !dry_run::is_enabled() {
+        writes
+            .update_one(
+                users_collection,
+                doc! { "user_id": user_id },
+                doc! { "$set": { "address_notice": format!(
+                    "Deposits are on hold: {}. Set a valid address with POST /preferences/solana_address",
+                    reason
+                ) } },
+            )
+            .await?;
+    }
+    writes.commit().await?;
+    send_alert(&format!(
+        "Transaction {} of user {} held: {}",
+        state_machine.tx_id(),
+        user_id,
+        reason
+    ))
+    .await;
+    Ok(())
+}
+
+// Why a deposit needs review: it is over MAX_DEPOSIT_BTC, or takes its user past MAX_DAILY_DEPOSIT_BTC
+// together with their other BTC deposits settled in the last 24 hours
+async fn deposit_limit_exceeded(
+    transactions_collection: &Collection<Document>,
+    tx_id: ObjectId,
+    user_id: i64,
+    amount_sats: u64,
+) -> Result<Option<String>, AppError> {
+    let amount = sats_to_btc(amount_sats);
+    if let Some(max_deposit) = config().max_deposit_btc {
+        if amount > max_deposit {
+            return Ok(Some(format!("Deposit of {} BTC is over the {} BTC deposit limit", amount, max_deposit)));
+        }
+    }
+    let Some(max_daily_deposit) = config().max_daily_deposit_btc else {
+        return Ok(None);
+    };
+
+    let since = BsonDateTime::from_millis(BsonDateTime::now().timestamp_millis() - 24 * 3_600_000);
+    let not_credited = [
+        TransactionState::DepositPending.as_str(),
+        TransactionState::DepositFailed.as_str(),
+        TransactionState::Expired.as_str(),
+        TransactionState::UnderReview.as_str(),
+    ];
+    let pipeline = vec![
+        doc! { "$match": {
+            "_id": { "$ne": tx_id },
+            "user_id": user_id,
+            "settled_at": { "$gte": since },
+            "state": { "$nin": not_credited.to_vec() },
+            "deposit_asset": { "$in": [Bson::Null, "BTC"] },
+        } },
+        // What the exchange credited, the bot's `amount` is only what the user said they would send
+        doc! { "$group": { "_id": Bson::Null, "total": { "$sum": "$deposit_amount_sats" } } },
+    ];
+    let mut cursor = transactions_collection.aggregate(pipeline, None).await?;
+    let settled_today = if cursor.advance().await? {
+        match cursor.deserialize_current()?.get("total") {
+            Some(Bson::Int64(total)) => sats_to_btc(*total as u64),
+            Some(Bson::Int32(total)) => sats_to_btc(*total as u64),
+            _ => 0.0,
+        }
+    } else {
+        0.0
+    };
+    if settled_today + amount > max_daily_deposit {
+        return Ok(Some(format!(
+            "Deposit of {} BTC takes the user to {} BTC in 24 hours, over the {} BTC daily limit",
+            amount,
+            settled_today + amount,
+            max_daily_deposit
+        )));
+    }
+    Ok(None)
+}
+
+// Parks a settled deposit that is over the deposit limits UnderReview, tells the user and alerts the
+// operator. The deposit stays on the exchange untouched until an admin approves it.
+async fn hold_for_review(
+    state_machine: &TransactionStateMachine,
+    user_id: i64,
     amount: f64,
+    reason: &str,
+) -> Result<(), AppError> {
+    if !state_machine
+        .try_transition(TransactionState::DepositSettled, TransactionState::UnderReview, doc! { "review_reason": reason })
+        .await?
+    {
+        return Ok(());
+    }
+    notify_user(
+        user_id,
+        format!("Your deposit of {} BTC is being reviewed and will be processed once it is approved.", amount),
+    );
+    send_alert(&format!(
+        "Transaction {} of user {} held for review: {}. Approve it with POST /admin/transactions/{}/approve",
+        state_machine.tx_id(),
+        user_id,
+        reason,
+        state_machine.tx_id()
+    ))
+    .await;
+    Ok(())
+}
+
+// Moves the user's held deposits back to DepositSettled and runs them through the pipeline to
+// their (now valid) Solana address. Returns the ids of the released transactions.
+pub(crate) async fn release_held_transactions(
+    users_collection: &Collection<User>,
+    transactions_collection: &Collection<Document>,
+    user: &User,
+    user_sol_address: Pubkey,
+) -> Result<Vec<ObjectId>, AppError> {
+    let filter = doc! {
+        "state": TransactionState::NeedsAttention.as_str(),
+        "$or": [ { "user_id": user.user_id }, { "user_id": user.user_id as i32 } ],
+    };
+    let mut cursor = transactions_collection.find(filter, None).await?;
+    let mut held = Vec::new();
+    while cursor.advance().await? {
+        held.push(cursor.deserialize_current()?);
+    }
+
+    let mut released = Vec::new();
+    for tx in held {
+        let Ok(tx_id) = tx.get_object_id("_id") else {
+            continue;
+        };
+        // Without a recorded settled amount there is nothing safe to sell, it stays held for an admin
+        let Some(amount) = settled_amount(&tx) else {
+            eprintln!("Held transaction {} has no settled amount, leaving it for manual review", tx_id);
+            continue;
+        };
+        let state_machine = TransactionStateMachine::new(transactions_collection.clone(), tx_id);
+        if !state_machine
+            .try_transition(TransactionState::NeedsAttention, TransactionState::DepositSettled, Document::new())
+            .await?
+        {
+            continue;
+        }
+        released.push(tx_id);
+
+        let deposit_asset = tx.get_str("deposit_asset").unwrap_or("BTC").to_string();
+        let users_collection = users_collection.clone();
+        let transactions_collection = transactions_collection.clone();
+        let user_id = user.user_id;
+        let total_deposit = user.deposited_sats();
+        let slippage_bps = user.slippage_bps.unwrap_or(DEFAULT_SLIPPAGE_BPS);
+        spawn(async move {
+            if let Err(e) = process_successful_transaction(
+                &PipelineClients::live(),
+                amount,
+                &deposit_asset,
+                user_sol_address,
+                user_id,
+                &users_collection,
+                &transactions_collection,
+                &state_machine,
+                total_deposit,
+                slippage_bps,
+            )
+            .await
+            {
+                eprintln!("Released transaction {} failed: {:?}", tx_id, e);
+                match defer_if_upstream_down(&state_machine, &e, amount).await {
+                    Ok(true) => {}
+                    _ => {
+                        if let Err(e) = state_machine.fail(&e.report()).await {
+                            eprintln!("Error marking transaction {} failed: {:?}", tx_id, e);
+                        }
+                    }
+                }
+            }
+        });
+    }
+    Ok(released)
+}
+
+// Defers a settled deposit that couldn't be processed because an upstream's circuit breaker is open,
+// nothing has been sold for it yet. Returns false when the error is another one or the deposit has
+// moved on, and has to be failed.
+async fn defer_if_upstream_down(
+    state_machine: &TransactionStateMachine,
+    error: &AppError,
+    amount: f64,
+) -> Result<bool, AppError> {
+    if !matches!(error, AppError::UpstreamUnavailable(_)) {
+        return Ok(false);
+    }
+    let deferred = state_machine.defer(&error.report(), amount).await?;
+    if deferred {
+        println!("Transaction {} deferred: {}", state_machine.tx_id(), error);
+    }
+    Ok(deferred)
+}
+
+// Runs deferred deposits through the pipeline once Kraken takes calls again. They are resumed one at
+// a time, so the first one failing on a still-down Kraken leaves the rest deferred.
+async fn resume_deferred_transactions(clients: &PipelineClients, db: &Database) -> Result<(), AppError> {
+    if circuit_breaker::KRAKEN.is_open() {
+        return Ok(());
+    }
+    let users_collection = db.collection::<User>("users");
+    let transactions_collection = db.collection::<Document>("transactions");
+    let mut cursor = transactions_collection
+        .find(doc! { "state": TransactionState::Deferred.as_str() }, None)
+        .await?;
+    let mut deferred = Vec::new();
+    while cursor.advance().await? {
+        deferred.push(cursor.deserialize_current()?);
+    }
+
+    for tx in deferred {
+        let amount = tx.get_f64("deferred_amount").or_else(|_| tx.get_f64("amount"));
+        let (tx_id, amount) = match (tx.get_object_id("_id"), amount) {
+            (Ok(tx_id), Ok(amount)) => (tx_id, amount),
+            _ => continue,
+        };
+        // The bot stores user_id as either an int32 or an int64
+        let user_id = match tx.get("user_id") {
+            Some(Bson::Int32(user_id)) => *user_id as i64,
+            Some(Bson::Int64(user_id)) => *user_id,
+            _ => continue,
+        };
+        let user = match users_collection.find_one(doc! { "user_id": user_id }, None).await? {
+            Some(user) => user,
+            None => continue,
+        };
+        let state_machine = TransactionStateMachine::new(transactions_collection.clone(), tx_id);
+        if !state_machine
+            .try_transition(TransactionState::Deferred, TransactionState::DepositSettled, Document::new())
+            .await?
+        {
+            continue;
+        }
+        let user_sol_address = match solana_address(user.solana_public_key.as_deref().unwrap_or_default()) {
+            Ok(address) => address,
+            Err(e) => {
+                hold_for_attention(&users_collection, &state_machine, user_id, &e.to_string()).await?;
+                continue;
+            }
+        };
+
+        println!("Resuming deferred transaction {}", tx_id);
+        if let Err(e) = process_successful_transaction(
+            clients,
+            amount,
+            tx.get_str("deposit_asset").unwrap_or("BTC"),
+            user_sol_address,
+            user_id,
+            &users_collection,
+            &transactions_collection,
+            &state_machine,
+            user.deposited_sats(),
+            user.slippage_bps.unwrap_or(DEFAULT_SLIPPAGE_BPS),
+        )
+        .await
+        {
+            if defer_if_upstream_down(&state_machine, &e, amount).await? {
+                break;
+            }
+            eprintln!("Deferred transaction {} failed: {:?}", tx_id, e);
+            state_machine.fail(&e.report()).await?;
+        }
+    }
+    Ok(())
+}
+
+// Processes a successful transaction, including selling the deposited asset (BTC unless noted) for
+// USD, buying SOL, and withdrawing assets
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn process_successful_transaction(
+    clients: &PipelineClients,
+    amount: f64,
+    deposit_asset: &str,
     user_sol_address: Pubkey,
     user_id: i64,
     users_collection: &Collection<User>,
-    // transactions_collection: &Collection<Document>,
-    new_total_deposit: f64,
+    transactions_collection: &Collection<Document>,
+    state_machine: &TransactionStateMachine,
+    new_total_deposit_sats: u64,
+    slippage_bps: u16,
 ) -> Result<(), AppError> {
     println!("Processing successful transaction for user_id={}", user_id);
+    let tx_id = state_machine.tx_id();
 
-    let swap_amount = amount;
-    if swap_amount <= 0.0 {
+    // Exchange amounts are computed as decimals, the deposit amount is read from MongoDB as a double
+    let swap_amount = to_decimal(amount)?;
+    if swap_amount <= Decimal::ZERO {
         eprintln!(
             "Swap amount is non-positive, skipping swap for user: {:?}",
             user_id
@@ -276,98 +1169,1032 @@ async fn process_successful_transaction(
         return Ok(());
     }
 
-    if swap_amount < 0.0001 {
-        eprintln!("Volume too small: {} < 0.0001", swap_amount);
+    let min_volume = exchange::minimum_sell_volume(deposit_asset);
+    if swap_amount < min_volume {
+        eprintln!("Volume too small: {} < {}", swap_amount, min_volume);
         return Err(AppError::CustomError("Volume too small".to_string()));
     }
 
-    // Perform BTC to USD swap
-    println!("Selling {} BTC", swap_amount);
-    let btc_usd_response = execute_swap("BTCUSD", OrderSide::Sell, swap_amount).await?;
-    println!("BTC to USD swap response: {:?}", btc_usd_response);
+    // Resolved before anything is traded, so a user mapped to a key that isn't registered fails here
+    let withdrawal_key = withdrawal_addresses::for_user(users_collection, user_id).await?;
 
-    // Calculate the amount of SOL to buy with the USD obtained from the BTC swap
-    let sol_amount = btc_usd_response["notional_sol_value"]
-        .as_f64()
-        .unwrap_or_else(|| {
-            btc_usd_response["notional_usd_value"]
-                .as_f64()
-                .unwrap_or(0.0)
-        });
+    // Sell the deposited asset for USD
+    let exchange = &clients.exchange;
+    exchange.ensure_balance(deposit_asset, swap_amount).await?;
+    println!("Selling {} {}", swap_amount, deposit_asset);
+    // Wait for the sell to fill before spending its proceeds
+    let sell_pair = format!("{}USD", deposit_asset);
+    let (sell_txid, sell_order) = exchange.place_order_and_wait(&sell_pair, OrderSide::Sell, swap_amount).await?;
+    record_order_fill(transactions_collection, tx_id, "sell", &sell_txid, &sell_order).await?;
+    Journal::for_transaction(tx_id, "sell", user_id)
+        .transfer(Account::Exchange, Account::User(user_id), deposit_asset, sell_order.volume_executed)
+        .transfer(Account::User(user_id), Account::Exchange, "USD", sell_order.cost - sell_order.fee)
+        .record()
+        .await;
+    state_machine
+        .transition(TransactionState::DepositSettled, TransactionState::Sold)
+        .await?;
+
+    // Calculate the amount of SOL to buy with the USD obtained from the sale
+    let usd_proceeds = sell_order.cost - sell_order.fee;
+    // Sized at the exchange's own ticker, which limit orders are priced from
+    let sol_value_in_usd = exchange.get_asset_value("SOL").await?;
+    let sol_amount = usd_proceeds
+        .checked_div(sol_value_in_usd)
+        .ok_or_else(|| AppError::PriceError("SOL price is zero".to_string()))?;
     println!("Buying {} SOL", sol_amount);
 
     // Perform USD to SOL swap
-    let usd_sol_response = execute_swap("SOLUSD", OrderSide::Buy, sol_amount).await?;
-    println!("USD to SOL swap response: {:?}", usd_sol_response);
-
-    // Withdraw the SOL to the user's address
-    let amount_to_withdraw = usd_sol_response["notional_sol_value"]
-        .as_f64()
-        .unwrap_or(0.0);
-    if amount_to_withdraw < 0.0001 {
+    exchange.ensure_balance("USD", usd_proceeds).await?;
+    let (buy_txid, buy_order) = exchange.place_order_and_wait("SOLUSD", OrderSide::Buy, sol_amount).await?;
+    record_order_fill(transactions_collection, tx_id, "buy", &buy_txid, &buy_order).await?;
+    Journal::for_transaction(tx_id, "buy", user_id)
+        .transfer(Account::Exchange, Account::User(user_id), "USD", buy_order.cost + buy_order.fee)
+        .transfer(Account::User(user_id), Account::Exchange, "SOL", buy_order.volume_executed)
+        .record()
+        .await;
+    state_machine
+        .transition(TransactionState::Sold, TransactionState::SolBought)
+        .await?;
+
+    // Withdraw the SOL to the user's address. The exchange quotes the fee and limit first, so an amount
+    // under its minimum fails here with a clear error instead of as a failed withdrawal.
+    let amount_to_withdraw = buy_order.volume_executed;
+    let withdrawal_quote = exchange
+        .quote_withdrawal("SOL", &withdrawal_key.key, amount_to_withdraw)
+        .await?;
+    if let Some(limit) = withdrawal_quote.limit {
+        if amount_to_withdraw > limit {
+            return Err(AppError::CustomError(format!(
+                "Withdrawal of {} SOL is over the {} SOL limit of withdrawal key `{}`",
+                amount_to_withdraw, limit, withdrawal_key.key
+            )));
+        }
+    }
+    // The fee comes out of the withdrawn SOL, only the rest arrives to be swapped
+    let amount_received = withdrawal_quote.net_amount;
+    println!(
+        "Withdrawal of {} SOL quoted at a {} SOL fee, {} SOL arrives",
+        amount_to_withdraw, withdrawal_quote.fee, amount_received
+    );
+    if sol_decimal_to_lamports(amount_received) < MIN_WITHDRAWAL_LAMPORTS {
         eprintln!(
-            "Amount to withdraw too small: {} < 0.0001",
-            amount_to_withdraw
+            "Amount to withdraw too small: {} < {} lamports",
+            amount_received, MIN_WITHDRAWAL_LAMPORTS
         );
         return Err(AppError::CustomError(
             "Amount to withdraw too small".to_string(),
         ));
     }
+    exchange.ensure_balance("SOL", amount_to_withdraw).await?;
     println!("Withdrawing {} SOL", amount_to_withdraw);
-    withdraw_assets(
+    println!("Withdrawing through key `{}` to {}", withdrawal_key.key, withdrawal_key.address);
+    let withdraw_response = exchange.withdraw_assets(
         "SOL",
-        "bottest",
-        "fdXt9eYUTCCeDdrURxS9u6ALnHPLXBNuc1MNqmSR7jA",
+        &withdrawal_key.key,
+        &withdrawal_key.address,
         amount_to_withdraw,
     )
     .await?;
 
+    // Look up the exchange side of the fee breakdown. The SOL has left the exchange at this point, so
+    // a failed lookup falls back to the quoted fee.
+    let withdrawal_fee = match withdraw_response["refid"].as_str() {
+        Some(refid) => match exchange.get_withdrawal_status("SOL", refid).await {
+            Ok(withdrawal) => withdrawal
+                .and_then(|withdrawal| withdrawal["fee"].as_str().and_then(|fee| fee.parse::<Decimal>().ok()))
+                .unwrap_or(withdrawal_quote.fee),
+            Err(e) => {
+                eprintln!("Failed to look up the withdrawal fee of transaction {}: {:?}", tx_id, e);
+                withdrawal_quote.fee
+            }
+        },
+        None => withdrawal_quote.fee,
+    };
+
+    // SOL withdrawn to an address other than the bot wallet goes straight to its owner
+    let to_bot_wallet = withdrawal_key.address == bot_wallet_address()?.to_string();
+
+    // Record the withdrawal, its fees and the user's total purchased amount together
+    let mut writes = AtomicWrites::start(users_collection.client()).await?;
+    if !state_machine
+        .try_transition_in(
+            &mut writes,
+            TransactionState::SolBought,
+            TransactionState::Withdrawn,
+            doc! {
+                "fees.trading_fee_usd": to_stored(sell_order.fee + buy_order.fee),
+                "fees.withdrawal_fee_sol": to_stored(withdrawal_fee),
+                "withdrawal.key": &withdrawal_key.key,
+                "withdrawal.address": &withdrawal_key.address,
+                "withdrawal.amount": to_stored(amount_to_withdraw),
+                "withdrawal.amount_received": to_stored(amount_received),
+                "withdrawal.refid": withdraw_response["refid"].as_str(),
+                "withdrawal.withdrawn_at": BsonDateTime::now(),
+                "withdrawal.swap_to": user_sol_address.to_string(),
+                "withdrawal.slippage_bps": slippage_bps as i32,
+            },
+        )
+        .await?
+    {
+        return Err(AppError::CustomError(format!(
+            "Transaction {} is no longer in state {}",
+            tx_id,
+            TransactionState::SolBought
+        )));
+    }
+    if !dry_run::is_enabled() {
+        writes
+            .update_one(
+                users_collection,
+                doc! { "user_id": user_id },
+                doc! { "$set": { "total_purchased": sats_to_btc(new_total_deposit_sats) } },
+            )
+            .await?;
+    }
+    // The withdrawal fee is the user's. SOL sent outside the bot wallet is delivered, what the user is
+    // owed goes down by all of it.
+    let journal = Journal::for_transaction(tx_id, "withdrawal", user_id);
+    let journal = if to_bot_wallet {
+        journal
+            .transfer(Account::Exchange, Account::BotWallet, "SOL", amount_received)
+            .transfer(Account::Exchange, Account::User(user_id), "SOL", amount_to_withdraw - amount_received)
+    } else {
+        journal.transfer(Account::Exchange, Account::User(user_id), "SOL", amount_to_withdraw)
+    };
+    journal.post(&mut writes).await?;
+    writes.commit().await?;
+
+    // SOL withdrawn to an address other than the bot wallet can't be swapped, the transaction ends with
+    // the withdrawal
+    if !to_bot_wallet {
+        state_machine
+            .transition(TransactionState::Withdrawn, TransactionState::Completed)
+            .await?;
+        println!(
+            "Withdrew {} SOL for user {} to {}, outside the bot wallet, no swap",
+            amount_received, user_id, withdrawal_key.address
+        );
+        return Ok(());
+    }
+
+    // A withdrawal the exchange made is followed until its SOL is in the bot wallet, and the withdrawal
+    // tracker schedules the swap from there. Simulated withdrawals have nothing to wait for.
+    if withdraw_response["refid"].is_string() {
+        println!("Withdrawal for transaction {} sent, the swap follows once the SOL arrives", tx_id);
+        return Ok(());
+    }
+
     // Execute a lockin transaction on the Solana blockchain in a new thread
-    let slippage_bps = 1500; // Slippage tolerance in basis points
     info!("Creating LockinClient...");
 
-    spawn(async move {
-        match LockinClient::new().await {
-            Ok(lockin_client) => {
-                let lockin_mint = Pubkey::from_str("8Ki8DpuWNxu9VsS3kQbarsCWMcFGWkzzA8pUPto9zBd5").unwrap();
-                let native_sol_mint = Pubkey::from_str("So11111111111111111111111111111111111111112").unwrap();
-                info!("Executing swap to user Solana address: {:?}", user_sol_address);
-
-                match lockin_client
-                    .execute(
-                        native_sol_mint,
-                        lockin_mint,
-                        amount_to_withdraw,
-                        user_sol_address,
-                        slippage_bps,
-                    )
-                    .await
-                {
-                    Ok(_) => info!("Lockin transaction executed successfully on Solana blockchain."),
-                    Err(e) => {
-                        eprintln!("Error executing Lockin transaction: {:?}", e);
-                        if let Err(refund_error) = lockin_client
-                            .initiate_refund(user_sol_address, amount_to_withdraw as u64)
-                            .await
-                        {
-                            eprintln!("Error processing refund: {:?}", refund_error);
-                        }
+    let clients = clients.clone();
+    // The swap stays in the deposit's trace and under its request ID
+    let swap_request_id = request_id::current_or_new();
+    spawn(
+        request_id::scope(swap_request_id, async move {
+            run_lockin_swap(
+                &clients,
+                Some(tx_id),
+                user_id,
+                user_sol_address,
+                sol_decimal_to_lamports(amount_received),
+                slippage_bps,
+            )
+            .await;
+        })
+        .in_current_span(),
+    );
+
+    Ok(())
+}
+
+// Persists the fill price and fees of an exchange order on the transaction document
+async fn record_order_fill(
+    transactions_collection: &Collection<Document>,
+    tx_id: ObjectId,
+    side: &str,
+    txid: &str,
+    order: &OrderFill,
+) -> Result<(), AppError> {
+    if dry_run::is_enabled() {
+        return Ok(());
+    }
+
+    let mut fill = Document::new();
+    fill.insert(
+        format!("kraken_orders.{}", side),
+        doc! {
+            "txid": txid,
+            "status": order.status.clone(),
+            "volume": to_stored(order.volume_executed),
+            "price": to_stored(order.average_price),
+            "cost": to_stored(order.cost),
+            "fee": to_stored(order.fee),
+        },
+    );
+    let update = doc! { "$set": fill };
+    retry_with_backoff(&retry::MONGO, "Recording order fill", retry::is_transient_mongo_error, || {
+        transactions_collection.update_one(doc! { "_id": tx_id }, update.clone(), None)
+    })
+    .await?;
+    println!("Recorded {} order fill: {}", side, txid);
+    Ok(())
+}
+
+// How one leg of a lockin stage ended
+enum LegResult {
+    Delivered,
+    Refunded,
+    Failed(String),
+    // Failures in dry-run mode leave the transaction as it is
+    Skipped,
+}
+
+// Swaps SOL into the user's allocation, all lockin token without one, dead-lettering each leg whose
+// every retry fails
+#[tracing::instrument(name = "lockin_swap", skip_all, fields(user_id = user_id, amount_lamports = amount_lamports, slippage_bps = slippage_bps))]
+pub async fn run_lockin_swap(
+    clients: &PipelineClients,
+    tx_id: Option<ObjectId>,
+    user_id: i64,
+    user_sol_address: Pubkey,
+    amount_lamports: u64,
+    slippage_bps: u16,
+) {
+    let legs = match allocation::user_legs(user_id, amount_lamports).await {
+        Ok(legs) => legs,
+        Err(e) => {
+            eprintln!("Error splitting the swap of user {}: {:?}", user_id, e);
+            return;
+        }
+    };
+
+    let mut results = Vec::with_capacity(legs.len());
+    for leg in legs {
+        results.push(run_swap_leg(clients, tx_id, user_id, user_sol_address, leg, slippage_bps).await);
+    }
+    settle_legs(tx_id, &results).await;
+}
+
+// Swaps one leg's SOL into its mint, or sends it as SOL, and records what it delivered
+async fn run_swap_leg(
+    clients: &PipelineClients,
+    tx_id: Option<ObjectId>,
+    user_id: i64,
+    user_sol_address: Pubkey,
+    leg: SwapLeg,
+    slippage_bps: u16,
+) -> LegResult {
+    let native_sol_mint = Pubkey::from_str(NATIVE_SOL_MINT).unwrap();
+    let amount_lamports = leg.amount_lamports;
+    let lockin_client = match clients.lockin_client() {
+        Ok(lockin_client) => lockin_client,
+        Err(e) => {
+            eprintln!("Failed to create LockinClient: {:?}", e);
+            return LegResult::Skipped;
+        }
+    };
+    info!("Executing swap into {} to user Solana address: {:?}", leg.output_mint, user_sol_address);
+
+    // While the bot wallet or Kraken is short of fee money the swap is deferred like during an outage
+    let result = if balance_watchdog::swaps_held() {
+        Err(anyhow::Error::new(SwapsHeld))
+    } else {
+        lockin_client
+            .execute(
+                native_sol_mint,
+                leg.output_mint,
+                amount_lamports,
+                user_sol_address,
+                slippage_bps,
+                leg.stake,
+            )
+            .await
+    };
+    match result {
+        Ok(SwapResult::DryRun) => LegResult::Delivered,
+        Ok(SwapResult::NotSent(reason)) => {
+            // Nothing was sent, so the leg's SOL goes back to the user rather than sitting in the bot wallet
+            eprintln!("Swap into {} not sent: {}", leg.output_mint, reason);
+            refund_leg(&lockin_client, tx_id, user_id, user_sol_address, &leg).await
+        }
+        Ok(SwapResult::Delivered(outcome)) => {
+            info!("Lockin transaction executed successfully on Solana blockchain.");
+            if let Err(db_error) = record_stake_deposit(tx_id, user_id, &outcome).await {
+                eprintln!("Error recording stake deposit: {:?}", db_error);
+            }
+            notify_delivery(user_id, &leg, &outcome).await;
+            let kind = match (&outcome.stake_deposit, &outcome.route_plan) {
+                (Some(_), _) => "stake",
+                (None, None) => "transfer",
+                (None, Some(_)) => "swap",
+            };
+            solana_txs::record_receipt(tx_id, user_id, kind, leg.index, &outcome.signature).await;
+            record_delivery(tx_id, Some(&outcome), "swap", leg.index, user_id, amount_lamports).await;
+            if let Some(tx_id) = tx_id {
+                if leg.output_mint.to_string() == config().lockin_mint {
+                    if let Err(db_error) = record_swap_fees(tx_id, &outcome).await {
+                        eprintln!("Error recording swap fees: {:?}", db_error);
+                    }
+                    if let Err(e) = cost_basis::record_acquisition(tx_id, &outcome).await {
+                        eprintln!("Error recording the cost basis of transaction {}: {:?}", tx_id, e);
+                    }
+                } else if let Err(db_error) = record_allocation_leg(tx_id, &leg, &outcome).await {
+                    eprintln!("Error recording allocation leg: {:?}", db_error);
+                }
+            }
+            LegResult::Delivered
+        }
+        Err(e) => {
+            eprintln!("Error executing Lockin transaction: {:?}", e);
+            if dry_run::is_enabled() {
+                dry_run::record_action("lockin_swap_failed", json!({ "error": e.to_string() })).await;
+                LegResult::Skipped
+            } else if e.downcast_ref::<CircuitOpen>().is_some()
+                || e.downcast_ref::<SwapsHeld>().is_some()
+                || e.downcast_ref::<SpendBlocked>().is_some()
+            {
+                // Nothing was sent, the SOL stays in the bot wallet until Jupiter and Solana
+                // RPC recover, or the balances are topped up, and the poller re-drives the swap. A
+                // swap over the spending limits waits for an admin to approve the spend instead.
+                let spend_approval_id = e.downcast_ref::<SpendBlocked>().map(|blocked| blocked.approval_id);
+                let failed_swap = FailedSwap {
+                    id: None,
+                    transaction_id: tx_id,
+                    user_id,
+                    input_mint: native_sol_mint.to_string(),
+                    output_mint: leg.output_mint.to_string(),
+                    amount: lamports_to_sol(amount_lamports),
+                    receiving_address: user_sol_address.to_string(),
+                    slippage_bps,
+                    leg: leg.index as i32,
+                    stake: leg.stake,
+                    attempts: 0,
+                    error: e.to_string(),
+                    simulation_failure: None,
+                    quote: None,
+                    redriven: false,
+                    deferred: spend_approval_id.is_none(),
+                    spend_approval_id,
+                    created_at: BsonDateTime::now(),
+                };
+                if let Err(db_error) = record_failed_swap(failed_swap).await {
+                    eprintln!("Error recording deferred swap: {:?}", db_error);
+                }
+                LegResult::Failed(e.to_string())
+            } else if let Some((attempts, reason, quote, failure)) = match e.downcast_ref::<LockinClientError>() {
+                Some(LockinClientError::RetriesExhausted { attempts, reason, quote, failure }) => {
+                    Some((*attempts, reason, quote, *failure))
+                }
+                // Nothing was sent, the SOL stays in the bot wallet until the swap is re-driven
+                Some(LockinClientError::QuoteRejected { reason, quote }) => Some((0, reason, quote, None)),
+                _ => None,
+            } {
+                if let (Some(tx_id), Some(failure)) = (tx_id, failure) {
+                    if let Err(db_error) = record_simulation_failure(tx_id, failure).await {
+                        eprintln!("Error recording simulation failure: {:?}", db_error);
                     }
                 }
+                let failed_swap = FailedSwap {
+                    id: None,
+                    transaction_id: tx_id,
+                    user_id,
+                    input_mint: native_sol_mint.to_string(),
+                    output_mint: leg.output_mint.to_string(),
+                    amount: lamports_to_sol(amount_lamports),
+                    receiving_address: user_sol_address.to_string(),
+                    slippage_bps,
+                    leg: leg.index as i32,
+                    stake: leg.stake,
+                    attempts: attempts as i32,
+                    error: reason.clone(),
+                    simulation_failure: failure.map(str::to_string),
+                    quote: quote.clone(),
+                    redriven: false,
+                    deferred: false,
+                    spend_approval_id: None,
+                    created_at: BsonDateTime::now(),
+                };
+                if let Err(db_error) = record_failed_swap(failed_swap).await {
+                    eprintln!("Error recording failed swap: {:?}", db_error);
+                }
+                LegResult::Failed(e.to_string())
+            } else if let Some(LockinClientError::SwapRefunded { refund_signature, .. }) =
+                e.downcast_ref::<LockinClientError>()
+            {
+                // The client already refunded the unconfirmed swap
+                if let Some(refund_signature) = refund_signature {
+                    solana_txs::record_receipt(tx_id, user_id, "refund", leg.index, refund_signature).await;
+                }
+                record_delivery(tx_id, None, "refund", leg.index, user_id, amount_lamports).await;
+                LegResult::Refunded
+            } else {
+                refund_leg(&lockin_client, tx_id, user_id, user_sol_address, &leg).await
+            }
+        }
+    }
+}
+
+// Refunds the SOL of a leg that wasn't swapped back to the user
+async fn refund_leg(
+    lockin_client: &LockinClient,
+    tx_id: Option<ObjectId>,
+    user_id: i64,
+    user_sol_address: Pubkey,
+    leg: &SwapLeg,
+) -> LegResult {
+    match lockin_client.initiate_refund(user_sol_address, leg.amount_lamports).await {
+        Ok(refund_signature) => {
+            if let Some(refund_signature) = refund_signature {
+                solana_txs::record_receipt(tx_id, user_id, "refund", leg.index, &refund_signature).await;
+            }
+            record_delivery(tx_id, None, "refund", leg.index, user_id, leg.amount_lamports).await;
+            LegResult::Refunded
+        }
+        Err(refund_error) => {
+            eprintln!("Error processing refund: {:?}", refund_error);
+            LegResult::Failed(refund_error.to_string())
+        }
+    }
+}
+
+// Settles a lockin stage's transaction from its legs: failed when any leg failed, refunded when every
+// leg was refunded and swapped otherwise
+async fn settle_legs(tx_id: Option<ObjectId>, results: &[LegResult]) {
+    let errors: Vec<&str> = results
+        .iter()
+        .filter_map(|result| match result {
+            LegResult::Failed(error) => Some(error.as_str()),
+            _ => None,
+        })
+        .collect();
+    if !errors.is_empty() {
+        settle_swap_state(tx_id, TransactionState::Failed, Some(errors.join("; "))).await;
+    } else if !results.is_empty() && results.iter().all(|result| matches!(result, LegResult::Refunded)) {
+        settle_swap_state(tx_id, TransactionState::Refunded, None).await;
+    } else if results.is_empty() || results.iter().any(|result| !matches!(result, LegResult::Skipped)) {
+        settle_swap_state(tx_id, TransactionState::Swapped, None).await;
+    }
+}
+
+// Records SOL leaving the bot wallet for the user, swapped into LOCKIN or refunded. The network fee of a
+// swap is the service's, its platform fee is charged to the user and either sent to the treasury or kept
+// in the bot wallet.
+async fn record_delivery(
+    tx_id: Option<ObjectId>,
+    outcome: Option<&SwapOutcome>,
+    kind: &'static str,
+    leg_index: usize,
+    user_id: i64,
+    amount_lamports: u64,
+) {
+    let journal = match (tx_id, outcome) {
+        (Some(tx_id), _) => Journal::for_transaction(tx_id, kind, user_id).part(leg_index),
+        (None, Some(outcome)) => Journal::new(&outcome.signature, kind, user_id),
+        (None, None) => return,
+    };
+    let network_fee = outcome.map_or(0, |outcome| outcome.network_fee_lamports);
+    let platform_fee = outcome.map_or(0, |outcome| outcome.platform_fee_lamports).min(amount_lamports);
+    let to_treasury = outcome.is_some_and(|outcome| outcome.platform_fee_to_treasury);
+    journal
+        .transfer(
+            Account::BotWallet,
+            Account::User(user_id),
+            "SOL",
+            Decimal::new((amount_lamports - platform_fee) as i64, 9),
+        )
+        .transfer(Account::PlatformFees, Account::User(user_id), "SOL", Decimal::new(platform_fee as i64, 9))
+        .transfer(
+            Account::BotWallet,
+            Account::PlatformFees,
+            "SOL",
+            Decimal::new(if to_treasury { platform_fee as i64 } else { 0 }, 9),
+        )
+        .transfer(Account::BotWallet, Account::Fees, "SOL", Decimal::new(network_fee as i64, 9))
+        .record()
+        .await;
+}
+
+// Moves the transaction out of Withdrawn once its lockin swap has settled, a swap lands in Completed
+pub(crate) async fn settle_swap_state(tx_id: Option<ObjectId>, to: TransactionState, error: Option<String>) {
+    let tx_id = match tx_id {
+        Some(tx_id) => tx_id,
+        None => return,
+    };
+    let result = async {
+        let state_machine = TransactionStateMachine::new(get_transactions_collection().await?, tx_id);
+        match to {
+            TransactionState::Failed => state_machine.fail(&error.unwrap_or_default()).await,
+            TransactionState::Swapped => {
+                state_machine
+                    .transition(TransactionState::Withdrawn, TransactionState::Swapped)
+                    .await?;
+                state_machine
+                    .transition(TransactionState::Swapped, TransactionState::Completed)
+                    .await
             }
-            Err(e) => eprintln!("Failed to create LockinClient: {:?}", e),
+            _ => state_machine.transition(TransactionState::Withdrawn, to).await,
         }
+    }
+    .await;
+    if let Err(e) = result {
+        eprintln!("Error updating state of transaction {}: {:?}", tx_id, e);
+    }
+}
+
+// Records why the swap's last simulation failed on the transaction, as a SimulationFailure category
+async fn record_simulation_failure(tx_id: ObjectId, failure: &str) -> Result<(), AppError> {
+    get_transactions_collection()
+        .await?
+        .update_one(doc! { "_id": tx_id }, doc! { "$set": { "simulation_failure": failure } }, None)
+        .await?;
+    Ok(())
+}
+
+// Records the on-chain side of the fee breakdown and the Jupiter route on the transaction document
+pub(crate) async fn record_swap_fees(tx_id: ObjectId, outcome: &SwapOutcome) -> Result<(), AppError> {
+    let transactions_collection = get_transactions_collection().await?;
+    let route_plan = outcome
+        .route_plan
+        .as_ref()
+        .and_then(|route_plan| to_bson(route_plan).ok())
+        .unwrap_or(Bson::Null);
+    let update = doc! { "$set": {
+        "fees.network_fee_lamports": outcome.network_fee_lamports as i64,
+        "fees.platform_fee_lamports": outcome.platform_fee_lamports as i64,
+        "fees.platform_fee_to_treasury": outcome.platform_fee_to_treasury,
+        "fees.price_impact_pct": outcome.price_impact_pct,
+        "lockin_signature": outcome.signature.clone(),
+        "swap_in_amount": outcome.in_amount as i64,
+        "swap_out_amount": outcome.out_amount.map(|amount| amount as i64),
+        "route_plan": route_plan,
+    } };
+    // The swap already landed, so losing this write to a transient error would lose its record
+    retry_with_backoff(&retry::MONGO, "Recording swap fees", retry::is_transient_mongo_error, || {
+        transactions_collection.update_one(doc! { "_id": tx_id }, update.clone(), None)
+    })
+    .await?;
+    println!("Recorded swap fees for transaction: {}", tx_id);
+    Ok(())
+}
+
+// Records what a leg of a split allocation into a mint other than LOCKIN delivered, keyed by its mint
+async fn record_allocation_leg(tx_id: ObjectId, leg: &SwapLeg, outcome: &SwapOutcome) -> Result<(), AppError> {
+    let transactions_collection = get_transactions_collection().await?;
+    let route_plan = outcome
+        .route_plan
+        .as_ref()
+        .and_then(|route_plan| to_bson(route_plan).ok())
+        .unwrap_or(Bson::Null);
+    let update = doc! { "$set": {
+        format!("allocation_legs.{}", leg.output_mint): {
+            "amount_lamports": leg.amount_lamports as i64,
+            "signature": outcome.signature.clone(),
+            "out_amount": outcome.out_amount.map(|amount| amount as i64),
+            "network_fee_lamports": outcome.network_fee_lamports as i64,
+            "platform_fee_lamports": outcome.platform_fee_lamports as i64,
+            "price_impact_pct": outcome.price_impact_pct,
+            "route_plan": route_plan,
+            "stake_deposit": outcome.stake_deposit.as_ref().and_then(|deposit| to_bson(deposit).ok()),
+        },
+    } };
+    retry_with_backoff(&retry::MONGO, "Recording allocation leg", retry::is_transient_mongo_error, || {
+        transactions_collection.update_one(doc! { "_id": tx_id }, update.clone(), None)
+    })
+    .await?;
+    println!("Recorded allocation leg {} of transaction: {}", leg.output_mint, tx_id);
+    Ok(())
+}
+
+// Tells the user what a leg delivered, naming the token by its symbol rather than its mint
+async fn notify_delivery(user_id: i64, leg: &SwapLeg, outcome: &SwapOutcome) {
+    let sol = lamports_to_sol(leg.amount_lamports);
+    let message = match (&outcome.stake_deposit, &outcome.route_plan) {
+        (Some(stake_deposit), _) => format!(
+            "{} SOL of your deposit was staked into {}.",
+            sol,
+            token_metadata::symbol_or_mint(&stake_deposit.pool_mint).await
+        ),
+        (None, Some(_)) => {
+            let mint = leg.output_mint.to_string();
+            let info = token_metadata::token_info(std::slice::from_ref(&mint))
+                .await
+                .ok()
+                .and_then(|mut infos| infos.remove(&mint))
+                .unwrap_or_default();
+            let symbol = info.symbol.unwrap_or(mint);
+            match (outcome.out_amount, info.decimals) {
+                (Some(out_amount), Some(decimals)) => format!(
+                    "{} SOL of your deposit was swapped into {} {}.",
+                    sol,
+                    out_amount as f64 / 10f64.powi(decimals as i32),
+                    symbol
+                ),
+                _ => format!("{} SOL of your deposit was swapped into {}.", sol, symbol),
+            }
+        }
+        (None, None) => format!("{} SOL of your deposit was sent to your wallet.", sol),
+    };
+    notify_user(user_id, message);
+}
+
+// Records the stake pool deposit of a staked SOL leg, the user's pool token account holds their stake
+async fn record_stake_deposit(tx_id: Option<ObjectId>, user_id: i64, outcome: &SwapOutcome) -> Result<(), AppError> {
+    let Some(stake_deposit) = &outcome.stake_deposit else {
+        return Ok(());
+    };
+    let stake_deposits_collection = get_stake_deposits_collection().await?;
+    stake_deposits_collection
+        .insert_one(
+            doc! {
+                "user_id": user_id,
+                "tx_id": tx_id,
+                "stake_pool": &stake_deposit.stake_pool,
+                "pool_mint": &stake_deposit.pool_mint,
+                "pool_token_account": &stake_deposit.pool_token_account,
+                "lamports": stake_deposit.lamports as i64,
+                "signature": &outcome.signature,
+                "created_at": BsonDateTime::now(),
+            },
+            None,
+        )
+        .await?;
+    println!("Recorded stake deposit {} for user {}", outcome.signature, user_id);
+    Ok(())
+}
+
+// Persists a swap that exhausted its retries into the dead-letter queue
+async fn record_failed_swap(failed_swap: FailedSwap) -> Result<(), AppError> {
+    let failed_swaps_collection = get_failed_swaps_collection().await?;
+    failed_swaps_collection.insert_one(failed_swap, None).await?;
+    println!("Failed swap recorded in dead-letter queue.");
+    Ok(())
+}
+
+// Fails a Withdrawn transaction whose swap was interrupted by a restart and dead-letters each of its
+// legs that neither sent a Solana transaction nor already sits in the queue. A leg may have landed
+// without its receipt being recorded, so the entries say to check on-chain before re-driving them.
+pub(crate) async fn dead_letter_interrupted_swap(
+    tx_id: ObjectId,
+    user_id: i64,
+    user_sol_address: Pubkey,
+    amount_lamports: u64,
+    slippage_bps: u16,
+) -> Result<(), AppError> {
+    let reason = "Swap was interrupted before it recorded an outcome, check it on-chain before re-driving it";
+    // The transition is the claim, so only one poller dead-letters the swap
+    let claimed = TransactionStateMachine::new(get_transactions_collection().await?, tx_id)
+        .try_transition(
+            TransactionState::Withdrawn,
+            TransactionState::Failed,
+            doc! { "processing_error": reason, "failed_in_state": TransactionState::Withdrawn.as_str() },
+        )
+        .await?;
+    if !claimed {
+        return Ok(());
+    }
+
+    let solana_txs_collection = get_solana_txs_collection().await?;
+    let failed_swaps_collection = get_failed_swaps_collection().await?;
+    let native_sol_mint = Pubkey::from_str(NATIVE_SOL_MINT).unwrap();
+    let mut dead_lettered = 0;
+    for leg in allocation::user_legs(user_id, amount_lamports).await? {
+        let sent = solana_txs_collection
+            .count_documents(doc! { "tx_id": tx_id, "leg": leg.index as i32 }, None)
+            .await?
+            > 0;
+        let queued = failed_swaps_collection
+            .count_documents(doc! { "transaction_id": tx_id, "leg": leg.index as i32, "redriven": false }, None)
+            .await?
+            > 0;
+        if sent || queued {
+            continue;
+        }
+        record_failed_swap(FailedSwap {
+            id: None,
+            transaction_id: Some(tx_id),
+            user_id,
+            input_mint: native_sol_mint.to_string(),
+            output_mint: leg.output_mint.to_string(),
+            amount: lamports_to_sol(leg.amount_lamports),
+            receiving_address: user_sol_address.to_string(),
+            slippage_bps,
+            leg: leg.index as i32,
+            stake: leg.stake,
+            attempts: 0,
+            error: reason.to_string(),
+            simulation_failure: None,
+            quote: None,
+            redriven: false,
+            deferred: false,
+            spend_approval_id: None,
+            created_at: BsonDateTime::now(),
+        })
+        .await?;
+        dead_lettered += 1;
+    }
+
+    send_alert(&format!(
+        "The swap of transaction {} was interrupted, {} leg(s) dead-lettered for review",
+        tx_id, dead_lettered
+    ))
+    .await;
+    Ok(())
+}
+
+// Claims the first dead-lettered swap matching `filter` and runs it again, putting its transaction
+// back in Withdrawn so the new attempt can settle it. A failed re-drive lands back in the queue as a
+// new entry. Returns the id of the re-driven swap, or None when no swap matched.
+pub(crate) async fn redrive_swap(mut filter: Document) -> Result<Option<ObjectId>, AppError> {
+    // Mark the job as re-driven first so it can only be picked up once
+    filter.insert("redriven", false);
+    let failed_swap = match get_failed_swaps_collection()
+        .await?
+        .find_one_and_update(filter, doc! { "$set": { "redriven": true } }, None)
+        .await?
+    {
+        Some(failed_swap) => failed_swap,
+        None => return Ok(None),
+    };
+
+    let receiving_address = Pubkey::from_str(&failed_swap.receiving_address)
+        .map_err(|_| AppError::BadRequest("Invalid receiving address".to_string()))?;
+    let leg = SwapLeg {
+        index: failed_swap.leg.max(0) as usize,
+        output_mint: Pubkey::from_str(&failed_swap.output_mint)
+            .map_err(|_| AppError::BadRequest("Invalid output mint".to_string()))?,
+        amount_lamports: sol_to_lamports(failed_swap.amount),
+        stake: failed_swap.stake,
+    };
+
+    if let Some(tx_id) = failed_swap.transaction_id {
+        TransactionStateMachine::new(get_transactions_collection().await?, tx_id)
+            .try_transition(TransactionState::Failed, TransactionState::Withdrawn, Document::new())
+            .await?;
+    }
+
+    // Only the failed leg runs again, the others already delivered
+    spawn(async move {
+        let result = run_swap_leg(
+            &PipelineClients::live(),
+            failed_swap.transaction_id,
+            failed_swap.user_id,
+            receiving_address,
+            leg,
+            failed_swap.slippage_bps,
+        )
+        .await;
+        settle_legs(failed_swap.transaction_id, &[result]).await;
     });
+    Ok(failed_swap.id)
+}
 
-    // Update the user's total purchased amount in the users collection
-    users_collection
-        .update_one(
-            doc! { "user_id": user_id },
-            doc! { "$set": { "total_purchased": new_total_deposit } },
+// Expires Kraken deposits that have stayed pending for longer than DEPOSIT_PENDING_EXPIRY_HOURS since
+// Kraken first reported them, telling their users. Deposits from the on-chain and LND watchers carry
+// a `source` and are left to them.
+async fn expire_stale_deposits(db: &Database) -> Result<(), AppError> {
+    let transactions_collection = db.collection::<Document>("transactions");
+    let cutoff = BsonDateTime::from_millis(
+        BsonDateTime::now().timestamp_millis() - config().deposit_pending_expiry_hours as i64 * 3_600_000,
+    );
+    let mut cursor = transactions_collection
+        .find(
+            doc! {
+                "source": { "$exists": false },
+                "kraken_first_seen_at": { "$lt": cutoff },
+                "kraken_status": { "$nin": ["Success", "Failure"] },
+            },
             None,
         )
         .await?;
-    println!("Updated total purchased amount for user: {:?}", user_id);
+    let mut stale = Vec::new();
+    while cursor.advance().await? {
+        stale.push(cursor.deserialize_current()?);
+    }
 
+    for tx in stale {
+        if TransactionState::of_document(&tx) != TransactionState::DepositPending {
+            continue;
+        }
+        let Ok(tx_id) = tx.get_object_id("_id") else {
+            continue;
+        };
+        let kraken_status = tx.get_str("kraken_status").unwrap_or("Pending");
+        let state_machine = TransactionStateMachine::new(transactions_collection.clone(), tx_id);
+        if !state_machine
+            .try_transition(
+                TransactionState::DepositPending,
+                TransactionState::Expired,
+                doc! { "processing_error": format!("Still {} on Kraken after {}h", kraken_status, config().deposit_pending_expiry_hours) },
+            )
+            .await?
+        {
+            continue;
+        }
+        println!("Expired transaction {} still {} on Kraken", tx_id, kraken_status);
+        let user_id = match tx.get("user_id") {
+            Some(Bson::Int32(user_id)) => *user_id as i64,
+            Some(Bson::Int64(user_id)) => *user_id,
+            _ => continue,
+        };
+        notify_user(
+            user_id,
+            format!(
+                "Your deposit of {} BTC has been pending at the exchange for over {} hours and has expired. It will still be processed if the exchange credits it.",
+                tx.get_f64("amount").unwrap_or_default(),
+                config().deposit_pending_expiry_hours
+            ),
+        );
+    }
     Ok(())
 }
+
+// Re-drives the swaps deferred while Jupiter or Solana RPC was down or swaps were held for low
+// balances. While a breaker is half-open a single swap is sent as its probe, once both are closed the
+// rest follow.
+async fn redrive_deferred_swaps() -> Result<(), AppError> {
+    let breakers = [&circuit_breaker::JUPITER, &circuit_breaker::SOLANA_RPC];
+    if balance_watchdog::swaps_held() || breakers.iter().any(|breaker| breaker.is_open()) {
+        return Ok(());
+    }
+    while let Some(id) = redrive_swap(doc! { "deferred": true }).await? {
+        println!("Re-driving deferred swap {}", id);
+        if !breakers.iter().all(|breaker| breaker.is_closed()) {
+            break;
+        }
+    }
+    Ok(())
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mongo::get_database;
+    use crate::test_support::{self, MockExchange, MockSolanaRpc};
+    use solana_sdk::signature::{Keypair, Signer};
+    use solana_sdk::system_instruction::SystemInstruction;
+    use solana_sdk::system_program;
+    use solana_sdk::transaction::VersionedTransaction;
+
+    // A user keeping all their SOL as SOL, and a pending deposit to their Lightning address. User ids
+    // are random so tests sharing the database don't see each other's documents.
+    async fn seed_deposit(db: &Database, recipient: &Pubkey) -> (i64, ObjectId, String) {
+        let user_id = rand::random::<u32>() as i64 + 1_000_000_000;
+        let address = format!("lnbc-test-{}", user_id);
+        db.collection::<Document>("users")
+            .insert_one(
+                doc! {
+                    "user_id": user_id,
+                    "total_deposit": 0.0,
+                    "lockin_total": 0.0,
+                    "solana_public_key": recipient.to_string(),
+                    "allocation": [ { "mint": NATIVE_SOL_MINT, "bps": 10000 } ],
+                },
+                None,
+            )
+            .await
+            .unwrap();
+        let mut tx = doc! { "user_id": user_id, "address": &address, "amount": 0.01, "timestamp": BsonDateTime::now() };
+        tx.extend(crate::transaction_state::initial_state_fields(TransactionState::DepositPending));
+        let tx_id = db
+            .collection::<Document>("transactions")
+            .insert_one(tx, None)
+            .await
+            .unwrap()
+            .inserted_id
+            .as_object_id()
+            .unwrap();
+        (user_id, tx_id, address)
+    }
+
+    async fn state_of(db: &Database, tx_id: ObjectId) -> TransactionState {
+        let tx = db
+            .collection::<Document>("transactions")
+            .find_one(doc! { "_id": tx_id }, None)
+            .await
+            .unwrap()
+            .unwrap();
+        TransactionState::of_document(&tx)
+    }
+
+    // The swap runs on a spawned task, so the test waits for the transaction to reach `to`
+    async fn wait_for_state(db: &Database, tx_id: ObjectId, to: TransactionState) {
+        let mut state = state_of(db, tx_id).await;
+        for _ in 0..100 {
+            if state == to {
+                return;
+            }
+            sleep(Duration::from_millis(100)).await;
+            state = state_of(db, tx_id).await;
+        }
+        panic!("Transaction {} is {}, expected {}", tx_id, state, to);
+    }
+
+    // The system transfers of a transaction, destination and lamports
+    fn transfers(transaction: &VersionedTransaction) -> Vec<(Pubkey, u64)> {
+        let keys = transaction.message.static_account_keys();
+        transaction
+            .message
+            .instructions()
+            .iter()
+            .filter(|instruction| keys[instruction.program_id_index as usize] == system_program::id())
+            .filter_map(|instruction| match bincode::deserialize(&instruction.data).ok()? {
+                SystemInstruction::Transfer { lamports } => Some((keys[instruction.accounts[1] as usize], lamports)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn settled_amount_goes_by_the_credited_sats() {
+        assert_eq!(settled_amount(&doc! { "amount": 5.0, "deposit_amount_sats": 1_000_000i64 }), Some(0.01));
+        // The bot's amount alone is never trusted
+        assert_eq!(settled_amount(&doc! { "amount": 5.0 }), None);
+        assert_eq!(settled_amount(&doc! { "deposit_amount_sats": 0i64 }), None);
+        assert_eq!(settled_amount(&doc! { "amount": 5.0, "purchase_amount": 0.5 }), Some(0.5));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MongoDB replica set at TEST_MONGO_URL"]
+    async fn deposit_is_sold_withdrawn_and_sent_to_the_user() {
+        test_support::init().await;
+        let db = get_database().await.unwrap();
+        let recipient = Keypair::new().pubkey();
+        let (user_id, tx_id, address) = seed_deposit(&db, &recipient).await;
+        let exchange = Arc::new(MockExchange::default());
+        let rpc = Arc::new(MockSolanaRpc::default());
+        let clients = test_support::clients(exchange.clone(), rpc.clone());
+        exchange.deposit(&format!("REF-{}", user_id), &address, "0.01", BsonDateTime::now().timestamp_millis() / 1000);
+
+        poll_deposits(&clients, &db).await.unwrap();
+
+        // 0.01 BTC sells for 600 USD less the 1.2 USD fee, which buys 3.992 SOL at 150 USD
+        let orders = exchange.orders.lock().unwrap().clone();
+        let pairs: Vec<(&str, Decimal)> = orders.iter().map(|(pair, _, volume)| (pair.as_str(), *volume)).collect();
+        assert_eq!(pairs, vec![("BTCUSD", Decimal::new(1, 2)), ("SOLUSD", Decimal::new(3992, 3))]);
+        let withdrawals = exchange.withdrawals.lock().unwrap().clone();
+        assert_eq!(withdrawals, vec![("SOL".to_string(), "bot-wallet".to_string(), Decimal::new(3992, 3))]);
+        assert_eq!(state_of(&db, tx_id).await, TransactionState::Withdrawn);
+        let user = db.collection::<User>("users").find_one(doc! { "user_id": user_id }, None).await.unwrap().unwrap();
+        assert_eq!(user.total_deposit_sats, Some(1_000_000));
+        assert_eq!(user.total_deposit, 0.01);
+        let tx = db.collection::<Document>("transactions").find_one(doc! { "_id": tx_id }, None).await.unwrap().unwrap();
+        assert_eq!(tx.get_i64("deposit_amount_sats"), Ok(1_000_000));
+
+        // Nothing is swapped while the withdrawal is on its way
+        *exchange.withdrawal_status.lock().unwrap() = Some(json!({ "status": "Pending", "fee": "0.01" }));
+        withdrawal_tracker::track_withdrawals(&clients).await.unwrap();
+        assert_eq!(state_of(&db, tx_id).await, TransactionState::Withdrawn);
+        assert!(rpc.sent.lock().unwrap().is_empty());
+
+        let withdrawal_txid = format!("withdrawal-{}", user_id);
+        rpc.land(&withdrawal_txid);
+        *exchange.withdrawal_status.lock().unwrap() =
+            Some(json!({ "status": "Success", "txid": withdrawal_txid, "fee": "0.01" }));
+        withdrawal_tracker::track_withdrawals(&clients).await.unwrap();
+        wait_for_state(&db, tx_id, TransactionState::Completed).await;
+
+        // 3.982 SOL arrived, less the 10% platform fee and the network fee
+        let received = 3_982_000_000u64;
+        let sent = rpc.sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(transfers(&sent[0]), vec![(recipient, received - received / 10 - rpc.fee)]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MongoDB replica set at TEST_MONGO_URL"]
+    async fn failed_withdrawal_fails_the_transaction_without_a_swap() {
+        test_support::init().await;
+        let db = get_database().await.unwrap();
+        let (user_id, tx_id, address) = seed_deposit(&db, &Keypair::new().pubkey()).await;
+        let exchange = Arc::new(MockExchange::default());
+        let rpc = Arc::new(MockSolanaRpc::default());
+        let clients = test_support::clients(exchange.clone(), rpc.clone());
+        exchange.deposit(&format!("REF-{}", user_id), &address, "0.01", BsonDateTime::now().timestamp_millis() / 1000);
+
+        poll_deposits(&clients, &db).await.unwrap();
+        *exchange.withdrawal_status.lock().unwrap() = Some(json!({ "status": "Failure" }));
+        withdrawal_tracker::track_withdrawals(&clients).await.unwrap();
+
+        assert_eq!(state_of(&db, tx_id).await, TransactionState::Failed);
+        assert!(rpc.sent.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MongoDB replica set at TEST_MONGO_URL"]
+    async fn deposit_is_processed_once_across_poll_cycles() {
+        test_support::init().await;
+        let db = get_database().await.unwrap();
+        let (user_id, tx_id, address) = seed_deposit(&db, &Keypair::new().pubkey()).await;
+        let exchange = Arc::new(MockExchange::default());
+        let clients = test_support::clients(exchange.clone(), Arc::new(MockSolanaRpc::default()));
+        exchange.deposit(&format!("REF-{}", user_id), &address, "0.01", BsonDateTime::now().timestamp_millis() / 1000);
+
+        poll_deposits(&clients, &db).await.unwrap();
+        poll_deposits(&clients, &db).await.unwrap();
+
+        assert_eq!(exchange.orders.lock().unwrap().len(), 2);
+        assert_eq!(exchange.withdrawals.lock().unwrap().len(), 1);
+        assert_eq!(state_of(&db, tx_id).await, TransactionState::Withdrawn);
+    }
+}
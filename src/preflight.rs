@@ -0,0 +1,139 @@
+// preflight.rs
+use crate::config::{config, ExchangeKind};
+use crate::error_handling::AppError;
+use crate::exchange::coinbase::CoinbaseExchange;
+use crate::exchange::kraken::{get_balances, get_deposit_status, get_withdrawal_info, validate_order};
+use crate::exchange::Exchange;
+use crate::mongo::get_database;
+use crate::signer;
+use crate::wallets::solana::init_master_mnemonic;
+use crate::withdrawal_addresses;
+use kraken_rest_client::OrderSide;
+use rust_decimal::Decimal;
+use mongodb::bson::doc;
+
+// Verifies everything the pipeline depends on before the server starts, so a missing permission or
+// withdrawal key fails the deploy instead of a swap an hour later. Returns every problem found.
+pub async fn run_preflight_checks() -> Result<(), Vec<String>> {
+    let mut problems = Vec::new();
+
+    match signer::init().await {
+        Ok(signer) => println!("Preflight: bot wallet {} loaded from the {} signer.", signer.pubkey(), signer.name()),
+        Err(e) => problems.push(format!("The bot wallet signer could not be loaded: {}", describe(&e))),
+    }
+
+    match init_master_mnemonic().await {
+        Ok(()) => println!("Preflight: Solana master mnemonic loaded."),
+        Err(e) => problems.push(format!("The Solana master mnemonic could not be loaded: {}", describe(&e))),
+    }
+
+    match get_database().await {
+        Ok(db) => {
+            if let Err(e) = db.run_command(doc! { "ping": 1 }, None).await {
+                problems.push(format!("MongoDB at MONGO_URL is not reachable: {}", e));
+            } else {
+                println!("Preflight: MongoDB reachable.");
+            }
+        }
+        Err(e) => problems.push(format!("Failed to connect to MongoDB at MONGO_URL: {}", describe(&e))),
+    }
+
+    match config().exchange {
+        ExchangeKind::Kraken => check_kraken(&mut problems).await,
+        ExchangeKind::Coinbase => check_coinbase(&mut problems).await,
+    }
+    check_withdrawal_keys(&mut problems).await;
+
+    if problems.is_empty() {
+        println!("Preflight checks passed.");
+        Ok(())
+    } else {
+        Err(problems)
+    }
+}
+
+async fn check_kraken(problems: &mut Vec<String>) {
+    // Each call needs a different permission on the Kraken API key
+    if let Err(e) = get_balances().await {
+        problems.push(kraken_problem("Query Funds", &e));
+    }
+    if let Err(e) = get_deposit_status("XBT", "Bitcoin Lightning", None).await {
+        problems.push(kraken_problem("Deposit Funds", &e));
+    }
+    if let Err(e) = validate_order("SOLUSD", OrderSide::Buy, Decimal::ONE).await {
+        problems.push(kraken_problem("Create & Modify Orders", &e));
+    }
+}
+
+// Every withdrawal key users can be routed through has to exist on Kraken, and one has to be the default
+async fn check_withdrawal_keys(problems: &mut Vec<String>) {
+    let keys = match withdrawal_addresses::all_keys().await {
+        Ok(keys) => keys,
+        Err(e) => {
+            problems.push(format!("Failed to load the withdrawal keys: {}", describe(&e)));
+            return;
+        }
+    };
+    if !keys.iter().any(|key| key.default) {
+        problems.push(
+            "No default withdrawal key, register one with POST /admin/withdrawal_keys or set KRAKEN_WITHDRAWAL_KEY and KRAKEN_WITHDRAWAL_ADDRESS"
+                .to_string(),
+        );
+    }
+    if config().exchange != ExchangeKind::Kraken {
+        return;
+    }
+    for key in keys {
+        if let Err(e) = get_withdrawal_info("SOL", &key.key, Decimal::ONE).await {
+            let message = describe(&e);
+            if message.contains("Unknown withdraw key") || message.contains("Invalid key") {
+                problems.push(format!(
+                    "Kraken withdrawal key `{}` does not exist, add {} under Funding > Withdraw with that exact description",
+                    key.key, key.address
+                ));
+            } else {
+                problems.push(kraken_problem("Withdraw Funds", &e));
+            }
+        } else {
+            println!("Preflight: withdrawal key `{}` found on Kraken.", key.key);
+        }
+    }
+}
+
+// Coinbase has no read-only way to test the trade and transfer scopes, so only the key and the
+// accounts the pipeline moves funds through are checked
+async fn check_coinbase(problems: &mut Vec<String>) {
+    let coinbase = CoinbaseExchange::from_config();
+    for asset in ["BTC", "USD", "SOL"] {
+        if let Err(e) = coinbase.get_balance(asset).await {
+            let message = describe(&e);
+            if message.contains("401") {
+                problems.push(
+                    "Coinbase rejected COINBASE_API_KEY/COINBASE_API_SECRET, check the key has the view, trade and transfer scopes"
+                        .to_string(),
+                );
+                return;
+            }
+            problems.push(format!("Coinbase check for the {} account failed: {}", asset, message));
+        }
+    }
+}
+
+fn kraken_problem(permission: &str, error: &AppError) -> String {
+    let message = describe(error);
+    if message.contains("Permission denied") {
+        format!(
+            "Kraken API key is missing the `{}` permission, enable it under Settings > API for KRAKEN_API_KEY",
+            permission
+        )
+    } else if message.contains("Invalid key") || message.contains("Invalid signature") {
+        "Kraken rejected KRAKEN_API_KEY/KRAKEN_API_SECRET, check both are copied from the same API key".to_string()
+    } else {
+        format!("Kraken check for `{}` failed: {}", permission, message)
+    }
+}
+
+// The full error chain, the underlying message is what tells the operator what to fix
+fn describe(error: &AppError) -> String {
+    error.report()
+}
@@ -0,0 +1,244 @@
+// price.rs
+use crate::config::{config, PriceSourceKind};
+use crate::error_handling::AppError;
+use async_trait::async_trait;
+use reqwest::Client;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+// A source of USD spot prices. Assets use the pipeline's tickers ("BTC", "SOL", "USDC").
+#[async_trait]
+pub trait PriceSource: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn usd_price(&self, asset: &str) -> Result<f64, AppError>;
+}
+
+// Kraken's public ticker, reading the last trade price of the <ASSET>USD pair
+pub struct KrakenPrice {
+    client: Client,
+}
+
+#[async_trait]
+impl PriceSource for KrakenPrice {
+    fn name(&self) -> &'static str {
+        "Kraken"
+    }
+
+    async fn usd_price(&self, asset: &str) -> Result<f64, AppError> {
+        let pair = match asset {
+            "BTC" => "XBTUSD".to_string(),
+            other => format!("{}USD", other),
+        };
+        let response: Value = self
+            .client
+            .get("https://api.kraken.com/0/public/Ticker")
+            .query(&[("pair", pair.as_str())])
+            .send()
+            .await?
+            .json()
+            .await?;
+        if let Some(errors) = response["error"].as_array().filter(|errors| !errors.is_empty()) {
+            return Err(AppError::PriceError(format!("Kraken ticker {}: {:?}", pair, errors)));
+        }
+
+        // Kraken keys the result by its own pair name ("XXBTZUSD"), one pair was asked for so
+        // there is exactly one entry
+        let ticker = response["result"]
+            .as_object()
+            .filter(|result| result.len() == 1)
+            .and_then(|result| result.values().next())
+            .ok_or_else(|| AppError::PriceError(format!("No Kraken ticker for {}", pair)))?;
+        parse_price(&ticker["c"][0]).ok_or_else(|| AppError::PriceError(format!("No last price for {}", pair)))
+    }
+}
+
+// CoinGecko's simple price API, with the demo API key when one is set
+pub struct CoinGeckoPrice {
+    client: Client,
+    api_url: String,
+    api_key: Option<String>,
+}
+
+fn coingecko_id(asset: &str) -> Option<&'static str> {
+    match asset {
+        "BTC" | "XBT" => Some("bitcoin"),
+        "SOL" => Some("solana"),
+        "ETH" => Some("ethereum"),
+        "USDC" => Some("usd-coin"),
+        "USDT" => Some("tether"),
+        _ => None,
+    }
+}
+
+#[async_trait]
+impl PriceSource for CoinGeckoPrice {
+    fn name(&self) -> &'static str {
+        "CoinGecko"
+    }
+
+    async fn usd_price(&self, asset: &str) -> Result<f64, AppError> {
+        let id = coingecko_id(asset)
+            .ok_or_else(|| AppError::PriceError(format!("CoinGecko has no id for {}", asset)))?;
+        let mut request = self
+            .client
+            .get(format!("{}/simple/price", self.api_url))
+            .query(&[("ids", id), ("vs_currencies", "usd")]);
+        if let Some(api_key) = &self.api_key {
+            request = request.header("x-cg-demo-api-key", api_key);
+        }
+        let response: Value = request.send().await?.json().await?;
+        response[id]["usd"]
+            .as_f64()
+            .ok_or_else(|| AppError::PriceError(format!("No CoinGecko price for {}", asset)))
+    }
+}
+
+// Pyth price feeds served by Hermes
+pub struct PythPrice {
+    client: Client,
+    hermes_url: String,
+}
+
+fn pyth_feed_id(asset: &str) -> Option<&'static str> {
+    match asset {
+        "BTC" | "XBT" => Some("e62df6c8b4a85fe1a67db44dc12de5db330f7ac66b72dc658afedf0f4a415b43"),
+        "SOL" => Some("ef0d8b6fda2ceba41da15d4095d1da392a0d2f8ed0c6c7bc0f4cfac8c280b56d"),
+        "ETH" => Some("ff61491a931112ddf1bd8147cd1b641375f79f5825126d665480874634fd0ace"),
+        "USDC" => Some("eaa020c61cc479712813461ce153894a96a6c00b21ed0cfc2798d1f9a9e9c94a"),
+        "USDT" => Some("2b89b9dc8fdf9f34709a5b106b472f0f39bb6ca9ce04b0fd7f2e971688e2e53b"),
+        _ => None,
+    }
+}
+
+#[async_trait]
+impl PriceSource for PythPrice {
+    fn name(&self) -> &'static str {
+        "Pyth"
+    }
+
+    async fn usd_price(&self, asset: &str) -> Result<f64, AppError> {
+        let feed_id = pyth_feed_id(asset)
+            .ok_or_else(|| AppError::PriceError(format!("No Pyth feed for {}", asset)))?;
+        let response: Value = self
+            .client
+            .get(format!("{}/v2/updates/price/latest", self.hermes_url))
+            .query(&[("ids[]", feed_id), ("parsed", "true")])
+            .send()
+            .await?
+            .json()
+            .await?;
+
+        // Pyth publishes an integer price with a base 10 exponent
+        let price = &response["parsed"][0]["price"];
+        let mantissa = parse_price(&price["price"]);
+        let exponent = price["expo"].as_i64();
+        match (mantissa, exponent) {
+            (Some(mantissa), Some(exponent)) => Ok(mantissa * 10f64.powi(exponent as i32)),
+            _ => Err(AppError::PriceError(format!("No Pyth price for {}", asset))),
+        }
+    }
+}
+
+fn parse_price(value: &Value) -> Option<f64> {
+    value
+        .as_str()
+        .and_then(|price| price.parse().ok())
+        .or_else(|| value.as_f64())
+        .filter(|price: &f64| price.is_finite() && *price > 0.0)
+}
+
+// Tries each source in order until one answers, caching answers for PRICE_CACHE_TTL_SECS
+pub struct PriceOracle {
+    sources: Vec<Arc<dyn PriceSource>>,
+    ttl: Duration,
+    cache: Mutex<HashMap<String, (f64, Instant)>>,
+}
+
+impl PriceOracle {
+    pub fn new(sources: Vec<Arc<dyn PriceSource>>, ttl: Duration) -> Self {
+        PriceOracle {
+            sources,
+            ttl,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn from_config() -> Self {
+        let config = config();
+        let client = Client::new();
+        let sources = config
+            .price_sources
+            .iter()
+            .map(|kind| -> Arc<dyn PriceSource> {
+                match kind {
+                    PriceSourceKind::Kraken => Arc::new(KrakenPrice { client: client.clone() }),
+                    PriceSourceKind::CoinGecko => Arc::new(CoinGeckoPrice {
+                        client: client.clone(),
+                        api_url: config.coingecko_api_url.clone(),
+                        api_key: config.coingecko_api_key.clone(),
+                    }),
+                    PriceSourceKind::Pyth => Arc::new(PythPrice {
+                        client: client.clone(),
+                        hermes_url: config.pyth_hermes_url.clone(),
+                    }),
+                }
+            })
+            .collect();
+        PriceOracle::new(sources, Duration::from_secs(config.price_cache_ttl_secs))
+    }
+
+    // The oracle shared by the whole process, so every caller benefits from the cache
+    pub fn shared() -> Arc<PriceOracle> {
+        static ORACLE: OnceLock<Arc<PriceOracle>> = OnceLock::new();
+        ORACLE.get_or_init(|| Arc::new(PriceOracle::from_config())).clone()
+    }
+
+    fn cached(&self, asset: &str) -> Option<f64> {
+        let cache = self.cache.lock().ok()?;
+        cache
+            .get(asset)
+            .filter(|(_, fetched_at)| fetched_at.elapsed() < self.ttl)
+            .map(|(price, _)| *price)
+    }
+}
+
+#[async_trait]
+impl PriceSource for PriceOracle {
+    fn name(&self) -> &'static str {
+        "oracle"
+    }
+
+    async fn usd_price(&self, asset: &str) -> Result<f64, AppError> {
+        let asset = match asset {
+            "XBT" | "XXBT" => "BTC",
+            other => other,
+        };
+        if let Some(price) = self.cached(asset) {
+            return Ok(price);
+        }
+
+        let mut failures = Vec::new();
+        for source in &self.sources {
+            match source.usd_price(asset).await {
+                Ok(price) => {
+                    if let Ok(mut cache) = self.cache.lock() {
+                        cache.insert(asset.to_string(), (price, Instant::now()));
+                    }
+                    return Ok(price);
+                }
+                Err(e) => {
+                    eprintln!("{} price for {} unavailable: {:?}", source.name(), asset, e);
+                    failures.push(source.name());
+                }
+            }
+        }
+        Err(AppError::PriceError(format!(
+            "No price for {} from {}",
+            asset,
+            failures.join(", ")
+        )))
+    }
+}
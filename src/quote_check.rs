@@ -0,0 +1,98 @@
+// quote_check.rs
+// Sanity check of Jupiter quotes against prices that don't come from Jupiter. SOL is priced by the
+// price oracle (Kraken, CoinGecko or Pyth) and every other mint by its most liquid DexScreener pair.
+// A quote whose out amount strays more than QUOTE_MAX_DEVIATION_BPS from the amount those prices
+// imply is rejected, a manipulated pool or a stale route would otherwise settle at the user's expense.
+// Without a reference price the quote is let through, the check can't tell it is wrong.
+use crate::allocation::NATIVE_SOL_MINT;
+use crate::config::config;
+use crate::error_handling::AppError;
+use crate::price::{PriceOracle, PriceSource};
+use crate::token_metadata::token_info;
+use reqwest::Client;
+use serde_json::Value;
+use solana_sdk::pubkey::Pubkey;
+use std::time::Duration;
+
+const DEXSCREENER_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Checks a Jupiter quote, the error is the reason it was rejected
+pub async fn check(input_mint: &Pubkey, output_mint: &Pubkey, quote: &Value) -> Result<(), String> {
+    let max_deviation_bps = config().quote_max_deviation_bps;
+    if max_deviation_bps == 0 {
+        return Ok(());
+    }
+    let amount = |key: &str| quote[key].as_str().and_then(|amount| amount.parse::<u64>().ok());
+    let (Some(in_amount), Some(out_amount)) = (amount("inAmount"), amount("outAmount")) else {
+        return Err("The quote has no in or out amount".to_string());
+    };
+
+    let expected_out = match reference_out_amount(input_mint, output_mint, in_amount).await {
+        Ok(Some(expected_out)) if expected_out > 0.0 => expected_out,
+        Ok(_) => {
+            eprintln!("No reference price for {} -> {}, skipping the quote check", input_mint, output_mint);
+            return Ok(());
+        }
+        Err(e) => {
+            eprintln!("Reference price for {} -> {} unavailable, skipping the quote check: {:?}", input_mint, output_mint, e);
+            return Ok(());
+        }
+    };
+    let deviation_bps = (out_amount as f64 - expected_out).abs() / expected_out * 10_000.0;
+    println!(
+        "Quote check {} -> {}: quoted {}, expected {:.0}, {:.0} bps apart",
+        input_mint, output_mint, out_amount, expected_out, deviation_bps
+    );
+    if deviation_bps > max_deviation_bps as f64 {
+        return Err(format!(
+            "Quoted {} out for {} in, {:.0} bps away from the {:.0} the reference price implies (at most {} bps)",
+            out_amount, in_amount, deviation_bps, expected_out, max_deviation_bps
+        ));
+    }
+    Ok(())
+}
+
+// The out amount, in base units, `in_amount` is worth at the reference prices. None when a price or
+// the decimals of either mint are unknown.
+async fn reference_out_amount(input_mint: &Pubkey, output_mint: &Pubkey, in_amount: u64) -> Result<Option<f64>, AppError> {
+    let (input_mint, output_mint) = (input_mint.to_string(), output_mint.to_string());
+    let infos = token_info(&[input_mint.clone(), output_mint.clone()]).await?;
+    let decimals = |mint: &String| infos.get(mint).and_then(|info| info.decimals);
+    let (Some(input_decimals), Some(output_decimals)) = (decimals(&input_mint), decimals(&output_mint)) else {
+        return Ok(None);
+    };
+    let (Some(input_price), Some(output_price)) = (usd_price(&input_mint).await?, usd_price(&output_mint).await?) else {
+        return Ok(None);
+    };
+
+    let in_usd = in_amount as f64 / 10f64.powi(input_decimals as i32) * input_price;
+    Ok(Some(in_usd / output_price * 10f64.powi(output_decimals as i32)))
+}
+
+async fn usd_price(mint: &str) -> Result<Option<f64>, AppError> {
+    if mint == NATIVE_SOL_MINT {
+        return PriceOracle::shared().usd_price("SOL").await.map(Some);
+    }
+    dexscreener_price(mint).await
+}
+
+// The USD price of the mint in its most liquid DexScreener pair
+async fn dexscreener_price(mint: &str) -> Result<Option<f64>, AppError> {
+    let response: Value = Client::new()
+        .get(format!("{}/latest/dex/tokens/{}", config().dexscreener_api_url, mint))
+        .timeout(DEXSCREENER_TIMEOUT)
+        .send()
+        .await?
+        .json()
+        .await?;
+    let liquidity = |pair: &Value| pair["liquidity"]["usd"].as_f64().unwrap_or(0.0);
+    let price = response["pairs"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|pair| pair["chainId"] == "solana" && pair["baseToken"]["address"] == mint)
+        .max_by(|a, b| liquidity(a).total_cmp(&liquidity(b)))
+        .and_then(|pair| pair["priceUsd"].as_str()?.parse::<f64>().ok())
+        .filter(|price| price.is_finite() && *price > 0.0);
+    Ok(price)
+}
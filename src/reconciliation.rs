@@ -0,0 +1,264 @@
+// reconciliation.rs
+// Nightly reconciliation of the Kraken account against the transactions. Once a day, after
+// RECONCILIATION_HOUR_UTC, the ledger entries of the previous UTC day are pulled from Kraken and
+// matched against Mongo: BTC deposits no transaction was created for, trades of orders no transaction
+// placed and fees that differ from the ones recorded on the transaction end up in a report in the
+// `reconciliation_reports` collection, keyed by the day it covers.
+use crate::config::config;
+use crate::dry_run;
+use crate::error_handling::AppError;
+use crate::exchange::kraken::{get_ledgers, get_trades_history};
+use crate::exchange::{to_decimal, to_stored};
+use crate::maintenance;
+use crate::mongo::{get_reconciliation_reports_collection, get_transactions_collection};
+use chrono::{NaiveDate, Timelike, Utc};
+use mongodb::bson::{doc, DateTime as BsonDateTime, Document};
+use mongodb::options::ReplaceOptions;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use tokio::time::{interval, Duration};
+
+// Recorded fees are stored as floats, smaller differences are rounding
+const FEE_TOLERANCE: Decimal = dec!(0.0001);
+
+// Starts a scheduler that reconciles the previous day once its report is due
+pub async fn start_reconciliation_scheduler() -> Result<(), AppError> {
+    let mut interval = interval(Duration::from_secs(600));
+    loop {
+        interval.tick().await;
+        // Simulated orders and withdrawals never reach the ledger
+        if maintenance::is_enabled() || dry_run::simulates_exchange() {
+            continue;
+        }
+        let now = Utc::now();
+        if now.hour() < config().reconciliation_hour_utc {
+            continue;
+        }
+        let Some(day) = now.date_naive().pred_opt() else {
+            continue;
+        };
+        match report_exists(day).await {
+            Ok(true) => continue,
+            Ok(false) => {}
+            Err(e) => {
+                eprintln!("Reconciliation scheduler failed: {:?}", e);
+                continue;
+            }
+        }
+        if let Err(e) = reconcile_day(day).await {
+            eprintln!("Reconciliation of {} failed: {:?}", day, e);
+        }
+    }
+}
+
+async fn report_exists(day: NaiveDate) -> Result<bool, AppError> {
+    let reports_collection = get_reconciliation_reports_collection().await?;
+    Ok(reports_collection.find_one(doc! { "_id": day.to_string() }, None).await?.is_some())
+}
+
+// Matches the Kraken ledger of one UTC day against the transactions and stores the report
+pub async fn reconcile_day(day: NaiveDate) -> Result<Document, AppError> {
+    let start = day
+        .and_hms_opt(0, 0, 0)
+        .ok_or_else(|| AppError::CustomError(format!("Invalid reconciliation day {}", day)))?
+        .and_utc()
+        .timestamp();
+    let end = start + 86_400;
+    println!("Reconciling the Kraken ledger of {}", day);
+
+    // Kraken's start is exclusive, its end inclusive
+    let ledger = get_ledgers(start - 1, end - 1).await?;
+    let trades = get_trades_history(start - 1, end - 1).await?;
+
+    // BTC deposits, fees per order and fees per withdrawal as Kraken booked them
+    let mut deposits = BTreeMap::new();
+    let mut order_fees: BTreeMap<String, Decimal> = BTreeMap::new();
+    let mut trade_orders: BTreeMap<String, String> = BTreeMap::new();
+    let mut unknown_trades = Vec::new();
+    let mut withdrawal_fees: BTreeMap<String, Decimal> = BTreeMap::new();
+    for entry in ledger.values() {
+        let refid = entry["refid"].as_str().unwrap_or_default().to_string();
+        let asset = entry["asset"].as_str().unwrap_or_default();
+        let fee = ledger_decimal(entry, "fee");
+        match entry["type"].as_str().unwrap_or_default() {
+            "deposit" if matches!(asset, "XXBT" | "XBT") => {
+                deposits.insert(refid, entry.clone());
+            }
+            "trade" => match trades.get(&refid).and_then(|trade| trade["ordertxid"].as_str()) {
+                Some(order_txid) => {
+                    trade_orders.insert(refid, order_txid.to_string());
+                    // The pipeline's pairs are quoted in USD and QueryOrders reports fees in USD
+                    if matches!(asset, "ZUSD" | "USD") {
+                        *order_fees.entry(order_txid.to_string()).or_default() += fee;
+                    } else {
+                        order_fees.entry(order_txid.to_string()).or_default();
+                    }
+                }
+                None => unknown_trades.push(ledger_summary(&refid, entry)),
+            },
+            "withdrawal" => {
+                *withdrawal_fees.entry(refid).or_default() += fee;
+            }
+            _ => {}
+        }
+    }
+
+    let transactions_collection = get_transactions_collection().await?;
+
+    // Deposits nobody was credited for
+    let deposit_refids: Vec<String> = deposits.keys().cloned().collect();
+    let mut cursor = transactions_collection
+        .find(doc! { "refid": { "$in": deposit_refids } }, None)
+        .await?;
+    let mut credited = HashSet::new();
+    while cursor.advance().await? {
+        let tx = cursor.deserialize_current()?;
+        if let Ok(refid) = tx.get_str("refid") {
+            credited.insert(refid.to_string());
+        }
+    }
+    let missing_deposits: Vec<Document> = deposits
+        .iter()
+        .filter(|(refid, _)| !credited.contains(*refid))
+        .map(|(refid, entry)| ledger_summary(refid, entry))
+        .collect();
+
+    // Orders placed for transactions, and the fees recorded with them
+    let order_txids: Vec<String> = order_fees.keys().cloned().collect();
+    let mut cursor = transactions_collection
+        .find(
+            doc! { "$or": [
+                { "kraken_orders.sell.txid": { "$in": order_txids.clone() } },
+                { "kraken_orders.buy.txid": { "$in": order_txids } },
+            ] },
+            None,
+        )
+        .await?;
+    let mut recorded_orders = HashMap::new();
+    while cursor.advance().await? {
+        let tx = cursor.deserialize_current()?;
+        let (Ok(tx_id), Ok(orders)) = (tx.get_object_id("_id"), tx.get_document("kraken_orders")) else {
+            continue;
+        };
+        for side in ["sell", "buy"] {
+            if let Ok(order) = orders.get_document(side) {
+                if let Ok(txid) = order.get_str("txid") {
+                    let fee = order.get_f64("fee").unwrap_or_default();
+                    recorded_orders.insert(txid.to_string(), (tx_id, side, fee));
+                }
+            }
+        }
+    }
+
+    let mut unmatched_trades = unknown_trades;
+    for (trade_id, order_txid) in &trade_orders {
+        if !recorded_orders.contains_key(order_txid) {
+            if let Some(trade) = trades.get(trade_id) {
+                unmatched_trades.push(doc! {
+                    "trade_id": trade_id,
+                    "order_txid": order_txid,
+                    "pair": trade["pair"].as_str(),
+                    "type": trade["type"].as_str(),
+                    "volume": trade["vol"].as_str(),
+                    "cost": trade["cost"].as_str(),
+                    "time": trade["time"].as_f64(),
+                });
+            }
+        }
+    }
+
+    let mut fee_discrepancies = Vec::new();
+    for (order_txid, ledger_fee) in &order_fees {
+        if let Some((tx_id, side, recorded_fee)) = recorded_orders.get(order_txid) {
+            let recorded_fee = to_decimal(*recorded_fee)?;
+            if (recorded_fee - ledger_fee).abs() > FEE_TOLERANCE {
+                fee_discrepancies.push(doc! {
+                    "tx_id": tx_id,
+                    "kind": format!("{}_order", side),
+                    "refid": order_txid,
+                    "recorded": to_stored(recorded_fee),
+                    "ledger": to_stored(*ledger_fee),
+                });
+            }
+        }
+    }
+
+    // Withdrawal fees recorded for transactions
+    let withdrawal_refids: Vec<String> = withdrawal_fees.keys().cloned().collect();
+    let mut cursor = transactions_collection
+        .find(doc! { "withdrawal.refid": { "$in": withdrawal_refids } }, None)
+        .await?;
+    while cursor.advance().await? {
+        let tx = cursor.deserialize_current()?;
+        let Ok(tx_id) = tx.get_object_id("_id") else {
+            continue;
+        };
+        let Some(refid) = tx.get_document("withdrawal").ok().and_then(|withdrawal| withdrawal.get_str("refid").ok())
+        else {
+            continue;
+        };
+        let Some(ledger_fee) = withdrawal_fees.get(refid) else {
+            continue;
+        };
+        let recorded_fee = tx
+            .get_document("fees")
+            .ok()
+            .and_then(|fees| fees.get_f64("withdrawal_fee_sol").ok())
+            .unwrap_or_default();
+        let recorded_fee = to_decimal(recorded_fee)?;
+        if (recorded_fee - ledger_fee).abs() > FEE_TOLERANCE {
+            fee_discrepancies.push(doc! {
+                "tx_id": tx_id,
+                "kind": "withdrawal",
+                "refid": refid,
+                "recorded": to_stored(recorded_fee),
+                "ledger": to_stored(*ledger_fee),
+            });
+        }
+    }
+
+    let report = doc! {
+        "_id": day.to_string(),
+        "from": BsonDateTime::from_millis(start * 1000),
+        "to": BsonDateTime::from_millis(end * 1000),
+        "generated_at": BsonDateTime::now(),
+        "ledger_entries": ledger.len() as i64,
+        "clean": missing_deposits.is_empty() && unmatched_trades.is_empty() && fee_discrepancies.is_empty(),
+        "missing_deposits": missing_deposits,
+        "unmatched_trades": unmatched_trades,
+        "fee_discrepancies": fee_discrepancies,
+    };
+    get_reconciliation_reports_collection()
+        .await?
+        .replace_one(
+            doc! { "_id": day.to_string() },
+            report.clone(),
+            ReplaceOptions::builder().upsert(true).build(),
+        )
+        .await?;
+    println!(
+        "Reconciled {} ledger entries of {}: {}",
+        ledger.len(),
+        day,
+        if report.get_bool("clean").unwrap_or(false) { "clean" } else { "discrepancies found" }
+    );
+    Ok(report)
+}
+
+// Kraken reports ledger amounts as strings
+fn ledger_decimal(entry: &Value, field: &str) -> Decimal {
+    entry[field].as_str().and_then(|value| value.parse().ok()).unwrap_or_default()
+}
+
+fn ledger_summary(refid: &str, entry: &Value) -> Document {
+    doc! {
+        "refid": refid,
+        "type": entry["type"].as_str(),
+        "asset": entry["asset"].as_str(),
+        "amount": entry["amount"].as_str(),
+        "fee": entry["fee"].as_str(),
+        "time": entry["time"].as_f64(),
+    }
+}
@@ -0,0 +1,99 @@
+// redact.rs
+// Keeps secrets out of the logs. Values of fields named like a key, secret, mnemonic, password or
+// token are replaced when formatted: by the Redacted newtype for struct fields, by redact_json for
+// JSON payloads and by the field formatter of the log output for tracing fields.
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+use std::ops::Deref;
+use tracing_subscriber::field::MakeExt;
+use tracing_subscriber::fmt::format::{self, FormatFields};
+
+pub const REDACTED: &str = "[REDACTED]";
+
+const SENSITIVE_WORDS: &[&str] = &[
+    "key", "apikey", "secret", "mnemonic", "password", "passphrase", "seed", "token", "macaroon", "privkey",
+];
+
+// Whether a field holds a secret, going by the words of its name (snake_case, kebab-case or camelCase).
+// Public keys are not secret.
+pub fn is_sensitive(name: &str) -> bool {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut previous_lowercase = false;
+    for c in name.chars() {
+        if (!c.is_alphanumeric() || (c.is_uppercase() && previous_lowercase)) && !word.is_empty() {
+            words.push(std::mem::take(&mut word));
+        }
+        if c.is_alphanumeric() {
+            word.push(c.to_ascii_lowercase());
+        }
+        previous_lowercase = c.is_lowercase();
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    !words.iter().any(|word| word == "public" || word == "pubkey")
+        && words.iter().any(|word| SENSITIVE_WORDS.contains(&word.as_str()))
+}
+
+// A value that is never formatted, for request fields holding secrets. Serializes and derefs as the
+// value itself.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Redacted<T>(pub T);
+
+impl<T> Deref for Redacted<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> fmt::Debug for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl<T> fmt::Display for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+// A copy of a JSON payload for logging, with the values of sensitive fields replaced at any depth
+pub fn redact_json(value: &Value) -> Value {
+    match value {
+        Value::Object(object) => Value::Object(
+            object
+                .iter()
+                .map(|(name, value)| {
+                    let value = if is_sensitive(name) {
+                        Value::String(REDACTED.to_string())
+                    } else {
+                        redact_json(value)
+                    };
+                    (name.clone(), value)
+                })
+                .collect(),
+        ),
+        Value::Array(values) => Value::Array(values.iter().map(redact_json).collect()),
+        other => other.clone(),
+    }
+}
+
+// Field formatter for the log output, formatting like the default one except for sensitive fields
+pub fn fields() -> impl for<'writer> FormatFields<'writer> + 'static {
+    format::debug_fn(|writer, field, value| {
+        if field.name() == "message" {
+            write!(writer, "{:?}", value)
+        } else if is_sensitive(field.name()) {
+            write!(writer, "{}={}", field, REDACTED)
+        } else {
+            write!(writer, "{}={:?}", field, value)
+        }
+    })
+    .delimited(" ")
+}
@@ -0,0 +1,240 @@
+// refunds.rs
+// A refund is recorded as `sending` before it is signed, and each signature it is sent with is stored
+// before the transaction goes out. A refund whose confirmation timed out may still have landed, so
+// before sending it again the retry job looks its signatures up and waits for the last one's blockhash
+// to expire.
+use crate::error_handling::AppError;
+use crate::lockin::LockinClient;
+use crate::mongo::{get_refunds_collection, Refund};
+use crate::validation::solana_address;
+use mongodb::bson::{doc, oid::ObjectId, DateTime as BsonDateTime};
+use solana_sdk::pubkey::Pubkey;
+
+const MAX_REFUND_ATTEMPTS: i32 = 5;
+// A refund left `sending` or `retrying` this long was abandoned by a process that died mid-send
+const STALE_REFUND_MILLIS: i64 = 10 * 60 * 1000;
+
+// Lamports the bot wallet still has to pay out for refunds being sent or waiting to be retried
+pub(crate) async fn owed_to_pending_refunds() -> Result<u64, AppError> {
+    let pipeline = vec![
+        doc! { "$match": { "$or": [
+            { "status": { "$in": ["sending", "retrying"] } },
+            { "status": "failed", "attempts": { "$lt": MAX_REFUND_ATTEMPTS } },
+        ] } },
+        doc! { "$group": { "_id": null, "lamports": { "$sum": "$lamports" } } },
+    ];
+    let mut cursor = get_refunds_collection().await?.aggregate(pipeline, None).await?;
+    let mut owed = 0;
+    if cursor.advance().await? {
+        owed = cursor.deserialize_current()?.get_i64("lamports").unwrap_or(0);
+    }
+    Ok(owed.max(0) as u64)
+}
+
+// Records a refund about to be sent for the first time and returns its id
+pub async fn start_refund(recipient: &Pubkey, lamports: u64) -> Result<ObjectId, AppError> {
+    let now = BsonDateTime::now();
+    let refund = Refund {
+        id: None,
+        recipient: recipient.to_string(),
+        lamports: lamports as i64,
+        signature: None,
+        signatures: Vec::new(),
+        last_valid_block_height: None,
+        status: "sending".to_string(),
+        attempts: 1,
+        last_error: None,
+        created_at: now,
+        updated_at: now,
+    };
+    get_refunds_collection()
+        .await?
+        .insert_one(refund, None)
+        .await?
+        .inserted_id
+        .as_object_id()
+        .ok_or_else(|| AppError::CustomError("Inserted refund has no ObjectId".to_string()))
+}
+
+// Stores the signature of a refund transaction before it is sent
+pub async fn record_attempt(refund_id: ObjectId, signature: &str, last_valid_block_height: u64) -> Result<(), AppError> {
+    get_refunds_collection()
+        .await?
+        .update_one(
+            doc! { "_id": refund_id },
+            doc! {
+                "$push": { "signatures": signature },
+                "$set": { "last_valid_block_height": last_valid_block_height as i64, "updated_at": BsonDateTime::now() },
+            },
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+// Records how the first attempt at a refund ended
+pub async fn finish_refund(refund_id: ObjectId, result: &anyhow::Result<String>) -> Result<(), AppError> {
+    let update = match result {
+        Ok(signature) => doc! { "$set": {
+            "status": "sent",
+            "signature": signature,
+            "updated_at": BsonDateTime::now(),
+        } },
+        Err(e) => doc! { "$set": {
+            "status": "failed",
+            "last_error": e.to_string(),
+            "updated_at": BsonDateTime::now(),
+        } },
+    };
+    get_refunds_collection()
+        .await?
+        .update_one(doc! { "_id": refund_id }, update, None)
+        .await?;
+    Ok(())
+}
+
+// Retries failed refunds until they are sent or run out of attempts, along with refunds a dead process
+// left `sending` or `retrying`
+pub async fn retry_failed_refunds() -> Result<(), AppError> {
+    let refunds_collection = get_refunds_collection().await?;
+    let stale_before = BsonDateTime::from_millis(BsonDateTime::now().timestamp_millis() - STALE_REFUND_MILLIS);
+    let mut cursor = refunds_collection
+        .find(
+            doc! {
+                "attempts": { "$lt": MAX_REFUND_ATTEMPTS },
+                "$or": [
+                    { "status": "failed" },
+                    { "status": { "$in": ["sending", "retrying"] }, "updated_at": { "$lt": stale_before } },
+                ],
+            },
+            None,
+        )
+        .await?;
+
+    let mut lockin_client = None;
+    while cursor.advance().await? {
+        let refund = cursor.deserialize_current()?;
+        let refund_id = match refund.id {
+            Some(refund_id) => refund_id,
+            None => continue,
+        };
+
+        // Claim the refund so a concurrent retry can't send it twice
+        let claimed = refunds_collection
+            .update_one(
+                doc! { "_id": refund_id, "status": &refund.status, "updated_at": refund.updated_at },
+                doc! { "$set": { "status": "retrying", "updated_at": BsonDateTime::now() } },
+                None,
+            )
+            .await?;
+        if claimed.modified_count != 1 {
+            continue;
+        }
+
+        if lockin_client.is_none() {
+            lockin_client = Some(LockinClient::new().await?);
+        }
+        let client = lockin_client.as_ref().unwrap();
+
+        // An earlier attempt may have landed after its confirmation timed out
+        match landed_signature(client, &refund).await {
+            Ok(Landed::Yes(signature)) => {
+                println!("Refund {} had already landed: {}", refund_id, signature);
+                refunds_collection
+                    .update_one(
+                        doc! { "_id": refund_id },
+                        doc! { "$set": {
+                            "status": "sent",
+                            "signature": signature,
+                            "last_error": null,
+                            "updated_at": BsonDateTime::now(),
+                        } },
+                        None,
+                    )
+                    .await?;
+                continue;
+            }
+            Ok(Landed::No) => {}
+            Ok(Landed::Pending) => {
+                release(refund_id, "The last attempt can still land, waiting for its blockhash to expire").await?;
+                continue;
+            }
+            Err(e) => {
+                eprintln!("Failed to check earlier attempts of refund {}: {:?}", refund_id, e);
+                release(refund_id, &format!("Failed to check earlier attempts: {:#}", e)).await?;
+                continue;
+            }
+        }
+
+        // Stored recipients are checked again, a bad one fails the attempt instead of burning the refund
+        let result = match solana_address(&refund.recipient) {
+            Ok(recipient) => client
+                .send_refund(refund_id, recipient, refund.lamports as u64)
+                .await
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        let update = match result {
+            Ok(signature) => {
+                println!("Refund {} retried successfully: {}", refund_id, signature);
+                doc! { "$set": {
+                    "status": "sent",
+                    "signature": signature,
+                    "last_error": null,
+                    "updated_at": BsonDateTime::now(),
+                }, "$inc": { "attempts": 1 } }
+            }
+            Err(e) => {
+                eprintln!("Refund {} retry failed: {:?}", refund_id, e);
+                doc! { "$set": {
+                    "status": "failed",
+                    "last_error": e,
+                    "updated_at": BsonDateTime::now(),
+                }, "$inc": { "attempts": 1 } }
+            }
+        };
+        refunds_collection
+            .update_one(doc! { "_id": refund_id }, update, None)
+            .await?;
+    }
+    Ok(())
+}
+
+enum Landed {
+    Yes(String),
+    No,
+    // The last attempt isn't found yet but its blockhash is still valid
+    Pending,
+}
+
+// Whether one of the refund's earlier attempts landed. The last one is only given up on once its
+// blockhash has expired, after which it can't land anymore.
+async fn landed_signature(client: &LockinClient, refund: &Refund) -> anyhow::Result<Landed> {
+    if refund.signatures.is_empty() {
+        return Ok(Landed::No);
+    }
+    let expired = match refund.last_valid_block_height {
+        Some(last_valid_block_height) => client.get_block_height().await? > last_valid_block_height as u64,
+        None => true,
+    };
+    // Looked up after the block height, so an attempt landing in between is still found
+    for signature in &refund.signatures {
+        if client.signature_status(signature).await? == Some(true) {
+            return Ok(Landed::Yes(signature.clone()));
+        }
+    }
+    Ok(if expired { Landed::No } else { Landed::Pending })
+}
+
+// Hands a claimed refund back to the retry job without counting an attempt
+async fn release(refund_id: ObjectId, reason: &str) -> Result<(), AppError> {
+    get_refunds_collection()
+        .await?
+        .update_one(
+            doc! { "_id": refund_id },
+            doc! { "$set": { "status": "failed", "last_error": reason, "updated_at": BsonDateTime::now() } },
+            None,
+        )
+        .await?;
+    Ok(())
+}
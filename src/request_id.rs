@@ -0,0 +1,59 @@
+// request_id.rs
+// Request IDs for correlating a failure a user reports with the logs. Every HTTP request and every
+// poller cycle runs with an ID, which is a field of its tracing span (and so of its log lines), part
+// of every error response and stored on transactions that fail.
+use axum::http::{HeaderValue, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::future::Future;
+use tracing::Instrument;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+const MAX_INCOMING_LEN: usize = 64;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+pub fn generate() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+// The ID of the request or poller cycle the current task runs for
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+// The current ID, or a new one for work that doesn't run under a request
+pub fn current_or_new() -> String {
+    current().unwrap_or_else(generate)
+}
+
+// Runs `future` under `id`, in a span carrying it
+pub async fn scope<F: Future>(id: String, future: F) -> F::Output {
+    let span = tracing::info_span!("request", request_id = %id);
+    REQUEST_ID.scope(id, future.instrument(span)).await
+}
+
+// Middleware giving each request an ID, reusing a well-formed one sent by the caller or a proxy, and
+// returning it in the x-request-id response header
+pub async fn assign_request_id<B>(request: Request<B>, next: Next<B>) -> Response {
+    let id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_INCOMING_LEN
+                && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+        .map(str::to_string)
+        .unwrap_or_else(generate);
+
+    let mut response = scope(id.clone(), next.run(request)).await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
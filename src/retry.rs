@@ -0,0 +1,79 @@
+// retry.rs
+// Retrying of calls to flaky upstreams (Kraken, Jupiter, Solana RPC, MongoDB) with jittered
+// exponential backoff. Only idempotent calls are retried: Kraken orders and withdrawals never are.
+use rand::Rng;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::sleep;
+
+// How often and how far apart a call is attempted
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub max_attempts: usize,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Backoff {
+    pub const fn new(max_attempts: usize, initial_delay: Duration, max_delay: Duration) -> Self {
+        Self { max_attempts, initial_delay, max_delay }
+    }
+
+    // The delay after failed attempt `attempt` (1-based): the initial delay doubled per attempt up to
+    // the maximum, then jittered down by up to half so concurrent callers don't retry in lockstep
+    pub fn delay(&self, attempt: usize) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(16) as u32;
+        let delay = self.initial_delay.saturating_mul(1 << exponent).min(self.max_delay);
+        let jitter = rand::thread_rng().gen_range(0.5..=1.0);
+        delay.mul_f64(jitter)
+    }
+}
+
+// Kraken REST reads
+pub const KRAKEN: Backoff = Backoff::new(3, Duration::from_millis(500), Duration::from_secs(4));
+// Jupiter quotes
+pub const JUPITER: Backoff = Backoff::new(3, Duration::from_millis(500), Duration::from_secs(4));
+// Solana JSON-RPC requests, after failing over through every endpoint
+pub const SOLANA_RPC: Backoff = Backoff::new(3, Duration::from_millis(250), Duration::from_secs(2));
+// MongoDB writes, on top of the driver's single retry
+pub const MONGO: Backoff = Backoff::new(3, Duration::from_millis(200), Duration::from_secs(2));
+
+// Network errors, server selection timeouts and errors MongoDB labels as retryable
+pub fn is_transient_mongo_error(error: &mongodb::error::Error) -> bool {
+    use mongodb::error::{ErrorKind, RETRYABLE_WRITE_ERROR, TRANSIENT_TRANSACTION_ERROR};
+    matches!(
+        *error.kind,
+        ErrorKind::Io(_) | ErrorKind::ServerSelection { .. } | ErrorKind::ConnectionPoolCleared { .. }
+    ) || error.contains_label(RETRYABLE_WRITE_ERROR)
+        || error.contains_label(TRANSIENT_TRANSACTION_ERROR)
+}
+
+// Runs `operation` until it succeeds, fails with an error `should_retry` rejects, or has been
+// attempted `backoff.max_attempts` times, returning the last result
+pub async fn retry_with_backoff<T, E, F, Fut, P>(
+    backoff: &Backoff,
+    name: &str,
+    should_retry: P,
+    mut operation: F,
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    P: Fn(&E) -> bool,
+{
+    let mut attempt = 1;
+    loop {
+        match operation().await {
+            Err(error) if attempt < backoff.max_attempts && should_retry(&error) => {
+                let delay = backoff.delay(attempt);
+                eprintln!(
+                    "{} failed (attempt {}/{}), retrying in {:?}",
+                    name, attempt, backoff.max_attempts, delay
+                );
+                sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
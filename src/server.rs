@@ -1,23 +1,116 @@
 // server.rs
 use std::sync::Arc;
 
+use axum::middleware;
 use axum::Router;
-use axum::routing::{post, get};
+use axum::routing::{delete, get, post};
 use tokio::signal;
 use tracing::info;
 
-use crate::handlers::register::register;
+use crate::handlers::register::{register, retrieve_secrets};
 use crate::handlers::decrypt::decrypt_keys_handler;
+use crate::handlers::admin::{
+    approve_transaction, get_transaction, list_failed_swaps, list_refunds, list_user_transactions, list_users, maintenance_status,
+    pause_poller, poller_status, redrive_failed_swap, require_admin, resume_poller, retry_transaction,
+    set_maintenance, volume_stats, list_withdrawal_keys, register_withdrawal_key, remove_withdrawal_key,
+    set_user_withdrawal_key, list_reconciliation_reports, get_reconciliation_report, run_reconciliation,
+    ledger_balances, user_ledger, volume_report, list_spend_approvals, approve_spend, reject_spend,
+};
+use crate::maintenance::reject_writes;
+use crate::handlers::preferences::{
+    clear_allocation, clear_autobuy, set_allocation, set_autobuy, set_solana_address, set_stake, update_slippage,
+};
+use crate::handlers::dca::{get_dca_plan, set_dca_plan, stop_dca_plan};
+use crate::handlers::lightning::create_lightning_invoice;
+use crate::handlers::bitcoin::create_deposit_address;
+use crate::handlers::tokens::{convert_tokens, get_token_balances};
+use crate::handlers::holdings::{get_cost_basis, get_user_holdings};
+use crate::handlers::transactions::get_user_transaction;
+use crate::handlers::export::{export_ethereum_keystore, export_history, export_watch_only};
+use crate::handlers::backup::{export_backup, import_backup};
+use crate::handlers::validate::validate_address;
+use crate::handlers::sign::sign_message;
+use crate::handlers::sweep::sweep_wallet;
+use crate::handlers::sessions::{create_session, revoke_session};
+use crate::handlers::api_keys::{create_api_key, list_api_keys, revoke_api_key, rotate_key};
+use crate::handlers::two_factor::{enroll_totp, verify_totp};
 use crate::mongo::AppState;
+use crate::telemetry::trace_request;
+use crate::request_id::assign_request_id;
 
 pub fn create_app(db: mongodb::Database) -> Router {
     let app_state = Arc::new(AppState { db });
     Router::new()
     .route("/register", post(register))
-    .route("/decrypt_keys", get(decrypt_keys_handler))
+    .route("/register/secrets", post(retrieve_secrets))
+    .route("/preferences/slippage", post(update_slippage))
+    .route("/preferences/autobuy", post(set_autobuy).delete(clear_autobuy))
+    .route("/preferences/allocation", post(set_allocation).delete(clear_allocation))
+    .route("/preferences/stake", post(set_stake))
+    .route("/preferences/solana_address", post(set_solana_address))
+    .route("/dca", get(get_dca_plan).post(set_dca_plan).delete(stop_dca_plan))
+    .route("/lightning/invoice", post(create_lightning_invoice))
+    .route("/bitcoin/address", post(create_deposit_address))
+    .route("/tokens", get(get_token_balances))
+    .route("/tokens/convert", post(convert_tokens))
+    .route("/holdings", get(get_user_holdings))
+    .route("/holdings/cost_basis", get(get_cost_basis))
+    .route("/transactions/export", get(export_history))
+    .route("/transactions/:id", get(get_user_transaction))
+    .route("/export/watch_only", get(export_watch_only))
+    .route("/sign_message", post(sign_message))
+    .route("/sweep", post(sweep_wallet))
+    .route("/export/backup", post(export_backup))
+    .route("/export/keystore", post(export_ethereum_keystore))
+    .route("/import/backup", post(import_backup))
+    .route_layer(middleware::from_fn(reject_writes))
+    // Validation, sessions, API keys and key retrieval don't move funds, so they stay available during
+    // maintenance
+    .route("/validate_address", post(validate_address))
+    .route("/sessions", post(create_session).delete(revoke_session))
+    .route("/api_keys", get(list_api_keys).post(create_api_key))
+    .route("/api_keys/:id", delete(revoke_api_key))
+    .route("/rotate_key", post(rotate_key))
+    .route("/2fa/enroll", post(enroll_totp))
+    .route("/2fa/verify", post(verify_totp))
+    .route("/decrypt_keys", post(decrypt_keys_handler))
+    .nest("/admin", admin_routes())
+    .layer(middleware::from_fn(trace_request))
+    .layer(middleware::from_fn(assign_request_id))
     .with_state(app_state)
 }
 
+// Admin endpoints, all behind the x-admin-key header
+fn admin_routes() -> Router<Arc<AppState>> {
+    Router::new()
+    .route("/users", get(list_users))
+    .route("/users/:user_id/transactions", get(list_user_transactions))
+    .route("/transactions/:id", get(get_transaction))
+    .route("/transactions/:id/retry", post(retry_transaction))
+    .route("/transactions/:id/approve", post(approve_transaction))
+    .route("/poller", get(poller_status))
+    .route("/poller/pause", post(pause_poller))
+    .route("/poller/resume", post(resume_poller))
+    .route("/stats", get(volume_stats))
+    .route("/reports/volume", get(volume_report))
+    .route("/maintenance", get(maintenance_status).post(set_maintenance))
+    .route("/failed_swaps", get(list_failed_swaps))
+    .route("/failed_swaps/:id/redrive", post(redrive_failed_swap))
+    .route("/refunds", get(list_refunds))
+    .route("/spend_approvals", get(list_spend_approvals))
+    .route("/spend_approvals/:id/approve", post(approve_spend))
+    .route("/spend_approvals/:id/reject", post(reject_spend))
+    .route("/withdrawal_keys", get(list_withdrawal_keys).post(register_withdrawal_key))
+    .route("/withdrawal_keys/:key", delete(remove_withdrawal_key))
+    .route("/users/:user_id/withdrawal_key", post(set_user_withdrawal_key))
+    .route("/reconciliation", get(list_reconciliation_reports))
+    .route("/reconciliation/:day", get(get_reconciliation_report))
+    .route("/reconciliation/:day/run", post(run_reconciliation))
+    .route("/ledger", get(ledger_balances))
+    .route("/users/:user_id/ledger", get(user_ledger))
+    .route_layer(middleware::from_fn(require_admin))
+}
+
 pub async fn shutdown_signal() {
     let ctrl_c = async {
         signal::ctrl_c()
@@ -0,0 +1,145 @@
+// sessions.rs
+// Short-lived, scoped session tokens. Sensitive operations never take the API key itself, the API key
+// only opens a session for the scopes a caller needs, so a leaked session token expires quickly and
+// can't do anything outside its scopes. Since only a hash of the API key is stored, each session keeps
+// the API key sealed under its own token to decrypt the user's secrets with.
+use axum::http::HeaderMap;
+use mongodb::bson::{doc, DateTime as BsonDateTime};
+use mongodb::Database;
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+use crate::api_keys::{ApiKeyAuth, SCOPE_DECRYPT, SCOPE_WITHDRAW};
+use crate::encryption::{open_for_token, seal_for_token};
+use crate::entropy;
+use crate::error_handling::AppError;
+use crate::mongo::User;
+
+pub const SCOPE_DECRYPT_KEYS: &str = "decrypt-keys";
+pub const SCOPE_SIGN_SOLANA: &str = "sign-solana";
+pub const SCOPE_SIGN_ETHEREUM: &str = "sign-ethereum";
+pub const SCOPE_SIGN_BITCOIN: &str = "sign-bitcoin";
+pub const SCOPE_SWEEP: &str = "sweep";
+pub const SCOPE_EXPORT_BACKUP: &str = "export-backup";
+pub const SCOPE_IMPORT_BACKUP: &str = "import-backup";
+pub const SCOPE_EXPORT_KEYSTORE: &str = "export-keystore";
+
+pub const SCOPES: &[&str] = &[
+    SCOPE_DECRYPT_KEYS,
+    SCOPE_SIGN_SOLANA,
+    SCOPE_SIGN_ETHEREUM,
+    SCOPE_SIGN_BITCOIN,
+    SCOPE_SWEEP,
+    SCOPE_EXPORT_BACKUP,
+    SCOPE_IMPORT_BACKUP,
+    SCOPE_EXPORT_KEYSTORE,
+];
+
+// The API key scope needed to open a session with `scope`
+fn api_key_scope(scope: &str) -> &'static str {
+    match scope {
+        SCOPE_SWEEP | SCOPE_SIGN_SOLANA | SCOPE_SIGN_ETHEREUM | SCOPE_SIGN_BITCOIN => SCOPE_WITHDRAW,
+        _ => SCOPE_DECRYPT,
+    }
+}
+
+// An authorized session: its user and the API key their secrets are encrypted with
+pub struct Session {
+    pub user: User,
+    pub secrets_key: Zeroizing<String>,
+}
+
+// Sessions are looked up by the SHA-256 of their token, the token itself is never stored
+fn token_hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+// Opens a session for the authenticated user limited to `scopes`, returning the token and its expiry.
+// The API key has to allow every scope, and the key the secrets are encrypted with is kept sealed
+// under the token.
+pub async fn create_session(
+    db: &Database,
+    auth: &ApiKeyAuth,
+    scopes: &[String],
+    ttl_secs: u64,
+) -> Result<(String, BsonDateTime), AppError> {
+    if let Some(unknown) = scopes.iter().find(|scope| !SCOPES.contains(&scope.as_str())) {
+        return Err(AppError::BadRequest(format!(
+            "Unknown scope {}, expected one of {}",
+            unknown,
+            SCOPES.join(", ")
+        )));
+    }
+    if scopes.is_empty() {
+        return Err(AppError::BadRequest("A session needs at least one scope".to_string()));
+    }
+    if !scopes.iter().all(|scope| auth.allows(api_key_scope(scope))) {
+        return Err(AppError::Forbidden);
+    }
+
+    let token = hex::encode(entropy::random_bytes::<32>()?);
+    let expires_at = BsonDateTime::from_millis(BsonDateTime::now().timestamp_millis() + ttl_secs as i64 * 1000);
+    db.collection("sessions")
+        .insert_one(
+            doc! {
+                "token_hash": token_hash(&token),
+                "user_id": auth.user.user_id,
+                "api_key_id": &auth.key_id,
+                "scopes": scopes,
+                "secrets_key": seal_for_token(&token, &auth.secrets_key)?,
+                "created_at": BsonDateTime::now(),
+                "expires_at": expires_at,
+            },
+            None,
+        )
+        .await?;
+    Ok((token, expires_at))
+}
+
+// Reads the session token from the `Authorization: Bearer` header
+fn session_token(headers: &HeaderMap) -> Result<&str, AppError> {
+    headers
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+        .ok_or(AppError::Unauthorized)
+}
+
+// Checks the request carries a live session with `scope` and returns its user along with the
+// unsealed API key
+pub async fn authorize(db: &Database, headers: &HeaderMap, scope: &str) -> Result<Session, AppError> {
+    let token = session_token(headers)?;
+    let filter = doc! {
+        "token_hash": token_hash(token),
+        "scopes": scope,
+        "expires_at": { "$gt": BsonDateTime::now() },
+    };
+    let session = db
+        .collection::<mongodb::bson::Document>("sessions")
+        .find_one(filter, None)
+        .await?
+        .ok_or(AppError::Unauthorized)?;
+    let user_id = session.get_i64("user_id").map_err(|_| AppError::Unauthorized)?;
+    let secrets_key = session
+        .get_str("secrets_key")
+        .map_err(|_| AppError::Unauthorized)
+        .and_then(|sealed| open_for_token(token, sealed))?;
+    let user = db
+        .collection::<User>("users")
+        .find_one(doc! { "user_id": user_id }, None)
+        .await?
+        .ok_or(AppError::Unauthorized)?;
+    Ok(Session { user, secrets_key })
+}
+
+// Ends the session of the request's token before it expires
+pub async fn revoke_session(db: &Database, headers: &HeaderMap) -> Result<bool, AppError> {
+    let result = db
+        .collection::<mongodb::bson::Document>("sessions")
+        .delete_one(doc! { "token_hash": token_hash(session_token(headers)?) }, None)
+        .await?;
+    Ok(result.deleted_count == 1)
+}
+
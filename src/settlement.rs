@@ -0,0 +1,60 @@
+// settlement.rs
+// When a deposit may be credited. A deposit has to be settled (Kraken reports it as Success, or its
+// on-chain transaction has BTC_MIN_CONFIRMATIONS confirmations) and then stay settled for
+// DEPOSIT_SETTLEMENT_DELAY_SECS. The time it settled is kept on the transaction, and cleared when the
+// deposit stops looking settled (a Kraken hold, a reorg), so the delay starts over.
+use crate::config::config;
+use crate::dry_run;
+use crate::error_handling::AppError;
+use mongodb::bson::{doc, DateTime as BsonDateTime, Document};
+use mongodb::Collection;
+
+// Records whether the deposit of `tx` is settled and returns whether it can be credited now
+pub async fn is_creditable(
+    transactions_collection: &Collection<Document>,
+    tx: &Document,
+    settled: bool,
+) -> Result<bool, AppError> {
+    let tx_id = tx
+        .get_object_id("_id")
+        .map_err(|_| AppError::CustomError("Transaction document is missing _id".to_string()))?;
+    let settled_since = tx.get_datetime("settled_since").ok().copied();
+
+    if !settled {
+        if settled_since.is_some() {
+            println!("Deposit of transaction {} is no longer settled, restarting its settlement delay", tx_id);
+            if !dry_run::is_enabled() {
+                transactions_collection
+                    .update_one(doc! { "_id": tx_id }, doc! { "$unset": { "settled_since": "", "creditable_at": "" } }, None)
+                    .await?;
+            }
+        }
+        return Ok(false);
+    }
+
+    let delay_millis = config().deposit_settlement_delay_secs as i64 * 1000;
+    let settled_since = match settled_since {
+        Some(settled_since) => settled_since,
+        None => {
+            let now = BsonDateTime::now();
+            if !dry_run::is_enabled() {
+                transactions_collection
+                    .update_one(
+                        doc! { "_id": tx_id },
+                        doc! { "$set": {
+                            "settled_since": now,
+                            "creditable_at": BsonDateTime::from_millis(now.timestamp_millis() + delay_millis),
+                        } },
+                        None,
+                    )
+                    .await?;
+            }
+            now
+        }
+    };
+    let creditable = BsonDateTime::now().timestamp_millis() - settled_since.timestamp_millis() >= delay_millis;
+    if !creditable {
+        println!("Deposit of transaction {} is settled, waiting out its settlement delay", tx_id);
+    }
+    Ok(creditable)
+}
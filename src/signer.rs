@@ -0,0 +1,186 @@
+// signer.rs
+// The bot wallet's signer. SIGNER_BACKEND picks where its key lives: `env` reads PRIVATE_KEY, `mongo`
+// decrypts a key stored in the settings collection under a data key wrapped by KEY_WRAPPER, and
+// `remote` sends every message to a signing service over HTTP (an HSM gateway or a separate signer
+// host) so the key never enters this process. LockinClient only sees the WalletSigner trait.
+//
+// The first start on the mongo backend encrypts PRIVATE_KEY into Mongo, after which it can be
+// removed from the environment. A remote signer answers `GET /pubkey` with {"pubkey"} and
+// `POST /sign` {"pubkey", "message"} (base64) with {"signature"} (base58), and every signature it
+// returns is verified before it is used.
+use crate::config::{config, SignerBackendKind};
+use crate::error_handling::AppError;
+use crate::key_wrap::{key_wrapper, open_setting, seal_setting};
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD as base64_engine;
+use base64::Engine;
+use mongodb::bson::doc;
+use reqwest::Client;
+use serde_json::{json, Value};
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{Keypair, Signature, Signer};
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use zeroize::Zeroizing;
+
+// Settings document holding the encrypted key, its id is also the AES-GCM associated data
+const BOT_WALLET_KEY_ID: &str = "bot_wallet_key";
+const REMOTE_SIGNER_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Signs the bot wallet's transactions
+#[async_trait]
+pub trait WalletSigner: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn pubkey(&self) -> Pubkey;
+
+    async fn sign_message(&self, message: &[u8]) -> Result<Signature, AppError>;
+}
+
+// A keypair held in memory, read from PRIVATE_KEY or decrypted from Mongo
+pub struct KeypairSigner {
+    name: &'static str,
+    keypair: Keypair,
+}
+
+#[async_trait]
+impl WalletSigner for KeypairSigner {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn pubkey(&self) -> Pubkey {
+        self.keypair.pubkey()
+    }
+
+    async fn sign_message(&self, message: &[u8]) -> Result<Signature, AppError> {
+        Ok(self.keypair.sign_message(message))
+    }
+}
+
+// A signing service holding the key, see the module comment for its API
+pub struct RemoteSigner {
+    client: Client,
+    url: String,
+    token: Option<String>,
+    pubkey: Pubkey,
+}
+
+impl RemoteSigner {
+    // Asks the service which key it signs with
+    async fn connect(url: &str, token: Option<String>) -> Result<Self, AppError> {
+        let client = Client::new();
+        let mut request = client.get(format!("{}/pubkey", url)).timeout(REMOTE_SIGNER_TIMEOUT);
+        if let Some(token) = &token {
+            request = request.bearer_auth(token);
+        }
+        let response: Value = request.send().await?.error_for_status()?.json().await?;
+        let pubkey = response["pubkey"]
+            .as_str()
+            .and_then(|pubkey| Pubkey::from_str(pubkey).ok())
+            .ok_or_else(|| AppError::CustomError(format!("The remote signer returned no pubkey: {}", response)))?;
+        Ok(RemoteSigner { client, url: url.to_string(), token, pubkey })
+    }
+}
+
+#[async_trait]
+impl WalletSigner for RemoteSigner {
+    fn name(&self) -> &'static str {
+        "remote"
+    }
+
+    fn pubkey(&self) -> Pubkey {
+        self.pubkey
+    }
+
+    async fn sign_message(&self, message: &[u8]) -> Result<Signature, AppError> {
+        let mut request = self
+            .client
+            .post(format!("{}/sign", self.url))
+            .timeout(REMOTE_SIGNER_TIMEOUT)
+            .json(&json!({ "pubkey": self.pubkey.to_string(), "message": base64_engine.encode(message) }));
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        let response: Value = request.send().await?.error_for_status()?.json().await?;
+        let signature = response["signature"]
+            .as_str()
+            .and_then(|signature| Signature::from_str(signature).ok())
+            .ok_or_else(|| AppError::CustomError(format!("The remote signer returned no signature: {}", response)))?;
+        if !signature.verify(self.pubkey.as_ref(), message) {
+            return Err(AppError::CustomError(format!(
+                "The remote signer returned a signature that doesn't verify against {}",
+                self.pubkey
+            )));
+        }
+        Ok(signature)
+    }
+}
+
+static SIGNER: OnceLock<Arc<dyn WalletSigner>> = OnceLock::new();
+
+// Loads the configured signer, once. Called by the preflight checks before anything signs.
+pub async fn init() -> Result<Arc<dyn WalletSigner>, AppError> {
+    if let Some(signer) = SIGNER.get() {
+        return Ok(signer.clone());
+    }
+    let config = config();
+    let signer: Arc<dyn WalletSigner> = match config.signer_backend {
+        SignerBackendKind::Env => Arc::new(KeypairSigner {
+            name: "env",
+            keypair: keypair_from_base58(&config.private_key)?,
+        }),
+        SignerBackendKind::Mongo => Arc::new(KeypairSigner {
+            name: "mongo",
+            keypair: load_mongo_keypair().await?,
+        }),
+        SignerBackendKind::Remote => {
+            Arc::new(RemoteSigner::connect(&config.remote_signer_url, config.remote_signer_token.clone()).await?)
+        }
+    };
+    Ok(SIGNER.get_or_init(|| signer).clone())
+}
+
+// The signer loaded by `init`
+pub fn shared() -> Result<Arc<dyn WalletSigner>, AppError> {
+    SIGNER
+        .get()
+        .cloned()
+        .ok_or_else(|| AppError::CustomError("The bot wallet signer is not loaded".to_string()))
+}
+
+fn keypair_from_base58(private_key: &str) -> Result<Keypair, AppError> {
+    // Keypair zeroes its secret key on drop, the decoded copy has to be zeroed by hand
+    let bytes = Zeroizing::new(
+        bs58::decode(private_key)
+            .into_vec()
+            .map_err(|_| AppError::CustomError("PRIVATE_KEY is not base58".to_string()))?,
+    );
+    Keypair::from_bytes(&bytes).map_err(|e| AppError::CustomError(format!("Invalid keypair bytes: {}", e)))
+}
+
+// The keypair bytes are sealed in the settings collection, see key_wrap::seal_setting
+async fn load_mongo_keypair() -> Result<Keypair, AppError> {
+    if let Some(bytes) = open_setting(BOT_WALLET_KEY_ID).await? {
+        return Keypair::from_bytes(&bytes).map_err(|e| AppError::CustomError(format!("Invalid keypair bytes: {}", e)));
+    }
+
+    if config().private_key.is_empty() {
+        return Err(AppError::CustomError(
+            "No bot wallet key is stored in Mongo, start once with PRIVATE_KEY set to store it".to_string(),
+        ));
+    }
+    let keypair = keypair_from_base58(&config().private_key)?;
+    if key_wrapper().is_none() {
+        return Err(AppError::CustomError("SIGNER_BACKEND=mongo needs a KEY_WRAPPER".to_string()));
+    }
+    let keypair_bytes = Zeroizing::new(keypair.to_bytes());
+    let fields = doc! { "pubkey": keypair.pubkey().to_string() };
+    seal_setting(BOT_WALLET_KEY_ID, keypair_bytes.as_slice(), fields).await?;
+    println!(
+        "Stored bot wallet key {} encrypted in Mongo, PRIVATE_KEY can be removed from the environment",
+        keypair.pubkey()
+    );
+    Ok(keypair)
+}
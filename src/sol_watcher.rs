@@ -0,0 +1,405 @@
+// sol_watcher.rs
+use crate::config::config;
+use crate::cost_basis;
+use crate::dry_run;
+use crate::error_handling::AppError;
+use crate::ledger::{Account, Journal};
+use crate::lockin::{LockinClient, SwapResult, DEFAULT_SLIPPAGE_BPS};
+use crate::maintenance;
+use crate::mongo::{get_transactions_collection, get_users_collection, User};
+use crate::poller::{is_poller_paused, record_swap_fees, run_lockin_swap, settle_swap_state, PipelineClients};
+use crate::transaction_state::{initial_state_fields, TransactionState, TransactionStateMachine};
+use crate::wallets::solana::user_keypair;
+use mongodb::bson::{doc, oid::ObjectId, DateTime as BsonDateTime, Document};
+use mongodb::options::UpdateOptions;
+use mongodb::Collection;
+use rust_decimal::Decimal;
+use serde_json::{json, Value};
+use solana_sdk::pubkey::Pubkey;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::time::interval;
+
+const SOLANA_SOURCE: &str = "Solana deposit";
+const SIGNATURE_PAGE_LIMIT: u64 = 100;
+const LAMPORTS_DECIMALS: u8 = 9;
+
+// An inbound SOL or SPL transfer to a user's generated Solana address
+#[derive(Debug, Clone)]
+struct SolanaDeposit {
+    signature: String,
+    // None for native SOL
+    mint: Option<Pubkey>,
+    // The token account an SPL transfer landed in
+    token_account: Option<Pubkey>,
+    // Lamports or token base units
+    amount: u64,
+    decimals: u8,
+    time: i64,
+}
+
+impl SolanaDeposit {
+    fn asset(&self) -> String {
+        self.mint.map(|mint| mint.to_string()).unwrap_or_else(|| "SOL".to_string())
+    }
+
+    fn ui_amount(&self) -> f64 {
+        self.amount as f64 / 10f64.powi(self.decimals as i32)
+    }
+
+    fn decimal_amount(&self) -> Decimal {
+        Decimal::new(self.amount as i64, self.decimals as u32)
+    }
+}
+
+// Checks every user's Solana address every SOL_WATCHER_INTERVAL_SECS. Deposits are swept into the
+// bot wallet and swapped into LOCKIN on Jupiter straight away, without going through the exchange.
+pub async fn start_sol_watcher() -> Result<(), AppError> {
+    let clients = PipelineClients::live();
+    let mut interval = interval(Duration::from_secs(config().sol_watcher_interval_secs));
+    loop {
+        interval.tick().await;
+        if maintenance::is_enabled() || is_poller_paused() {
+            continue;
+        }
+        if let Err(e) = scan_addresses(&clients).await {
+            eprintln!("Solana watcher failed: {:?}", e);
+        }
+    }
+}
+
+async fn scan_addresses(clients: &PipelineClients) -> Result<(), AppError> {
+    let users_collection = get_users_collection().await?;
+    let transactions_collection = get_transactions_collection().await?;
+    let lockin_client = clients.lockin_client()?;
+    let lockin_mint = Pubkey::from_str(&config().lockin_mint)
+        .map_err(|e| AppError::CustomError(format!("Invalid LOCKIN_MINT: {}", e)))?;
+
+    let mut cursor = users_collection
+        .find(doc! { "solana_public_key": { "$nin": [null, ""] } }, None)
+        .await?;
+    while cursor.advance().await? {
+        let user = cursor.deserialize_current()?;
+        if let Err(e) = scan_user(
+            clients,
+            &lockin_client,
+            &users_collection,
+            &transactions_collection,
+            &user,
+            &lockin_mint,
+        )
+        .await
+        {
+            eprintln!("Error checking Solana deposits for user {}: {:?}", user.user_id, e);
+        }
+    }
+    Ok(())
+}
+
+// Walks the signatures on the user's address since the last one checked, oldest first
+async fn scan_user(
+    clients: &PipelineClients,
+    lockin_client: &LockinClient,
+    users_collection: &Collection<User>,
+    transactions_collection: &Collection<Document>,
+    user: &User,
+    lockin_mint: &Pubkey,
+) -> Result<(), AppError> {
+    let address = match user.solana_public_key.as_deref().map(Pubkey::from_str) {
+        Some(Ok(address)) => address,
+        _ => return Ok(()),
+    };
+
+    let mut options = json!({ "limit": SIGNATURE_PAGE_LIMIT, "commitment": "finalized" });
+    if let Some(last_signature) = &user.solana_deposit_cursor {
+        options["until"] = json!(last_signature);
+    }
+    let response = clients
+        .solana_rpc
+        .send_request("getSignaturesForAddress", json!([address.to_string(), options]))
+        .await?;
+    let signatures = response["result"].as_array().cloned().unwrap_or_default();
+
+    // The first scan only marks where the address history currently ends
+    if user.solana_deposit_cursor.is_none() {
+        let newest = signatures.first().and_then(|entry| entry["signature"].as_str());
+        if let Some(newest) = newest {
+            save_cursor(users_collection, user.user_id, newest).await?;
+        }
+        return Ok(());
+    }
+
+    for entry in signatures.iter().rev() {
+        let signature = match entry["signature"].as_str() {
+            Some(signature) => signature,
+            None => continue,
+        };
+        if entry["err"].is_null() {
+            let deposits = find_deposits(clients, signature, &address, &lockin_client.pubkey(), lockin_mint).await?;
+            for deposit in deposits {
+                process_deposit(clients, lockin_client, transactions_collection, user, &address, &deposit).await?;
+            }
+        }
+        save_cursor(users_collection, user.user_id, signature).await?;
+    }
+    Ok(())
+}
+
+async fn save_cursor(users_collection: &Collection<User>, user_id: i64, signature: &str) -> Result<(), AppError> {
+    users_collection
+        .update_one(
+            doc! { "user_id": user_id },
+            doc! { "$set": { "solana_deposit_cursor": signature } },
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+// Reads the SOL and SPL balance changes of the address out of a finalized transaction
+async fn find_deposits(
+    clients: &PipelineClients,
+    signature: &str,
+    address: &Pubkey,
+    bot_wallet: &Pubkey,
+    lockin_mint: &Pubkey,
+) -> Result<Vec<SolanaDeposit>, AppError> {
+    let response = clients
+        .solana_rpc
+        .send_request(
+            "getTransaction",
+            json!([signature, {
+                "encoding": "jsonParsed",
+                "commitment": "finalized",
+                "maxSupportedTransactionVersion": 0,
+            }]),
+        )
+        .await?;
+    let tx = &response["result"];
+    let account_keys = match tx["transaction"]["message"]["accountKeys"].as_array() {
+        Some(account_keys) => account_keys,
+        None => return Ok(Vec::new()),
+    };
+
+    // Transactions the user signed are sweeps or their own spending, and ones the bot signed are
+    // LOCKIN deliveries and refunds
+    let signed_by = |key: &Pubkey| {
+        account_keys
+            .iter()
+            .any(|account| account["signer"] == true && account["pubkey"] == key.to_string())
+    };
+    if signed_by(address) || signed_by(bot_wallet) {
+        return Ok(Vec::new());
+    }
+
+    let meta = &tx["meta"];
+    let time = tx["blockTime"]
+        .as_i64()
+        .unwrap_or_else(|| BsonDateTime::now().timestamp_millis() / 1000);
+    let mut deposits = Vec::new();
+
+    if let Some(index) = account_keys.iter().position(|account| account["pubkey"] == address.to_string()) {
+        let pre = meta["preBalances"][index].as_u64().unwrap_or(0);
+        let post = meta["postBalances"][index].as_u64().unwrap_or(0);
+        if post > pre {
+            deposits.push(SolanaDeposit {
+                signature: signature.to_string(),
+                mint: None,
+                token_account: None,
+                amount: post - pre,
+                decimals: LAMPORTS_DECIMALS,
+                time,
+            });
+        }
+    }
+
+    let token_amount = |balance: &Value| -> u64 {
+        balance["uiTokenAmount"]["amount"]
+            .as_str()
+            .and_then(|amount| amount.parse().ok())
+            .unwrap_or(0)
+    };
+    for balance in meta["postTokenBalances"].as_array().into_iter().flatten() {
+        if balance["owner"] != address.to_string() {
+            continue;
+        }
+        let mint = match balance["mint"].as_str().map(Pubkey::from_str) {
+            Some(Ok(mint)) if mint != *lockin_mint => mint,
+            _ => continue,
+        };
+        let account_index = balance["accountIndex"].clone();
+        let pre = meta["preTokenBalances"]
+            .as_array()
+            .and_then(|balances| balances.iter().find(|pre| pre["accountIndex"] == account_index))
+            .map(token_amount)
+            .unwrap_or(0);
+        let post = token_amount(balance);
+        let token_account = account_index
+            .as_u64()
+            .and_then(|index| account_keys.get(index as usize))
+            .and_then(|account| account["pubkey"].as_str())
+            .and_then(|pubkey| Pubkey::from_str(pubkey).ok());
+        if post > pre && token_account.is_some() {
+            deposits.push(SolanaDeposit {
+                signature: signature.to_string(),
+                mint: Some(mint),
+                token_account,
+                amount: post - pre,
+                decimals: balance["uiTokenAmount"]["decimals"].as_u64().unwrap_or(0) as u8,
+                time,
+            });
+        }
+    }
+    Ok(deposits)
+}
+
+// Records the deposit once, claims it and hands it to the swap
+async fn process_deposit(
+    clients: &PipelineClients,
+    lockin_client: &LockinClient,
+    transactions_collection: &Collection<Document>,
+    user: &User,
+    address: &Pubkey,
+    deposit: &SolanaDeposit,
+) -> Result<(), AppError> {
+    let asset = deposit.asset();
+    println!(
+        "Solana deposit {} of {} {} for user {}",
+        deposit.signature,
+        deposit.ui_amount(),
+        asset,
+        user.user_id
+    );
+
+    // In dry-run mode the deposit is not recorded and each one is only run through once
+    if dry_run::is_enabled() {
+        let refid = format!("{}:{}", deposit.signature, asset);
+        if dry_run::has_processed_deposit(&refid).await? {
+            return Ok(());
+        }
+        dry_run::record_action(
+            "process_deposit",
+            json!({ "refid": refid, "user_id": user.user_id, "asset": asset, "amount": deposit.ui_amount() }),
+        )
+        .await;
+        let state_machine = TransactionStateMachine::new(transactions_collection.clone(), ObjectId::new());
+        return sweep_and_swap(clients, lockin_client, &state_machine, user, address, deposit).await;
+    }
+
+    let filter = doc! { "refid": &deposit.signature, "source": SOLANA_SOURCE, "deposit_asset": &asset };
+    let mut new_deposit = doc! {
+        "user_id": user.user_id,
+        "amount": deposit.ui_amount(),
+        "address": address.to_string(),
+        "timestamp": BsonDateTime::from_millis(deposit.time * 1000),
+    };
+    new_deposit.extend(initial_state_fields(TransactionState::DepositPending));
+    transactions_collection
+        .update_one(
+            filter.clone(),
+            doc! { "$setOnInsert": new_deposit },
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .await?;
+
+    let tx = match transactions_collection.find_one(filter, None).await? {
+        Some(tx) => tx,
+        None => return Ok(()),
+    };
+    let tx_id = tx
+        .get_object_id("_id")
+        .map_err(|_| AppError::CustomError("Transaction document is missing _id".to_string()))?;
+    let state_machine = TransactionStateMachine::new(transactions_collection.clone(), tx_id);
+    if !state_machine
+        .try_transition(TransactionState::DepositPending, TransactionState::DepositSettled, Document::new())
+        .await?
+    {
+        return Ok(());
+    }
+    Journal::for_transaction(tx_id, "deposit", user.user_id)
+        .transfer(Account::User(user.user_id), Account::UserWallet(user.user_id), &deposit.asset(), deposit.decimal_amount())
+        .record()
+        .await;
+
+    if let Err(e) = sweep_and_swap(clients, lockin_client, &state_machine, user, address, deposit).await {
+        eprintln!("Error processing Solana deposit {}: {:?}", deposit.signature, e);
+        state_machine.fail(&e.report()).await?;
+    }
+    Ok(())
+}
+
+// Moves the deposit into the bot wallet and swaps it into LOCKIN delivered back to the same address
+async fn sweep_and_swap(
+    clients: &PipelineClients,
+    lockin_client: &LockinClient,
+    state_machine: &TransactionStateMachine,
+    user: &User,
+    address: &Pubkey,
+    deposit: &SolanaDeposit,
+) -> Result<(), AppError> {
+    let owner = user_keypair(user, user.api_key.as_deref()).await?;
+    let sweep_signature = match (deposit.mint, deposit.token_account) {
+        (Some(mint), Some(token_account)) => {
+            lockin_client
+                .sweep_token(&owner, token_account, mint, deposit.amount, deposit.decimals)
+                .await?
+        }
+        _ => lockin_client.sweep_sol(&owner, deposit.amount).await?,
+    };
+    println!("Swept Solana deposit {} into the bot wallet: {}", deposit.signature, sweep_signature);
+    if !state_machine
+        .try_transition(
+            TransactionState::DepositSettled,
+            TransactionState::Withdrawn,
+            doc! { "sweep_signature": &sweep_signature },
+        )
+        .await?
+    {
+        return Err(AppError::CustomError(format!(
+            "Transaction {} is no longer in state {}",
+            state_machine.tx_id(),
+            TransactionState::DepositSettled
+        )));
+    }
+    Journal::for_transaction(state_machine.tx_id(), "sweep", user.user_id)
+        .transfer(Account::UserWallet(user.user_id), Account::BotWallet, &deposit.asset(), deposit.decimal_amount())
+        .record()
+        .await;
+
+    let tx_id = Some(state_machine.tx_id()).filter(|_| !dry_run::is_enabled());
+    let slippage_bps = user.slippage_bps.unwrap_or(DEFAULT_SLIPPAGE_BPS);
+    match deposit.mint {
+        None => run_lockin_swap(clients, tx_id, user.user_id, *address, deposit.amount, slippage_bps).await,
+        Some(mint) => {
+            let lockin_mint = Pubkey::from_str(&config().lockin_mint)
+                .map_err(|e| AppError::CustomError(format!("Invalid LOCKIN_MINT: {}", e)))?;
+            match lockin_client
+                .execute_token(mint, lockin_mint, deposit.amount, *address, slippage_bps)
+                .await
+            {
+                Ok(SwapResult::DryRun) => settle_swap_state(tx_id, TransactionState::Swapped, None).await,
+                Ok(SwapResult::Delivered(outcome)) => {
+                    if let Some(tx_id) = tx_id {
+                        Journal::for_transaction(tx_id, "swap", user.user_id)
+                            .transfer(Account::BotWallet, Account::User(user.user_id), &deposit.asset(), deposit.decimal_amount())
+                            .transfer(Account::BotWallet, Account::Fees, "SOL", Decimal::new(outcome.network_fee_lamports as i64, 9))
+                            .record()
+                            .await;
+                        record_swap_fees(tx_id, &outcome).await?;
+                        if let Err(e) = cost_basis::record_acquisition(tx_id, &outcome).await {
+                            eprintln!("Error recording the cost basis of transaction {}: {:?}", tx_id, e);
+                        }
+                    }
+                    settle_swap_state(tx_id, TransactionState::Swapped, None).await;
+                }
+                // The tokens stay in the bot wallet for manual reconciliation
+                Ok(SwapResult::NotSent(reason)) => {
+                    settle_swap_state(tx_id, TransactionState::Failed, Some(format!("Swap not sent: {}", reason))).await
+                }
+                // The tokens stay in the bot wallet for manual reconciliation
+                Err(e) => settle_swap_state(tx_id, TransactionState::Failed, Some(format!("{:#}", e))).await,
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,93 @@
+// solana_txs.rs
+// Receipts of the Solana transactions a deposit ends in: its lockin swaps, SOL transfers, stake
+// deposits and refunds. Each receipt is stored in the `solana_txs` collection under its signature,
+// linked to the deposit by `tx_id`, with the slot, block time, compute units and final status read
+// back from the chain once the transaction has confirmed.
+use crate::config::{config, Network};
+use crate::error_handling::AppError;
+use crate::lockin::{PooledRpc, RpcPool, SolanaRpc};
+use crate::mongo::get_solana_txs_collection;
+use mongodb::bson::{doc, oid::ObjectId, DateTime as BsonDateTime, Document};
+use mongodb::options::{FindOptions, ReplaceOptions};
+use serde_json::json;
+
+// The Solana Explorer page of a transaction on the configured cluster
+pub fn explorer_url(signature: &str) -> String {
+    match config().network {
+        Network::Devnet => format!("https://explorer.solana.com/tx/{}?cluster=devnet", signature),
+        Network::Mainnet => format!("https://explorer.solana.com/tx/{}", signature),
+    }
+}
+
+// Fetches the receipt of a confirmed transaction and stores it. `kind` is "swap", "transfer", "stake"
+// or "refund" and `leg` the allocation leg it belongs to. Errors are logged, a missing receipt
+// doesn't undo the transaction.
+pub async fn record_receipt(tx_id: Option<ObjectId>, user_id: i64, kind: &str, leg: usize, signature: &str) {
+    if let Err(e) = try_record_receipt(tx_id, user_id, kind, leg, signature).await {
+        eprintln!("Error recording the receipt of {} {}: {:?}", kind, signature, e);
+    }
+}
+
+async fn try_record_receipt(
+    tx_id: Option<ObjectId>,
+    user_id: i64,
+    kind: &str,
+    leg: usize,
+    signature: &str,
+) -> Result<(), AppError> {
+    let rpc = PooledRpc::new(RpcPool::shared());
+    let response = rpc
+        .send_request(
+            "getTransaction",
+            json!([signature, { "encoding": "json", "commitment": "confirmed", "maxSupportedTransactionVersion": 0 }]),
+        )
+        .await?;
+    let result = &response["result"];
+    let meta = &result["meta"];
+    let status = if result.is_null() {
+        "unconfirmed"
+    } else if meta["err"].is_null() {
+        "success"
+    } else {
+        "failed"
+    };
+
+    let receipt = doc! {
+        "_id": signature,
+        "tx_id": tx_id,
+        "user_id": user_id,
+        "kind": kind,
+        "leg": leg as i32,
+        "slot": result["slot"].as_u64().map(|slot| slot as i64),
+        "block_time": result["blockTime"].as_i64(),
+        "compute_units_consumed": meta["computeUnitsConsumed"].as_u64().map(|units| units as i64),
+        "fee_lamports": meta["fee"].as_u64().map(|fee| fee as i64),
+        "status": status,
+        "error": (!meta["err"].is_null()).then(|| meta["err"].to_string()),
+        "recorded_at": BsonDateTime::now(),
+    };
+    let options = ReplaceOptions::builder().upsert(true).build();
+    get_solana_txs_collection()
+        .await?
+        .replace_one(doc! { "_id": signature }, receipt, options)
+        .await?;
+    println!("Recorded {} receipt {} ({})", kind, signature, status);
+    Ok(())
+}
+
+// The receipts of a deposit in the order they were recorded, each with its explorer link
+pub async fn receipts(tx_id: ObjectId) -> Result<Vec<Document>, AppError> {
+    let options = FindOptions::builder().sort(doc! { "recorded_at": 1 }).build();
+    let mut cursor = get_solana_txs_collection().await?.find(doc! { "tx_id": tx_id }, options).await?;
+    let mut receipts = Vec::new();
+    while cursor.advance().await? {
+        let mut receipt: Document = cursor.deserialize_current()?;
+        let signature = receipt.get_str("_id").unwrap_or_default().to_string();
+        receipt.remove("_id");
+        receipt.remove("tx_id");
+        receipt.insert("explorer_url", explorer_url(&signature));
+        receipt.insert("signature", signature);
+        receipts.push(receipt);
+    }
+    Ok(receipts)
+}
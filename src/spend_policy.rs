@@ -0,0 +1,292 @@
+// spend_policy.rs
+// Spending limits on the bot wallet. Before the bot wallet signs, the SOL its instructions move out of
+// it (system transfers and account creations paid from it), plus the SOL value of SPL tokens it
+// swaps away, is checked against MAX_SPEND_PER_TX_SOL and, together with what confirmed or is
+// reserved in the last hour, against MAX_SPEND_PER_HOUR_SOL. The hourly check reserves the amount
+// before it sums, so concurrent transactions can't both fit under the cap. A reservation is turned
+// into a spend once its transaction confirms and released when it fails or expires. An instruction
+// set over either cap isn't signed: it is queued in `spend_approvals` for an admin, and the operator is
+// alerted. Once approved, the same outflows (same destinations and amounts) pass the caps until a
+// transaction carrying them confirms, rebuilt and retried transactions included.
+// Transfers to COLD_WALLET_ADDRESS aren't spending and are left out.
+use crate::alerts::send_alert;
+use crate::config::config;
+use crate::dry_run;
+use crate::error_handling::AppError;
+use crate::mongo::{get_bot_wallet_spends_collection, get_spend_approvals_collection};
+use crate::units::{lamports_to_sol, sol_to_lamports};
+use mongodb::bson::{doc, oid::ObjectId, DateTime as BsonDateTime};
+use mongodb::options::UpdateOptions;
+use serde_json::json;
+use solana_sdk::instruction::Instruction;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::system_instruction::SystemInstruction;
+use solana_sdk::system_program;
+use std::str::FromStr;
+use thiserror::Error;
+
+const SPEND_WINDOW_MILLIS: i64 = 60 * 60 * 1000;
+
+// The error of an instruction set that wasn't signed because it is over the spending limits
+#[derive(Debug, Error)]
+#[error("Spending {} SOL from the bot wallet is over the limits ({reason}), waiting for approval {approval_id}", lamports_to_sol(*.lamports))]
+pub struct SpendBlocked {
+    pub approval_id: ObjectId,
+    pub lamports: u64,
+    pub reason: String,
+}
+
+// SPL tokens a transaction moves out of the bot wallet, with their SOL value. Jupiter moves a swap's
+// input tokens inside its route, so they can't be read off the instructions like SOL transfers.
+#[derive(Debug, Clone, Copy)]
+pub struct TokenOutflow {
+    pub mint: Pubkey,
+    pub amount: u64,
+    pub lamports: u64,
+}
+
+// The SOL the instructions move out of `payer`, per destination in instruction order, followed by the
+// SOL value of the tokens, keyed by mint
+pub fn outflows(instructions: &[Instruction], payer: &Pubkey, tokens: &[TokenOutflow]) -> Vec<(Pubkey, u64)> {
+    let cold_wallet = config().cold_wallet_address.as_deref().and_then(|address| Pubkey::from_str(address).ok());
+    let token_outflows = tokens.iter().map(|token| (token.mint, token.lamports));
+    instructions
+        .iter()
+        .filter(|instruction| instruction.program_id == system_program::id() && instruction.accounts.len() >= 2)
+        .filter(|instruction| instruction.accounts[0].pubkey == *payer)
+        .filter_map(|instruction| {
+            let lamports = match bincode::deserialize::<SystemInstruction>(&instruction.data).ok()? {
+                SystemInstruction::Transfer { lamports } => lamports,
+                SystemInstruction::CreateAccount { lamports, .. } => lamports,
+                _ => return None,
+            };
+            Some((instruction.accounts[1].pubkey, lamports))
+        })
+        .filter(|(destination, _)| Some(*destination) != cold_wallet)
+        .chain(token_outflows)
+        .filter(|(_, lamports)| *lamports > 0)
+        .collect()
+}
+
+// Whether MAX_SPEND_PER_TX_SOL or MAX_SPEND_PER_HOUR_SOL is set
+pub fn is_enabled() -> bool {
+    let config = config();
+    config.max_spend_per_tx_sol.is_some() || config.max_spend_per_hour_sol.is_some()
+}
+
+// Checks what the instructions and tokens spend from `payer` against the limits, queueing it for
+// approval when it is over them. A spend within the hourly cap stays reserved until record_spend or
+// release is called with the same instructions and tokens.
+pub async fn authorize(instructions: &[Instruction], payer: &Pubkey, tokens: &[TokenOutflow]) -> anyhow::Result<()> {
+    let config = config();
+    if !is_enabled() {
+        return Ok(());
+    }
+    let outflows = outflows(instructions, payer, tokens);
+    let lamports: u64 = outflows.iter().map(|(_, lamports)| lamports).sum();
+    if lamports == 0 {
+        return Ok(());
+    }
+    let fingerprint = fingerprint(&outflows, tokens);
+
+    let mut reason = None;
+    if let Some(max_per_tx) = config.max_spend_per_tx_sol {
+        if lamports > sol_to_lamports(max_per_tx) {
+            reason = Some(format!("over the {} SOL per transaction cap", max_per_tx));
+        }
+    }
+    if let (None, Some(max_per_hour)) = (&reason, config.max_spend_per_hour_sol) {
+        // Reserved first, so a transaction checking at the same time counts this one
+        let reservation = match dry_run::is_enabled() {
+            true => None,
+            false => Some(reserve(&fingerprint, lamports).await?),
+        };
+        let spent = spent_last_hour().await?;
+        let others = match reservation {
+            Some(_) => spent.saturating_sub(lamports),
+            None => spent,
+        };
+        if others.saturating_add(lamports) > sol_to_lamports(max_per_hour) {
+            if let Some(reservation) = reservation {
+                get_bot_wallet_spends_collection()
+                    .await?
+                    .delete_one(doc! { "_id": reservation }, None)
+                    .await?;
+            }
+            reason = Some(format!(
+                "{} SOL already spent or reserved in the last hour, over the {} SOL per hour cap",
+                lamports_to_sol(others),
+                max_per_hour
+            ));
+        }
+    }
+    let Some(reason) = reason else {
+        return Ok(());
+    };
+    if dry_run::is_enabled() {
+        dry_run::record_action("spend_blocked", json!({ "lamports": lamports, "reason": reason })).await;
+        return Ok(());
+    }
+
+    let approvals = get_spend_approvals_collection().await?;
+    if approvals
+        .find_one(doc! { "fingerprint": &fingerprint, "status": "approved" }, None)
+        .await?
+        .is_some()
+    {
+        println!("Approved spend of {} SOL from the bot wallet: {}", lamports_to_sol(lamports), fingerprint);
+        return Ok(());
+    }
+
+    let destinations: Vec<_> = outflows
+        .iter()
+        .map(|(destination, lamports)| doc! { "destination": destination.to_string(), "lamports": *lamports as i64 })
+        .collect();
+    let options = UpdateOptions::builder().upsert(true).build();
+    let result = approvals
+        .update_one(
+            doc! { "fingerprint": &fingerprint, "status": "pending" },
+            doc! {
+                "$setOnInsert": {
+                    "outflows": destinations,
+                    "lamports": lamports as i64,
+                    "reason": &reason,
+                    "created_at": BsonDateTime::now(),
+                },
+                "$set": { "last_blocked_at": BsonDateTime::now() },
+            },
+            options,
+        )
+        .await?;
+    let approval_id = match result.upserted_id.and_then(|id| id.as_object_id()) {
+        Some(approval_id) => {
+            send_alert(&format!(
+                "Blocked a bot wallet spend of {} SOL ({}), approve or reject it at /admin/spend_approvals/{}/approve or /reject",
+                lamports_to_sol(lamports),
+                reason,
+                approval_id
+            ))
+            .await;
+            approval_id
+        }
+        None => approvals
+            .find_one(doc! { "fingerprint": &fingerprint, "status": "pending" }, None)
+            .await?
+            .and_then(|approval| approval.get_object_id("_id").ok())
+            .ok_or_else(|| anyhow::anyhow!("Pending spend approval disappeared"))?,
+    };
+    Err(SpendBlocked { approval_id, lamports, reason }.into())
+}
+
+// Records SOL a confirmed transaction moved out of `payer` for the hourly cap in place of its
+// reservation, using up the approval it went through on
+pub async fn record_spend(signature: &str, instructions: &[Instruction], payer: &Pubkey, tokens: &[TokenOutflow]) {
+    if let Err(e) = try_record_spend(signature, instructions, payer, tokens).await {
+        eprintln!("Error recording bot wallet spend {}: {:?}", signature, e);
+    }
+}
+
+async fn try_record_spend(
+    signature: &str,
+    instructions: &[Instruction],
+    payer: &Pubkey,
+    tokens: &[TokenOutflow],
+) -> Result<(), AppError> {
+    let outflows = outflows(instructions, payer, tokens);
+    let lamports: u64 = outflows.iter().map(|(_, lamports)| lamports).sum();
+    if lamports == 0 {
+        return Ok(());
+    }
+    let fingerprint = fingerprint(&outflows, tokens);
+    let spends = get_bot_wallet_spends_collection().await?;
+    spends
+        .insert_one(
+            doc! {
+                "_id": signature,
+                "lamports": lamports as i64,
+                "fingerprint": &fingerprint,
+                "created_at": BsonDateTime::now(),
+            },
+            None,
+        )
+        .await?;
+    spends
+        .find_one_and_delete(doc! { "fingerprint": &fingerprint, "reserved": true }, None)
+        .await?;
+    get_spend_approvals_collection()
+        .await?
+        .update_one(
+            doc! { "fingerprint": &fingerprint, "status": "approved" },
+            doc! { "$set": { "status": "used", "signature": signature, "used_at": BsonDateTime::now() } },
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+// Releases the reservation of a transaction that failed or expired without spending, a rebuilt
+// transaction reserves again
+pub async fn release(instructions: &[Instruction], payer: &Pubkey, tokens: &[TokenOutflow]) {
+    let outflows = outflows(instructions, payer, tokens);
+    if outflows.is_empty() || config().max_spend_per_hour_sol.is_none() {
+        return;
+    }
+    let fingerprint = fingerprint(&outflows, tokens);
+    let result = match get_bot_wallet_spends_collection().await {
+        Ok(spends) => spends
+            .find_one_and_delete(doc! { "fingerprint": &fingerprint, "reserved": true }, None)
+            .await
+            .map_err(AppError::from),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        eprintln!("Error releasing bot wallet spend reservation {}: {:?}", fingerprint, e);
+    }
+}
+
+// Reserves `lamports` of the hourly cap. Reservations count like confirmed spends, one whose
+// transaction never reports back stops counting when it leaves the hour.
+async fn reserve(fingerprint: &str, lamports: u64) -> Result<ObjectId, AppError> {
+    let reservation = ObjectId::new();
+    get_bot_wallet_spends_collection()
+        .await?
+        .insert_one(
+            doc! {
+                "_id": reservation,
+                "lamports": lamports as i64,
+                "fingerprint": fingerprint,
+                "reserved": true,
+                "created_at": BsonDateTime::now(),
+            },
+            None,
+        )
+        .await?;
+    Ok(reservation)
+}
+
+async fn spent_last_hour() -> Result<u64, AppError> {
+    let since = BsonDateTime::from_millis(BsonDateTime::now().timestamp_millis() - SPEND_WINDOW_MILLIS);
+    let pipeline = vec![
+        doc! { "$match": { "created_at": { "$gte": since } } },
+        doc! { "$group": { "_id": null, "lamports": { "$sum": "$lamports" } } },
+    ];
+    let mut cursor = get_bot_wallet_spends_collection().await?.aggregate(pipeline, None).await?;
+    let mut spent = 0;
+    if cursor.advance().await? {
+        spent = cursor.deserialize_current()?.get_i64("lamports").unwrap_or(0).max(0) as u64;
+    }
+    Ok(spent)
+}
+
+// Identifies a set of outflows across rebuilt transactions and re-quoted swaps. Tokens are identified
+// by their amount rather than their SOL value, which moves with every quote.
+fn fingerprint(outflows: &[(Pubkey, u64)], tokens: &[TokenOutflow]) -> String {
+    let mut parts: Vec<String> = outflows
+        .iter()
+        .filter(|(destination, _)| !tokens.iter().any(|token| token.mint == *destination))
+        .map(|(destination, lamports)| format!("{}:{}", destination, lamports))
+        .chain(tokens.iter().map(|token| format!("{}:{}", token.mint, token.amount)))
+        .collect();
+    parts.sort();
+    parts.join(",")
+}
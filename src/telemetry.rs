@@ -0,0 +1,70 @@
+// telemetry.rs
+// Tracing setup. Spans and events are always logged, with secret fields redacted, and spans are also
+// exported over OTLP when OTEL_EXPORTER_OTLP_ENDPOINT is set. Every deposit the poller or a watcher
+// picks up starts its own trace, covering the Kraken orders and the Solana swap stages with their
+// latencies.
+use axum::extract::MatchedPath;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{runtime, trace, Resource};
+use tracing::Instrument;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+use crate::config::config;
+use crate::redact;
+
+// Installs the global subscriber, needs the configuration to be loaded
+pub fn init() {
+    let exporter = config().otel_exporter_otlp_endpoint.as_deref().and_then(|endpoint| {
+        let resource = Resource::new(vec![KeyValue::new("service.name", config().otel_service_name.clone())]);
+        let tracer = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_endpoint(endpoint))
+            .with_trace_config(trace::config().with_resource(resource))
+            .install_batch(runtime::Tokio);
+        match tracer {
+            Ok(tracer) => Some(tracing_opentelemetry::layer().with_tracer(tracer)),
+            Err(e) => {
+                eprintln!("Failed to start the OTLP trace exporter: {:?}", e);
+                None
+            }
+        }
+    });
+
+    tracing_subscriber::registry()
+        .with(LevelFilter::INFO)
+        .with(tracing_subscriber::fmt::layer().fmt_fields(redact::fields()))
+        .with(exporter)
+        .init();
+}
+
+// Flushes the spans that haven't been exported yet
+pub async fn shutdown() {
+    if config().otel_exporter_otlp_endpoint.is_some() {
+        let _ = tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider).await;
+    }
+}
+
+// Middleware wrapping each request in a span named after its route, with the response status
+pub async fn trace_request<B>(request: Request<B>, next: Next<B>) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| request.uri().path().to_string());
+    let span = tracing::info_span!(
+        "http_request",
+        otel.name = %format!("{} {}", request.method(), route),
+        http.method = %request.method(),
+        http.route = %route,
+        http.status_code = tracing::field::Empty,
+    );
+    let response = next.run(request).instrument(span.clone()).await;
+    span.record("http.status_code", response.status().as_u16());
+    response
+}
@@ -0,0 +1,346 @@
+// test_support.rs
+// In-memory stand-ins for the exchange, the price oracle, the Solana RPC and Jupiter, so the deposit
+// pipeline can run end to end in tests. The pipeline keeps its state in MongoDB, so the tests that run
+// it need a throwaway replica set at TEST_MONGO_URL. They are ignored by default, run them with
+// `TEST_MONGO_URL=... cargo test -- --ignored`.
+use crate::error_handling::AppError;
+use crate::exchange::{Exchange, OrderFill, WithdrawalQuote};
+use crate::lockin::{JupiterApi, SolanaRpc};
+use crate::poller::PipelineClients;
+use crate::price::PriceSource;
+use crate::signer;
+use anyhow::Result;
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD as base64_engine;
+use base64::Engine;
+use jupiter_swap_api_client::{
+    quote::{QuoteRequest, QuoteResponse},
+    swap::{SwapInstructionsResponse, SwapRequest, SwapResponse},
+};
+use kraken_rest_client::OrderSide;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde_json::{json, Value};
+use solana_sdk::{
+    account::Account,
+    hash::Hash,
+    pubkey::Pubkey,
+    signature::{Keypair, Signature, Signer},
+    transaction::{Transaction, VersionedTransaction},
+};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::sync::OnceCell;
+
+// A BIP39 test vector, user wallets derived from it are only ever used in tests
+const TEST_MNEMONIC: &str =
+    "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+static BOT_WALLET: OnceLock<Keypair> = OnceLock::new();
+static SIGNER_LOADED: OnceCell<()> = OnceCell::const_new();
+
+// The bot wallet of the test configuration
+pub fn bot_wallet() -> &'static Keypair {
+    BOT_WALLET.get_or_init(Keypair::new)
+}
+
+// Points the configuration at the test database with a generated bot wallet and loads the signer.
+// Has to run before anything reads the configuration, every test touching it calls this first.
+pub async fn init() {
+    SIGNER_LOADED
+        .get_or_init(|| async {
+            let mongo_url = std::env::var("TEST_MONGO_URL")
+                .unwrap_or_else(|_| "mongodb://localhost:27017/?replicaSet=rs0".to_string());
+            let settings = [
+                ("CONFIG_FILE", "/dev/null".to_string()),
+                ("MONGO_URL", mongo_url),
+                ("PRIVATE_KEY", bot_wallet().to_base58_string()),
+                ("SOLANA_MASTER_MNEMONIC", TEST_MNEMONIC.to_string()),
+                // The master mnemonic is only kept in plaintext on devnet
+                ("KEY_WRAPPER", "local".to_string()),
+                ("MASTER_KEY", "07".repeat(32)),
+                ("KRAKEN_API_KEY", "test".to_string()),
+                ("KRAKEN_API_SECRET", "dGVzdA==".to_string()),
+                ("KRAKEN_WITHDRAWAL_KEY", "bot-wallet".to_string()),
+                ("KRAKEN_WITHDRAWAL_ADDRESS", bot_wallet().pubkey().to_string()),
+                ("PRIORITY_FEE_MICRO_LAMPORTS", "1000".to_string()),
+                ("DRY_RUN", "false".to_string()),
+            ];
+            for (key, value) in settings {
+                std::env::set_var(key, value);
+            }
+            signer::init().await.expect("Failed to load the test signer");
+            crate::wallets::solana::init_master_mnemonic().await.expect("Failed to load the test mnemonic");
+        })
+        .await;
+}
+
+// The pipeline clients over the given mocks
+pub fn clients(exchange: Arc<MockExchange>, rpc: Arc<MockSolanaRpc>) -> PipelineClients {
+    PipelineClients {
+        exchange,
+        prices: Arc::new(FixedPrices),
+        solana_rpc: rpc,
+        jupiter: Arc::new(MockJupiter::default()),
+    }
+}
+
+// An exchange that reports every deposit it was given whatever the cursor, since tests running side by
+// side share it. Every order fills at fixed prices with a 0.2% fee and every withdrawal is taken.
+pub struct MockExchange {
+    pub deposits: Mutex<Vec<Value>>,
+    pub prices: HashMap<String, Decimal>,
+    pub withdrawal_fee: Decimal,
+    // What get_withdrawal_status reports for every withdrawal, none until the test sets it
+    pub withdrawal_status: Mutex<Option<Value>>,
+    pub orders: Mutex<Vec<(String, OrderSide, Decimal)>>,
+    pub withdrawals: Mutex<Vec<(String, String, Decimal)>>,
+}
+
+impl Default for MockExchange {
+    fn default() -> Self {
+        MockExchange {
+            deposits: Mutex::new(Vec::new()),
+            prices: HashMap::from([("BTC".to_string(), dec!(60000)), ("SOL".to_string(), dec!(150))]),
+            withdrawal_fee: dec!(0.01),
+            withdrawal_status: Mutex::new(None),
+            orders: Mutex::new(Vec::new()),
+            withdrawals: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl MockExchange {
+    // Reports a credited Lightning deposit of `amount` BTC to `address`
+    pub fn deposit(&self, refid: &str, address: &str, amount: &str, time: i64) {
+        self.deposits.lock().unwrap().push(json!({
+            "refid": refid,
+            "txid": refid,
+            "amount": amount,
+            "status": "Success",
+            "time": time,
+            "info": address,
+        }));
+    }
+
+    fn price(&self, asset: &str) -> Result<Decimal, AppError> {
+        let asset = if asset == "XBT" { "BTC" } else { asset };
+        self.prices
+            .get(asset)
+            .copied()
+            .ok_or_else(|| AppError::PriceError(format!("No mock price for {}", asset)))
+    }
+}
+
+#[async_trait]
+impl Exchange for MockExchange {
+    fn name(&self) -> &'static str {
+        "Mock"
+    }
+
+    async fn get_asset_value(&self, asset: &str) -> Result<Decimal, AppError> {
+        self.price(asset)
+    }
+
+    async fn get_deposit_status(&self, _asset: &str, _method: &str, _start: Option<i64>) -> Result<Value, AppError> {
+        Ok(Value::Array(self.deposits.lock().unwrap().clone()))
+    }
+
+    async fn get_balance(&self, _asset: &str) -> Result<Decimal, AppError> {
+        Ok(dec!(1000000))
+    }
+
+    async fn place_order_and_wait(
+        &self,
+        pair: &str,
+        side: OrderSide,
+        volume: Decimal,
+    ) -> Result<(String, OrderFill), AppError> {
+        let asset = pair.trim_end_matches("USD");
+        let price = self.price(asset)?;
+        let cost = volume * price;
+        let mut orders = self.orders.lock().unwrap();
+        orders.push((pair.to_string(), side, volume));
+        let fill = OrderFill {
+            status: "closed".to_string(),
+            volume_executed: volume,
+            cost,
+            fee: (cost * dec!(0.002)).round_dp(8),
+            average_price: price,
+        };
+        Ok((format!("ORDER-{}", orders.len()), fill))
+    }
+
+    async fn withdraw_assets(&self, asset: &str, key: &str, _address: &str, amount: Decimal) -> Result<Value, AppError> {
+        let mut withdrawals = self.withdrawals.lock().unwrap();
+        withdrawals.push((asset.to_string(), key.to_string(), amount));
+        Ok(json!({ "refid": format!("WITHDRAWAL-{}", withdrawals.len()) }))
+    }
+
+    async fn quote_withdrawal(&self, _asset: &str, _key: &str, amount: Decimal) -> Result<WithdrawalQuote, AppError> {
+        Ok(WithdrawalQuote {
+            fee: self.withdrawal_fee,
+            limit: None,
+            net_amount: amount - self.withdrawal_fee,
+        })
+    }
+
+    async fn get_withdrawal_status(&self, _asset: &str, _refid: &str) -> Result<Option<Value>, AppError> {
+        Ok(self.withdrawal_status.lock().unwrap().clone())
+    }
+}
+
+// The mock exchange's prices as the price oracle
+pub struct FixedPrices;
+
+#[async_trait]
+impl PriceSource for FixedPrices {
+    fn name(&self) -> &'static str {
+        "Fixed"
+    }
+
+    async fn usd_price(&self, asset: &str) -> Result<f64, AppError> {
+        match asset {
+            "BTC" => Ok(60000.0),
+            "SOL" => Ok(150.0),
+            _ => Err(AppError::PriceError(format!("No mock price for {}", asset))),
+        }
+    }
+}
+
+// A cluster that confirms every transaction it is sent at the next block. The block height stays put,
+// so blockhashes never expire.
+pub struct MockSolanaRpc {
+    pub balance: u64,
+    pub block_height: u64,
+    pub fee: u64,
+    // Signatures the cluster reports as confirmed, sent transactions and any the test adds
+    pub landed: Mutex<HashSet<String>>,
+    pub sent: Mutex<Vec<VersionedTransaction>>,
+}
+
+impl Default for MockSolanaRpc {
+    fn default() -> Self {
+        MockSolanaRpc {
+            balance: 100_000_000_000,
+            block_height: 1_000,
+            fee: 5_000,
+            landed: Mutex::new(HashSet::new()),
+            sent: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl MockSolanaRpc {
+    // Makes the cluster report `signature` as confirmed, for transactions sent by someone else
+    pub fn land(&self, signature: &str) {
+        self.landed.lock().unwrap().insert(signature.to_string());
+    }
+
+    fn has_landed(&self, signature: &Value) -> bool {
+        signature.as_str().is_some_and(|signature| self.landed.lock().unwrap().contains(signature))
+    }
+
+    fn send_transaction(&self, params: &Value) -> Result<Value> {
+        let encoded = params[0].as_str().ok_or_else(|| anyhow::anyhow!("sendTransaction without a transaction"))?;
+        let transaction: VersionedTransaction = bincode::deserialize(&base64_engine.decode(encoded)?)?;
+        let signature = transaction.signatures[0].to_string();
+        self.land(&signature);
+        self.sent.lock().unwrap().push(transaction);
+        Ok(json!(signature))
+    }
+}
+
+#[async_trait]
+impl SolanaRpc for MockSolanaRpc {
+    async fn send_request(&self, method: &str, params: Value) -> Result<Value> {
+        let result = match method {
+            "getBalance" => json!({ "context": { "slot": 1 }, "value": self.balance }),
+            "getLatestBlockhash" => json!({
+                "context": { "slot": 1 },
+                "value": { "blockhash": Hash::new_unique().to_string(), "lastValidBlockHeight": self.block_height + 150 },
+            }),
+            "getBlockHeight" => json!(self.block_height),
+            "getFeeForMessage" => json!({ "context": { "slot": 1 }, "value": self.fee }),
+            "getMinimumBalanceForRentExemption" => json!(2_039_280),
+            "getRecentPrioritizationFees" => json!([]),
+            "sendTransaction" => self.send_transaction(&params)?,
+            "getSignatureStatuses" => {
+                let statuses: Vec<Value> = params[0]
+                    .as_array()
+                    .map(|signatures| {
+                        signatures
+                            .iter()
+                            .map(|signature| match self.has_landed(signature) {
+                                true => json!({ "slot": 1, "confirmations": null, "err": null, "confirmationStatus": "finalized" }),
+                                false => Value::Null,
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                json!({ "context": { "slot": 1 }, "value": statuses })
+            }
+            "getTransaction" if self.has_landed(&params[0]) => json!({
+                "slot": 1,
+                "blockTime": chrono::Utc::now().timestamp(),
+                "meta": {
+                    "err": null,
+                    "fee": self.fee,
+                    "computeUnitsConsumed": 450,
+                    "preTokenBalances": [],
+                    "postTokenBalances": [],
+                },
+            }),
+            _ => Value::Null,
+        };
+        Ok(json!({ "jsonrpc": "2.0", "id": 1, "result": result }))
+    }
+
+    async fn get_account(&self, address: &Pubkey) -> Result<Account> {
+        Err(anyhow::anyhow!("Account {} not found", address))
+    }
+
+    async fn get_multiple_accounts(&self, addresses: &[Pubkey]) -> Result<Vec<Option<Account>>> {
+        Ok(vec![None; addresses.len()])
+    }
+
+    async fn send_and_confirm_transaction(&self, transaction: &Transaction) -> Result<Signature> {
+        let signature = transaction.signatures[0];
+        self.land(&signature.to_string());
+        self.sent.lock().unwrap().push(transaction.clone().into());
+        Ok(signature)
+    }
+
+    // Nothing listens there, so confirmation falls back to polling
+    fn ws_url(&self) -> String {
+        "ws://127.0.0.1:9".to_string()
+    }
+}
+
+// Jupiter without any routes, every call fails
+#[derive(Default)]
+pub struct MockJupiter {
+    pub calls: Mutex<Vec<String>>,
+}
+
+impl MockJupiter {
+    fn no_route<T>(&self, call: &str) -> Result<T> {
+        self.calls.lock().unwrap().push(call.to_string());
+        Err(anyhow::anyhow!("MockJupiter has no route for {}", call))
+    }
+}
+
+#[async_trait]
+impl JupiterApi for MockJupiter {
+    async fn quote(&self, _request: &QuoteRequest) -> Result<QuoteResponse> {
+        self.no_route("quote")
+    }
+
+    async fn swap(&self, _request: &SwapRequest) -> Result<SwapResponse> {
+        self.no_route("swap")
+    }
+
+    async fn swap_instructions(&self, _request: &SwapRequest) -> Result<SwapInstructionsResponse> {
+        self.no_route("swap_instructions")
+    }
+}
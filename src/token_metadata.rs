@@ -0,0 +1,180 @@
+// token_metadata.rs
+// Human readable info about mints: name, symbol and metadata URI from the Metaplex metadata account,
+// or the Token-2022 metadata extension for mints without one, decimals from the mint and the logo
+// from the `image` of the JSON the URI points to. Lookups are cached in the `token_metadata`
+// collection, keyed by mint, and refreshed once they are older than METADATA_TTL_SECS.
+use crate::allocation::NATIVE_SOL_MINT;
+use crate::error_handling::AppError;
+use crate::lockin::{PooledRpc, RpcPool, SolanaRpc, TOKEN_2022_PROGRAM_ID};
+use crate::mongo::get_token_metadata_collection;
+use mongodb::bson::{doc, DateTime as BsonDateTime, Document};
+use mongodb::options::ReplaceOptions;
+use reqwest::Client;
+use serde::Serialize;
+use serde_json::{json, Value};
+use solana_sdk::pubkey::Pubkey;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+
+const METADATA_PROGRAM_ID: &str = "metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bt518x1s";
+// Mints and their metadata accounts are read in one getMultipleAccounts call, capped at 100 accounts
+const BATCH_SIZE: usize = 50;
+const METADATA_TTL_SECS: i64 = 7 * 86_400;
+const LOGO_FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+// Size of a mint account and the offset of its decimals, the same for both token programs
+const MINT_LEN: usize = 82;
+const MINT_DECIMALS_OFFSET: usize = 44;
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TokenInfo {
+    pub mint: String,
+    pub symbol: Option<String>,
+    pub name: Option<String>,
+    pub decimals: Option<u8>,
+    pub logo_uri: Option<String>,
+}
+
+// The info of each mint, from the cache where it is fresh. Mints that can't be read at all are left out.
+pub async fn token_info(mints: &[String]) -> Result<HashMap<String, TokenInfo>, AppError> {
+    let collection = get_token_metadata_collection().await?;
+    let fresh_after = BsonDateTime::from_millis(BsonDateTime::now().timestamp_millis() - METADATA_TTL_SECS * 1000);
+    let mut infos = HashMap::new();
+    let mut cursor = collection
+        .find(doc! { "_id": { "$in": mints.to_vec() }, "fetched_at": { "$gte": fresh_after } }, None)
+        .await?;
+    while cursor.advance().await? {
+        let cached: Document = cursor.deserialize_current()?;
+        let info = from_cache(&cached);
+        infos.insert(info.mint.clone(), info);
+    }
+
+    let missing: Vec<String> = mints.iter().filter(|mint| !infos.contains_key(*mint)).cloned().collect();
+    if missing.is_empty() {
+        return Ok(infos);
+    }
+    for info in fetch_token_info(&missing).await? {
+        let cached = doc! {
+            "_id": &info.mint,
+            "symbol": &info.symbol,
+            "name": &info.name,
+            "decimals": info.decimals.map(|decimals| decimals as i32),
+            "logo_uri": &info.logo_uri,
+            "fetched_at": BsonDateTime::now(),
+        };
+        let options = ReplaceOptions::builder().upsert(true).build();
+        if let Err(e) = collection.replace_one(doc! { "_id": &info.mint }, cached, options).await {
+            eprintln!("Failed to cache the metadata of {}: {:?}", info.mint, e);
+        }
+        infos.insert(info.mint.clone(), info);
+    }
+    Ok(infos)
+}
+
+// The mint's symbol, or the mint itself when it has none, for messages to users
+pub async fn symbol_or_mint(mint: &str) -> String {
+    match token_info(&[mint.to_string()]).await {
+        Ok(infos) => infos.get(mint).and_then(|info| info.symbol.clone()).unwrap_or_else(|| mint.to_string()),
+        Err(e) => {
+            eprintln!("Failed to look up the metadata of {}: {:?}", mint, e);
+            mint.to_string()
+        }
+    }
+}
+
+fn from_cache(cached: &Document) -> TokenInfo {
+    TokenInfo {
+        mint: cached.get_str("_id").unwrap_or_default().to_string(),
+        symbol: cached.get_str("symbol").ok().map(str::to_string),
+        name: cached.get_str("name").ok().map(str::to_string),
+        decimals: cached.get_i32("decimals").ok().map(|decimals| decimals as u8),
+        logo_uri: cached.get_str("logo_uri").ok().map(str::to_string),
+    }
+}
+
+// Reads the mints and their metadata accounts from the chain
+async fn fetch_token_info(mints: &[String]) -> Result<Vec<TokenInfo>, AppError> {
+    let rpc = PooledRpc::new(RpcPool::shared());
+    let metadata_program = Pubkey::from_str(METADATA_PROGRAM_ID)
+        .map_err(|e| AppError::CustomError(format!("Invalid metadata program id: {}", e)))?;
+    let client = Client::new();
+
+    let mut infos = Vec::new();
+    let mints: Vec<Pubkey> = mints.iter().filter_map(|mint| Pubkey::from_str(mint).ok()).collect();
+    for chunk in mints.chunks(BATCH_SIZE) {
+        let mut addresses = chunk.to_vec();
+        addresses.extend(chunk.iter().map(|mint| {
+            Pubkey::find_program_address(&[b"metadata", metadata_program.as_ref(), mint.as_ref()], &metadata_program).0
+        }));
+        let accounts = rpc.get_multiple_accounts(&addresses).await?;
+        let (mint_accounts, metadata_accounts) = accounts.split_at(chunk.len());
+
+        for ((mint, mint_account), metadata_account) in chunk.iter().zip(mint_accounts).zip(metadata_accounts) {
+            // Native SOL stands for SOL itself, not the wrapped SOL mint
+            if mint.to_string() == NATIVE_SOL_MINT {
+                infos.push(TokenInfo {
+                    mint: mint.to_string(),
+                    symbol: Some("SOL".to_string()),
+                    name: Some("Solana".to_string()),
+                    decimals: Some(9),
+                    logo_uri: None,
+                });
+                continue;
+            }
+            let Some(mint_account) = mint_account else {
+                continue;
+            };
+            let decimals = (mint_account.data.len() >= MINT_LEN).then(|| mint_account.data[MINT_DECIMALS_OFFSET]);
+            let mut metadata = metadata_account.as_ref().and_then(|account| parse_metadata(&account.data));
+            if metadata.is_none() && mint_account.owner.to_string() == TOKEN_2022_PROGRAM_ID {
+                metadata = token_2022_metadata(&rpc, mint).await;
+            }
+            let (name, symbol, uri) = metadata.unwrap_or_default();
+            let logo_uri = if uri.is_empty() { None } else { fetch_logo_uri(&client, &uri).await };
+            infos.push(TokenInfo {
+                mint: mint.to_string(),
+                symbol: Some(symbol).filter(|symbol| !symbol.is_empty()),
+                name: Some(name).filter(|name| !name.is_empty()),
+                decimals,
+                logo_uri,
+            });
+        }
+    }
+    Ok(infos)
+}
+
+// Metadata accounts start with a key byte, the update authority and the mint, followed by the
+// Borsh encoded name, symbol and URI which are padded with NUL bytes
+fn parse_metadata(data: &[u8]) -> Option<(String, String, String)> {
+    let mut offset = 1 + 32 + 32;
+    let mut read_string = || {
+        let len = u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?) as usize;
+        offset += 4;
+        let bytes = data.get(offset..offset + len)?;
+        offset += len;
+        Some(String::from_utf8_lossy(bytes).trim_end_matches('\0').trim().to_string())
+    };
+    let name = read_string()?;
+    let symbol = read_string()?;
+    let uri = read_string()?;
+    Some((name, symbol, uri))
+}
+
+// The (name, symbol, URI) of a Token-2022 mint's metadata extension, as parsed by the RPC node
+async fn token_2022_metadata(rpc: &PooledRpc, mint: &Pubkey) -> Option<(String, String, String)> {
+    let response = rpc
+        .send_request("getAccountInfo", json!([mint.to_string(), { "encoding": "jsonParsed" }]))
+        .await
+        .ok()?;
+    let extensions = response["result"]["value"]["data"]["parsed"]["info"]["extensions"].as_array()?;
+    let state = &extensions.iter().find(|extension| extension["extension"] == "tokenMetadata")?["state"];
+    let field = |name: &str| state[name].as_str().unwrap_or_default().trim().to_string();
+    Some((field("name"), field("symbol"), field("uri")))
+}
+
+// The `image` of the off-chain metadata JSON, a missing or slow document just leaves the logo out
+async fn fetch_logo_uri(client: &Client, uri: &str) -> Option<String> {
+    let response = client.get(uri).timeout(LOGO_FETCH_TIMEOUT).send().await.ok()?;
+    let metadata: Value = response.json().await.ok()?;
+    metadata["image"].as_str().filter(|image| !image.is_empty()).map(str::to_string)
+}
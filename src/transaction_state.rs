@@ -0,0 +1,354 @@
+// transaction_state.rs
+use crate::dry_run;
+use crate::error_handling::AppError;
+use crate::mongo::AtomicWrites;
+use crate::request_id;
+use mongodb::bson::{doc, oid::ObjectId, DateTime as BsonDateTime, Document};
+use mongodb::Collection;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// Lifecycle of a deposit from Kraken through to LOCKIN landing in the user's wallet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransactionState {
+    DepositPending,
+    DepositSettled,
+    Sold,
+    SolBought,
+    Withdrawn,
+    Swapped,
+    Completed,
+    Refunded,
+    Failed,
+    NeedsAttention,
+    Deferred,
+    DepositFailed,
+    Expired,
+    UnderReview,
+}
+
+impl TransactionState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionState::DepositPending => "DepositPending",
+            TransactionState::DepositSettled => "DepositSettled",
+            TransactionState::Sold => "Sold",
+            TransactionState::SolBought => "SolBought",
+            TransactionState::Withdrawn => "Withdrawn",
+            TransactionState::Swapped => "Swapped",
+            TransactionState::Completed => "Completed",
+            TransactionState::Refunded => "Refunded",
+            TransactionState::Failed => "Failed",
+            TransactionState::NeedsAttention => "NeedsAttention",
+            TransactionState::Deferred => "Deferred",
+            TransactionState::DepositFailed => "DepositFailed",
+            TransactionState::Expired => "Expired",
+            TransactionState::UnderReview => "UnderReview",
+        }
+    }
+
+    pub fn parse(state: &str) -> Option<Self> {
+        match state {
+            "DepositPending" => Some(TransactionState::DepositPending),
+            "DepositSettled" => Some(TransactionState::DepositSettled),
+            "Sold" => Some(TransactionState::Sold),
+            "SolBought" => Some(TransactionState::SolBought),
+            "Withdrawn" => Some(TransactionState::Withdrawn),
+            "Swapped" => Some(TransactionState::Swapped),
+            "Completed" => Some(TransactionState::Completed),
+            "Refunded" => Some(TransactionState::Refunded),
+            "Failed" => Some(TransactionState::Failed),
+            "NeedsAttention" => Some(TransactionState::NeedsAttention),
+            "Deferred" => Some(TransactionState::Deferred),
+            "DepositFailed" => Some(TransactionState::DepositFailed),
+            "Expired" => Some(TransactionState::Expired),
+            "UnderReview" => Some(TransactionState::UnderReview),
+            _ => None,
+        }
+    }
+
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            TransactionState::Completed | TransactionState::Refunded | TransactionState::DepositFailed
+        )
+    }
+
+    // The allowed edges of the state machine. A settled deposit can complete straight away when it is
+    // held on Kraken for autobuy or DCA, or skip the exchange when it was sent to the user's Solana
+    // address, and a withdrawal outside the bot wallet completes without a swap. A settled deposit
+    // for a user whose Solana address is invalid is held in NeedsAttention until the address is fixed,
+    // and one that can't be processed while an upstream is down is Deferred until it recovers. One
+    // over the deposit limits waits UnderReview until an admin approves it. A deposit the exchange
+    // reports as failed ends in DepositFailed, and one left pending for too long is Expired, to go
+    // back to pending if the exchange credits it after all. A failed transaction can be retried from
+    // a settled deposit or re-driven from its withdrawn funds.
+    pub fn can_transition_to(&self, next: TransactionState) -> bool {
+        use TransactionState::*;
+        match (self, next) {
+            (DepositPending, DepositSettled) => true,
+            (DepositPending, DepositFailed) | (DepositPending, Expired) => true,
+            (Expired, DepositPending) | (Expired, DepositFailed) => true,
+            (DepositSettled, Sold) | (DepositSettled, Completed) | (DepositSettled, Withdrawn) => true,
+            (DepositSettled, NeedsAttention) | (NeedsAttention, DepositSettled) => true,
+            (DepositSettled, Deferred) | (Deferred, DepositSettled) => true,
+            (DepositSettled, UnderReview) | (UnderReview, DepositSettled) => true,
+            (Sold, SolBought) => true,
+            (SolBought, Withdrawn) => true,
+            (Withdrawn, Swapped) | (Withdrawn, Refunded) | (Withdrawn, Completed) => true,
+            (Swapped, Completed) => true,
+            (Failed, DepositSettled) | (Failed, Withdrawn) => true,
+            (current, Failed) => !current.is_terminal() && *current != Failed,
+            _ => false,
+        }
+    }
+
+    // Reads the state of a transaction document. Documents written before the state machine only
+    // carry the `processed` flag, which maps to pending or completed.
+    pub fn of_document(tx: &Document) -> TransactionState {
+        match tx.get_str("state").ok().and_then(TransactionState::parse) {
+            Some(state) => state,
+            None if tx.get_bool("processed").unwrap_or(false) => TransactionState::Completed,
+            None => TransactionState::DepositPending,
+        }
+    }
+}
+
+impl fmt::Display for TransactionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Fields for a newly inserted transaction document starting out in `state`
+pub fn initial_state_fields(state: TransactionState) -> Document {
+    let now = BsonDateTime::now();
+    doc! {
+        "state": state.as_str(),
+        "state_updated_at": now,
+        "state_history": [ { "state": state.as_str(), "at": now } ],
+    }
+}
+
+// Moves a transaction document through its lifecycle. Every transition is a compare-and-set on the
+// current state, so two workers can never advance the same transaction twice.
+pub struct TransactionStateMachine {
+    collection: Collection<Document>,
+    tx_id: ObjectId,
+}
+
+impl TransactionStateMachine {
+    pub fn new(collection: Collection<Document>, tx_id: ObjectId) -> Self {
+        TransactionStateMachine { collection, tx_id }
+    }
+
+    pub fn tx_id(&self) -> ObjectId {
+        self.tx_id
+    }
+
+    pub async fn current(&self) -> Result<TransactionState, AppError> {
+        let tx = self
+            .collection
+            .find_one(doc! { "_id": self.tx_id }, None)
+            .await?
+            .ok_or(AppError::NotFound)?;
+        Ok(TransactionState::of_document(&tx))
+    }
+
+    // Attempts `from` -> `to`, returning false when the transaction is no longer in `from`
+    pub async fn try_transition(
+        &self,
+        from: TransactionState,
+        to: TransactionState,
+        extra: Document,
+    ) -> Result<bool, AppError> {
+        self.try_transition_in(&mut AtomicWrites::none(), from, to, extra).await
+    }
+
+    // Like `try_transition`, as part of `writes`
+    pub async fn try_transition_in(
+        &self,
+        writes: &mut AtomicWrites,
+        from: TransactionState,
+        to: TransactionState,
+        extra: Document,
+    ) -> Result<bool, AppError> {
+        if !from.can_transition_to(to) {
+            return Err(AppError::CustomError(format!(
+                "Invalid transaction state transition: {} -> {}",
+                from, to
+            )));
+        }
+
+        // Transaction documents are left untouched in dry-run mode
+        if dry_run::is_enabled() {
+            println!("Dry run: transaction {} {} -> {}", self.tx_id, from, to);
+            return Ok(true);
+        }
+
+        let now = BsonDateTime::now();
+        let mut set = extra;
+        set.insert("state", to.as_str());
+        set.insert("state_updated_at", now);
+        let update = doc! {
+            "$set": set,
+            "$push": { "state_history": { "state": to.as_str(), "at": now } },
+        };
+
+        let mut filter = state_filter(from);
+        filter.insert("_id", self.tx_id);
+        let result = writes.update_one(&self.collection, filter, update).await?;
+        if result.modified_count == 1 {
+            println!("Transaction {} {} -> {}", self.tx_id, from, to);
+        }
+        Ok(result.modified_count == 1)
+    }
+
+    // Like `try_transition`, but losing the race is an error
+    pub async fn transition(&self, from: TransactionState, to: TransactionState) -> Result<(), AppError> {
+        if self.try_transition(from, to, Document::new()).await? {
+            Ok(())
+        } else {
+            Err(AppError::CustomError(format!(
+                "Transaction {} is no longer in state {}",
+                self.tx_id, from
+            )))
+        }
+    }
+
+    // Moves the transaction to Failed from whichever non-terminal state it is in, keeping the error
+    pub async fn fail(&self, error: &str) -> Result<(), AppError> {
+        let current = self.current().await?;
+        if !current.can_transition_to(TransactionState::Failed) {
+            return Ok(());
+        }
+        self.try_transition(
+            current,
+            TransactionState::Failed,
+            doc! {
+                "processing_error": error,
+                "failed_in_state": current.as_str(),
+                "failed_request_id": request_id::current(),
+            },
+        )
+        .await?;
+        Ok(())
+    }
+
+    // Parks a settled deposit that couldn't be processed because an upstream is down, to be resumed
+    // with `amount` once it recovers. Returns false when the deposit has moved on, and has to be
+    // failed instead.
+    pub async fn defer(&self, reason: &str, amount: f64) -> Result<bool, AppError> {
+        self.try_transition(
+            TransactionState::DepositSettled,
+            TransactionState::Deferred,
+            doc! { "deferred_reason": reason, "deferred_amount": amount },
+        )
+        .await
+    }
+}
+
+// Matches documents in `state`, including pending documents written before the state machine
+fn state_filter(state: TransactionState) -> Document {
+    if state == TransactionState::DepositPending {
+        doc! { "$or": [
+            { "state": state.as_str() },
+            { "state": { "$exists": false }, "processed": { "$ne": true } },
+        ] }
+    } else {
+        doc! { "state": state.as_str() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use TransactionState::*;
+
+    const ALL: [TransactionState; 14] = [
+        DepositPending,
+        DepositSettled,
+        Sold,
+        SolBought,
+        Withdrawn,
+        Swapped,
+        Completed,
+        Refunded,
+        Failed,
+        NeedsAttention,
+        Deferred,
+        DepositFailed,
+        Expired,
+        UnderReview,
+    ];
+
+    #[test]
+    fn states_round_trip_through_their_names() {
+        for state in ALL {
+            assert_eq!(TransactionState::parse(state.as_str()), Some(state));
+        }
+        assert_eq!(TransactionState::parse("Processing"), None);
+    }
+
+    #[test]
+    fn the_happy_paths_are_allowed() {
+        let paths: [&[TransactionState]; 4] = [
+            &[DepositPending, DepositSettled, Sold, SolBought, Withdrawn, Swapped, Completed],
+            &[DepositPending, DepositSettled, Withdrawn, Refunded],
+            &[DepositPending, DepositSettled, Completed],
+            &[DepositPending, Expired, DepositPending, DepositSettled, UnderReview, DepositSettled, Completed],
+        ];
+        for path in paths {
+            for edge in path.windows(2) {
+                assert!(edge[0].can_transition_to(edge[1]), "{} -> {}", edge[0], edge[1]);
+            }
+        }
+    }
+
+    #[test]
+    fn terminal_states_have_no_way_out() {
+        for from in ALL.into_iter().filter(TransactionState::is_terminal) {
+            for to in ALL {
+                assert!(!from.can_transition_to(to), "{} -> {}", from, to);
+            }
+        }
+    }
+
+    #[test]
+    fn every_live_state_can_fail_once() {
+        for state in ALL.into_iter().filter(|state| !state.is_terminal() && *state != Failed) {
+            assert!(state.can_transition_to(Failed), "{} -> Failed", state);
+        }
+        assert!(!Failed.can_transition_to(Failed));
+        assert!(Failed.can_transition_to(DepositSettled));
+        assert!(Failed.can_transition_to(Withdrawn));
+        assert!(!Failed.can_transition_to(Completed));
+    }
+
+    #[test]
+    fn steps_cannot_be_skipped_or_undone() {
+        let forbidden = [
+            (DepositPending, Sold),
+            (DepositPending, Completed),
+            (Sold, Withdrawn),
+            (SolBought, Sold),
+            (Swapped, Withdrawn),
+            (Withdrawn, DepositSettled),
+            (Deferred, Completed),
+            (UnderReview, Completed),
+            (NeedsAttention, Withdrawn),
+            (Expired, DepositSettled),
+        ];
+        for (from, to) in forbidden {
+            assert!(!from.can_transition_to(to), "{} -> {}", from, to);
+        }
+    }
+
+    #[test]
+    fn legacy_documents_map_from_the_processed_flag() {
+        assert_eq!(TransactionState::of_document(&doc! { "processed": true }), Completed);
+        assert_eq!(TransactionState::of_document(&doc! { "processed": false }), DepositPending);
+        assert_eq!(TransactionState::of_document(&doc! {}), DepositPending);
+        assert_eq!(TransactionState::of_document(&doc! { "state": "Swapped", "processed": false }), Swapped);
+    }
+}
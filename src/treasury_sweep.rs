@@ -0,0 +1,140 @@
+// treasury_sweep.rs
+// Hot/cold treasury sweeps. Every TREASURY_SWEEP_INTERVAL_SECS the bot wallet's SOL above
+// HOT_WALLET_CEILING_SOL is moved to COLD_WALLET_ADDRESS, so a leaked hot key exposes at most the
+// ceiling. SOL still owed to swaps that failed or were deferred and haven't been re-driven, to swaps
+// still running and to refunds not yet sent is kept in the hot wallet on top of the ceiling. Each
+// sweep is recorded in `treasury_sweeps` and the ledger, and the operator is told about it.
+use crate::alerts::{notify_operator, send_alert};
+use crate::allocation::NATIVE_SOL_MINT;
+use crate::config::config;
+use crate::dry_run;
+use crate::error_handling::AppError;
+use crate::ledger::{Account, Journal};
+use crate::lockin::{bot_wallet_address, LockinClient, PooledRpc, RpcPool, SolanaRpc};
+use crate::maintenance;
+use crate::mongo::{get_failed_swaps_collection, get_transactions_collection, get_treasury_sweeps_collection};
+use crate::refunds::owed_to_pending_refunds;
+use crate::transaction_state::TransactionState;
+use crate::solana_txs::explorer_url;
+use crate::units::{lamports_to_sol, sol_to_lamports};
+use mongodb::bson::{doc, DateTime as BsonDateTime};
+use rust_decimal::Decimal;
+use serde_json::json;
+use solana_sdk::pubkey::Pubkey;
+use std::str::FromStr;
+use tokio::time::{interval, Duration};
+
+// Base fee of the sweep transaction, charged to the bot wallet
+const SWEEP_FEE_LAMPORTS: u64 = 5_000;
+
+pub async fn start_treasury_sweep_scheduler() -> Result<(), AppError> {
+    let mut interval = interval(Duration::from_secs(config().treasury_sweep_interval_secs));
+    loop {
+        interval.tick().await;
+        if maintenance::is_enabled() {
+            continue;
+        }
+        if let Err(e) = sweep_to_cold_wallet().await {
+            eprintln!("Treasury sweep failed: {:?}", e);
+            send_alert(&format!("Treasury sweep to cold storage failed: {}", e)).await;
+        }
+    }
+}
+
+async fn sweep_to_cold_wallet() -> Result<(), AppError> {
+    let config = config();
+    let Some(cold_wallet_address) = config.cold_wallet_address.as_deref() else {
+        return Ok(());
+    };
+    let cold_wallet = Pubkey::from_str(cold_wallet_address)
+        .map_err(|_| AppError::CustomError("Invalid COLD_WALLET_ADDRESS".to_string()))?;
+
+    let bot_wallet = bot_wallet_address()?;
+    let response = PooledRpc::new(RpcPool::shared())
+        .send_request("getBalance", json!([bot_wallet.to_string(), { "commitment": "confirmed" }]))
+        .await?;
+    let balance = response["result"]["value"]
+        .as_u64()
+        .ok_or_else(|| AppError::CustomError(format!("getBalance returned no balance: {}", response)))?;
+    let reserved = owed_to_pending_swaps().await? + owed_to_running_swaps().await? + owed_to_pending_refunds().await?;
+    let keep = sol_to_lamports(config.hot_wallet_ceiling_sol) + reserved;
+    let lamports = balance.saturating_sub(keep).saturating_sub(SWEEP_FEE_LAMPORTS);
+    if lamports == 0 {
+        return Ok(());
+    }
+
+    println!(
+        "Sweeping {} SOL from bot wallet {} to cold storage {} ({} SOL held, {} SOL kept for pending swaps and refunds)",
+        lamports_to_sol(lamports),
+        bot_wallet,
+        cold_wallet,
+        lamports_to_sol(balance),
+        lamports_to_sol(reserved)
+    );
+    if dry_run::is_enabled() {
+        let details = json!({ "destination": cold_wallet.to_string(), "lamports": lamports, "balance": balance });
+        dry_run::record_action("treasury_sweep", details).await;
+        return Ok(());
+    }
+    let lockin_client = LockinClient::new().await?;
+    let signature = lockin_client.send_to_cold_wallet(cold_wallet, lamports).await?;
+
+    get_treasury_sweeps_collection()
+        .await?
+        .insert_one(
+            doc! {
+                "_id": &signature,
+                "source": bot_wallet.to_string(),
+                "destination": cold_wallet.to_string(),
+                "lamports": lamports as i64,
+                "balance_before": balance as i64,
+                "reserved_lamports": reserved as i64,
+                "created_at": BsonDateTime::now(),
+            },
+            None,
+        )
+        .await?;
+    Journal::new(&signature, "treasury_sweep", 0)
+        .transfer(Account::BotWallet, Account::ColdWallet, "SOL", Decimal::new(lamports as i64, 9))
+        .transfer(Account::BotWallet, Account::Fees, "SOL", Decimal::new(SWEEP_FEE_LAMPORTS as i64, 9))
+        .record()
+        .await;
+    notify_operator(&format!(
+        "Swept {} SOL from the bot wallet to cold storage {}: {}",
+        lamports_to_sol(lamports),
+        cold_wallet,
+        explorer_url(&signature)
+    ))
+    .await;
+    Ok(())
+}
+
+// Lamports the bot wallet still holds for SOL swaps that failed or were deferred and haven't been
+// re-driven
+async fn owed_to_pending_swaps() -> Result<u64, AppError> {
+    let pipeline = vec![
+        doc! { "$match": { "redriven": false, "input_mint": NATIVE_SOL_MINT } },
+        doc! { "$group": { "_id": null, "amount": { "$sum": "$amount" } } },
+    ];
+    let mut cursor = get_failed_swaps_collection().await?.aggregate(pipeline, None).await?;
+    let mut owed = 0.0;
+    if cursor.advance().await? {
+        owed = cursor.deserialize_current()?.get_f64("amount").unwrap_or(0.0);
+    }
+    Ok(sol_to_lamports(owed))
+}
+
+// Lamports withdrawn for swaps that haven't settled yet. SOL still on its way from the exchange is
+// counted too, so it isn't swept between arriving and the swap being scheduled.
+async fn owed_to_running_swaps() -> Result<u64, AppError> {
+    let pipeline = vec![
+        doc! { "$match": { "state": TransactionState::Withdrawn.as_str() } },
+        doc! { "$group": { "_id": null, "amount": { "$sum": "$withdrawal.amount_received" } } },
+    ];
+    let mut cursor = get_transactions_collection().await?.aggregate(pipeline, None).await?;
+    let mut owed = 0.0;
+    if cursor.advance().await? {
+        owed = cursor.deserialize_current()?.get_f64("amount").unwrap_or(0.0);
+    }
+    Ok(sol_to_lamports(owed))
+}
@@ -0,0 +1,105 @@
+// units.rs
+// Conversions between the decimal amounts exchanges and users deal in and the integer base units
+// (lamports, satoshis) amounts are computed in. Decimal amounts are converted once at the edge,
+// rounding to the nearest base unit, so sums and differences don't drift.
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+
+pub const LAMPORTS_PER_SOL: u64 = 1_000_000_000;
+pub const SATS_PER_BTC: u64 = 100_000_000;
+
+// Rounds a decimal amount to base units, negative and non-finite amounts are zero and amounts past
+// u64::MAX saturate
+fn to_base_units(amount: f64, units_per_whole: u64) -> u64 {
+    if !amount.is_finite() || amount <= 0.0 {
+        return 0;
+    }
+    (amount * units_per_whole as f64).round() as u64
+}
+
+pub fn sol_to_lamports(sol: f64) -> u64 {
+    to_base_units(sol, LAMPORTS_PER_SOL)
+}
+
+// Exact for exchange amounts, which are decimals already
+pub fn sol_decimal_to_lamports(sol: Decimal) -> u64 {
+    (sol * Decimal::from(LAMPORTS_PER_SOL)).round().to_u64().unwrap_or(0)
+}
+
+pub fn lamports_to_sol(lamports: u64) -> f64 {
+    lamports as f64 / LAMPORTS_PER_SOL as f64
+}
+
+pub fn sats_to_btc(sats: u64) -> f64 {
+    sats as f64 / SATS_PER_BTC as f64
+}
+
+pub fn btc_to_sats(btc: f64) -> u64 {
+    to_base_units(btc, SATS_PER_BTC)
+}
+
+// Exact for exchange amounts, which are decimals already
+pub fn btc_decimal_to_sats(btc: Decimal) -> u64 {
+    (btc * Decimal::from(SATS_PER_BTC)).round().to_u64().unwrap_or(0)
+}
+
+pub fn sats_to_btc_decimal(sats: u64) -> Decimal {
+    Decimal::new(sats.min(i64::MAX as u64) as i64, 8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn one_satoshi_survives_both_ways() {
+        assert_eq!(btc_to_sats(0.00000001), 1);
+        assert_eq!(btc_decimal_to_sats(Decimal::from_str("0.00000001").unwrap()), 1);
+        assert_eq!(sats_to_btc_decimal(1), Decimal::from_str("0.00000001").unwrap());
+        assert_eq!(sats_to_btc(1), 0.00000001);
+    }
+
+    #[test]
+    fn rounds_to_the_nearest_satoshi() {
+        assert_eq!(btc_to_sats(0.000000014), 1);
+        assert_eq!(btc_to_sats(0.000000016), 2);
+        assert_eq!(btc_decimal_to_sats(Decimal::from_str("0.123456789").unwrap()), 12_345_679);
+        assert_eq!(btc_decimal_to_sats(Decimal::from_str("0.123456781").unwrap()), 12_345_678);
+    }
+
+    #[test]
+    fn summed_deposits_do_not_drift() {
+        // 0.1 + 0.2 is 0.30000000000000004 as doubles
+        let sats = btc_to_sats(0.1) + btc_to_sats(0.2);
+        assert_eq!(sats, 30_000_000);
+        assert_eq!(sats_to_btc_decimal(sats), Decimal::from_str("0.3").unwrap());
+
+        let total: u64 = (0..1000).map(|_| btc_to_sats(0.001)).sum();
+        assert_eq!(total, SATS_PER_BTC);
+    }
+
+    #[test]
+    fn the_whole_supply_fits() {
+        let supply = 21_000_000 * SATS_PER_BTC;
+        assert_eq!(btc_to_sats(21_000_000.0), supply);
+        assert_eq!(btc_decimal_to_sats(Decimal::from(21_000_000)), supply);
+        assert_eq!(btc_decimal_to_sats(sats_to_btc_decimal(supply)), supply);
+    }
+
+    #[test]
+    fn invalid_amounts_are_zero() {
+        assert_eq!(btc_to_sats(0.0), 0);
+        assert_eq!(btc_to_sats(-0.5), 0);
+        assert_eq!(btc_to_sats(f64::NAN), 0);
+        assert_eq!(btc_to_sats(f64::INFINITY), 0);
+        assert_eq!(btc_decimal_to_sats(Decimal::from_str("-0.5").unwrap()), 0);
+    }
+
+    #[test]
+    fn lamports_round_trip() {
+        assert_eq!(sol_to_lamports(0.000000001), 1);
+        assert_eq!(sol_decimal_to_lamports(Decimal::from_str("3.992").unwrap()), 3_992_000_000);
+        assert_eq!(lamports_to_sol(sol_to_lamports(1.5)), 1.5);
+    }
+}
@@ -0,0 +1,61 @@
+// validation.rs
+// Checks addresses before anything is sent to them. A mistyped address would otherwise lose the
+// funds for good, so every withdrawal and refund recipient goes through here first.
+use bdk::bitcoin::{Address, Network};
+use solana_sdk::pubkey::Pubkey;
+use std::str::FromStr;
+use tiny_keccak::keccak256;
+
+use crate::error_handling::AppError;
+
+// Parses a base58 Solana public key. The all-zero key (the system program) is rejected, anything
+// sent there is burnt.
+pub fn solana_address(address: &str) -> Result<Pubkey, AppError> {
+    let pubkey = Pubkey::from_str(address.trim())
+        .map_err(|_| AppError::InvalidAddress(format!("{:?} is not a base58 Solana public key", address)))?;
+    if pubkey == Pubkey::default() {
+        return Err(AppError::InvalidAddress("the system program can't receive funds".to_string()));
+    }
+    Ok(pubkey)
+}
+
+// Parses a Bitcoin address and checks it belongs to `network`
+pub fn bitcoin_address(address: &str, network: Network) -> Result<Address, AppError> {
+    let parsed = Address::from_str(address.trim())
+        .map_err(|e| AppError::InvalidAddress(format!("{:?} is not a Bitcoin address: {}", address, e)))?;
+    if !parsed.is_valid_for_network(network) {
+        return Err(AppError::InvalidAddress(format!("{} is not a {} address", parsed, network)));
+    }
+    Ok(parsed)
+}
+
+// Parses an Ethereum address and returns it in its EIP-55 checksummed form. All lowercase or all
+// uppercase addresses carry no checksum, mixed case ones must match it.
+pub fn ethereum_address(address: &str) -> Result<String, AppError> {
+    let address = address.trim();
+    let hex = address
+        .strip_prefix("0x")
+        .filter(|hex| hex.len() == 40 && hex.chars().all(|c| c.is_ascii_hexdigit()))
+        .ok_or_else(|| AppError::InvalidAddress(format!("{:?} is not a 0x prefixed 20 byte address", address)))?;
+    let checksummed = checksum_address(hex);
+    let has_checksum = hex.chars().any(|c| c.is_ascii_lowercase()) && hex.chars().any(|c| c.is_ascii_uppercase());
+    if has_checksum && checksummed[2..] != *hex {
+        return Err(AppError::InvalidAddress(format!("{} fails its EIP-55 checksum", address)));
+    }
+    Ok(checksummed)
+}
+
+// EIP-55: each letter is uppercased when the matching nibble of keccak256(lowercase hex) is 8 or more
+pub fn checksum_address(hex: &str) -> String {
+    let lower = hex.trim_start_matches("0x").to_lowercase();
+    let hash = keccak256(lower.as_bytes());
+    let checksummed: String = lower
+        .chars()
+        .enumerate()
+        .map(|(i, c)| {
+            let nibble = if i % 2 == 0 { hash[i / 2] >> 4 } else { hash[i / 2] & 0x0f };
+            if nibble >= 8 { c.to_ascii_uppercase() } else { c }
+        })
+        .collect();
+    format!("0x{}", checksummed)
+}
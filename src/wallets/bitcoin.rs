@@ -1,11 +1,20 @@
 // bitcoin.rs
-use bdk::bitcoin::Network;
+use bdk::bitcoin::secp256k1::{Message, Secp256k1};
+use bdk::bitcoin::util::bip32::{DerivationPath, ExtendedPrivKey};
+use bdk::bitcoin::util::misc::signed_msg_hash;
+use bdk::bitcoin::{Address, Network, PublicKey};
+use base64::engine::general_purpose::STANDARD as base64_engine;
+use base64::Engine;
+use std::str::FromStr;
 use bdk::database::MemoryDatabase;
-use bdk::keys::{DerivableKey, GeneratableKey, GeneratedKey, ExtendedKey, bip39::{Mnemonic, WordCount, Language}};
+use bdk::keys::{DerivableKey, ExtendedKey};
 use bdk::template::Bip84;
-use bdk::{miniscript, Wallet, KeychainKind};
+use bdk::wallet::AddressIndex;
+use bdk::{Wallet, KeychainKind};
 use serde::Serialize;
+use zeroize::Zeroize;
 
+use crate::entropy;
 use crate::error_handling::AppError;
 
 #[derive(Serialize)]
@@ -13,36 +22,112 @@ pub struct WalletResponse {
     pub mnemonic: String,
     pub public_key: String,
     pub private_key: String,
+    pub address: String,
 }
 
-pub(crate) async fn generate_bitcoin_wallet() -> Result<WalletResponse, AppError> {
-    let network = Network::Testnet; // Or this can be Network::Bitcoin, Network::Signet or Network::Regtest
+impl Drop for WalletResponse {
+    fn drop(&mut self) {
+        self.mnemonic.zeroize();
+        self.private_key.zeroize();
+    }
+}
 
+// Generates a BIP84 wallet on `network`, normally the configured BITCOIN_NETWORK
+pub(crate) async fn generate_bitcoin_wallet(network: Network) -> Result<WalletResponse, AppError> {
     // Generate fresh mnemonic
-    let mnemonic: GeneratedKey<_, miniscript::Segwitv0> = Mnemonic::generate((WordCount::Words12, Language::English)).unwrap();
+    let mnemonic = entropy::mnemonic()?;
     // Convert mnemonic to string
     let mnemonic_words = mnemonic.to_string();
-    // Parse a mnemonic
-    let mnemonic  = Mnemonic::parse(&mnemonic_words).unwrap();
     // Generate the extended key
-    let xkey: ExtendedKey = mnemonic.into_extended_key().unwrap();
+    let xkey: ExtendedKey = mnemonic.into_extended_key().map_err(|e| wallet_error(&e.to_string()))?;
     // Get xprv from the extended key
-    let xprv = xkey.into_xprv(network).unwrap();
+    let xprv = xkey
+        .into_xprv(network)
+        .ok_or_else(|| wallet_error("Failed to derive the extended private key"))?;
 
-    // Create a BDK wallet structure using BIP 84 descriptor ("m/84h/1h/0h/0" and "m/84h/1h/0h/1")
+    // Create a BDK wallet structure using BIP 84 descriptors, the coin type follows the network
+    // ("m/84h/0h/0h" on mainnet, "m/84h/1h/0h" on the test networks)
     let wallet = Wallet::new(
         Bip84(xprv, KeychainKind::External),
         Some(Bip84(xprv, KeychainKind::Internal)),
         network,
         MemoryDatabase::default(),
-    ).unwrap();
+    )?;
 
     let public_key = wallet.get_descriptor_for_keychain(KeychainKind::External).to_string();
     let private_key = xprv.to_string(); // Extract the private key
+    let address = wallet.get_address(AddressIndex::Peek(0))?.address.to_string();
 
     Ok(WalletResponse {
         mnemonic: mnemonic_words,
         public_key,
         private_key,
+        address,
+    })
+}
+
+// Watch-only view of a user's wallet, importable into Sparrow or Electrum
+#[derive(Serialize)]
+pub struct WatchOnlyWallet {
+    pub receive_descriptor: String,
+    pub change_descriptor: String,
+    pub xpub: Option<String>,
+    pub first_address: String,
+}
+
+// Builds the receive and change descriptors from the stored receive descriptor, which holds the
+// account xpub with its key origin ("wpkh([fingerprint/84'/0'/0']xpub.../0/*)#checksum")
+pub(crate) fn watch_only_wallet(descriptor: &str, network: Network) -> Result<WatchOnlyWallet, AppError> {
+    let receive = descriptor.split('#').next().unwrap_or_default();
+    let change = receive.replacen("/0/*)", "/1/*)", 1);
+    if change == receive {
+        return Err(wallet_error("Descriptor has no receive path"));
+    }
+
+    // Parsing both through BDK checks them and gives them fresh checksums
+    let wallet = Wallet::new(receive, Some(change.as_str()), network, MemoryDatabase::default())?;
+    let xpub = receive
+        .split(']')
+        .nth(1)
+        .and_then(|key| key.split('/').next())
+        .map(|key| key.to_string());
+    Ok(WatchOnlyWallet {
+        receive_descriptor: wallet.get_descriptor_for_keychain(KeychainKind::External).to_string(),
+        change_descriptor: wallet.get_descriptor_for_keychain(KeychainKind::Internal).to_string(),
+        xpub,
+        first_address: wallet.get_address(AddressIndex::Peek(0))?.address.to_string(),
     })
-}
\ No newline at end of file
+}
+
+// Derives the receive address at `index` of a user's stored receive descriptor
+pub(crate) fn receive_address(descriptor: &str, network: Network, index: u32) -> Result<String, AppError> {
+    let wallet = Wallet::new(descriptor, None, network, MemoryDatabase::default())?;
+    Ok(wallet.get_address(AddressIndex::Peek(index))?.address.to_string())
+}
+
+// Signs a message with the key of receive address `index` (m/84'/coin'/0'/0/index) in the BIP-137
+// format Electrum and Sparrow verify. Returns the address and the base64 signature.
+pub(crate) fn sign_message(xprv: &str, network: Network, index: u32, message: &str) -> Result<(String, String), AppError> {
+    let secp = Secp256k1::new();
+    let coin_type = if network == Network::Bitcoin { 0 } else { 1 };
+    let path = DerivationPath::from_str(&format!("m/84'/{}'/0'/0/{}", coin_type, index))
+        .map_err(|e| wallet_error(&e.to_string()))?;
+    let secret_key = ExtendedPrivKey::from_str(xprv)
+        .map_err(|e| wallet_error(&e.to_string()))?
+        .derive_priv(&secp, &path)
+        .map_err(|e| wallet_error(&e.to_string()))?
+        .private_key;
+    let public_key = PublicKey::new(secret_key.public_key(&secp));
+    let address = Address::p2wpkh(&public_key, network).map_err(|e| wallet_error(&e.to_string()))?;
+
+    let digest = Message::from_slice(&signed_msg_hash(message)[..]).map_err(|e| wallet_error(&e.to_string()))?;
+    let (recovery_id, signature) = secp.sign_ecdsa_recoverable(&digest, &secret_key).serialize_compact();
+    // BIP-137 header for a native segwit (P2WPKH) address
+    let mut bytes = vec![39 + recovery_id.to_i32() as u8];
+    bytes.extend_from_slice(&signature);
+    Ok((address.to_string(), base64_engine.encode(bytes)))
+}
+
+fn wallet_error(message: &str) -> AppError {
+    AppError::CustomError(format!("Bitcoin wallet error: {}", message))
+}
@@ -1,10 +1,38 @@
 // ethereum.rs
-use std::time::{SystemTime, UNIX_EPOCH};
-use rand::{rngs::StdRng, SeedableRng};
-use secp256k1::{Secp256k1, PublicKey, SecretKey};
+use std::str::FromStr;
+use bdk::bitcoin::util::bip32::{DerivationPath, ExtendedPrivKey};
+use bdk::keys::bip39::Mnemonic;
+use ctr::cipher::{KeyIvInit, StreamCipher};
+use secp256k1::{Message, Secp256k1, PublicKey, SecretKey};
 use serde::{Serialize, Deserialize};
+use serde_json::{json, Value};
 use tiny_keccak::keccak256;
+use zeroize::Zeroizing;
 use hex;
+use crate::config::{config, Network};
+use crate::entropy;
+use crate::error_handling::AppError;
+
+// BIP44 path of the first account's first address, the one wallets like MetaMask import
+pub const DERIVATION_PATH: &str = "m/44'/60'/0'/0/0";
+
+// An ERC-20 stablecoin accepted as a deposit
+#[derive(Debug)]
+pub struct Erc20Token {
+    pub symbol: &'static str,
+    pub contract: &'static str,
+    pub decimals: u32,
+}
+
+const MAINNET_TOKENS: &[Erc20Token] = &[
+    Erc20Token { symbol: "USDC", contract: "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48", decimals: 6 },
+    Erc20Token { symbol: "USDT", contract: "0xdac17f958d2ee523a2206206994597c13d831ec7", decimals: 6 },
+];
+
+// Circle's USDC on Sepolia, there is no official USDT deployment
+const SEPOLIA_TOKENS: &[Erc20Token] = &[
+    Erc20Token { symbol: "USDC", contract: "0x1c7d4b196cb0c7b01d743fbc6116a902379c7238", decimals: 6 },
+];
 
 // Define the structure for an Ethereum wallet
 #[derive(Serialize, Deserialize, Debug)]
@@ -12,15 +40,99 @@ pub struct EthereumWallet {
     pub secret_key: SecretKey,
     pub public_key: PublicKey,
     pub public_address: String,
+    pub derivation_path: String,
 }
 
-// Function to generate a key pair (secret key, public key) and the corresponding public address
-pub fn generate_keypair() -> (SecretKey, PublicKey, String) {
+// Function to derive the key pair (secret key, public key) and public address at DERIVATION_PATH of a
+// BIP39 mnemonic, so the key can be recovered from the same backup as the Bitcoin wallet
+pub fn derive_keypair(mnemonic: &str) -> Result<(SecretKey, PublicKey, String), AppError> {
     let secp = Secp256k1::new(); // Create a new secp256k1 context
-    let mut rng = StdRng::seed_from_u64(get_nstime()); // Initialize a random number generator with a seed
-    let (secret_key, public_key) = secp.generate_keypair(&mut rng); // Generate a key pair
+    let mnemonic = Mnemonic::parse(mnemonic).map_err(|e| wallet_error(&e.to_string()))?;
+    let seed = Zeroizing::new(mnemonic.to_seed("")); // BIP39 seed without a passphrase, as for the Bitcoin wallet
+    // The network only changes how an xprv is serialized, it has no effect on the derived keys
+    let master = ExtendedPrivKey::new_master(bdk::bitcoin::Network::Bitcoin, seed.as_slice())
+        .map_err(|e| wallet_error(&e.to_string()))?;
+    let path = DerivationPath::from_str(DERIVATION_PATH).map_err(|e| wallet_error(&e.to_string()))?;
+    let secret_key = master
+        .derive_priv(&secp, &path)
+        .map_err(|e| wallet_error(&e.to_string()))?
+        .private_key;
+    let public_key = PublicKey::from_secret_key(&secp, &secret_key); // Get the matching public key
     let public_address = public_key_address(&public_key); // Generate the public address from the public key
-    (secret_key, public_key, public_address) // Return the key pair and public address
+    Ok((secret_key, public_key, public_address)) // Return the key pair and public address
+}
+
+// Function to sign a message the way personal_sign does (EIP-191 version 0x45), returning the
+// 65 byte r || s || v signature as 0x prefixed hex
+pub fn sign_personal_message(secret_key_hex: &str, message: &[u8]) -> Result<String, AppError> {
+    let secret_key = SecretKey::from_str(secret_key_hex.trim_start_matches("0x"))
+        .map_err(|e| wallet_error(&e.to_string()))?;
+    let mut prefixed = format!("\x19Ethereum Signed Message:\n{}", message.len()).into_bytes();
+    prefixed.extend_from_slice(message);
+    let digest = Message::from_slice(&keccak256(&prefixed)).map_err(|e| wallet_error(&e.to_string()))?;
+    let (recovery_id, signature) = Secp256k1::new()
+        .sign_ecdsa_recoverable(&digest, &secret_key)
+        .serialize_compact();
+    let mut bytes = signature.to_vec();
+    bytes.push(27 + recovery_id.to_i32() as u8);
+    Ok(format!("0x{}", hex::encode(bytes)))
+}
+
+// geth's standard scrypt cost (N = 2^18, r = 8, p = 1), what MetaMask and geth expect
+const KEYSTORE_SCRYPT_LOG_N: u8 = 18;
+const KEYSTORE_SCRYPT_R: u32 = 8;
+const KEYSTORE_SCRYPT_P: u32 = 1;
+
+type Aes128Ctr = ctr::Ctr128BE<aes::Aes128>;
+
+// Function to encrypt a hex encoded secret key as a Web3 Secret Storage (V3 keystore) JSON. This
+// takes a few hundred milliseconds and 256 MiB of memory by design, so call it off the async workers.
+pub fn keystore_v3(secret_key_hex: &str, password: &str) -> Result<Value, AppError> {
+    let secret_key = SecretKey::from_str(secret_key_hex.trim_start_matches("0x"))
+        .map_err(|e| wallet_error(&e.to_string()))?;
+    let public_key = PublicKey::from_secret_key(&Secp256k1::new(), &secret_key);
+    let address = public_key_address(&public_key);
+
+    let salt: [u8; 32] = entropy::random_bytes()?;
+    let iv: [u8; 16] = entropy::random_bytes()?;
+    let params = scrypt::Params::new(KEYSTORE_SCRYPT_LOG_N, KEYSTORE_SCRYPT_R, KEYSTORE_SCRYPT_P, 32)
+        .map_err(|e| wallet_error(&e.to_string()))?;
+    let mut derived_key = Zeroizing::new([0u8; 32]);
+    scrypt::scrypt(password.as_bytes(), &salt, &params, derived_key.as_mut_slice())
+        .map_err(|e| wallet_error(&e.to_string()))?;
+
+    // The first half of the derived key encrypts, the second half authenticates the ciphertext
+    let mut ciphertext = secret_key.secret_bytes().to_vec();
+    Aes128Ctr::new_from_slices(&derived_key[..16], &iv)
+        .map_err(|e| wallet_error(&e.to_string()))?
+        .apply_keystream(&mut ciphertext);
+    let mut mac_input = Zeroizing::new(derived_key[16..32].to_vec());
+    mac_input.extend_from_slice(&ciphertext);
+    let mac = keccak256(&mac_input);
+
+    Ok(json!({
+        "version": 3,
+        "id": entropy::uuid()?.to_string(),
+        "address": address.trim_start_matches("0x"),
+        "crypto": {
+            "cipher": "aes-128-ctr",
+            "cipherparams": { "iv": hex::encode(iv) },
+            "ciphertext": hex::encode(ciphertext),
+            "kdf": "scrypt",
+            "kdfparams": {
+                "dklen": 32,
+                "n": 1u32 << KEYSTORE_SCRYPT_LOG_N,
+                "r": KEYSTORE_SCRYPT_R,
+                "p": KEYSTORE_SCRYPT_P,
+                "salt": hex::encode(salt),
+            },
+            "mac": hex::encode(mac),
+        },
+    }))
+}
+
+fn wallet_error(message: &str) -> AppError {
+    AppError::CustomError(format!("Ethereum wallet error: {}", message))
 }
 
 // Function to derive the public address from a public key
@@ -31,8 +143,20 @@ pub fn public_key_address(public_key: &PublicKey) -> String {
     format!("0x{}", hex::encode(&hash[12..])) // Format the last 20 bytes of the hash as a hex string
 }
 
-// Function to get the current time in nanoseconds since the UNIX epoch
-pub fn get_nstime() -> u64 {
-    let dur = SystemTime::now().duration_since(UNIX_EPOCH).unwrap(); // Get the duration since the UNIX epoch
-    dur.as_secs() << 30 | dur.subsec_nanos() as u64 // Combine seconds and nanoseconds into a single u64 value
+// Function to derive the public address from the hex encoded public key stored on a user
+pub fn address_from_public_key(public_key: &str) -> Option<String> {
+    PublicKey::from_str(public_key).ok().map(|public_key| public_key_address(&public_key))
+}
+
+// Function to list the tokens accepted on the configured network
+pub fn supported_tokens() -> &'static [Erc20Token] {
+    match config().network {
+        Network::Mainnet => MAINNET_TOKENS,
+        Network::Devnet => SEPOLIA_TOKENS,
+    }
+}
+
+// Function to look up an accepted token by its symbol
+pub fn find_token(symbol: &str) -> Option<&'static Erc20Token> {
+    supported_tokens().iter().find(|token| token.symbol.eq_ignore_ascii_case(symbol))
 }
@@ -1,25 +1,160 @@
 // solana.rs
+use mongodb::bson::doc;
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
 use serde::Serialize; // Importing serde for serialization
 use solana_sdk::bs58; // Importing bs58 for base58 encoding
-use solana_sdk::signer::keypair::Keypair; // Importing Keypair from solana_sdk for key generation
+use solana_sdk::derivation_path::DerivationPath;
+use solana_sdk::signer::keypair::{
+    generate_seed_from_seed_phrase_and_passphrase, keypair_from_seed_and_derivation_path, Keypair,
+};
 use solana_sdk::signer::Signer; // Importing Signer trait for signing operations
+use zeroize::{Zeroize, Zeroizing};
 
+use crate::config::{config, Network};
 use crate::error_handling::AppError; // Importing custom error handling
+use crate::encryption::{decrypt_field, SecretField};
+use crate::key_wrap::{key_wrapper, open_setting, seal_setting};
+use crate::mongo::{get_settings_collection, User};
+use std::sync::OnceLock;
+
+// Settings document holding the next unused derivation index
+const HD_SETTINGS_ID: &str = "solana_hd_wallets";
+// Settings document holding SOLANA_MASTER_MNEMONIC sealed under KEY_WRAPPER
+const MASTER_MNEMONIC_ID: &str = "solana_master_mnemonic";
+
+static MASTER_MNEMONIC: OnceLock<Zeroizing<String>> = OnceLock::new();
+
+// Function to load the master mnemonic, once, before any wallet is derived. With KEY_WRAPPER set the
+// first start seals SOLANA_MASTER_MNEMONIC into the settings collection, after which it can be removed
+// from the environment. The mnemonic derives every user's Solana wallet without their API key, so
+// keeping it in plaintext in the environment is only allowed on devnet.
+pub async fn init_master_mnemonic() -> Result<(), AppError> {
+    if MASTER_MNEMONIC.get().is_some() {
+        return Ok(());
+    }
+    if key_wrapper().is_none() {
+        if config().network != Network::Devnet {
+            return Err(AppError::CustomError(
+                "KEY_WRAPPER is required outside devnet, SOLANA_MASTER_MNEMONIC derives every user's Solana wallet \
+                 and is only kept in plaintext in the environment for development"
+                    .to_string(),
+            ));
+        }
+        eprintln!(
+            "WARNING: KEY_WRAPPER is not set, SOLANA_MASTER_MNEMONIC is read in plaintext from the environment. \
+             Anyone who can read it can derive every user's Solana wallet. This is only allowed on devnet."
+        );
+    }
+    let configured = config().solana_master_mnemonic.as_deref();
+    let stored = match key_wrapper() {
+        Some(_) => open_setting(MASTER_MNEMONIC_ID).await?,
+        None => None,
+    };
+    let mnemonic = match (stored, configured) {
+        (Some(stored), configured) => {
+            let stored = Zeroizing::new(String::from_utf8(stored.to_vec()).map_err(|_| AppError::DecryptionError)?);
+            // Wallets derived from one can't be found from the other
+            if configured.is_some_and(|configured| configured != stored.as_str()) {
+                return Err(AppError::CustomError(
+                    "SOLANA_MASTER_MNEMONIC differs from the mnemonic stored in Mongo".to_string(),
+                ));
+            }
+            stored
+        }
+        (None, Some(configured)) => {
+            if key_wrapper().is_some() {
+                seal_setting(MASTER_MNEMONIC_ID, configured.as_bytes(), doc! {}).await?;
+                println!(
+                    "Stored the Solana master mnemonic encrypted in Mongo, SOLANA_MASTER_MNEMONIC can be removed \
+                     from the environment"
+                );
+            }
+            Zeroizing::new(configured.to_string())
+        }
+        (None, None) => {
+            return Err(AppError::CustomError(
+                "SOLANA_MASTER_MNEMONIC is required until it is stored in Mongo under a KEY_WRAPPER".to_string(),
+            ))
+        }
+    };
+    let _ = MASTER_MNEMONIC.set(mnemonic);
+    Ok(())
+}
 
 // Define the structure for the response of the Solana wallet generation
 #[derive(Serialize)]
 pub struct SolWalletResponse {
     pub public_key: String,
     pub private_key: String,
+    pub derivation_path: String,
+}
+
+impl Drop for SolWalletResponse {
+    fn drop(&mut self) {
+        self.private_key.zeroize();
+    }
+}
+
+// Function to build the BIP44 path of a user wallet, m/44'/501'/index'
+pub fn derivation_path(index: u32) -> DerivationPath {
+    DerivationPath::new_bip44(Some(index), None)
 }
 
-// Asynchronous function to generate a Solana wallet
-pub(crate) async fn generate_solana_wallet() -> Result<SolWalletResponse, AppError> {
-    let keypair = Keypair::new(); // Generate a new keypair
+// Function to format the path of a user wallet the way wallets display it
+pub fn derivation_path_label(index: u32) -> String {
+    format!("m/44'/501'/{}'", index)
+}
+
+// Function to derive the keypair at `index` from the master mnemonic
+pub fn derive_keypair(index: u32) -> Result<Keypair, AppError> {
+    let mnemonic = MASTER_MNEMONIC
+        .get()
+        .ok_or_else(|| AppError::CustomError("The Solana master mnemonic is not loaded".to_string()))?;
+    let seed = Zeroizing::new(generate_seed_from_seed_phrase_and_passphrase(mnemonic, ""));
+    keypair_from_seed_and_derivation_path(&seed, Some(derivation_path(index)))
+        .map_err(|e| AppError::CustomError(format!("Failed to derive Solana keypair {}: {}", index, e)))
+}
+
+// Function to reserve the next derivation index, indexes are never handed out twice
+pub(crate) async fn next_derivation_index() -> Result<u32, AppError> {
+    let settings = get_settings_collection().await?;
+    let options = FindOneAndUpdateOptions::builder()
+        .upsert(true)
+        .return_document(ReturnDocument::After)
+        .build();
+    let counter = settings
+        .find_one_and_update(doc! { "_id": HD_SETTINGS_ID }, doc! { "$inc": { "next_index": 1_i64 } }, options)
+        .await?
+        .ok_or_else(|| AppError::CustomError("Failed to reserve a derivation index".to_string()))?;
+    let next_index = counter.get_i64("next_index").unwrap_or(1);
+    u32::try_from(next_index - 1).map_err(|_| AppError::CustomError("Derivation indexes exhausted".to_string()))
+}
+
+// Function to derive a user's Solana wallet at the next free index of the master mnemonic
+pub(crate) async fn generate_solana_wallet() -> Result<(u32, SolWalletResponse), AppError> {
+    let index = next_derivation_index().await?;
+    let keypair = derive_keypair(index)?; // Derive the keypair for this index
     let public_key = keypair.pubkey().to_string(); // Get the public key as a string
     let private_key = bs58::encode(keypair.to_bytes()).into_string(); // Encode the private key in base58
-    Ok(SolWalletResponse {
-        public_key,
-        private_key,
-    }) // Return the public and private keys in the response struct
+    Ok((
+        index,
+        SolWalletResponse {
+            public_key,
+            private_key,
+            derivation_path: derivation_path_label(index),
+        },
+    )) // Return the index with the public and private keys
+}
+
+// Function to restore a user's Solana keypair, derived wallets from their index and older random
+// wallets from their encrypted private key, which needs the user's API key
+pub(crate) async fn user_keypair(user: &User, api_key: Option<&str>) -> Result<Keypair, AppError> {
+    if let Some(index) = user.solana_derivation_index {
+        let index = u32::try_from(index).map_err(|_| AppError::DecryptionError)?;
+        return derive_keypair(index);
+    }
+    let api_key = api_key.ok_or(AppError::DecryptionError)?;
+    let private_key = decrypt_field(user, SecretField::SolanaPrivateKey, api_key).await?;
+    let bytes = Zeroizing::new(bs58::decode(private_key.as_str()).into_vec().map_err(|_| AppError::DecryptionError)?);
+    Keypair::from_bytes(&bytes).map_err(|_| AppError::DecryptionError)
 }
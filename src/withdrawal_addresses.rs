@@ -0,0 +1,69 @@
+// withdrawal_addresses.rs
+// Where the exchange sends the SOL bought for a deposit. Kraken only withdraws to addresses added under
+// Funding > Withdraw, each under a description: the withdrawal key. The keys are registered in the
+// `withdrawal_keys` collection and a user can be mapped to one with `withdrawal_key`. Everyone else
+// withdraws through the registered key marked default, or KRAKEN_WITHDRAWAL_KEY when none is.
+use crate::config::config;
+use crate::error_handling::AppError;
+use crate::mongo::{get_withdrawal_keys_collection, User, WithdrawalKey};
+use mongodb::bson::{doc, DateTime as BsonDateTime};
+use mongodb::Collection;
+
+// The withdrawal key the user's SOL goes through
+pub async fn for_user(users_collection: &Collection<User>, user_id: i64) -> Result<WithdrawalKey, AppError> {
+    let user_key = users_collection
+        .find_one(doc! { "user_id": user_id }, None)
+        .await?
+        .and_then(|user| user.withdrawal_key);
+    match user_key {
+        Some(key) => get_withdrawal_keys_collection()
+            .await?
+            .find_one(doc! { "_id": &key }, None)
+            .await?
+            .ok_or_else(|| {
+                AppError::CustomError(format!("Withdrawal key `{}` of user {} is not registered", key, user_id))
+            }),
+        None => default_key().await?.ok_or_else(|| {
+            AppError::CustomError(
+                "No default withdrawal key, register one with POST /admin/withdrawal_keys or set KRAKEN_WITHDRAWAL_KEY"
+                    .to_string(),
+            )
+        }),
+    }
+}
+
+// The registered key marked default, or the one from the configuration
+pub async fn default_key() -> Result<Option<WithdrawalKey>, AppError> {
+    if let Some(key) = get_withdrawal_keys_collection()
+        .await?
+        .find_one(doc! { "default": true }, None)
+        .await?
+    {
+        return Ok(Some(key));
+    }
+    Ok(config()
+        .kraken_withdrawal_key
+        .clone()
+        .zip(config().kraken_withdrawal_address.clone())
+        .map(|(key, address)| WithdrawalKey { key, address, default: true, created_at: BsonDateTime::now() }))
+}
+
+// Every withdrawal key in use: the registered ones and the configured fallback
+pub async fn all_keys() -> Result<Vec<WithdrawalKey>, AppError> {
+    let mut cursor = get_withdrawal_keys_collection().await?.find(doc! {}, None).await?;
+    let mut keys = Vec::new();
+    while cursor.advance().await? {
+        keys.push(cursor.deserialize_current()?);
+    }
+    if let (Some(key), Some(address)) = (&config().kraken_withdrawal_key, &config().kraken_withdrawal_address) {
+        if !keys.iter().any(|registered| &registered.key == key) {
+            keys.push(WithdrawalKey {
+                key: key.clone(),
+                address: address.clone(),
+                default: !keys.iter().any(|registered| registered.default),
+                created_at: BsonDateTime::now(),
+            });
+        }
+    }
+    Ok(keys)
+}
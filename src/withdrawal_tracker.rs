@@ -0,0 +1,211 @@
+// withdrawal_tracker.rs
+// Follows the SOL withdrawn for a deposit from the exchange to the bot wallet. The lockin swap is only
+// scheduled once the exchange reports the withdrawal as sent and its Solana transaction is confirmed,
+// so it never runs against SOL that hasn't arrived. Pending withdrawals are read from their
+// transaction documents every poll cycle, so tracking carries on across restarts. A swap claimed by a
+// process that died before it settled is dead-lettered once it is INTERRUPTED_SWAP_MINS old.
+use crate::alerts::send_alert;
+use crate::config::config;
+use crate::error_handling::AppError;
+use crate::mongo::get_transactions_collection;
+use crate::poller::{dead_letter_interrupted_swap, run_lockin_swap, PipelineClients};
+use crate::request_id;
+use crate::transaction_state::{TransactionState, TransactionStateMachine};
+use crate::units::sol_to_lamports;
+use mongodb::bson::{doc, oid::ObjectId, Bson, DateTime as BsonDateTime, Document};
+use solana_sdk::pubkey::Pubkey;
+use std::str::FromStr;
+use tokio::task::spawn;
+
+// No swap runs this long, one still unsettled after it was claimed was interrupted
+const INTERRUPTED_SWAP_MINS: i64 = 60;
+
+// Where and how a transaction's withdrawn SOL is swapped
+struct PendingSwap {
+    user_id: i64,
+    swap_to: Pubkey,
+    amount_lamports: u64,
+    slippage_bps: u16,
+}
+
+impl PendingSwap {
+    fn of(tx_id: ObjectId, tx: &Document, withdrawal: &Document) -> Result<Self, AppError> {
+        let user_id = match tx.get("user_id") {
+            Some(Bson::Int32(user_id)) => *user_id as i64,
+            Some(Bson::Int64(user_id)) => *user_id,
+            _ => return Err(AppError::CustomError(format!("Transaction {} has no user_id", tx_id))),
+        };
+        let swap_to = Pubkey::from_str(withdrawal.get_str("swap_to").unwrap_or_default())
+            .map_err(|_| AppError::CustomError(format!("Transaction {} has no swap address", tx_id)))?;
+        Ok(PendingSwap {
+            user_id,
+            swap_to,
+            amount_lamports: sol_to_lamports(withdrawal.get_f64("amount_received").unwrap_or_default()),
+            slippage_bps: withdrawal.get_i32("slippage_bps").unwrap_or_default() as u16,
+        })
+    }
+}
+
+// Checks every withdrawal still on its way to the bot wallet
+pub async fn track_withdrawals(clients: &PipelineClients) -> Result<(), AppError> {
+    let transactions_collection = get_transactions_collection().await?;
+    let mut cursor = transactions_collection
+        .find(
+            doc! {
+                "state": TransactionState::Withdrawn.as_str(),
+                "withdrawal.refid": { "$type": "string" },
+                "withdrawal.arrived_at": { "$exists": false },
+            },
+            None,
+        )
+        .await?;
+    let mut pending = Vec::new();
+    while cursor.advance().await? {
+        pending.push(cursor.deserialize_current()?);
+    }
+
+    for tx in pending {
+        if let Err(e) = track_withdrawal(clients, &tx).await {
+            eprintln!("Tracking the withdrawal of transaction {:?} failed: {}", tx.get_object_id("_id"), e.report());
+        }
+    }
+    recover_interrupted_swaps().await
+}
+
+async fn track_withdrawal(clients: &PipelineClients, tx: &Document) -> Result<(), AppError> {
+    let transactions_collection = get_transactions_collection().await?;
+    let tx_id = tx
+        .get_object_id("_id")
+        .map_err(|_| AppError::CustomError("Transaction document is missing _id".to_string()))?;
+    let withdrawal = tx.get_document("withdrawal").map_err(|_| {
+        AppError::CustomError(format!("Transaction {} has no withdrawal", tx_id))
+    })?;
+    let refid = withdrawal.get_str("refid").unwrap_or_default();
+    let state_machine = TransactionStateMachine::new(transactions_collection.clone(), tx_id);
+
+    let status = clients.exchange.get_withdrawal_status("SOL", refid).await?;
+    let status_name = status.as_ref().and_then(|status| status["status"].as_str()).unwrap_or("Unknown");
+    let txid = status.as_ref().and_then(|status| status["txid"].as_str()).filter(|txid| !txid.is_empty());
+
+    if status_name == "Failure" {
+        let reason = format!("Withdrawal {} failed on {}, the SOL is back on the exchange", refid, clients.exchange.name());
+        state_machine.fail(&reason).await?;
+        send_alert(&format!("Transaction {}: {}", tx_id, reason)).await;
+        return Ok(());
+    }
+
+    let arrived = match (status_name, txid) {
+        ("Success", Some(txid)) => match clients.lockin_client()?.signature_status(txid).await? {
+            Some(true) => true,
+            Some(false) => {
+                let reason = format!("Withdrawal {} transaction {} failed on Solana", refid, txid);
+                state_machine.fail(&reason).await?;
+                send_alert(&format!("Transaction {}: {}", tx_id, reason)).await;
+                return Ok(());
+            }
+            None => false,
+        },
+        _ => false,
+    };
+
+    if !arrived {
+        alert_if_overdue(tx_id, withdrawal, refid, status_name).await?;
+        return Ok(());
+    }
+
+    // Marking the arrival claims the swap, so it is scheduled once even with overlapping cycles
+    let claimed = transactions_collection
+        .update_one(
+            doc! {
+                "_id": tx_id,
+                "state": TransactionState::Withdrawn.as_str(),
+                "withdrawal.arrived_at": { "$exists": false },
+            },
+            doc! { "$set": { "withdrawal.arrived_at": BsonDateTime::now(), "withdrawal.txid": txid } },
+            None,
+        )
+        .await?;
+    if claimed.modified_count != 1 {
+        return Ok(());
+    }
+
+    let swap = PendingSwap::of(tx_id, tx, withdrawal)?;
+    println!("Withdrawal {} of transaction {} arrived, scheduling the swap", refid, tx_id);
+
+    let clients = clients.clone();
+    spawn(request_id::scope(request_id::generate(), async move {
+        run_lockin_swap(&clients, Some(tx_id), swap.user_id, swap.swap_to, swap.amount_lamports, swap.slippage_bps).await;
+    }));
+    Ok(())
+}
+
+// Dead-letters swaps whose arrival was claimed but that never settled, because the process died
+// between the claim and the swap or during it. The claim keeps the tracker from scheduling them again,
+// so they would otherwise stay Withdrawn forever. A re-driven swap puts its transaction back in
+// Withdrawn, which restarts the clock.
+async fn recover_interrupted_swaps() -> Result<(), AppError> {
+    let cutoff = BsonDateTime::from_millis(BsonDateTime::now().timestamp_millis() - INTERRUPTED_SWAP_MINS * 60_000);
+    let mut cursor = get_transactions_collection()
+        .await?
+        .find(
+            doc! {
+                "state": TransactionState::Withdrawn.as_str(),
+                "withdrawal.arrived_at": { "$lt": cutoff },
+                "state_updated_at": { "$lt": cutoff },
+            },
+            None,
+        )
+        .await?;
+    let mut interrupted = Vec::new();
+    while cursor.advance().await? {
+        interrupted.push(cursor.deserialize_current()?);
+    }
+
+    for tx in interrupted {
+        let Ok(tx_id) = tx.get_object_id("_id") else {
+            continue;
+        };
+        let result = match tx.get_document("withdrawal") {
+            Ok(withdrawal) => match PendingSwap::of(tx_id, &tx, withdrawal) {
+                Ok(swap) => {
+                    dead_letter_interrupted_swap(tx_id, swap.user_id, swap.swap_to, swap.amount_lamports, swap.slippage_bps)
+                        .await
+                }
+                Err(e) => Err(e),
+            },
+            Err(_) => Err(AppError::CustomError(format!("Transaction {} has no withdrawal", tx_id))),
+        };
+        if let Err(e) = result {
+            eprintln!("Recovering the interrupted swap of transaction {} failed: {}", tx_id, e.report());
+        }
+    }
+    Ok(())
+}
+
+// Alerts the operator once about a withdrawal that has taken longer than WITHDRAWAL_ARRIVAL_TIMEOUT_MINS
+async fn alert_if_overdue(
+    tx_id: ObjectId,
+    withdrawal: &Document,
+    refid: &str,
+    status_name: &str,
+) -> Result<(), AppError> {
+    let Ok(withdrawn_at) = withdrawal.get_datetime("withdrawn_at") else {
+        return Ok(());
+    };
+    let timeout_mins = config().withdrawal_arrival_timeout_mins;
+    let overdue = BsonDateTime::now().timestamp_millis() - withdrawn_at.timestamp_millis()
+        > timeout_mins as i64 * 60_000;
+    if !overdue || withdrawal.get_bool("overdue_alerted").unwrap_or(false) {
+        return Ok(());
+    }
+    get_transactions_collection()
+        .await?
+        .update_one(doc! { "_id": tx_id }, doc! { "$set": { "withdrawal.overdue_alerted": true } }, None)
+        .await?;
+    send_alert(&format!(
+        "Withdrawal {} of transaction {} hasn't reached the bot wallet after {} minutes (exchange status: {})",
+        refid, tx_id, timeout_mins, status_name
+    ))
+    .await;
+    Ok(())
+}